@@ -0,0 +1,267 @@
+//! Access logging: one record for every request a connection answers.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// What is logged about one request.
+#[derive(Debug, Clone)]
+pub struct AccessRecord<'a> {
+    pub method: &'a str,             // Request method, after any override
+    pub path: &'a str,               // Request path, without the query
+    pub status: u16,                 // Status code of the response
+    pub bytes: usize,                // Length of the response body
+    pub duration: Duration,          // From the request's first byte to the response being written
+    pub remote_ip: Option<IpAddr>,   // The client's address, if the socket has one
+    pub request_id: Option<&'a str>, // The request's `X-Request-Id` header
+}
+
+impl AccessRecord<'_> {
+    /// Render the record as a single-line JSON object.
+    ///
+    /// The fields are `method`, `path`, `status`, `bytes`, `duration_ms`,
+    /// `remote_ip` and `request_id`, the last two being `null` when unknown.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"method\":");
+        push_json_string(&mut json, self.method);
+        json.push_str(",\"path\":");
+        push_json_string(&mut json, self.path);
+        let _ = write!(
+            json,
+            ",\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\"remote_ip\":",
+            self.status,
+            self.bytes,
+            self.duration.as_secs_f64() * 1000.0
+        );
+        match self.remote_ip {
+            Some(ip) => push_json_string(&mut json, &ip.to_string()),
+            None => json.push_str("null"),
+        }
+        json.push_str(",\"request_id\":");
+        match self.request_id {
+            Some(id) => push_json_string(&mut json, id),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Receives an `AccessRecord` for every request a connection answers.
+///
+/// Set one as `Config::access_log` to turn access logging on. It is called
+/// from the worker thread serving the connection, after the response has
+/// been written.
+pub trait RequestLogger: Send + Sync {
+    /// Log one request.
+    fn log(&self, record: &AccessRecord<'_>);
+}
+
+/// Logs each request as a short human-readable line on stdout, such as
+/// `GET /index.html 200 1024B 1.2ms`.
+#[derive(Debug, Default)]
+pub struct StdoutLogger;
+
+impl RequestLogger for StdoutLogger {
+    fn log(&self, record: &AccessRecord<'_>) {
+        println!(
+            "{} {} {} {}B {:.1?}",
+            record.method, record.path, record.status, record.bytes, record.duration
+        );
+    }
+}
+
+/// Logs each request as one JSON object per line, for log pipelines.
+///
+/// See `AccessRecord::to_json` for the fields.
+pub struct JsonLogger {
+    output: Mutex<Box<dyn Write + Send>>, // Where the lines go
+}
+
+impl JsonLogger {
+    /// Write the log lines to `output`.
+    pub fn new(output: impl Write + Send + 'static) -> JsonLogger {
+        JsonLogger {
+            output: Mutex::new(Box::new(output)),
+        }
+    }
+
+    /// Write the log lines to stdout.
+    pub fn stdout() -> JsonLogger {
+        JsonLogger::new(io::stdout())
+    }
+}
+
+impl RequestLogger for JsonLogger {
+    fn log(&self, record: &AccessRecord<'_>) {
+        let mut line = record.to_json();
+        line.push('\n');
+
+        // A line that can't be written is dropped rather than failing the
+        // request it describes. The lock is only poisoned if a writer
+        // panicked, and the writer itself is still usable then.
+        let mut output = self.output.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = output.write_all(line.as_bytes());
+        let _ = output.flush();
+    }
+}
+
+// Append `value` to `json` as a quoted JSON string.
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, iter::Peekable, str::Chars, sync::Arc};
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        String(String),
+        Number(f64),
+        Null,
+    }
+
+    // Parse a JSON object of strings, numbers and nulls, as `to_json`
+    // writes, panicking on anything that isn't valid JSON.
+    fn parse_object(json: &str) -> HashMap<String, Value> {
+        let mut chars = json.chars().peekable();
+        let mut object = HashMap::new();
+        assert_eq!(chars.next(), Some('{'));
+        loop {
+            let name = parse_string(&mut chars);
+            assert_eq!(chars.next(), Some(':'));
+            let value = match chars.peek() {
+                Some('"') => Value::String(parse_string(&mut chars)),
+                Some('n') => {
+                    let null: String = chars.by_ref().take(4).collect();
+                    assert_eq!(null, "null");
+                    Value::Null
+                }
+                _ => {
+                    let mut number = String::new();
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '.') {
+                        number.push(c);
+                        chars.next();
+                    }
+                    Value::Number(number.parse().unwrap())
+                }
+            };
+            assert!(object.insert(name, value).is_none(), "a repeated field");
+            match chars.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                other => panic!("unexpected {other:?} in {json}"),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {json}");
+        object
+    }
+
+    fn parse_string(chars: &mut Peekable<Chars<'_>>) -> String {
+        assert_eq!(chars.next(), Some('"'));
+        let mut string = String::new();
+        loop {
+            match chars.next().expect("an unterminated string") {
+                '"' => return string,
+                '\\' => match chars.next().unwrap() {
+                    'n' => string.push('\n'),
+                    'r' => string.push('\r'),
+                    't' => string.push('\t'),
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let code = u32::from_str_radix(&hex, 16).unwrap();
+                        string.push(char::from_u32(code).unwrap());
+                    }
+                    c @ ('"' | '\\' | '/') => string.push(c),
+                    c => panic!("invalid escape \\{c}"),
+                },
+                c if c.is_control() => panic!("unescaped control character {c:?}"),
+                c => string.push(c),
+            }
+        }
+    }
+
+    // Lines a `JsonLogger` wrote, shared with the test.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record<'a>(path: &'a str, request_id: Option<&'a str>) -> AccessRecord<'a> {
+        AccessRecord {
+            method: "GET",
+            path,
+            status: 200,
+            bytes: 1024,
+            duration: Duration::from_micros(1500),
+            remote_ip: Some([127, 0, 0, 1].into()),
+            request_id,
+        }
+    }
+
+    #[test]
+    fn each_request_is_logged_as_a_line_of_valid_json() {
+        let captured = Captured::default();
+        let logger = JsonLogger::new(captured.clone());
+        logger.log(&record("/index.html", Some("abc-123")));
+        logger.log(&AccessRecord {
+            remote_ip: None,
+            ..record("/", None)
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().map(parse_object).collect();
+        assert_eq!(lines.len(), 2);
+
+        let first = &lines[0];
+        assert_eq!(first["method"], Value::String("GET".into()));
+        assert_eq!(first["path"], Value::String("/index.html".into()));
+        assert_eq!(first["status"], Value::Number(200.0));
+        assert_eq!(first["bytes"], Value::Number(1024.0));
+        assert_eq!(first["duration_ms"], Value::Number(1.5));
+        assert_eq!(first["remote_ip"], Value::String("127.0.0.1".into()));
+        assert_eq!(first["request_id"], Value::String("abc-123".into()));
+
+        assert_eq!(lines[1]["remote_ip"], Value::Null);
+        assert_eq!(lines[1]["request_id"], Value::Null);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let path = "/a \"quoted\" \\ path\n\t\u{1}é";
+        let json = record(path, Some("}{")).to_json();
+
+        let object = parse_object(&json);
+        assert_eq!(object["path"], Value::String(path.into()));
+        assert_eq!(object["request_id"], Value::String("}{".into()));
+    }
+}
@@ -0,0 +1,148 @@
+//! Operator endpoints for adjusting a running server.
+//!
+//! They are only answered when `Config::admin_token` is set, and only to
+//! requests that send it as `Authorization: Bearer <token>`.
+
+use crate::{
+    config::Config, error_page, method::Method, request::Request, response::Response, ThreadPool,
+};
+
+/// Where the pool endpoint is served.
+pub const POOL_PATH: &str = "/admin/pool";
+
+/// Answer a request for `POOL_PATH`.
+///
+/// `GET` reports the pool's size and how many of its workers are running,
+/// `HEAD` gets the same headers without the report, and
+/// `POST /admin/pool?size=N` resizes the pool to `N` workers with
+/// `ThreadPool::resize`. Without an admin token in `config` the endpoint
+/// doesn't exist and this answers 404; a missing or wrong token gets 401.
+pub fn pool(request: &Request, config: &Config, pool: &ThreadPool) -> Response {
+    let Some(token) = &config.admin_token else {
+        return error_page::render(Some(request), 404);
+    };
+    if !is_authorized(request, token) {
+        return error_page::render(Some(request), 401).with_header("WWW-Authenticate", "Bearer");
+    }
+
+    match request.method() {
+        Method::Get | Method::Head => {}
+        Method::Post => {
+            let size = request
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|param| param.strip_prefix("size="))
+                .and_then(|size| size.parse::<usize>().ok())
+                .filter(|&size| size > 0);
+            match size {
+                Some(size) => pool.resize(size),
+                None => return error_page::render(Some(request), 400),
+            }
+        }
+        _ => return error_page::render(Some(request), 405).with_header("Allow", "GET, HEAD, POST"),
+    }
+
+    let response = Response::new(200)
+        .with_header("Content-Type", "text/plain; charset=utf-8")
+        .with_body(format!(
+            "size={}\nrunning={}\n",
+            pool.size(),
+            pool.started_workers()
+        ));
+
+    // HEAD gets the same headers as GET, but no body.
+    if *request.method() == Method::Head {
+        let length = response.body().len();
+        return response
+            .with_header("Content-Length", length.to_string())
+            .with_body(Vec::new());
+    }
+    response
+}
+
+// Whether the request carries `token` as a bearer token. The comparison
+// takes as long for a near miss as for a wild guess, so the token can't be
+// recovered one byte at a time by timing the answers.
+fn is_authorized(request: &Request, token: &str) -> bool {
+    let Some(sent) = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn ask(method: &str, target: &str, token: Option<&str>, pool: &ThreadPool) -> Response {
+        let config = Config {
+            admin_token: Some("secret".to_string()),
+            ..Config::default()
+        };
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {token}\r\n"))
+            .unwrap_or_default();
+        let head = format!("{method} {target} HTTP/1.1\r\nHost: localhost\r\n{authorization}\r\n");
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        super::pool(&request, &config, pool)
+    }
+
+    #[test]
+    fn posting_a_size_resizes_the_pool() {
+        let pool = ThreadPool::new(1);
+        let response = ask("POST", "/admin/pool?size=3", Some("secret"), &pool);
+        assert_eq!(response.status(), 200);
+        assert_eq!(pool.size(), 3);
+        assert!(response.body().starts_with(b"size=3\n"));
+
+        let response = ask("GET", POOL_PATH, Some("secret"), &pool);
+        assert_eq!(response.status(), 200);
+        for target in ["/admin/pool?size=0", "/admin/pool?size=lots", POOL_PATH] {
+            assert_eq!(ask("POST", target, Some("secret"), &pool).status(), 400);
+        }
+        assert_eq!(pool.size(), 3);
+    }
+
+    #[test]
+    fn the_endpoint_needs_the_token() {
+        let pool = ThreadPool::new(1);
+        for token in [None, Some("wrong"), Some("secre")] {
+            let response = ask("POST", "/admin/pool?size=3", token, &pool);
+            assert_eq!(response.status(), 401);
+            assert_eq!(response.header("WWW-Authenticate"), Some("Bearer"));
+        }
+        assert_eq!(pool.size(), 1);
+
+        // Without a token configured, there is no endpoint at all.
+        let head = "GET /admin/pool HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        assert_eq!(
+            super::pool(&request, &Config::default(), &pool).status(),
+            404
+        );
+    }
+
+    #[test]
+    fn head_gets_the_report_headers_without_the_report() {
+        let pool = ThreadPool::new(2);
+        let get = ask("GET", POOL_PATH, Some("secret"), &pool);
+        let head = ask("HEAD", POOL_PATH, Some("secret"), &pool);
+
+        assert_eq!(head.status(), 200);
+        assert!(head.body().is_empty());
+        let length = get.body().len().to_string();
+        assert_eq!(head.header("Content-Length"), Some(length.as_str()));
+        assert_eq!(head.header("Content-Type"), get.header("Content-Type"));
+    }
+}
@@ -0,0 +1,327 @@
+//! Assembling a whole server, listener, pool, configuration and handler,
+//! in one place.
+
+use std::{io, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use crate::{
+    access_log::RequestLogger,
+    config::{Config, SharedConfig},
+    error_page,
+    handler::Handler,
+    listener,
+    request::{BodyReader, Request},
+    response::Response,
+    root_page::{self, RootPage},
+    server::{DrainReport, Server},
+    shutdown::{ShutdownToken, Waker},
+    static_files::StaticFiles,
+    ThreadPool,
+};
+
+/// How long open connections get to finish once shutdown begins, unless
+/// `ServerBuilder::shutdown_grace` says otherwise.
+pub const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Configures and creates an `App`, a server ready to run.
+///
+/// Everything starts from `Config::default()`, or from the `config` given,
+/// and without a handler the app serves the files under the static root,
+/// and the root page, if there is one, for `/`:
+///
+/// ```no_run
+/// use web_server::prelude::*;
+///
+/// let router = Router::new()
+///     .route("GET", "/", |_request: &Request| Response::new(200).with_body("Hello!"));
+///
+/// ServerBuilder::new()
+///     .addr("0.0.0.0:8080".parse().unwrap())
+///     .workers(8)
+///     .handler(router)
+///     .build()
+///     .unwrap()
+///     .run();
+/// ```
+pub struct ServerBuilder {
+    config: Config,                    // Settings for the listener, pool and connections
+    handler: Option<Box<dyn Handler>>, // Serves the requests; `None` serves static files
+    shutdown_grace: Duration,          // How long `run` lets connections finish
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            handler: None,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Start configuring a server with the default configuration.
+    pub fn new() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// Start from `config` instead, e.g. one read with `Config::from_file`.
+    /// Settings made before this are replaced.
+    pub fn config(mut self, config: Config) -> ServerBuilder {
+        self.config = config;
+        self
+    }
+
+    /// Listen on `addr`. Port 0 picks a free port, which
+    /// `App::local_addr` tells.
+    pub fn addr(mut self, addr: SocketAddr) -> ServerBuilder {
+        self.config.addr = addr;
+        self
+    }
+
+    /// Run `workers` threads in the pool.
+    pub fn workers(mut self, workers: usize) -> ServerBuilder {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Close a keep-alive connection after `timeout` without a request.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// Serve static files from `root` when no handler is set.
+    pub fn static_root(mut self, root: impl Into<PathBuf>) -> ServerBuilder {
+        self.config.static_root = root.into();
+        self
+    }
+
+    /// Answer `/` with `body`, served as `content_type`, without needing
+    /// any file for it. This wins over a file for `/` in the static root.
+    pub fn root_page(
+        mut self,
+        content_type: impl Into<String>,
+        body: impl Into<Vec<u8>>,
+    ) -> ServerBuilder {
+        self.config.root_page = Some(RootPage::new(content_type, body));
+        self
+    }
+
+    /// Log every request to `logger`.
+    pub fn access_log(mut self, logger: impl RequestLogger + 'static) -> ServerBuilder {
+        self.config.access_log = Some(Arc::new(logger));
+        self
+    }
+
+    /// Serve requests with `handler`, e.g. a `Router`.
+    pub fn handler(mut self, handler: impl Handler + 'static) -> ServerBuilder {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Give open connections `grace` to finish once shutdown begins. See
+    /// `Server::shutdown_graceful`.
+    pub fn shutdown_grace(mut self, grace: Duration) -> ServerBuilder {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Bind the listener and start the pool.
+    ///
+    /// The listener is a Unix domain socket if the configuration names one,
+    /// and TCP otherwise, with `Config::accept_threads` listeners sharing
+    /// the address. Fails if it can't be bound.
+    ///
+    /// # Panics
+    ///
+    /// The `build` function will panic if the worker count or the number of
+    /// accept threads is zero.
+    pub fn build(self) -> io::Result<App> {
+        let config = self.config;
+
+        let (server, local_addr) = match &config.unix_socket {
+            #[cfg(unix)]
+            Some(path) => {
+                let listener = listener::bind_unix(path, config.backlog)?;
+                (Server::new().unix_listener(listener), None)
+            }
+            _ => {
+                let listeners =
+                    listener::bind_many(config.addr, config.backlog, config.accept_threads)?;
+                let local_addr = listeners[0].local_addr()?;
+                let server = listeners.into_iter().fold(Server::new(), Server::listener);
+                (server, Some(local_addr))
+            }
+        };
+
+        let handler = match self.handler {
+            Some(handler) => handler,
+            None => {
+                let files = StaticFiles::from_config(&config);
+                let root_page = config.root_page.clone();
+                Box::new(move |request: &Request| match &root_page {
+                    Some(page) if request.path() == root_page::PATH => page.serve(request),
+                    _ => files
+                        .serve(request)
+                        .unwrap_or_else(|| error_page::render(Some(request), 404)),
+                })
+            }
+        };
+
+        Ok(App {
+            pool: ThreadPool::new(config.workers),
+            server,
+            local_addr,
+            config: Arc::new(SharedConfig::new(config)),
+            handler: BoxedHandler(handler),
+            shutdown_grace: self.shutdown_grace,
+        })
+    }
+}
+
+/// A server with its listener bound and its pool started, made by
+/// `ServerBuilder`.
+pub struct App {
+    server: Server,                 // The listener
+    local_addr: Option<SocketAddr>, // Where the listener is bound, for TCP
+    pool: ThreadPool,               // Runs the connections
+    config: Arc<SharedConfig>,      // Shared by every connection
+    handler: BoxedHandler,          // Serves the requests
+    shutdown_grace: Duration,       // How long connections get to finish
+}
+
+impl App {
+    /// The address the TCP listener is bound to, or `None` for a Unix
+    /// domain socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// The configuration the server runs with, to `reload` or to trigger
+    /// its `shutdown` token from another thread.
+    pub fn config(&self) -> &Arc<SharedConfig> {
+        &self.config
+    }
+
+    /// Serve until the configuration's shutdown token is triggered, then
+    /// shut down gracefully.
+    pub fn run(self) -> DrainReport {
+        let App {
+            server,
+            pool,
+            config,
+            handler,
+            shutdown_grace,
+            ..
+        } = self;
+
+        server.run(&pool, Arc::clone(&config), handler);
+        server.shutdown_graceful(pool, &config, shutdown_grace)
+    }
+
+    /// Serve until `token` is triggered, then shut down gracefully.
+    ///
+    /// This is `run` for a token owned elsewhere, e.g. one that also stops
+    /// other parts of the program.
+    pub fn run_until_shutdown(self, token: &ShutdownToken) -> DrainReport {
+        let shutdown = self.config.load().shutdown.clone();
+        let waker: Waker = Arc::new(move || shutdown.trigger());
+        let _watch = token.watch_idle(&waker);
+
+        self.run()
+    }
+}
+
+// A boxed handler, so `App` can hold whichever handler it was given.
+struct BoxedHandler(Box<dyn Handler>);
+
+impl Handler for BoxedHandler {
+    fn handle(&self, request: &Request) -> Response {
+        self.0.handle(request)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        self.0.streams_body(request)
+    }
+
+    fn handle_stream(&self, request: &Request, body: &mut BodyReader<'_>) -> Response {
+        self.0.handle_stream(request, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use super::*;
+
+    // Send one request to `app` while `run` runs it on another thread, then
+    // stop it with `stop` and return the response and the drain report.
+    fn serve_once(
+        app: App,
+        path: &str,
+        stop: impl FnOnce(),
+        run: impl FnOnce(App) -> DrainReport + Send + 'static,
+    ) -> (String, DrainReport) {
+        let addr = app.local_addr().unwrap();
+        let running = thread::spawn(move || run(app));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        stop();
+        (response, running.join().unwrap())
+    }
+
+    fn on_any_port() -> ServerBuilder {
+        ServerBuilder::new()
+            .addr("127.0.0.1:0".parse().unwrap())
+            .workers(2)
+            .shutdown_grace(Duration::from_secs(5))
+    }
+
+    #[test]
+    fn a_built_app_serves_its_handler_until_shutdown() {
+        let app = on_any_port()
+            .handler(|_: &Request| Response::new(200).with_body("built"))
+            .build()
+            .unwrap();
+
+        let config = Arc::clone(app.config());
+        let stop = || config.load().shutdown.trigger();
+
+        let (response, report) = serve_once(app, "/", stop, App::run);
+        assert!(response.ends_with("\r\n\r\nbuilt"), "{response}");
+        assert_eq!(report.forced_connections, 0);
+        assert!(report.pool.is_clean());
+    }
+
+    #[test]
+    fn an_app_without_a_handler_serves_static_files_until_its_token_fires() {
+        let root = std::env::temp_dir().join(format!("web_server-app-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("hello.txt"), b"from disk").unwrap();
+        let app = on_any_port().static_root(&root).build().unwrap();
+
+        let token = ShutdownToken::new();
+        let stopper = token.clone();
+
+        let (response, report) = serve_once(
+            app,
+            "/hello.txt",
+            || stopper.trigger(),
+            move |app| app.run_until_shutdown(&token),
+        );
+        assert!(response.ends_with("\r\n\r\nfrom disk"), "{response}");
+        assert_eq!(report.forced_connections, 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
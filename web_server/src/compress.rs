@@ -0,0 +1,377 @@
+//! Compression of response bodies, for clients that accept it.
+
+use crate::{
+    deflate, error_page,
+    handler::Handler,
+    method::Method,
+    mime, negotiate,
+    request::{BodyReader, Request},
+    response::Response,
+};
+
+/// Bodies smaller than this are sent as they are, unless
+/// `Compress::min_size` says otherwise.
+pub const DEFAULT_MIN_SIZE: usize = 1024;
+
+// The codings responses can be compressed with, best first.
+const SUPPORTED: &[&str] = &["gzip"];
+
+/// Wraps a handler to gzip its text responses for clients whose
+/// `Accept-Encoding` allows it.
+///
+/// The coding is picked with `negotiate::preferred_encoding`, so quality
+/// values, `*` and `identity` are honoured. A client that refuses both
+/// `identity` and every coding supported is answered 406 without reaching
+/// the handler.
+///
+/// Only successful responses are compressed, and only those with a body of
+/// at least the minimum size, a text `Content-Type`, and no
+/// `Content-Encoding` of their own. Partial content is left alone, since
+/// its range is of the uncompressed body. A compressed response's strong
+/// `ETag` gets `-gzip` added, so it doesn't match the uncompressed one, and
+/// the suffix is taken off again in `If-None-Match` and `If-Range` before
+/// the handler compares them with its own tag; a 304 gets back the tag
+/// the client sent. A HEAD request gets the `Content-Encoding` and
+/// `Content-Length` a GET would, without the body, which is why the handler
+/// is asked for a GET response to measure it.
+/// Responses that could be compressed get `Vary: Accept-Encoding`, whether
+/// they are or not, so caches keep the two apart.
+///
+/// ```no_run
+/// use web_server::{compress::Compress, prelude::*};
+///
+/// let router = Router::new().route("GET", "/report", |_request: &Request| {
+///     Response::new(200)
+///         .with_header("Content-Type", "text/csv")
+///         .with_body("date,visits\n".repeat(1000))
+/// });
+///
+/// let site = Compress::new(router).min_size(512);
+/// ```
+pub struct Compress<H> {
+    inner: H,        // The handler whose responses are compressed
+    min_size: usize, // Smallest body compressed
+}
+
+impl<H: Handler> Compress<H> {
+    /// Wrap `inner`, compressing bodies of at least `DEFAULT_MIN_SIZE`.
+    pub fn new(inner: H) -> Compress<H> {
+        Compress {
+            inner,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    /// Only compress bodies of at least `min_size` bytes, below which
+    /// saving a few bytes isn't worth the time.
+    pub fn min_size(mut self, min_size: usize) -> Compress<H> {
+        self.min_size = min_size;
+        self
+    }
+
+    // Compress `response` with `coding`, if it is worth it. A response to
+    // HEAD has no body to compress, so `as_get` answers `request` as a GET
+    // for the body a GET would get, and only its headers are kept.
+    fn compress(
+        &self,
+        request: &Request,
+        coding: &str,
+        mut response: Response,
+        as_get: impl FnOnce(&Request) -> Response,
+    ) -> Response {
+        if !is_compressible(&response) {
+            return response;
+        }
+        vary(&mut response, "Accept-Encoding");
+        if coding == "identity" {
+            return response;
+        }
+
+        if *request.method() == Method::Head {
+            let length = response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(response.body().len());
+            if length < self.min_size {
+                return response;
+            }
+            let mut full = as_get(&request.with_method(Method::Get));
+            if !is_compressible(&full) || full.body().len() < self.min_size {
+                return response;
+            }
+            vary(&mut full, "Accept-Encoding");
+            let mut compressed = encode(coding, full);
+            let length = compressed.body().len();
+            compressed.set_header("Content-Length", length.to_string());
+            return compressed.with_body(Vec::new());
+        }
+
+        if response.body().len() < self.min_size {
+            return response;
+        }
+        encode(coding, response)
+    }
+}
+
+// Compress the body of `response` with `coding`, and mark it as such.
+fn encode(coding: &str, mut response: Response) -> Response {
+    let body = deflate::gzip(response.body());
+    response.set_header("Content-Encoding", coding);
+    if response.header("Content-Length").is_some() {
+        response.set_header("Content-Length", body.len().to_string());
+    }
+    if let Some(etag) = response.header("ETag").filter(|etag| etag.starts_with('"')) {
+        let etag = format!("{}-{coding}\"", etag.trim_end_matches('"'));
+        response.set_header("ETag", etag);
+    }
+    response.with_body(body)
+}
+
+// `request` with the coding suffix that `encode` adds taken off the entity
+// tags in its `If-None-Match` and `If-Range`, so the handler compares them
+// with its own. `None` if none of them have one.
+fn without_coding_suffixes(request: &Request) -> Option<Request> {
+    let suffixed = ["If-None-Match", "If-Range"].iter().any(|name| {
+        request.header(name).is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| strip_suffix(tag.trim()).is_some())
+        })
+    });
+    if !suffixed {
+        return None;
+    }
+
+    let mut request = request.with_method(request.method().clone());
+    for name in ["If-None-Match", "If-Range"] {
+        request.map_header(name, |value| {
+            value
+                .split(',')
+                .map(|tag| strip_suffix(tag.trim()).unwrap_or_else(|| tag.trim().to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+    }
+    Some(request)
+}
+
+// The strong entity tag `tag` without the suffix of a supported coding, or
+// `None` if it has none.
+fn strip_suffix(tag: &str) -> Option<String> {
+    let opaque = tag.strip_prefix('"')?.strip_suffix('"')?;
+    SUPPORTED.iter().find_map(|coding| {
+        let base = opaque.strip_suffix(coding)?.strip_suffix('-')?;
+        Some(format!("\"{base}\""))
+    })
+}
+
+// A 304 confirms the tag the client holds, so one for a client holding the
+// compressed version gets the suffixed tag back.
+fn restore_suffix(request: &Request, mut response: Response) -> Response {
+    if response.status() != 304 {
+        return response;
+    }
+    let Some(etag) = response.header("ETag").map(str::to_string) else {
+        return response;
+    };
+    let held = request
+        .header("If-None-Match")
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find(|tag| strip_suffix(tag).as_deref() == Some(etag.as_str()));
+    if let Some(held) = held {
+        response.set_header("ETag", held);
+    }
+    response
+}
+
+// Whether `response` is one that would be compressed for a client that
+// wants it.
+fn is_compressible(response: &Response) -> bool {
+    let media_type = response
+        .header("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim());
+
+    matches!(response.status(), 200..=299)
+        && !matches!(response.status(), 204 | 206)
+        && response.header("Content-Encoding").is_none()
+        && media_type.is_some_and(mime::is_text)
+}
+
+// Add `name` to the response's `Vary` header.
+fn vary(response: &mut Response, name: &str) {
+    let value = match response.header("Vary") {
+        Some(existing) => format!("{existing}, {name}"),
+        None => name.to_string(),
+    };
+    response.set_header("Vary", value);
+}
+
+// The coding to answer `request` with, or `None` if it accepts none.
+fn negotiate(request: &Request) -> Option<&'static str> {
+    negotiate::preferred_encoding(request.header("Accept-Encoding"), SUPPORTED)
+}
+
+impl<H: Handler> Handler for Compress<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(coding) = negotiate(request) else {
+            return error_page::render(Some(request), 406);
+        };
+        let stripped = without_coding_suffixes(request);
+        let inner_request = stripped.as_ref().unwrap_or(request);
+        let response = self.inner.handle(inner_request);
+        let response = self.compress(inner_request, coding, response, |get| {
+            self.inner.handle(get)
+        });
+        restore_suffix(request, response)
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        negotiate(request).is_some() && self.inner.streams_body(request)
+    }
+
+    fn handle_stream(&self, request: &Request, body: &mut BodyReader<'_>) -> Response {
+        let Some(coding) = negotiate(request) else {
+            return error_page::render(Some(request), 406);
+        };
+        let stripped = without_coding_suffixes(request);
+        let inner_request = stripped.as_ref().unwrap_or(request);
+        let response = self.inner.handle_stream(inner_request, body);
+        // The body is spent, and a GET in place of a HEAD carries none.
+        let response = self.compress(inner_request, coding, response, |get| {
+            self.inner.handle(get)
+        });
+        restore_suffix(request, response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::Limits, router::Router};
+
+    const TEXT: &str = "date,visits\n";
+
+    fn request(method: &str, headers: &str) -> Request {
+        let head = format!("{method} /report HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    // A report with the tag `"v1"`, and a 304 for a client holding it. The
+    // `If-Range` it sees is echoed back.
+    fn report(request: &Request) -> Response {
+        let held = request.header("If-None-Match").unwrap_or("");
+        if held.split(',').any(|tag| tag.trim() == "\"v1\"") {
+            return Response::new(304).with_header("ETag", "\"v1\"");
+        }
+        let mut response = Response::new(200)
+            .with_header("Content-Type", "text/csv")
+            .with_header("ETag", "\"v1\"")
+            .with_body(TEXT.repeat(1000));
+        if let Some(if_range) = request.header("If-Range") {
+            response.set_header("X-If-Range", if_range);
+        }
+        response
+    }
+
+    // The body of a gzip member, decompressed.
+    fn gunzip(member: &[u8]) -> Vec<u8> {
+        deflate::inflate(&member[10..member.len() - 8], usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn a_client_accepting_gzip_gets_a_compressed_body() {
+        let site = Compress::new(report);
+        let response = site.handle(&request("GET", "Accept-Encoding: gzip;q=0.5, br;q=1.0\r\n"));
+
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("ETag"), Some("\"v1-gzip\""));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.body().len() < TEXT.len() * 1000);
+        assert_eq!(gunzip(response.body()), TEXT.repeat(1000).as_bytes());
+    }
+
+    #[test]
+    fn identity_is_sent_unless_the_client_forbids_it() {
+        let site = Compress::new(report);
+        let plain = site.handle(&request("GET", "Accept-Encoding: br\r\n"));
+        assert_eq!(plain.header("Content-Encoding"), None);
+        assert_eq!(plain.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(plain.body(), TEXT.repeat(1000).as_bytes());
+
+        let refused = site.handle(&request(
+            "GET",
+            "Accept-Encoding: identity;q=0, gzip;q=0\r\n",
+        ));
+        assert_eq!(refused.status(), 406);
+    }
+
+    #[test]
+    fn small_and_non_text_bodies_are_left_alone() {
+        let small = Compress::new(|_: &Request| {
+            Response::new(200)
+                .with_header("Content-Type", "text/plain")
+                .with_body("tiny")
+        });
+        let response = small.handle(&request("GET", "Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body(), b"tiny");
+
+        let image = Compress::new(|_: &Request| {
+            Response::new(200)
+                .with_header("Content-Type", "image/png")
+                .with_body(vec![0; 4096])
+        });
+        let response = image.handle(&request("GET", "Accept-Encoding: gzip\r\n"));
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.header("Vary"), None);
+    }
+
+    #[test]
+    fn the_gzip_suffix_is_stripped_from_conditional_headers() {
+        let site = Compress::new(report);
+
+        let headers = "Accept-Encoding: gzip\r\nIf-None-Match: \"v0\", \"v1-gzip\"\r\n";
+        let cached = site.handle(&request("GET", headers));
+        assert_eq!(cached.status(), 304);
+        assert_eq!(cached.header("ETag"), Some("\"v1-gzip\""));
+
+        let stale = site.handle(&request(
+            "GET",
+            "Accept-Encoding: gzip\r\nIf-None-Match: \"v0-gzip\"\r\n",
+        ));
+        assert_eq!(stale.status(), 200);
+
+        let headers = "Accept-Encoding: gzip\r\nIf-Range: \"v1-gzip\"\r\n";
+        let response = site.handle(&request("GET", headers));
+        assert_eq!(response.header("X-If-Range"), Some("\"v1\""));
+
+        // Weak tags never get the suffix, so they are left as they are.
+        let headers = "Accept-Encoding: gzip\r\nIf-Range: W/\"v1-gzip\"\r\n";
+        let response = site.handle(&request("GET", headers));
+        assert_eq!(response.header("X-If-Range"), Some("W/\"v1-gzip\""));
+    }
+
+    #[test]
+    fn head_gets_the_headers_of_a_compressed_get_without_the_body() {
+        let site = Compress::new(Router::new().route("GET", "/report", report));
+        let get = site.handle(&request("GET", "Accept-Encoding: gzip\r\n"));
+        let head = site.handle(&request("HEAD", "Accept-Encoding: gzip\r\n"));
+
+        assert_eq!(head.status(), 200);
+        assert!(head.body().is_empty());
+        assert_eq!(head.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(head.header("ETag"), get.header("ETag"));
+        assert_eq!(head.header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(
+            head.header("Content-Length"),
+            Some(get.body().len().to_string().as_str())
+        );
+
+        let plain = site.handle(&request("HEAD", ""));
+        assert_eq!(plain.header("Content-Encoding"), None);
+        let length = (TEXT.len() * 1000).to_string();
+        assert_eq!(plain.header("Content-Length"), Some(length.as_str()));
+    }
+}
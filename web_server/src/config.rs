@@ -0,0 +1,604 @@
+use std::{
+    fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    access_log::{JsonLogger, RequestLogger, StdoutLogger},
+    maintenance::Maintenance,
+    memory::MemoryBudget,
+    metrics::Metrics,
+    request::Limits,
+    root_page::RootPage,
+    shutdown::ShutdownToken,
+    tracker::ConnectionTracker,
+};
+
+/// Server configuration.
+///
+/// Every field has a sensible default, so callers usually start from
+/// `Config::default()` and only override what they need.
+#[derive(Clone)]
+pub struct Config {
+    pub addr: SocketAddr, // Address the listener binds to
+    pub workers: usize,   // Number of threads in the pool
+    pub backlog: i32,     // Maximum number of pending connections queued by the OS
+
+    // How many listeners share `addr` with `SO_REUSEPORT`, each accepting
+    // on its own thread. Off Linux there is just one.
+    pub accept_threads: usize,
+
+    // How many new connections are accepted per second, in bursts of up to
+    // that many; `None` accepts them as fast as they arrive.
+    pub max_accept_rate: Option<NonZeroU32>,
+
+    // On Unix, a socket file to listen on instead of `addr`.
+    pub unix_socket: Option<PathBuf>,
+
+    // How long an idle keep-alive connection may wait for its next request.
+    // Must not be zero, which sockets refuse as a read timeout.
+    pub keep_alive_timeout: Duration,
+    // How many requests a single connection may send before it is closed.
+    pub keep_alive_max: usize,
+    // How many connections may be open at once, counting those waiting for
+    // a worker; any more are answered 503 and closed. `None` sets no limit.
+    pub max_connections: Option<usize>,
+    // How many keep-alive connections may wait for their next request at
+    // once. Past it, the one idle longest is closed to make room for the
+    // next; zero turns keep-alive off. `None` sets no limit.
+    pub max_idle_connections: Option<usize>,
+    // Bytes each connection buffers as it reads requests. Bigger buffers
+    // take fewer reads for large heads and pipelined requests, at the cost
+    // of memory per open connection. Requests longer than the buffer are
+    // read fine either way.
+    pub read_buffer_size: usize,
+    // Bytes the status line and header fields of a response may take. A
+    // response with more, which only a handler bug would produce, is
+    // swapped for a 500.
+    pub max_response_header_size: usize,
+
+    pub static_root: PathBuf, // Directory static files are served from
+    pub charset: String,      // Charset advertised for static text files
+
+    // Sent as the `Cache-Control` header of static files; `None` sends
+    // none. A file whose name matches one of the rules' patterns gets that
+    // rule's value instead, the first rule winning. See
+    // `StaticFiles::with_cache_rule`.
+    pub cache_control: Option<String>,
+    pub cache_rules: Vec<(String, String)>,
+    pub limits: Limits, // Limits applied while reading requests
+
+    // Served for `/` from memory, ahead of any file for it in the static
+    // root; `None` leaves `/` to the files.
+    pub root_page: Option<RootPage>,
+
+    // Host names requests may be addressed to; anything else gets 400.
+    // `None` accepts any host.
+    pub allowed_hosts: Option<Vec<String>>,
+
+    // Whether a POST may name the method to route as, e.g. with
+    // `X-HTTP-Method-Override: DELETE`.
+    pub method_override: bool,
+
+    // Whether `/favicon.ico` is answered by the server itself.
+    pub serve_favicon: bool,
+    // Icon file served at `/favicon.ico`; `None` answers 204 No Content.
+    pub favicon: Option<PathBuf>,
+
+    // Sent as the `Server` header unless the handler set one; `None` sends
+    // no header at all.
+    pub server_header: Option<String>,
+
+    // Checked before routing; answers 503 while maintenance mode is on.
+    pub maintenance: Maintenance,
+    // Bounds the request and response bodies buffered across connections.
+    pub memory: MemoryBudget,
+    // Counts the open and idle connections the limits above apply to.
+    pub connections: ConnectionTracker,
+    // Receives a record of every request answered; `None` logs nothing.
+    pub access_log: Option<Arc<dyn RequestLogger>>,
+    // Requests taking longer than this to answer are logged as slow, apart
+    // from the access log; `None` logs none as slow.
+    pub slow_request_threshold: Option<Duration>,
+    // Bearer token the admin endpoints require; `None` turns them off.
+    pub admin_token: Option<String>,
+    // Counts every request answered, served at `/metrics`; `None` neither
+    // counts nor serves them.
+    pub metrics: Option<Metrics>,
+    // Triggered to make connections stop once their current request is done.
+    pub shutdown: ShutdownToken,
+}
+
+/// The default `Server` header, the crate's name and version, e.g.
+/// `web_server/0.1.0`.
+pub const DEFAULT_SERVER_HEADER: &str =
+    concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7878)),
+            workers: 4,
+            backlog: 128,
+            accept_threads: 1,
+            max_accept_rate: None,
+            unix_socket: None,
+            keep_alive_timeout: Duration::from_secs(5),
+            keep_alive_max: 100,
+            max_connections: None,
+            max_idle_connections: None,
+            read_buffer_size: 8 * 1024,
+            max_response_header_size: 64 * 1024,
+            static_root: PathBuf::from("./util"),
+            charset: String::from("utf-8"),
+            cache_control: None,
+            cache_rules: Vec::new(),
+            limits: Limits::default(),
+            root_page: None,
+            allowed_hosts: None,
+            method_override: false,
+            serve_favicon: true,
+            favicon: None,
+            server_header: Some(DEFAULT_SERVER_HEADER.to_string()),
+            maintenance: Maintenance::default(),
+            memory: MemoryBudget::default(),
+            connections: ConnectionTracker::default(),
+            access_log: None,
+            slow_request_threshold: None,
+            admin_token: None,
+            metrics: None,
+            shutdown: ShutdownToken::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Read a configuration file, starting from the defaults.
+    ///
+    /// The file holds one `key = value` setting per line, and lines starting
+    /// with `#` are comments. The recognised keys are `addr`, `unix_socket`,
+    /// `workers`, `backlog`, `accept_threads`, `max_accept_rate`
+    /// (connections per second), `static_root`, `charset`, `cache_control`,
+    /// where `off` sends none, `cache_rule`, a file name pattern and the
+    /// value for it, which may be given more than once,
+    /// `keep_alive_timeout` (in seconds),
+    /// `keep_alive_max`, `max_connections`, `max_idle_connections`,
+    /// `read_buffer_size`, `max_response_header_size`, `max_body_size`,
+    /// `max_drain_size`, `max_line_length` and `max_uri_length` (in bytes),
+    /// `max_headers`, `allowed_hosts` (a comma-separated list),
+    /// `method_override`, `serve_favicon` and `strict_line_endings` (`true`
+    /// or `false`), `favicon`, `max_buffered_bytes`, the memory budget
+    /// shared by all connections, `server_header`, where `off` sends none,
+    /// `access_log`, which is `off`, `stdout` for plain lines or `json` for
+    /// one JSON object per line on stdout, `slow_request_threshold` (in
+    /// milliseconds), where `off` logs none, `admin_token`, and `metrics`
+    /// (`true` or `false`).
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        let mut config = Config::default();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {message}", index + 1),
+                )
+            };
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid("expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            match key {
+                "addr" => config.addr = value.parse().map_err(|_| invalid("invalid address"))?,
+                "unix_socket" => config.unix_socket = Some(PathBuf::from(value)),
+                "workers" => {
+                    config.workers = value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "backlog" => {
+                    config.backlog = value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "accept_threads" => {
+                    let threads = value.parse().map_err(|_| invalid("invalid number"))?;
+                    if threads == 0 {
+                        return Err(invalid("at least one accept thread is needed"));
+                    }
+                    config.accept_threads = threads;
+                }
+                "max_accept_rate" => {
+                    let rate = value.parse().map_err(|_| invalid("invalid number"))?;
+                    let rate = NonZeroU32::new(rate)
+                        .ok_or_else(|| invalid("the accept rate must be at least 1"))?;
+                    config.max_accept_rate = Some(rate);
+                }
+                "static_root" => config.static_root = PathBuf::from(value),
+                "charset" => config.charset = value.to_string(),
+                "cache_control" => {
+                    config.cache_control = match value {
+                        "off" => None,
+                        _ => Some(value.to_string()),
+                    }
+                }
+                "cache_rule" => {
+                    let (pattern, cache_control) = value
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| invalid("expected a pattern and a value"))?;
+                    config
+                        .cache_rules
+                        .push((pattern.to_string(), cache_control.trim().to_string()));
+                }
+                "keep_alive_timeout" => {
+                    let secs = value.parse().map_err(|_| invalid("invalid number"))?;
+                    if secs == 0 {
+                        return Err(invalid("the keep-alive timeout must be at least 1 second"));
+                    }
+                    config.keep_alive_timeout = Duration::from_secs(secs);
+                }
+                "keep_alive_max" => {
+                    config.keep_alive_max = value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "read_buffer_size" => {
+                    let size = value.parse().map_err(|_| invalid("invalid number"))?;
+                    if size == 0 {
+                        return Err(invalid("the read buffer can't be empty"));
+                    }
+                    config.read_buffer_size = size;
+                }
+                "max_response_header_size" => {
+                    config.max_response_header_size =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "max_body_size" => {
+                    config.limits.max_body_size =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "max_drain_size" => {
+                    config.limits.max_drain_size =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "max_line_length" => {
+                    config.limits.max_line_length =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "max_uri_length" => {
+                    config.limits.max_uri_length =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "max_headers" => {
+                    config.limits.max_headers =
+                        value.parse().map_err(|_| invalid("invalid number"))?
+                }
+                "allowed_hosts" => {
+                    config.allowed_hosts = Some(
+                        value
+                            .split(',')
+                            .map(|host| host.trim().to_string())
+                            .filter(|host| !host.is_empty())
+                            .collect(),
+                    )
+                }
+                "max_connections" => {
+                    config.max_connections =
+                        Some(value.parse().map_err(|_| invalid("invalid number"))?)
+                }
+                "max_idle_connections" => {
+                    config.max_idle_connections =
+                        Some(value.parse().map_err(|_| invalid("invalid number"))?)
+                }
+                "max_buffered_bytes" => {
+                    let ceiling = value.parse().map_err(|_| invalid("invalid number"))?;
+                    config.memory = MemoryBudget::new(ceiling);
+                }
+                "method_override" => {
+                    config.method_override =
+                        value.parse().map_err(|_| invalid("invalid boolean"))?
+                }
+                "serve_favicon" => {
+                    config.serve_favicon = value.parse().map_err(|_| invalid("invalid boolean"))?
+                }
+                "strict_line_endings" => {
+                    config.limits.strict_line_endings =
+                        value.parse().map_err(|_| invalid("invalid boolean"))?
+                }
+                "favicon" => config.favicon = Some(PathBuf::from(value)),
+                "server_header" => {
+                    config.server_header = match value {
+                        "off" => None,
+                        _ => Some(value.to_string()),
+                    }
+                }
+                "access_log" => {
+                    config.access_log = match value {
+                        "off" => None,
+                        "stdout" => Some(Arc::new(StdoutLogger)),
+                        "json" => Some(Arc::new(JsonLogger::stdout())),
+                        _ => return Err(invalid("expected `off`, `stdout` or `json`")),
+                    }
+                }
+                "slow_request_threshold" => {
+                    config.slow_request_threshold = match value {
+                        "off" => None,
+                        _ => {
+                            let millis = value.parse().map_err(|_| invalid("invalid number"))?;
+                            Some(Duration::from_millis(millis))
+                        }
+                    }
+                }
+                "admin_token" => config.admin_token = Some(value.to_string()),
+                "metrics" => {
+                    let on: bool = value.parse().map_err(|_| invalid("invalid boolean"))?;
+                    config.metrics = on.then(Metrics::new);
+                }
+                _ => return Err(invalid(&format!("unknown setting `{key}`"))),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// A `Config` shared by every connection that can be replaced while the
+/// server is running.
+///
+/// Connections take a fresh snapshot with `load` for each request, so a
+/// `reload` takes effect on the next request without disturbing requests
+/// that are already in flight.
+pub struct SharedConfig {
+    current: RwLock<Arc<Config>>, // The snapshot handed out by `load`
+}
+
+impl SharedConfig {
+    /// Share `config` between connections.
+    pub fn new(config: Config) -> SharedConfig {
+        SharedConfig {
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    /// A snapshot of the current configuration.
+    pub fn load(&self) -> Arc<Config> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Swap in a new configuration.
+    ///
+    /// Only some settings can change while the server runs:
+    /// `max_accept_rate`, `static_root`, `charset`, `cache_control`,
+    /// `cache_rules`, `keep_alive_timeout`, `keep_alive_max`,
+    /// `max_connections`, `max_idle_connections`,
+    /// `read_buffer_size`, which applies to new connections,
+    /// `max_response_header_size`, `limits`, `root_page`, `allowed_hosts`,
+    /// `method_override`, `serve_favicon`, `favicon`, `server_header`,
+    /// `access_log`, `slow_request_threshold`, `admin_token` and `metrics`
+    /// are taken from `config`, though metrics already being collected
+    /// carry on counting from where they were rather than starting again
+    /// from zero. The listener and pool are already built, so `addr`,
+    /// `unix_socket`, `workers`, `backlog` and `accept_threads` keep their
+    /// current values. So do `maintenance`, whose switch may have been
+    /// flipped at runtime, `memory`, which counts the bodies already in
+    /// flight, `connections`, which counts the connections already open,
+    /// and `shutdown`, which connections are watching.
+    pub fn reload(&self, mut config: Config) {
+        let mut current = self.current.write().unwrap();
+
+        config.addr = current.addr;
+        config.unix_socket = current.unix_socket.clone();
+        config.workers = current.workers;
+        config.backlog = current.backlog;
+        config.accept_threads = current.accept_threads;
+        config.maintenance = current.maintenance.clone();
+        config.memory = current.memory.clone();
+        config.connections = current.connections.clone();
+        config.shutdown = current.shutdown.clone();
+        if config.metrics.is_some() && current.metrics.is_some() {
+            config.metrics = current.metrics.clone();
+        }
+
+        *current = Arc::new(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // Read `contents` as a configuration file.
+    fn parse(contents: &str) -> io::Result<Config> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "web_server-config-{}-{}.conf",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, contents).unwrap();
+        let config = Config::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    // The error reading `contents` fails with.
+    fn parse_err(contents: &str) -> io::Error {
+        match parse(contents) {
+            Ok(_) => panic!("{contents:?} was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn backlog_defaults_to_128() {
+        assert_eq!(Config::default().backlog, 128);
+    }
+
+    #[test]
+    fn backlog_is_read_from_the_file() {
+        let config = parse("# A bigger queue for bursts\nbacklog = 1024\n").unwrap();
+        assert_eq!(config.backlog, 1024);
+    }
+
+    #[test]
+    fn invalid_backlog_is_rejected_with_its_line() {
+        let err = parse_err("workers = 2\nbacklog = lots\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 2:"), "{err}");
+    }
+
+    #[test]
+    fn keep_alive_settings_are_read_from_the_file() {
+        let config = parse("keep_alive_timeout = 30\nkeep_alive_max = 7\n").unwrap();
+        assert_eq!(config.keep_alive_timeout, Duration::from_secs(30));
+        assert_eq!(config.keep_alive_max, 7);
+    }
+
+    #[test]
+    fn a_zero_keep_alive_timeout_is_rejected() {
+        let err = parse_err("keep_alive_timeout = 0\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("line 1:"), "{err}");
+    }
+
+    #[test]
+    fn a_reload_swaps_in_the_reloadable_settings_only() {
+        let shared = SharedConfig::new(Config::default());
+        let before = shared.load();
+
+        let mut config = parse("charset = latin1\nkeep_alive_max = 3\nworkers = 64\n").unwrap();
+        config.addr = "127.0.0.1:1".parse().unwrap();
+        shared.reload(config);
+
+        let after = shared.load();
+        assert_eq!(after.charset, "latin1");
+        assert_eq!(after.keep_alive_max, 3);
+        assert_eq!(after.addr, before.addr);
+        assert_eq!(after.workers, before.workers);
+
+        // Whoever loaded the old configuration keeps it.
+        assert_eq!(before.charset, "utf-8");
+    }
+
+    #[test]
+    fn max_headers_defaults_to_100_and_is_read_from_the_file() {
+        assert_eq!(Config::default().limits.max_headers, 100);
+        assert_eq!(parse("max_headers = 20\n").unwrap().limits.max_headers, 20);
+    }
+
+    #[test]
+    fn the_server_header_can_be_replaced_or_turned_off() {
+        let config = parse("server_header = custom/2\n").unwrap();
+        assert_eq!(config.server_header.as_deref(), Some("custom/2"));
+        let config = parse("server_header = off\n").unwrap();
+        assert_eq!(config.server_header, None);
+        let config = parse("").unwrap();
+        assert_eq!(config.server_header.as_deref(), Some(DEFAULT_SERVER_HEADER));
+    }
+
+    #[test]
+    fn the_accept_rate_must_be_at_least_one() {
+        let config = parse("max_accept_rate = 200\n").unwrap();
+        assert_eq!(config.max_accept_rate.map(NonZeroU32::get), Some(200));
+        let err = parse_err("max_accept_rate = 0\n");
+        assert!(err.to_string().contains("at least 1"), "{err}");
+    }
+
+    #[test]
+    fn strict_line_endings_can_be_turned_on() {
+        assert!(!parse("").unwrap().limits.strict_line_endings);
+        let config = parse("strict_line_endings = true\n").unwrap();
+        assert!(config.limits.strict_line_endings);
+    }
+
+    #[test]
+    fn read_buffer_size_is_read_but_cant_be_zero() {
+        assert_eq!(Config::default().read_buffer_size, 8 * 1024);
+        assert_eq!(
+            parse("read_buffer_size = 65536").unwrap().read_buffer_size,
+            65536
+        );
+        let err = parse_err("read_buffer_size = 0");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn connection_limits_are_off_unless_set() {
+        let config = Config::default();
+        assert_eq!(config.max_connections, None);
+        assert_eq!(config.max_idle_connections, None);
+
+        let config = parse("max_connections = 500\nmax_idle_connections = 0").unwrap();
+        assert_eq!(config.max_connections, Some(500));
+        assert_eq!(config.max_idle_connections, Some(0));
+        assert_eq!(
+            parse_err("max_connections = many").kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn metrics_are_turned_on_by_the_file_and_survive_a_reload() {
+        assert!(Config::default().metrics.is_none());
+        assert!(parse("metrics = false").unwrap().metrics.is_none());
+        let config = parse("metrics = true").unwrap();
+        config.metrics.as_ref().unwrap().record(200, Duration::ZERO);
+
+        let shared = SharedConfig::new(config);
+        shared.reload(parse("metrics = true").unwrap());
+        assert_eq!(shared.load().metrics.as_ref().unwrap().requests(200), 1);
+
+        shared.reload(Config::default());
+        assert!(shared.load().metrics.is_none());
+    }
+
+    #[test]
+    fn cache_control_and_its_rules_are_read_from_the_file() {
+        let config = parse(
+            "cache_control = public, max-age=3600\n\
+             cache_rule = *.*.js public, max-age=31536000, immutable\n\
+             cache_rule = *.html no-cache\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.cache_control.as_deref(),
+            Some("public, max-age=3600")
+        );
+        assert_eq!(
+            config.cache_rules,
+            [
+                (
+                    String::from("*.*.js"),
+                    String::from("public, max-age=31536000, immutable")
+                ),
+                (String::from("*.html"), String::from("no-cache")),
+            ]
+        );
+
+        assert_eq!(parse("cache_control = off\n").unwrap().cache_control, None);
+        let err = parse_err("cache_rule = *.js\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn the_slow_request_threshold_is_read_in_milliseconds() {
+        assert_eq!(Config::default().slow_request_threshold, None);
+        let config = parse("slow_request_threshold = 250\n").unwrap();
+        assert_eq!(
+            config.slow_request_threshold,
+            Some(Duration::from_millis(250))
+        );
+        let off = parse("slow_request_threshold = off\n").unwrap();
+        assert_eq!(off.slow_request_threshold, None);
+
+        let err = parse_err("slow_request_threshold = slow\n");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
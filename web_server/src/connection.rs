@@ -0,0 +1,1678 @@
+use std::{
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    access_log::AccessRecord,
+    config::{Config, SharedConfig},
+    error::HttpError,
+    error_page,
+    handler::Handler,
+    memory::Reservation,
+    method::Method,
+    request::{BodyReader, Limits, Request},
+    response::Response,
+    shutdown::Waker,
+    trace,
+};
+
+/// The methods listed in the answer to `OPTIONS *`.
+pub const SERVER_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Serve requests arriving on `stream` until the connection should close.
+///
+/// Each request is passed to `handler` and its response written back. The
+/// connection stays open between requests as long as the client wants
+/// keep-alive and `Config::keep_alive_max` hasn't been reached, unless the
+/// response is one of the errors that `closes_connection` lists. The
+/// configuration is loaded afresh for every request, so a reload applies from
+/// the next request on.
+///
+/// The stream can be anything readable and writable. Timeouts are the
+/// caller's job, e.g. via `TcpStream::set_read_timeout`; a read that times
+/// out ends the connection.
+///
+/// Once `Config::shutdown` is triggered, the request being served is
+/// answered with `Connection: close` and no further request is read.
+pub fn handle_connection<S, H>(stream: S, shared: &SharedConfig, handler: &H)
+where
+    S: Read + Write,
+    H: Handler + ?Sized,
+{
+    handle_connection_from(stream, &Peer::default(), shared, handler);
+}
+
+/// What is known about a connection besides its stream.
+#[derive(Clone, Default)]
+pub struct Peer {
+    pub remote_ip: Option<IpAddr>, // The client's address, shown in the access log
+    pub waker: Option<Waker>,      // Interrupts blocked reads and writes on the stream
+}
+
+/// Serve requests arriving on `stream` from `peer`.
+///
+/// This is `handle_connection` for callers that know more about the other
+/// end. Its address shows up in the access log, and with a waker, a
+/// connection idling between requests is closed as soon as shutdown is
+/// triggered rather than when its read times out, and a graceful shutdown
+/// can cut off one that is still busy when it runs out of time.
+pub fn handle_connection_from<S, H>(stream: S, peer: &Peer, shared: &SharedConfig, handler: &H)
+where
+    S: Read + Write,
+    H: Handler + ?Sized,
+{
+    let shutdown = shared.load().shutdown.clone();
+    let _open = shutdown.track_connection(peer.waker.clone());
+    serve_connection(stream, peer, shared, handler);
+}
+
+// Serve a connection that is already counted as open, by the accept loop
+// tracking it as soon as it was accepted.
+pub(crate) fn serve_connection<S, H>(stream: S, peer: &Peer, shared: &SharedConfig, handler: &H)
+where
+    S: Read + Write,
+    H: Handler + ?Sized,
+{
+    let _span = trace::connection(peer.remote_ip);
+    let capacity = shared.load().read_buffer_size.max(1);
+    let mut reader = BufReader::with_capacity(capacity, stream);
+    let mut served = 0;
+
+    loop {
+        let config = shared.load();
+        let max_requests = config.keep_alive_max.max(1);
+
+        // Wait for the first byte of the next request. Browsers often open
+        // a spare connection and close it again without sending anything,
+        // and a client done with a connection may shut down its writing
+        // half while still reading, so a clean end of stream here is a
+        // normal close, not an error. The same goes for the client going
+        // idle for too long or resetting the connection between requests:
+        // there is nobody left to answer.
+        //
+        // Once shutdown has begun, no new request is waited for.
+        if config.shutdown.is_triggered() || !wait_for_request(&mut reader, &config, peer, served) {
+            return;
+        }
+        let started = Instant::now();
+
+        // A buffered body's reservation is held until the response is out.
+        // Without one, the body is left on the connection for the handler.
+        let (mut request, request_memory) = match read_request(&mut reader, &config, handler) {
+            Ok(read) => read,
+            Err(err) => {
+                respond_to_error(reader.get_mut(), &err, &config);
+                return;
+            }
+        };
+
+        if config.method_override {
+            request.apply_method_override();
+        }
+        let _request_span = trace::request(&request);
+
+        // Keep the connection open only if the client asked for it and this
+        // wasn't the last request it is allowed on this connection.
+        served += 1;
+        let remaining = max_requests.saturating_sub(served);
+        let mut keep_alive = wants_keep_alive(&request) && remaining > 0;
+
+        // A streamed body is still on the connection, in the way of the next
+        // request, until it has been read to its end.
+        let streamed = request_memory.is_none();
+        let mut body_unread = streamed && request.has_body();
+        // Whether the request ended where the next bytes on the connection
+        // begin, which switching protocols relies on.
+        let mut body_finished = true;
+
+        // Requests addressed to a host we don't serve are refused outright.
+        // `OPTIONS *` asks about the server rather than a resource, so it is
+        // answered here without routing, and so is `CONNECT`, which asks for
+        // a tunnel. Maintenance mode is checked before any other request
+        // reaches the handler.
+        let mut response = if !host_allowed(&request, &config) {
+            error_page::render(Some(&request), 400)
+        } else if request.is_server_wide() {
+            Response::new(204).with_header("Allow", SERVER_METHODS)
+        } else if *request.method() == Method::Connect {
+            // Tunnels aren't supported. The client may start sending the
+            // tunnel's data straight away, so the connection can't be used
+            // for another request either.
+            keep_alive = false;
+            body_finished = false;
+            error_page::render(Some(&request), 501).with_header("Allow", SERVER_METHODS)
+        } else if let Some(response) = config.maintenance.check(&request) {
+            response
+        } else if streamed {
+            match stream_body(&mut reader, &request, &config, handler) {
+                Ok((response, finished)) => {
+                    body_unread = false;
+                    keep_alive &= finished;
+                    body_finished = finished;
+                    response
+                }
+                Err(err) => {
+                    respond_to_error(reader.get_mut(), &err, &config);
+                    return;
+                }
+            }
+        } else {
+            handler.handle(&request)
+        };
+        // A streamed body that never reached the handler is skipped too,
+        // unless there's too much of it. Either way the response stands, so
+        // a broken body just closes the connection.
+        if body_unread {
+            let skipped = BodyReader::new(&mut reader, &request, &config.limits)
+                .and_then(|mut body| skip_body(&mut body, &config.limits));
+            if !matches!(skipped, Ok(true)) {
+                keep_alive = false;
+                body_finished = false;
+            }
+        }
+
+        // Whichever handler answered a HEAD request, the response goes out
+        // without its body, or the client would read the body as the start
+        // of the next response.
+        if *request.method() == Method::Head {
+            response = response.without_body();
+        }
+
+        // A response body that doesn't fit in the memory budget is swapped
+        // for a 503, which frees it straight away.
+        let response_memory = config.memory.reserve(response.body().len());
+        if response_memory.is_none() {
+            response = error_page::render(Some(&request), 503);
+        }
+
+        // A head too big to send safely, which only a handler bug would
+        // produce, is swapped for a 500 too, before the client sees any of
+        // it.
+        let head_len = response.head_len();
+        if head_len > config.max_response_header_size {
+            let limit = config.max_response_header_size;
+            println!("The response's header fields take {head_len} bytes, over the {limit} allowed; answering 500.");
+            trace::error("the response's header fields are too large", &head_len);
+            response = error_page::render(Some(&request), 500);
+        }
+
+        // Switching protocols only makes sense if the client asked to, and
+        // once the request's body is no longer in the way.
+        let on_upgrade = response.take_upgrade();
+        let upgradable = request.version() == "HTTP/1.1"
+            && request.has_connection_token("upgrade")
+            && request.header("Upgrade").is_some();
+        if on_upgrade.is_some() && !(upgradable && body_finished) {
+            println!("The handler switched protocols without an upgrade request; answering 500.");
+            response = error_page::render(Some(&request), 500);
+        }
+        if let Some(on_upgrade) = on_upgrade.filter(|_| response.status() == 101) {
+            set_server_header(&mut response, &config);
+            let written = response.write_to(reader.get_mut());
+            log_request(&config, &request, &response, started, peer);
+            match written {
+                Ok(()) => on_upgrade(&mut Upgraded(&mut reader)),
+                Err(err) => trace::debug("failed to write a response", &err),
+            }
+            return;
+        }
+
+        // The handler can end the connection too, by answering with
+        // `Connection: close` or an error that leaves the connection in
+        // doubt, and so can shutting down.
+        if response
+            .header("Connection")
+            .is_some_and(|connection| has_token(connection, "close"))
+            || closes_connection(response.status())
+            || config.shutdown.is_triggered()
+        {
+            keep_alive = false;
+        }
+
+        // Staying open adds one more idle connection, so room is made for
+        // it first, by closing the one idle longest. If none of them can
+        // be closed, this one closes instead.
+        if keep_alive
+            && !config
+                .connections
+                .make_room_for_idle(config.max_idle_connections)
+        {
+            keep_alive = false;
+        }
+
+        set_server_header(&mut response, &config);
+        if keep_alive {
+            response.set_header("Connection", "keep-alive");
+            response.set_header(
+                "Keep-Alive",
+                format!(
+                    "timeout={}, max={remaining}",
+                    config.keep_alive_timeout.as_secs()
+                ),
+            );
+        } else {
+            response.set_header("Connection", "close");
+        }
+
+        let written = response.write_to(reader.get_mut());
+        log_request(&config, &request, &response, started, peer);
+
+        // A client that reset the connection before reading the response
+        // can't be told anything either.
+        if let Err(err) = &written {
+            trace::debug("failed to write a response", err);
+        }
+        if written.is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Whether a response with `status` always closes the connection, whatever
+/// the client asked for.
+///
+/// These are the errors for a request that couldn't be read properly: 400
+/// Bad Request, 408 Request Timeout, 413 Payload Too Large, 414 URI Too
+/// Long and 431 Request Header Fields Too Large. After one of them, what
+/// is left on the connection may be the rest of the broken request rather
+/// than the start of the next, so reading on could misread it. Other
+/// responses, errors such as 404 included, leave the connection as clean
+/// as a success does.
+pub fn closes_connection(status: u16) -> bool {
+    matches!(status, 400 | 408 | 413 | 414 | 431)
+}
+
+// Record the request in the access log and the metrics, if there are
+// any.
+fn log_request(
+    config: &Config,
+    request: &Request,
+    response: &Response,
+    started: Instant,
+    peer: &Peer,
+) {
+    if let Some(logger) = &config.access_log {
+        logger.log(&AccessRecord {
+            method: request.method().as_str(),
+            path: request.path(),
+            status: response.status(),
+            bytes: response.body().len(),
+            duration: started.elapsed(),
+            remote_ip: peer.remote_ip,
+            request_id: request.header("X-Request-Id"),
+        });
+    }
+    if let Some(metrics) = &config.metrics {
+        metrics.record(response.status(), started.elapsed());
+    }
+
+    // Slow requests get a line of their own, so they stand out from the
+    // access log, or are logged even without one.
+    let duration = started.elapsed();
+    if is_slow(config, duration) {
+        let (method, path) = (request.method(), request.path());
+        println!(
+            "Slow request: {method} {path} took {}ms to answer.",
+            duration.as_millis()
+        );
+        trace::slow_request(request, duration);
+    }
+}
+
+// Whether a request answered in `duration` took longer than the threshold,
+// if there is one.
+fn is_slow(config: &Config, duration: Duration) -> bool {
+    config
+        .slow_request_threshold
+        .is_some_and(|threshold| duration > threshold)
+}
+
+// A connection switched to another protocol. Reads come through the
+// buffer, which may already hold the start of the new protocol's data, and
+// writes go straight to the stream.
+struct Upgraded<'a, S>(&'a mut BufReader<S>);
+
+impl<S: Read> Read for Upgraded<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Write> Write for Upgraded<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+// Wait until the next request starts arriving, returning `false` if the
+// client has gone instead. While waiting, shutting down wakes the read. A
+// connection that has `served` requests already counts as idle meanwhile,
+// and may be closed to make room for another.
+fn wait_for_request<S: Read>(
+    reader: &mut BufReader<S>,
+    config: &Config,
+    peer: &Peer,
+    served: usize,
+) -> bool {
+    let _idle = peer
+        .waker
+        .as_ref()
+        .map(|waker| config.shutdown.watch_idle(waker));
+    let _keep_alive = (served > 0).then(|| config.connections.idle(peer.waker.as_ref()));
+
+    match reader.fill_buf() {
+        // A half-closed connection ends with no data, and so does a woken
+        // read.
+        Ok(buffer) => !buffer.is_empty(),
+        // The keep-alive timeout passed.
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
+        // Anything else is the connection breaking, usually a reset.
+        Err(err) => {
+            trace::debug("connection lost between requests", &err);
+            false
+        }
+    }
+}
+
+// Read the next request, answering `Expect: 100-continue` between the head
+// and the body. An unsupported expectation or an unacceptable body is
+// refused before the body is read, since the client may be holding it back.
+//
+// Unless the handler streams it, the body is read into the request and
+// counted against the memory budget: before it is read when
+// `Content-Length` gives its size, and afterwards for a chunked body. A
+// streamed body is left unread and gets no reservation.
+fn read_request<S, H>(
+    reader: &mut BufReader<S>,
+    config: &Config,
+    handler: &H,
+) -> Result<(Request, Option<Reservation>), HttpError>
+where
+    S: Read + Write,
+    H: Handler + ?Sized,
+{
+    let limits = &config.limits;
+    let mut request = Request::read_head(reader, limits)?;
+    request.check_body(limits)?;
+
+    let streamed = !request.is_server_wide() && handler.streams_body(&request);
+    let announced = request
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let memory = if streamed {
+        None
+    } else {
+        Some(
+            config
+                .memory
+                .reserve(announced)
+                .ok_or(HttpError::Overloaded)?,
+        )
+    };
+
+    if request.expects_continue()? && request.has_body() {
+        let stream = reader.get_mut();
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        stream.flush()?;
+    }
+
+    let Some(mut memory) = memory else {
+        return Ok((request, None));
+    };
+
+    request.read_body(reader, limits)?;
+
+    let unannounced = request.body().len().saturating_sub(announced);
+    if !memory.try_grow(unannounced) {
+        return Err(HttpError::Overloaded);
+    }
+
+    Ok((request, Some(memory)))
+}
+
+// Hand `request` to the handler along with a reader for its body. Whatever
+// the handler leaves unread is skipped afterwards, so the next request on
+// the connection starts in the right place. Also returns whether that
+// worked, which it doesn't if too much of the body was left.
+fn stream_body<S, H>(
+    reader: &mut BufReader<S>,
+    request: &Request,
+    config: &Config,
+    handler: &H,
+) -> Result<(Response, bool), HttpError>
+where
+    S: Read,
+    H: Handler + ?Sized,
+{
+    let mut body = BodyReader::new(reader, request, &config.limits)?;
+
+    let response = handler.handle_stream(request, &mut body);
+    if let Some(err) = body.take_failure() {
+        return Err(err);
+    }
+
+    let finished = skip_body(&mut body, &config.limits)?;
+    Ok((response, finished))
+}
+
+// Skip what is left of a streamed body, returning whether it ended within
+// `Limits::max_drain_size`.
+fn skip_body(body: &mut BodyReader<'_>, limits: &Limits) -> Result<bool, HttpError> {
+    body.skip_rest(limits.max_drain_size)
+        .map_err(|err| body.take_failure().unwrap_or_else(|| err.into()))
+}
+
+// Answer a request that couldn't be read, if the client can still hear us.
+// The connection is closed afterwards, since we can't tell where the next
+// request would begin.
+fn respond_to_error<W: Write>(writer: &mut W, err: &HttpError, config: &Config) {
+    trace::error("failed to read a request", err);
+    if let Some(status) = err.status() {
+        let mut response = error_page::render(None, status).with_header("Connection", "close");
+        set_server_header(&mut response, config);
+        let _ = response.write_to(writer);
+    }
+}
+
+// Add the configured `Server` header, unless the handler named the server
+// itself.
+fn set_server_header(response: &mut Response, config: &Config) {
+    if let Some(server) = &config.server_header {
+        if response.header("Server").is_none() {
+            response.set_header("Server", server.as_str());
+        }
+    }
+}
+
+// Whether the request's host is on `Config::allowed_hosts`, if there is an
+// allow-list. Hosts compare case-insensitively, and an entry without a port
+// matches the host on any port.
+fn host_allowed(request: &Request, config: &Config) -> bool {
+    let Some(allowed) = &config.allowed_hosts else {
+        return true;
+    };
+    let Some(host) = request.host() else {
+        return false;
+    };
+
+    let name = strip_port(host);
+    allowed
+        .iter()
+        .any(|entry| entry.eq_ignore_ascii_case(host) || entry.eq_ignore_ascii_case(name))
+}
+
+// The host name from a `host[:port]` authority, keeping the brackets of an
+// IPv6 literal such as `[::1]:7878`.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+// HTTP/1.1 connections are persistent unless the client says otherwise,
+// while HTTP/1.0 clients have to opt in with `Connection: keep-alive`.
+fn wants_keep_alive(request: &Request) -> bool {
+    // `close` wins even on HTTP/1.1, where keep-alive is the default, and
+    // even alongside other options such as `close, TE`.
+    if request.has_connection_token("close") {
+        false
+    } else if request.version() == "HTTP/1.1" {
+        true
+    } else {
+        request.has_connection_token("keep-alive")
+    }
+}
+
+// Whether a comma-separated header value such as `Connection` contains
+// `token`, compared case-insensitively.
+fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        handler::PipedBody,
+        maintenance::Maintenance,
+        metrics::{self, Metrics},
+        router::Router,
+        upgrade, ThreadPool,
+    };
+
+    // A connection whose client sent `input` and then closed its half, or
+    // had its reads fail with `failure`.
+    struct MockStream {
+        input: io::Cursor<Vec<u8>>,
+        failure: Option<ErrorKind>,
+        output: Vec<u8>,
+    }
+
+    impl MockStream {
+        fn new(input: &str) -> MockStream {
+            MockStream {
+                input: io::Cursor::new(input.as_bytes().to_vec()),
+                failure: None,
+                output: Vec::new(),
+            }
+        }
+
+        fn failing_with(mut self, kind: ErrorKind) -> MockStream {
+            self.failure = Some(kind);
+            self
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.read(buf)? {
+                0 if !buf.is_empty() => self.failure.map_or(Ok(0), |kind| Err(kind.into())),
+                read => Ok(read),
+            }
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Serve `stream` with `handler` and return the responses written back,
+    // one per element.
+    fn serve<H: Handler>(mut stream: MockStream, config: Config, handler: H) -> Vec<String> {
+        handle_connection(&mut stream, &SharedConfig::new(config), &handler);
+
+        let output = String::from_utf8(stream.output).unwrap();
+        output
+            .split("HTTP/1.1 ")
+            .skip(1)
+            .map(|response| format!("HTTP/1.1 {response}"))
+            .collect()
+    }
+
+    fn exchange<H: Handler>(config: Config, input: &str, handler: H) -> Vec<String> {
+        serve(MockStream::new(input), config, handler)
+    }
+
+    fn ok(_: &Request) -> Response {
+        Response::new(200).with_body("ok")
+    }
+
+    const GET: &str = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+    #[test]
+    fn keep_alive_max_counts_down_and_the_last_response_closes() {
+        let config = Config {
+            keep_alive_timeout: Duration::from_secs(7),
+            keep_alive_max: 3,
+            ..Config::default()
+        };
+        let responses = exchange(config, &GET.repeat(4), ok);
+
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].contains("Keep-Alive: timeout=7, max=2\r\n"));
+        assert!(responses[1].contains("Keep-Alive: timeout=7, max=1\r\n"));
+        assert!(responses[2].contains("Connection: close\r\n"));
+        assert!(!responses[2].contains("Keep-Alive"));
+    }
+
+    #[test]
+    fn a_malformed_request_is_answered_400() {
+        let responses = exchange(Config::default(), "GET /\r\n\r\n", ok);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 400 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn a_body_over_the_limit_is_answered_413() {
+        let mut config = Config::default();
+        config.limits.max_body_size = 4;
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let responses = exchange(config, request, ok);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 413 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_request_cut_off_by_a_timeout_is_answered_408() {
+        let stream =
+            MockStream::new("GET / HTTP/1.1\r\nHost: loc").failing_with(ErrorKind::WouldBlock);
+        let responses = serve(stream, Config::default(), ok);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 408 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_broken_connection_is_closed_without_a_response() {
+        let stream =
+            MockStream::new("GET / HTTP/1.1\r\nHost: loc").failing_with(ErrorKind::ConnectionReset);
+        assert!(serve(stream, Config::default(), ok).is_empty());
+
+        let stream = MockStream::new("").failing_with(ErrorKind::ConnectionReset);
+        assert!(serve(stream, Config::default(), ok).is_empty());
+    }
+
+    #[test]
+    fn an_idle_timeout_between_requests_closes_silently() {
+        let stream = MockStream::new(GET).failing_with(ErrorKind::WouldBlock);
+        let responses = serve(stream, Config::default(), ok);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn maintenance_mode_is_checked_before_the_handler() {
+        let maintenance = Maintenance::default().allow("/healthz");
+        maintenance.switch().store(true, Ordering::Relaxed);
+        let config = Config {
+            maintenance,
+            ..Config::default()
+        };
+        let request = format!("{GET}GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let responses = exchange(config, &request, ok);
+
+        assert_eq!(responses.len(), 2);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 503 "),
+            "{}",
+            responses[0]
+        );
+        assert!(
+            responses[1].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[1]
+        );
+    }
+
+    #[test]
+    fn a_reload_applies_from_the_next_request_on() {
+        let shared = Arc::new(SharedConfig::new(Config {
+            server_header: Some(String::from("before")),
+            ..Config::default()
+        }));
+        let reloader = Arc::clone(&shared);
+        let handler = move |_: &Request| {
+            reloader.reload(Config {
+                server_header: Some(String::from("after")),
+                ..Config::default()
+            });
+            Response::new(200)
+        };
+
+        let mut stream = MockStream::new(&GET.repeat(2));
+        handle_connection(&mut stream, &shared, &handler);
+
+        let output = String::from_utf8(stream.output).unwrap();
+        let (first, second) = output.split_at(output.rfind("HTTP/1.1 ").unwrap());
+        assert!(first.contains("Server: before\r\n"), "{first}");
+        assert!(second.contains("Server: after\r\n"), "{second}");
+    }
+
+    #[test]
+    fn a_custom_handler_answers_every_request() {
+        // Counts the requests it has seen, and says so.
+        struct Counter(std::sync::atomic::AtomicUsize);
+
+        impl Handler for Counter {
+            fn handle(&self, request: &Request) -> Response {
+                let seen = self.0.fetch_add(1, Ordering::Relaxed) + 1;
+                Response::new(200).with_body(format!("{} #{seen}", request.path()))
+            }
+        }
+
+        let request = format!("{GET}GET /again HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let responses = exchange(Config::default(), &request, Counter(Default::default()));
+
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].ends_with("\r\n\r\n/ #1"), "{}", responses[0]);
+        assert!(
+            responses[1].ends_with("\r\n\r\n/again #2"),
+            "{}",
+            responses[1]
+        );
+    }
+
+    #[test]
+    fn a_connection_closed_before_any_request_gets_no_response() {
+        assert!(exchange(Config::default(), "", ok).is_empty());
+    }
+
+    #[test]
+    fn a_request_line_cut_off_by_the_close_is_answered_400() {
+        let responses = exchange(Config::default(), "GET / HT", ok);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 400 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_fresh_connection_is_only_read_from_once_something_arrives_on_it() {
+        let config = Config::default();
+        let mut closed = BufReader::new(MockStream::new(""));
+        assert!(!wait_for_request(&mut closed, &config, &Peer::default(), 0));
+
+        // A partial line is the start of a request, which fails to parse.
+        let mut cut_off = BufReader::new(MockStream::new("GET / HT"));
+        assert!(wait_for_request(&mut cut_off, &config, &Peer::default(), 0));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn a_connection_closed_before_any_request_logs_no_error() {
+        let (responses, captured) = trace::capture(|| exchange(Config::default(), "", ok));
+        let events = captured.events;
+        assert!(responses.is_empty());
+        assert!(
+            events
+                .iter()
+                .all(|(level, _)| *level > tracing::Level::WARN),
+            "{events:?}"
+        );
+
+        // A broken request, unlike a probe, is worth logging.
+        let (_, captured) = trace::capture(|| exchange(Config::default(), "GET / HT", ok));
+        let events = captured.events;
+        assert!(
+            events
+                .iter()
+                .any(|(level, _)| *level == tracing::Level::ERROR),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn a_flood_of_header_fields_is_answered_431() {
+        let mut config = Config::default();
+        config.limits.max_headers = 100;
+        let fields = "X: y\r\n".repeat(500);
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{fields}\r\n");
+        let responses = exchange(config, &request, ok);
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 431 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn an_unsupported_expectation_is_answered_417() {
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nExpect: something-else\r\nContent-Length: 2\r\n\r\nhi";
+        let responses = exchange(Config::default(), request, |_: &Request| -> Response {
+            panic!("the handler was called")
+        });
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 417 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn expect_100_continue_is_told_to_continue() {
+        let request = "POST / HTTP/1.1\r\nHost: localhost\r\nExpect: 100-continue\r\nContent-Length: 2\r\n\r\nhi";
+        let echo = |request: &Request| Response::new(200).with_body(request.body().to_vec());
+        let responses = exchange(Config::default(), request, echo);
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[0], "HTTP/1.1 100 Continue\r\n\r\n");
+        assert!(
+            responses[1].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[1]
+        );
+        assert!(responses[1].ends_with("\r\n\r\nhi"), "{}", responses[1]);
+    }
+
+    #[test]
+    fn options_asterisk_lists_the_server_wide_methods() {
+        let request = "OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let responses = exchange(Config::default(), request, |_: &Request| -> Response {
+            panic!("the handler was called")
+        });
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 204 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains(&format!("Allow: {SERVER_METHODS}\r\n")));
+    }
+
+    #[test]
+    fn a_method_override_routes_a_post_as_delete_only_when_enabled() {
+        let router = || {
+            Router::new()
+                .route("POST", "/item", |_: &Request| {
+                    Response::new(200).with_body("posted")
+                })
+                .route("DELETE", "/item", |_: &Request| {
+                    Response::new(200).with_body("deleted")
+                })
+        };
+        let request =
+            "POST /item HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: DELETE\r\n\r\n";
+
+        let enabled = Config {
+            method_override: true,
+            ..Config::default()
+        };
+        let responses = exchange(enabled, request, router());
+        assert!(
+            responses[0].ends_with("\r\n\r\ndeleted"),
+            "{}",
+            responses[0]
+        );
+
+        let responses = exchange(Config::default(), request, router());
+        assert!(responses[0].ends_with("\r\n\r\nposted"), "{}", responses[0]);
+    }
+
+    #[test]
+    fn connection_close_on_http_1_1_ends_the_connection_after_one_response() {
+        let close = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Close\r\n\r\n";
+        let responses = exchange(Config::default(), &format!("{close}{GET}"), ok);
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].contains("Connection: close\r\n"),
+            "{}",
+            responses[0]
+        );
+        assert!(!responses[0].contains("Keep-Alive"), "{}", responses[0]);
+    }
+
+    #[test]
+    fn http_1_0_keeps_the_connection_only_when_asked() {
+        let request = "GET / HTTP/1.0\r\n\r\n";
+        assert_eq!(exchange(Config::default(), &request.repeat(2), ok).len(), 1);
+
+        let request = "GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        assert_eq!(exchange(Config::default(), &request.repeat(2), ok).len(), 2);
+    }
+
+    #[test]
+    fn a_request_past_the_memory_budget_is_answered_503_while_others_succeed() {
+        let shared = Arc::new(SharedConfig::new(Config {
+            memory: crate::memory::MemoryBudget::new(1000),
+            ..Config::default()
+        }));
+        let post = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 600\r\n\r\n{}",
+            "x".repeat(600)
+        );
+
+        // While the first request's body is held, a second one arrives on
+        // another connection, and doesn't fit beside it.
+        let (inner, config) = (post.clone(), Arc::clone(&shared));
+        let handler = move |request: &Request| {
+            if request.body().len() == 600 && config.load().memory.used() == 600 {
+                let mut second = MockStream::new(&inner);
+                handle_connection(&mut second, &config, &ok);
+                let second = String::from_utf8(second.output).unwrap();
+                assert!(second.starts_with("HTTP/1.1 503 "), "{second}");
+            }
+            Response::new(200)
+        };
+
+        let mut first = MockStream::new(&post);
+        handle_connection(&mut first, &shared, &handler);
+        let first = String::from_utf8(first.output).unwrap();
+        assert!(first.starts_with("HTTP/1.1 200 "), "{first}");
+        assert_eq!(shared.load().memory.used(), 0);
+    }
+
+    // Streams each upload to a file of its own and answers with its size.
+    struct Uploader(std::path::PathBuf);
+
+    impl Handler for Uploader {
+        fn handle(&self, _: &Request) -> Response {
+            Response::new(405)
+        }
+
+        fn streams_body(&self, _: &Request) -> bool {
+            true
+        }
+
+        fn handle_stream(&self, _: &Request, body: &mut BodyReader<'_>) -> Response {
+            let mut file = std::fs::File::create(&self.0).unwrap();
+            match io::copy(body, &mut file) {
+                Ok(copied) => Response::new(201).with_body(copied.to_string()),
+                Err(_) => Response::new(500),
+            }
+        }
+    }
+
+    fn upload(body_size: usize, limits: Limits) -> (Vec<String>, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "web_server-upload-{}-{body_size}",
+            std::process::id()
+        ));
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {body_size}\r\n\r\n{}",
+            "x".repeat(body_size)
+        );
+        let config = Config {
+            limits,
+            ..Config::default()
+        };
+        (exchange(config, &request, Uploader(path.clone())), path)
+    }
+
+    #[test]
+    fn a_large_upload_is_streamed_to_disk() {
+        let size = 5 * 1024 * 1024;
+        let limits = Limits {
+            max_body_size: 8 * 1024 * 1024,
+            ..Limits::default()
+        };
+        let (responses, path) = upload(size, limits);
+
+        assert!(
+            responses[0].starts_with("HTTP/1.1 201 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].ends_with(&format!("\r\n\r\n{size}")));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size as u64);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_streamed_upload_over_the_limit_is_answered_413() {
+        let (responses, path) = upload(2 * 1024 * 1024, Limits::default());
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 413 "),
+            "{}",
+            responses[0]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn a_piped_upload_is_consumed_on_its_pool_while_it_is_read() {
+        let size = 3 * 1024 * 1024;
+        let reader = std::thread::current().id();
+        let handler = PipedBody::new(Arc::new(ThreadPool::new(2)), 4, move |_, chunks| {
+            assert_ne!(std::thread::current().id(), reader);
+            let received: usize = chunks.map(|chunk| chunk.unwrap().len()).sum();
+            Response::new(201).with_body(received.to_string())
+        });
+        let config = Config {
+            limits: Limits {
+                max_body_size: 4 * 1024 * 1024,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {size}\r\n\r\n{}{GET}",
+            "x".repeat(size)
+        );
+
+        let responses = exchange(config, &request, handler);
+        assert_eq!(responses.len(), 2);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 201 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].ends_with(&format!("\r\n\r\n{size}")));
+    }
+
+    #[test]
+    fn a_piped_upload_over_the_limit_is_answered_413() {
+        let handler = PipedBody::new(Arc::new(ThreadPool::new(2)), 4, |_, chunks| {
+            let failed = chunks.into_iter().any(|chunk| chunk.is_err());
+            Response::new(if failed { 500 } else { 201 })
+        });
+        let size = 2 * 1024 * 1024;
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {size}\r\n\r\n{}",
+            "x".repeat(size)
+        );
+
+        let responses = exchange(Config::default(), &request, handler);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 413 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_chunked_upload_is_cut_off_at_the_limit_while_streaming() {
+        let path =
+            std::env::temp_dir().join(format!("web_server-upload-{}-chunked", std::process::id()));
+        let chunk = format!("10000\r\n{}\r\n", "x".repeat(0x10000));
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n",
+            chunk.repeat(32)
+        );
+
+        let responses = exchange(Config::default(), &request, Uploader(path.clone()));
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 413 "),
+            "{}",
+            responses[0]
+        );
+        // The handler saw the body up to the limit before it was stopped.
+        let written = std::fs::metadata(&path).unwrap().len();
+        assert!(
+            written <= Limits::default().max_body_size as u64,
+            "{written}"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shutting_down_mid_request_finishes_it_and_closes() {
+        let config = Config::default();
+        let shutdown = config.shutdown.clone();
+        let handler = move |_: &Request| {
+            shutdown.trigger();
+            Response::new(200)
+        };
+
+        let responses = exchange(config, &GET.repeat(2), handler);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains("\r\nConnection: close\r\n"));
+    }
+
+    fn status_for(config: Config, request: &str) -> String {
+        let responses = exchange(config, request, ok);
+        responses[0][9..12].to_string()
+    }
+
+    fn only_example_com() -> Config {
+        Config {
+            allowed_hosts: Some(vec!["example.com".to_string()]),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn requests_for_hosts_off_the_allow_list_are_answered_400() {
+        let get = |host: &str| format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+
+        assert_eq!(status_for(only_example_com(), &get("example.com")), "200");
+        assert_eq!(
+            status_for(only_example_com(), &get("EXAMPLE.com:8080")),
+            "200"
+        );
+        assert_eq!(status_for(only_example_com(), &get("evil.test")), "400");
+        assert_eq!(status_for(Config::default(), &get("evil.test")), "200");
+
+        // An absolute-form target names the host instead of the header.
+        let absolute = "GET http://example.com/ HTTP/1.1\r\nHost: evil.test\r\n\r\n";
+        assert_eq!(status_for(only_example_com(), absolute), "200");
+    }
+
+    #[test]
+    fn a_missing_host_is_answered_400_on_http_1_1_only() {
+        let no_host = "GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(status_for(Config::default(), no_host), "400");
+        let absolute = "GET http://example.com/ HTTP/1.1\r\n\r\n";
+        assert_eq!(status_for(Config::default(), absolute), "200");
+
+        // HTTP/1.0 has no `Host` requirement, unless hosts are checked.
+        let old = "GET / HTTP/1.0\r\n\r\n";
+        assert_eq!(status_for(Config::default(), old), "200");
+        assert_eq!(status_for(only_example_com(), old), "400");
+    }
+
+    #[test]
+    fn the_server_header_defaults_to_the_crate_version_and_can_be_changed() {
+        let server = |config: Config, handler: fn(&Request) -> Response| {
+            let responses = exchange(config, GET, handler);
+            let head = responses[0].split("\r\n\r\n").next().unwrap().to_string();
+            head.lines()
+                .find_map(|line| line.strip_prefix("Server: "))
+                .map(str::to_string)
+        };
+        let named = |_: &Request| Response::new(200).with_header("Server", "mine");
+
+        assert_eq!(
+            server(Config::default(), ok).as_deref(),
+            Some(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+        );
+        let custom = Config {
+            server_header: Some("custom/2".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(server(custom, ok).as_deref(), Some("custom/2"));
+        let suppressed = Config {
+            server_header: None,
+            ..Config::default()
+        };
+        assert_eq!(server(suppressed, ok), None);
+        assert_eq!(server(Config::default(), named).as_deref(), Some("mine"));
+    }
+
+    // Answers a streamed POST without reading any of its body.
+    struct Rejecter;
+
+    impl Handler for Rejecter {
+        fn handle(&self, _: &Request) -> Response {
+            Response::new(200).with_body("next")
+        }
+
+        fn streams_body(&self, request: &Request) -> bool {
+            *request.method() == Method::Post
+        }
+
+        fn handle_stream(&self, _: &Request, _: &mut BodyReader<'_>) -> Response {
+            Response::new(403)
+        }
+    }
+
+    fn post_then_get(body_size: usize) -> String {
+        format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {body_size}\r\n\r\n{}{GET}",
+            "x".repeat(body_size)
+        )
+    }
+
+    #[test]
+    fn an_unread_body_is_skipped_before_the_next_request() {
+        let size = Limits::default().max_drain_size;
+        let responses = exchange(Config::default(), &post_then_get(size), Rejecter);
+
+        assert_eq!(responses.len(), 2);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 403 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[1].ends_with("\r\n\r\nnext"), "{}", responses[1]);
+    }
+
+    #[test]
+    fn an_unread_body_too_big_to_skip_closes_the_connection() {
+        let size = Limits::default().max_drain_size + 1;
+        let responses = exchange(Config::default(), &post_then_get(size), Rejecter);
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 403 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains("\r\nConnection: close\r\n"));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn each_request_runs_in_a_span_with_its_method_and_path() {
+        let input = "GET /a?q=1 HTTP/1.1\r\nHost: localhost\r\n\r\nPOST /b HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n";
+        let (_, captured) = trace::capture(|| exchange(Config::default(), input, ok));
+
+        assert_eq!(
+            captured.spans,
+            [
+                "connection",
+                "request method=GET path=/a",
+                "request method=POST path=/b"
+            ]
+        );
+    }
+
+    #[test]
+    fn an_over_long_target_is_answered_414() {
+        let target = "a".repeat(Limits::default().max_uri_length);
+        let request = format!("GET /{target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert_eq!(status_for(Config::default(), &request), "414");
+    }
+
+    #[test]
+    fn a_half_close_or_a_reset_between_requests_ends_the_connection_unanswered() {
+        let config = Config::default();
+        let half_closed = MockStream::new("");
+        let reset = MockStream::new("").failing_with(ErrorKind::ConnectionReset);
+        for stream in [half_closed, reset] {
+            let mut reader = BufReader::new(stream);
+            assert!(!wait_for_request(&mut reader, &config, &Peer::default(), 1));
+        }
+
+        // Neither is taken for a request that failed to be read.
+        for stream in [
+            MockStream::new(GET),
+            MockStream::new(GET).failing_with(ErrorKind::ConnectionReset),
+        ] {
+            let responses = serve(stream, Config::default(), ok);
+            assert_eq!(responses.len(), 1);
+            assert!(responses[0].ends_with("\r\n\r\nok"), "{}", responses[0]);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn a_half_close_after_a_request_ends_quietly_but_a_reset_is_noted() {
+        let (responses, captured) =
+            trace::capture(|| serve(MockStream::new(GET), Config::default(), ok));
+        assert_eq!(responses.len(), 1);
+        assert!(captured.events.is_empty(), "{:?}", captured.events);
+
+        let reset = MockStream::new(GET).failing_with(ErrorKind::ConnectionReset);
+        let (responses, captured) = trace::capture(|| serve(reset, Config::default(), ok));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(
+            captured.events,
+            [(
+                tracing::Level::DEBUG,
+                "connection lost between requests".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn a_close_token_anywhere_in_the_list_ends_the_connection() {
+        let close = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, CLOSE\r\n\r\n";
+        assert_eq!(
+            exchange(Config::default(), &format!("{close}{GET}"), ok).len(),
+            1
+        );
+
+        let keep = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade\r\n\r\n";
+        assert_eq!(
+            exchange(Config::default(), &format!("{keep}{GET}"), ok).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn connect_is_answered_501_and_the_connection_closes() {
+        let connect = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+        // Whatever follows may be tunnel data, so it isn't read as a request.
+        let responses = exchange(Config::default(), &format!("{connect}{GET}"), ok);
+
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 501 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains("\r\nAllow: "));
+        assert!(responses[0].contains("\r\nConnection: close\r\n"));
+    }
+
+    #[test]
+    fn heads_bigger_than_the_read_buffer_still_parse() {
+        let cookie = "a".repeat(12 * 1024);
+        let config = Config {
+            read_buffer_size: 64,
+            limits: Limits {
+                max_line_length: 16 * 1024,
+                ..Limits::default()
+            },
+            ..Config::default()
+        };
+        let input = format!("GET / HTTP/1.1\r\nHost: localhost\r\nCookie: {cookie}\r\n\r\n{GET}");
+
+        let responses = exchange(config, &input, |request: &Request| {
+            let length = request.header("Cookie").map_or(0, str::len);
+            Response::new(200).with_body(length.to_string())
+        });
+        assert_eq!(responses.len(), 2, "{responses:?}");
+        assert!(responses[0].ends_with("\r\n\r\n12288"), "{}", responses[0]);
+        assert!(responses[1].ends_with("\r\n\r\n0"), "{}", responses[1]);
+    }
+
+    // An echo protocol, upgraded to when the client asks for it.
+    fn echo_upgrade(request: &Request) -> Response {
+        if !request.wants_upgrade("echo") {
+            return Response::new(426).with_header("Upgrade", "echo");
+        }
+        Response::upgrade("echo", |stream: &mut dyn upgrade::Stream| {
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).unwrap();
+            stream.write_all(&received).unwrap();
+        })
+    }
+
+    const UPGRADE: &str =
+        "GET /echo HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n";
+
+    #[test]
+    fn an_upgraded_connection_is_handed_over_raw_after_the_101() {
+        // What follows the request is the new protocol's, even if it looks
+        // like another HTTP request.
+        let mut stream = MockStream::new(&format!("{UPGRADE}raw bytes, then {GET}"));
+        handle_connection(
+            &mut stream,
+            &SharedConfig::new(Config::default()),
+            &echo_upgrade,
+        );
+
+        let output = String::from_utf8(stream.output).unwrap();
+        let (head, rest) = output.split_once("\r\n\r\n").unwrap();
+        assert!(
+            head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+            "{head}"
+        );
+        assert!(head.contains("\r\nUpgrade: echo"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert_eq!(rest, format!("raw bytes, then {GET}"));
+    }
+
+    #[test]
+    fn upgrading_a_request_that_didnt_ask_to_is_a_server_error() {
+        let handler = |_: &Request| Response::upgrade("echo", |_: &mut dyn upgrade::Stream| {});
+        let responses = exchange(Config::default(), GET, handler);
+        assert_eq!(responses.len(), 1);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 500 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_400_closes_a_keep_alive_connection_but_a_404_does_not() {
+        let by_path = |request: &Request| match request.path() {
+            "/bad" => Response::new(400),
+            _ => Response::new(404),
+        };
+
+        let bad = "GET /bad HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let responses = exchange(Config::default(), &format!("{bad}{GET}"), by_path);
+        assert_eq!(responses.len(), 1, "{responses:?}");
+        assert!(responses[0].starts_with("HTTP/1.1 400 "));
+        assert!(
+            responses[0].contains("Connection: close"),
+            "{}",
+            responses[0]
+        );
+
+        let missing = "GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let responses = exchange(Config::default(), &format!("{missing}{GET}"), by_path);
+        assert_eq!(responses.len(), 2, "{responses:?}");
+        assert!(
+            responses[0].contains("Connection: keep-alive"),
+            "{}",
+            responses[0]
+        );
+    }
+
+    #[test]
+    fn a_malformed_request_closes_the_connection_behind_it() {
+        let responses = exchange(Config::default(), &format!("NOT HTTP\r\n\r\n{GET}"), ok);
+        assert_eq!(responses.len(), 1, "{responses:?}");
+        assert!(responses[0].starts_with("HTTP/1.1 400 "));
+    }
+
+    #[test]
+    fn only_errors_for_unreadable_requests_always_close() {
+        for status in [400, 408, 413, 414, 431] {
+            assert!(closes_connection(status), "{status}");
+        }
+        for status in [200, 304, 404, 405, 500, 503] {
+            assert!(!closes_connection(status), "{status}");
+        }
+    }
+
+    #[test]
+    fn a_scrape_reports_the_requests_answered_before_it() {
+        let metrics = Metrics::new();
+        let config = Config {
+            metrics: Some(metrics.clone()),
+            ..Config::default()
+        };
+        // The scrape is rendered from the same shared counters.
+        let scraped = Config {
+            metrics: Some(metrics.clone()),
+            ..Config::default()
+        };
+        let handler = move |request: &Request| match request.path() {
+            metrics::PATH => metrics::serve(request, &scraped, None),
+            "/" => Response::new(200),
+            _ => Response::new(404),
+        };
+
+        let input = format!(
+            "{GET}{GET}GET /missing HTTP/1.1\r\nHost: localhost\r\n\r\n\
+             GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        );
+        let responses = exchange(config, &input, handler);
+        let scrape = &responses[3];
+        assert!(
+            scrape.contains("http_requests_total{status=\"200\"} 2\n"),
+            "{scrape}"
+        );
+        assert!(
+            scrape.contains("http_requests_total{status=\"404\"} 1\n"),
+            "{scrape}"
+        );
+        assert!(
+            scrape.contains("http_request_duration_seconds_count 3\n"),
+            "{scrape}"
+        );
+
+        // The scrape itself is counted once it has been answered.
+        assert_eq!(metrics.requests(200), 3);
+    }
+
+    #[test]
+    fn megabytes_of_header_fields_are_answered_500_instead() {
+        let bloated = |_: &Request| {
+            let mut response = Response::new(200).with_body("ok");
+            for i in 0..64 {
+                response.set_header(&format!("X-Bloat-{i}"), "x".repeat(32 * 1024));
+            }
+            response
+        };
+        let responses = exchange(Config::default(), &GET.repeat(2), bloated);
+
+        assert_eq!(responses.len(), 2);
+        for response in &responses {
+            assert!(response.starts_with("HTTP/1.1 500 "), "{response}");
+            assert!(!response.contains("X-Bloat"));
+            assert!(response.len() < 4096);
+        }
+    }
+
+    #[test]
+    fn a_head_just_under_the_cap_is_sent_as_is() {
+        let config = Config {
+            max_response_header_size: 1024,
+            ..Config::default()
+        };
+        let handler = |_: &Request| {
+            let mut response = Response::new(200);
+            response.set_header("X-Filler", "x".repeat(512));
+            response
+        };
+        let responses = exchange(config, GET, handler);
+        assert!(
+            responses[0].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[0]
+        );
+    }
+
+    // A handler that takes `delay` to answer.
+    #[cfg(feature = "tracing")]
+    fn taking(delay: Duration) -> impl Handler {
+        move |_: &Request| {
+            std::thread::sleep(delay);
+            Response::new(200)
+        }
+    }
+
+    #[test]
+    fn only_a_request_over_the_threshold_is_slow() {
+        let config = Config {
+            slow_request_threshold: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        assert!(is_slow(&config, Duration::from_millis(21)));
+        assert!(!is_slow(&config, Duration::from_millis(20)));
+        assert!(!is_slow(&config, Duration::from_millis(19)));
+        assert!(!is_slow(&Config::default(), Duration::from_secs(3600)));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn a_request_slower_than_the_threshold_is_warned_about() {
+        let config = Config {
+            slow_request_threshold: Some(Duration::from_millis(20)),
+            ..Config::default()
+        };
+        let input = "GET /report?year=2026 HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let (responses, captured) =
+            trace::capture(|| exchange(config, input, taking(Duration::from_millis(60))));
+        assert!(responses[0].starts_with("HTTP/1.1 200 "));
+
+        let slow: Vec<_> = captured
+            .events
+            .iter()
+            .zip(&captured.fields)
+            .filter(|((_, message), _)| message == "slow request")
+            .collect();
+        assert_eq!(slow.len(), 1, "{:?}", captured.events);
+        let ((level, _), fields) = slow[0];
+        assert_eq!(*level, tracing::Level::WARN);
+        assert!(
+            fields.starts_with(" method=GET path=/report duration_ms="),
+            "{fields}"
+        );
+        let millis: u64 = fields.rsplit('=').next().unwrap().parse().unwrap();
+        assert!(millis >= 60, "{fields}");
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn a_request_under_the_threshold_or_without_one_is_not() {
+        let under = Config {
+            slow_request_threshold: Some(Duration::from_secs(5)),
+            ..Config::default()
+        };
+        let (_, captured) = trace::capture(|| exchange(under, GET, ok));
+        assert!(
+            captured
+                .events
+                .iter()
+                .all(|(_, message)| message != "slow request"),
+            "{:?}",
+            captured.events
+        );
+
+        // With no threshold, no request is slow, however long it takes.
+        let (_, captured) =
+            trace::capture(|| exchange(Config::default(), GET, taking(Duration::from_millis(30))));
+        assert!(
+            captured
+                .events
+                .iter()
+                .all(|(_, message)| message != "slow request"),
+            "{:?}",
+            captured.events
+        );
+    }
+
+    #[test]
+    fn a_head_response_has_no_body_and_a_pipelined_get_after_it_parses() {
+        // A plain handler, unlike a router, answers HEAD with a body.
+        let page = |_: &Request| Response::new(200).with_body("<p>page</p>");
+        let input = format!("HEAD / HTTP/1.1\r\nHost: localhost\r\n\r\n{GET}");
+        let responses = exchange(Config::default(), &input, page);
+
+        assert_eq!(responses.len(), 2, "{responses:?}");
+        assert!(
+            responses[0].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].contains("Content-Length: 11\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\n"), "{}", responses[0]);
+        assert!(
+            responses[1].starts_with("HTTP/1.1 200 "),
+            "{}",
+            responses[1]
+        );
+        assert!(
+            responses[1].ends_with("\r\n\r\n<p>page</p>"),
+            "{}",
+            responses[1]
+        );
+    }
+
+    #[test]
+    fn a_head_request_for_a_missing_page_gets_the_404_without_its_body() {
+        let input = "HEAD /nothing HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let responses = exchange(Config::default(), input, |request: &Request| {
+            error_page::render(Some(request), 404)
+        });
+        assert!(
+            responses[0].starts_with("HTTP/1.1 404 "),
+            "{}",
+            responses[0]
+        );
+        assert!(responses[0].ends_with("\r\n\r\n"), "{}", responses[0]);
+        assert!(!responses[0].contains("Content-Length: 0\r\n"));
+    }
+}
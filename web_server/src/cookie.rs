@@ -0,0 +1,125 @@
+//! Parsing the `Cookie` request header and formatting `Set-Cookie`.
+
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+/// Split a `Cookie` header such as `name1=val1; name2=val2` into a map.
+///
+/// Whitespace around each pair is ignored, and a value may itself contain
+/// `=`. Pairs without an `=` are skipped. If a name appears twice, the first
+/// value wins, as browsers send the most specific cookie first.
+pub fn parse(header: &str) -> HashMap<String, String> {
+    let mut cookies = HashMap::new();
+
+    for pair in header.split(';') {
+        let Some((name, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            continue;
+        }
+
+        // A value may be wrapped in double quotes, which aren't part of it.
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        cookies
+            .entry(name.to_string())
+            .or_insert_with(|| value.to_string());
+    }
+
+    cookies
+}
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Optional attributes of a `Set-Cookie` header.
+///
+/// The default has no attributes at all, which makes a session cookie
+/// scoped to the current path.
+#[derive(Debug, Clone, Default)]
+pub struct CookieAttributes {
+    pub path: Option<String>,        // URL path the cookie applies to
+    pub domain: Option<String>,      // Host the cookie applies to
+    pub max_age: Option<Duration>,   // Lifetime, with whole-second precision
+    pub same_site: Option<SameSite>, // Cross-site sending policy
+    pub http_only: bool,             // Hide the cookie from scripts
+    pub secure: bool,                // Only send the cookie over HTTPS
+}
+
+/// Format the value of a `Set-Cookie` header.
+pub fn format_set_cookie(name: &str, value: &str, attrs: &CookieAttributes) -> String {
+    let mut cookie = format!("{name}={value}");
+
+    // Writing to a `String` can't fail, so the results are ignored.
+    if let Some(path) = &attrs.path {
+        let _ = write!(cookie, "; Path={path}");
+    }
+    if let Some(domain) = &attrs.domain {
+        let _ = write!(cookie, "; Domain={domain}");
+    }
+    if let Some(max_age) = attrs.max_age {
+        let _ = write!(cookie, "; Max-Age={}", max_age.as_secs());
+    }
+    if let Some(same_site) = attrs.same_site {
+        let same_site = match same_site {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        let _ = write!(cookie, "; SameSite={same_site}");
+    }
+    if attrs.http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if attrs.secure {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_multi_cookie_header_is_split_into_pairs() {
+        let cookies =
+            parse(" session=abc123 ;theme=\"dark\";  token=a=b==; flag; =x; session=later");
+
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies["session"], "abc123");
+        assert_eq!(cookies["theme"], "dark");
+        assert_eq!(cookies["token"], "a=b==");
+        assert!(!cookies.contains_key("flag"));
+    }
+
+    #[test]
+    fn set_cookie_lists_the_attributes_given() {
+        let attrs = CookieAttributes {
+            path: Some(String::from("/")),
+            max_age: Some(Duration::from_secs(3600)),
+            same_site: Some(SameSite::Lax),
+            http_only: true,
+            ..CookieAttributes::default()
+        };
+        assert_eq!(
+            format_set_cookie("session", "abc123", &attrs),
+            "session=abc123; Path=/; Max-Age=3600; SameSite=Lax; HttpOnly"
+        );
+        assert_eq!(
+            format_set_cookie("id", "1", &CookieAttributes::default()),
+            "id=1"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Cross-origin resource sharing, so pages on other origins can call the
+//! server from a browser.
+
+use std::time::Duration;
+
+use crate::{
+    handler::Handler,
+    method::Method,
+    request::{BodyReader, Request},
+    response::Response,
+};
+
+/// Wraps a handler to answer CORS preflight requests and mark its
+/// responses as readable by other origins.
+///
+/// A preflight, an `OPTIONS` request carrying `Origin` and
+/// `Access-Control-Request-Method`, is answered with 204 and the allowed
+/// methods and headers, without reaching the handler. Every other request
+/// goes to the handler, and its response gets `Access-Control-Allow-Origin`
+/// if the request's origin is allowed. A request from an origin that isn't
+/// allowed is still served, but without the header, so the browser keeps
+/// the response from the page.
+///
+/// Without `allow_origin`, any origin is allowed.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use web_server::{cors::Cors, prelude::*};
+///
+/// let router = Router::new()
+///     .route("GET", "/api/items", |_request: &Request| Response::new(200).with_body("[]"));
+///
+/// let api = Cors::new(router)
+///     .allow_origin("https://app.example.com")
+///     .allow_methods("GET, POST")
+///     .max_age(Duration::from_secs(600));
+/// ```
+pub struct Cors<H> {
+    inner: H,                     // The handler serving the actual requests
+    origins: Option<Vec<String>>, // Origins allowed; `None` allows any
+    methods: String,              // Sent as `Access-Control-Allow-Methods`
+    headers: Option<String>,      // Request headers allowed; `None` allows those asked for
+    max_age: Option<Duration>,    // How long a preflight may be cached; `None` doesn't say
+}
+
+impl<H: Handler> Cors<H> {
+    /// Wrap `inner`, allowing any origin to use `GET`, `HEAD` and `POST`.
+    pub fn new(inner: H) -> Cors<H> {
+        Cors {
+            inner,
+            origins: None,
+            methods: String::from("GET, HEAD, POST"),
+            headers: None,
+            max_age: None,
+        }
+    }
+
+    /// Allow `origin`, e.g. `https://app.example.com`. Once one is given,
+    /// only the origins given are allowed.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Cors<H> {
+        self.origins
+            .get_or_insert_with(Vec::new)
+            .push(origin.into());
+        self
+    }
+
+    /// Allow the comma-separated `methods` in preflights.
+    pub fn allow_methods(mut self, methods: impl Into<String>) -> Cors<H> {
+        self.methods = methods.into();
+        self
+    }
+
+    /// Allow the comma-separated request `headers` in preflights, instead
+    /// of whichever ones the preflight asks for.
+    pub fn allow_headers(mut self, headers: impl Into<String>) -> Cors<H> {
+        self.headers = Some(headers.into());
+        self
+    }
+
+    /// Let browsers cache a preflight's answer for `max_age`, sent as
+    /// `Access-Control-Max-Age` in whole seconds, instead of sending a
+    /// preflight before every request.
+    pub fn max_age(mut self, max_age: Duration) -> Cors<H> {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // The value of `Access-Control-Allow-Origin` for `request`, or `None`
+    // if it doesn't come from an allowed origin.
+    fn allowed_origin(&self, request: &Request) -> Option<String> {
+        let origin = request.header("Origin")?;
+        match &self.origins {
+            None => Some(String::from("*")),
+            Some(origins) => origins
+                .iter()
+                .any(|allowed| allowed == origin)
+                .then(|| origin.to_string()),
+        }
+    }
+
+    // The answer to a preflight request.
+    fn preflight(&self, request: &Request) -> Response {
+        let mut response = Response::new(204);
+        vary(&mut response, "Origin");
+        let Some(origin) = self.allowed_origin(request) else {
+            return response;
+        };
+
+        response.set_header("Access-Control-Allow-Origin", origin);
+        response.set_header("Access-Control-Allow-Methods", self.methods.clone());
+        match &self.headers {
+            Some(headers) => response.set_header("Access-Control-Allow-Headers", headers.clone()),
+            None => {
+                vary(&mut response, "Access-Control-Request-Headers");
+                if let Some(asked) = request.header("Access-Control-Request-Headers") {
+                    response.set_header("Access-Control-Allow-Headers", asked);
+                }
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            response.set_header("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        response
+    }
+
+    // Mark `response` as readable by the request's origin, if it is allowed.
+    fn allow(&self, request: &Request, mut response: Response) -> Response {
+        // The header depends on the origin unless every origin is allowed.
+        if self.origins.is_some() {
+            vary(&mut response, "Origin");
+        }
+        if let Some(origin) = self.allowed_origin(request) {
+            response.set_header("Access-Control-Allow-Origin", origin);
+        }
+        response
+    }
+}
+
+// Whether `request` is a CORS preflight rather than a plain `OPTIONS`.
+fn is_preflight(request: &Request) -> bool {
+    *request.method() == Method::Options
+        && request.header("Origin").is_some()
+        && request.header("Access-Control-Request-Method").is_some()
+}
+
+// Add `name` to the response's `Vary` header.
+fn vary(response: &mut Response, name: &str) {
+    let value = match response.header("Vary") {
+        Some(existing) => format!("{existing}, {name}"),
+        None => name.to_string(),
+    };
+    response.set_header("Vary", value);
+}
+
+impl<H: Handler> Handler for Cors<H> {
+    fn handle(&self, request: &Request) -> Response {
+        if is_preflight(request) {
+            return self.preflight(request);
+        }
+        self.allow(request, self.inner.handle(request))
+    }
+
+    fn streams_body(&self, request: &Request) -> bool {
+        !is_preflight(request) && self.inner.streams_body(request)
+    }
+
+    fn handle_stream(&self, request: &Request, body: &mut BodyReader<'_>) -> Response {
+        self.allow(request, self.inner.handle_stream(request, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let head = format!("{method} /api HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    fn ok(_: &Request) -> Response {
+        Response::new(200)
+    }
+
+    const PREFLIGHT: &str =
+        "Origin: https://app.example.com\r\nAccess-Control-Request-Method: POST\r\n";
+
+    #[test]
+    fn a_preflight_carries_the_configured_max_age() {
+        let cors = Cors::new(ok).max_age(Duration::from_secs(600));
+        let response = cors.handle(&request("OPTIONS", PREFLIGHT));
+        assert_eq!(response.status(), 204);
+        assert_eq!(response.header("Access-Control-Max-Age"), Some("600"));
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+
+        let response = Cors::new(ok).handle(&request("OPTIONS", PREFLIGHT));
+        assert_eq!(response.header("Access-Control-Max-Age"), None);
+    }
+
+    #[test]
+    fn only_allowed_origins_are_let_through() {
+        let cors = Cors::new(ok).allow_origin("https://app.example.com");
+        let allowed = cors.handle(&request("GET", "Origin: https://app.example.com\r\n"));
+        assert_eq!(allowed.status(), 200);
+        assert_eq!(
+            allowed.header("Access-Control-Allow-Origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(allowed.header("Vary"), Some("Origin"));
+
+        let other = cors.handle(&request("GET", "Origin: https://evil.test\r\n"));
+        assert_eq!(other.status(), 200);
+        assert_eq!(other.header("Access-Control-Allow-Origin"), None);
+
+        let preflight = "Origin: https://evil.test\r\nAccess-Control-Request-Method: POST\r\n";
+        let refused = cors.handle(&request("OPTIONS", preflight));
+        assert_eq!(refused.header("Access-Control-Allow-Methods"), None);
+    }
+}
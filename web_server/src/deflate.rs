@@ -0,0 +1,484 @@
+// Decompression of raw DEFLATE data (RFC 1951), the method zip archives
+// use, and the CRC-32 checksum they carry alongside it. Also compression
+// into gzip (RFC 1952), for response bodies.
+
+// Base lengths and extra bits of the length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+// Base distances and extra bits of the distance symbols 0 to 29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+// The order in which a dynamic block lists the code lengths of its
+// code length alphabet.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+// Decompress `input`, a complete raw DEFLATE stream.
+//
+// Returns `None` if the stream is malformed, or would decompress to more
+// than `limit` bytes, which keeps a small archive entry from expanding
+// without bound.
+pub(crate) fn inflate(input: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut bits = Bits::new(input);
+    let mut out = Vec::with_capacity(limit.min(1024 * 1024));
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored_block(&mut bits, &mut out, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                compressed_block(&mut bits, &mut out, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                compressed_block(&mut bits, &mut out, limit, &literals, &distances)?;
+            }
+            _ => return None,
+        }
+        if last {
+            return Some(out);
+        }
+    }
+}
+
+// Reads a DEFLATE stream's bits, least significant first.
+struct Bits<'a> {
+    input: &'a [u8], // The stream
+    position: usize, // Index of the next byte to load
+    buffer: u32,     // Bits loaded but not yet taken
+    count: u32,      // How many bits `buffer` holds
+}
+
+impl<'a> Bits<'a> {
+    fn new(input: &'a [u8]) -> Bits<'a> {
+        Bits {
+            input,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    // The next `n` bits, at most 16, as a number.
+    fn take(&mut self, n: u32) -> Option<u32> {
+        while self.count < n {
+            let byte = *self.input.get(self.position)?;
+            self.position += 1;
+            self.buffer |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Some(value)
+    }
+
+    // Skip to the next byte boundary and take `n` whole bytes.
+    fn take_bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        // Whole bytes left in the buffer were loaded ahead of time.
+        self.position -= (self.count / 8) as usize;
+        self.buffer = 0;
+        self.count = 0;
+
+        let bytes = self
+            .input
+            .get(self.position..self.position.checked_add(n)?)?;
+        self.position += n;
+        Some(bytes)
+    }
+}
+
+// A canonical Huffman code, decoded one bit at a time.
+struct Huffman {
+    counts: [u16; 16], // How many codes there are of each length
+    symbols: Vec<u16>, // The symbols, ordered by code
+}
+
+impl Huffman {
+    // The code giving each symbol the code length in `lengths`, where zero
+    // leaves the symbol out. Returns `None` for lengths that don't fit in a
+    // prefix code.
+    fn new(lengths: &[u8]) -> Option<Huffman> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+
+        // Each length may use up no more than the codes left for it.
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return None;
+            }
+        }
+
+        // The offset of each length's first symbol in `symbols`.
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+
+        Some(Huffman { counts, symbols })
+    }
+
+    // Decode the next symbol from `bits`.
+    fn decode(&self, bits: &mut Bits<'_>) -> Option<u16> {
+        // `code` is the bits read so far, `first` the first code of the
+        // current length and `index` the position of its symbol.
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return self.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+// Copy a block stored without compression.
+fn stored_block(bits: &mut Bits<'_>, out: &mut Vec<u8>, limit: usize) -> Option<()> {
+    let header = bits.take_bytes(4)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    let complement = u16::from_le_bytes([header[2], header[3]]);
+    if length != !complement || out.len() + usize::from(length) > limit {
+        return None;
+    }
+    out.extend_from_slice(bits.take_bytes(usize::from(length))?);
+    Some(())
+}
+
+// The codes that blocks of type 1 use, fixed by the format.
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    // Both are complete codes, so neither can fail to build.
+    let literals = Huffman::new(&lengths).unwrap();
+    let distances = Huffman::new(&[5; 30]).unwrap();
+    (literals, distances)
+}
+
+// Read the codes a block of type 2 describes in its header.
+fn dynamic_codes(bits: &mut Bits<'_>) -> Option<(Huffman, Huffman)> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let length_count = bits.take(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return None;
+    }
+
+    // First the code that the other two codes' lengths are written in.
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+        code_lengths[symbol] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    // Then the lengths of both codes, run-length encoded as one sequence.
+    let total = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let (length, repeat) = match code_lengths.decode(bits)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => (*lengths.last()?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            18 => (0, 11 + bits.take(7)?),
+            _ => return None,
+        };
+        for _ in 0..repeat {
+            lengths.push(length);
+        }
+        if lengths.len() > total {
+            return None;
+        }
+    }
+
+    // A block without an end-of-block code could never finish.
+    if lengths[256] == 0 {
+        return None;
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..])?;
+    Some((literals, distances))
+}
+
+// Decode a block of literals and back references with the given codes.
+fn compressed_block(
+    bits: &mut Bits<'_>,
+    out: &mut Vec<u8>,
+    limit: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Option<()> {
+    loop {
+        let symbol = usize::from(literals.decode(bits)?);
+        if symbol < 256 {
+            if out.len() >= limit {
+                return None;
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Some(());
+        }
+
+        let symbol = symbol - 257;
+        let length = usize::from(*LENGTH_BASE.get(symbol)?)
+            + bits.take(u32::from(LENGTH_EXTRA[symbol]))? as usize;
+
+        let symbol = usize::from(distances.decode(bits)?);
+        let distance = usize::from(*DISTANCE_BASE.get(symbol)?)
+            + bits.take(u32::from(DISTANCE_EXTRA[symbol]))? as usize;
+
+        if distance > out.len() || out.len() + length > limit {
+            return None;
+        }
+        // The copy may overlap what it is copying, repeating it, so it goes
+        // a byte at a time.
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+// The longest match a compressed block can copy, and the furthest back.
+const MAX_MATCH: usize = 258;
+const WINDOW_SIZE: usize = 32 * 1024;
+
+// How many earlier places with the same three bytes are tried for a match.
+// More finds longer matches, at the cost of time on repetitive input.
+const MAX_CHAIN: usize = 64;
+
+// Bits of the hash of three bytes that finds where they were seen before.
+const HASH_BITS: u32 = 15;
+
+// Compress `input` into a gzip member: a header, the input as a single
+// DEFLATE block with the fixed codes, and the input's CRC-32 and length.
+//
+// Matches are found by hashing every three bytes, which shrinks text well
+// for little code; the fixed codes spare encoding a table but make this
+// weaker on binary data, which callers should leave uncompressed anyway.
+pub(crate) fn gzip(input: &[u8]) -> Vec<u8> {
+    // Magic, method 8 (deflate), no flags, no time, no extra flags, OS
+    // unknown.
+    let mut out = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 255];
+    let mut bits = BitWriter::new(&mut out);
+
+    // A single last block with the fixed codes.
+    bits.put(1, 1);
+    bits.put(1, 2);
+    compress_block(input, &mut bits);
+    put_literal(&mut bits, 256);
+    bits.finish();
+
+    out.extend_from_slice(&crc32(input).to_le_bytes());
+    // The length is stored modulo 2^32.
+    out.extend_from_slice(&(input.len() as u32).to_le_bytes());
+    out
+}
+
+// Write `input` as literals and matches found in the 32 KiB before each
+// position.
+fn compress_block(input: &[u8], bits: &mut BitWriter<'_>) {
+    // The latest position each hash was seen at, and for every position
+    // the one before it with the same hash, offset by one so 0 means none.
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut prev = vec![0usize; input.len()];
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + 3 <= input.len() {
+            let h = hash(input, i);
+            prev[i] = head[h];
+            head[h] = i + 1;
+        }
+    };
+
+    let mut i = 0;
+    while i < input.len() {
+        let (length, distance) = longest_match(input, i, &head, &prev);
+        if length >= 3 {
+            put_match(bits, length, distance);
+            for j in i..i + length {
+                insert(j, &mut head, &mut prev);
+            }
+            i += length;
+        } else {
+            put_literal(bits, u16::from(input[i]));
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+}
+
+// The hash of the three bytes at `i`.
+fn hash(input: &[u8], i: usize) -> usize {
+    let bytes = u32::from(input[i]) << 16 | u32::from(input[i + 1]) << 8 | u32::from(input[i + 2]);
+    (bytes.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+// The longest earlier copy of the bytes at `i`, as a length and distance.
+// A length under 3 means there is none worth using.
+fn longest_match(input: &[u8], i: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if i + 3 > input.len() {
+        return (0, 0);
+    }
+    let limit = (input.len() - i).min(MAX_MATCH);
+
+    let (mut best_length, mut best_distance) = (0, 0);
+    let mut candidate = head[hash(input, i)];
+    for _ in 0..MAX_CHAIN {
+        let Some(start) = candidate.checked_sub(1) else {
+            break;
+        };
+        if i - start > WINDOW_SIZE {
+            break;
+        }
+
+        let length = input[start..]
+            .iter()
+            .zip(&input[i..i + limit])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best_length {
+            (best_length, best_distance) = (length, i - start);
+            if length == limit {
+                break;
+            }
+        }
+        candidate = prev[start];
+    }
+    (best_length, best_distance)
+}
+
+// Write a literal byte, or the end of block for 256, with the fixed
+// literal/length code.
+fn put_literal(bits: &mut BitWriter<'_>, symbol: u16) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    bits.put_code(u32::from(code), length);
+}
+
+// Write a copy of `length` bytes from `distance` back.
+fn put_match(bits: &mut BitWriter<'_>, length: usize, distance: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .unwrap();
+    put_literal(bits, 257 + index as u16);
+    let extra = length - usize::from(LENGTH_BASE[index]);
+    bits.put(extra as u32, u32::from(LENGTH_EXTRA[index]));
+
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .unwrap();
+    bits.put_code(index as u32, 5);
+    let extra = distance - usize::from(DISTANCE_BASE[index]);
+    bits.put(extra as u32, u32::from(DISTANCE_EXTRA[index]));
+}
+
+// Writes a DEFLATE stream's bits, least significant first.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>, // Where whole bytes go
+    buffer: u32,          // Bits not yet making up a whole byte
+    count: u32,           // How many bits `buffer` holds
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> BitWriter<'a> {
+        BitWriter {
+            out,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    // Write the low `n` bits of `value`, at most 16, as a number.
+    fn put(&mut self, value: u32, n: u32) {
+        self.buffer |= value << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Write an `n`-bit Huffman code, which goes most significant bit first.
+    fn put_code(&mut self, code: u32, n: u32) {
+        self.put(code.reverse_bits() >> (32 - n), n);
+    }
+
+    // Pad the last byte out with zeros.
+    fn finish(mut self) {
+        if self.count > 0 {
+            self.put(0, 8 - self.count);
+        }
+    }
+}
+
+// The CRC-32 lookup table, one entry per byte value.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// The CRC-32 of `bytes`, as zip and gzip compute it.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
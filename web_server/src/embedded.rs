@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use crate::{error_page, handler::Handler, method::Method, request::Request, response::Response};
+
+/// Serves static assets compiled into the executable.
+///
+/// Assets are registered by path along with their bytes and content type,
+/// typically from `include_bytes!`, so a single binary can serve its own
+/// front end without touching the filesystem. Each asset gets an `ETag`
+/// derived from a hash of its contents, which stays the same from one build
+/// to the next as long as the bytes do.
+#[derive(Default)]
+pub struct EmbeddedAssets {
+    assets: HashMap<String, EmbeddedAsset>, // Assets by request path
+}
+
+struct EmbeddedAsset {
+    bytes: &'static [u8], // The asset's contents
+    content_type: String, // Value of the Content-Type header
+    etag: String,         // Quoted entity tag computed from the contents
+}
+
+impl EmbeddedAssets {
+    /// Create an empty bundle.
+    pub fn new() -> EmbeddedAssets {
+        EmbeddedAssets::default()
+    }
+
+    /// Build a bundle from a map of path to bytes and content type.
+    pub fn from_map<P, C>(assets: HashMap<P, (&'static [u8], C)>) -> EmbeddedAssets
+    where
+        P: Into<String>,
+        C: Into<String>,
+    {
+        assets.into_iter().fold(
+            EmbeddedAssets::new(),
+            |bundle, (path, (bytes, content_type))| bundle.with_asset(path, bytes, content_type),
+        )
+    }
+
+    /// Add an asset served at `path`, e.g. `/app.js`.
+    pub fn with_asset(
+        mut self,
+        path: impl Into<String>,
+        bytes: &'static [u8],
+        content_type: impl Into<String>,
+    ) -> EmbeddedAssets {
+        let asset = EmbeddedAsset {
+            bytes,
+            content_type: content_type.into(),
+            etag: etag(bytes),
+        };
+        self.assets.insert(path.into(), asset);
+        self
+    }
+
+    /// Serve the asset registered at the request path.
+    ///
+    /// Returns `None` if there is no such asset.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        let asset = self.assets.get(request.path())?;
+
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Some(Response::new(405).with_header("Allow", "GET, HEAD"));
+        }
+
+        // The client's cached copy is still current.
+        if request.header("If-None-Match") == Some(asset.etag.as_str()) {
+            return Some(Response::new(304).with_header("ETag", asset.etag.clone()));
+        }
+
+        let response = Response::new(200)
+            .with_header("Content-Type", asset.content_type.clone())
+            .with_header("ETag", asset.etag.clone());
+
+        // HEAD gets the same headers as GET, but no body.
+        if *request.method() == Method::Head {
+            return Some(response.with_header("Content-Length", asset.bytes.len().to_string()));
+        }
+
+        Some(response.with_body(asset.bytes))
+    }
+}
+
+impl Handler for EmbeddedAssets {
+    /// Serve the asset, or a 404 if there isn't one at the request path.
+    fn handle(&self, request: &Request) -> Response {
+        self.serve(request)
+            .unwrap_or_else(|| error_page::render(Some(request), 404))
+    }
+}
+
+// A quoted entity tag for `bytes`, the same for the same bytes in every
+// build.
+pub(crate) fn etag(bytes: &[u8]) -> String {
+    format!("\"{:016x}\"", fnv1a(bytes))
+}
+
+// 64-bit FNV-1a. It's not cryptographic, but it is fast, spreads small
+// changes well and, unlike `DefaultHasher`, is guaranteed to give the same
+// result in every build, which keeps ETags stable across releases.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str, path: &str, headers: &str) -> Request {
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    fn bundle() -> EmbeddedAssets {
+        EmbeddedAssets::from_map(HashMap::from([
+            (
+                "/index.html",
+                (&b"<h1>hi</h1>"[..], "text/html; charset=utf-8"),
+            ),
+            ("/logo.png", (&b"\x89PNG"[..], "image/png")),
+        ]))
+    }
+
+    #[test]
+    fn each_asset_is_served_with_its_content_type() {
+        let assets = bundle();
+
+        let html = assets.handle(&request("GET", "/index.html", ""));
+        assert_eq!(html.status(), 200);
+        assert_eq!(
+            html.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(html.body(), b"<h1>hi</h1>");
+
+        let png = assets.handle(&request("GET", "/logo.png", ""));
+        assert_eq!(png.header("Content-Type"), Some("image/png"));
+        assert_eq!(png.body(), b"\x89PNG");
+
+        assert_eq!(
+            assets.handle(&request("GET", "/missing.js", "")).status(),
+            404
+        );
+    }
+
+    #[test]
+    fn etags_are_stable_and_answer_conditional_requests() {
+        let first = bundle().handle(&request("GET", "/index.html", ""));
+        let second = bundle().handle(&request("GET", "/index.html", ""));
+        let etag = first.header("ETag").unwrap();
+        assert_eq!(second.header("ETag"), Some(etag));
+        let png = bundle().handle(&request("GET", "/logo.png", ""));
+        assert_ne!(png.header("ETag"), Some(etag));
+
+        let cached = bundle().handle(&request(
+            "GET",
+            "/index.html",
+            &format!("If-None-Match: {etag}\r\n"),
+        ));
+        assert_eq!(cached.status(), 304);
+        assert!(cached.body().is_empty());
+    }
+
+    #[test]
+    fn the_hash_behind_the_etags_is_fnv_1a() {
+        assert_eq!(etag(b""), "\"cbf29ce484222325\"");
+        assert_eq!(etag(b"a"), "\"af63dc4c8601ec8c\"");
+        assert_eq!(etag(b"foobar"), "\"85944171f73967e8\"");
+    }
+}
@@ -0,0 +1,97 @@
+use std::{error::Error, fmt, io};
+
+use crate::request::ParseError;
+
+/// Everything that can go wrong while reading a request off a connection.
+///
+/// Transport failures and protocol failures are kept apart so the connection
+/// handler can decide, in one place, whether to answer with an error status
+/// or to simply drop the connection.
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),     // The connection failed or was closed by the client
+    Parse(ParseError), // The client sent something that isn't valid HTTP
+    TooLarge,          // The request exceeds a configured size limit
+    TooManyHeaders,    // The request has more header fields than allowed
+    UriTooLong,        // The request target is longer than allowed
+    ExpectationFailed, // The request's `Expect` header can't be met
+    Overloaded,        // Buffering the request would exceed the memory budget
+    Timeout,           // The client was too slow to send its request
+}
+
+impl HttpError {
+    /// The status code to answer with, or `None` if the connection should be
+    /// closed without a response because the client can't receive one.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Io(_) => None,
+            HttpError::Parse(_) => Some(400),
+            HttpError::TooLarge => Some(413),
+            HttpError::TooManyHeaders => Some(431),
+            HttpError::UriTooLong => Some(414),
+            HttpError::ExpectationFailed => Some(417),
+            HttpError::Overloaded => Some(503),
+            HttpError::Timeout => Some(408),
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Io(err) => write!(f, "connection error: {err}"),
+            HttpError::Parse(err) => err.fmt(f),
+            HttpError::TooLarge => f.write_str("request is too large"),
+            HttpError::TooManyHeaders => f.write_str("request has too many header fields"),
+            HttpError::UriTooLong => f.write_str("request target is too long"),
+            HttpError::ExpectationFailed => f.write_str("unsupported expectation"),
+            HttpError::Overloaded => f.write_str("server is out of memory for requests"),
+            HttpError::Timeout => f.write_str("timed out waiting for the request"),
+        }
+    }
+}
+
+impl Error for HttpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpError::Io(err) => Some(err),
+            HttpError::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        // A read timeout surfaces as `WouldBlock` on Unix and `TimedOut` on
+        // Windows.
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HttpError::Timeout,
+            _ => HttpError::Io(err),
+        }
+    }
+}
+
+impl From<ParseError> for HttpError {
+    fn from(err: ParseError) -> HttpError {
+        HttpError::Parse(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_timeouts_become_timeouts_and_other_failures_stay_io() {
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut] {
+            let err = HttpError::from(io::Error::from(kind));
+            assert!(matches!(err, HttpError::Timeout), "{kind:?}");
+            assert_eq!(err.status(), Some(408));
+        }
+
+        let err = HttpError::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(matches!(err, HttpError::Io(_)));
+        assert_eq!(err.status(), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Bodies for the error responses the server generates itself.
+//!
+//! They name the status and nothing else, so a small malicious request
+//! can't get a big response reflected back, or smuggle markup into one.
+//! Anything taken from a request for a generated body goes through `echo`.
+
+use crate::{
+    negotiate,
+    request::Request,
+    response::{reason_phrase, Response},
+};
+
+/// The most bytes of request data `echo` lets into a generated body.
+pub const MAX_ECHO_LENGTH: usize = 100;
+
+/// Make `text`, taken from a request, safe to put in a generated HTML body.
+///
+/// It is cut to `MAX_ECHO_LENGTH` bytes, ending in `...` if anything was
+/// left out, and escaped for HTML text and attribute values.
+pub fn echo(text: &str) -> String {
+    let mut end = text.len().min(MAX_ECHO_LENGTH);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    let mut echoed = escape_html(&text[..end]);
+    if end < text.len() {
+        echoed.push_str("...");
+    }
+    echoed
+}
+
+// Escape the characters that are special in HTML text and attributes.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Build an error response for `status`, rendered as JSON when the request's
+/// `Accept` header prefers it and as HTML otherwise.
+///
+/// Pass `None` when there is no parsed request to negotiate with, for
+/// example when the request itself was malformed.
+pub fn render(request: Option<&Request>, status: u16) -> Response {
+    if wants_json(request) {
+        json(status)
+    } else {
+        html(status)
+    }
+}
+
+/// Whether the error response to `request` should be JSON.
+pub fn wants_json(request: Option<&Request>) -> bool {
+    negotiate::prefers_json(request.and_then(|request| request.header("Accept")))
+}
+
+/// An error response with a JSON body like `{"error":"not found","status":404}`.
+pub fn json(status: u16) -> Response {
+    let message = reason_phrase(status).to_ascii_lowercase();
+
+    Response::new(status)
+        .with_header("Content-Type", "application/json; charset=utf-8")
+        .with_body(format!("{{\"error\":\"{message}\",\"status\":{status}}}"))
+}
+
+/// An error response with a small HTML page naming the status.
+pub fn html(status: u16) -> Response {
+    let reason = reason_phrase(status);
+
+    Response::new(status)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(format!(
+            "<!doctype html>\n<html lang=\"en\">\n    <head>\n        <meta charset=\"utf-8\" />\n        <title>{status} {reason}</title>\n    </head>\n    <body>\n        <h1>{status} {reason}</h1>\n    </body>\n</html>\n"
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request_accepting(accept: Option<&str>) -> Request {
+        let accept = accept.map(|accept| format!("Accept: {accept}\r\n"));
+        let head = format!(
+            "GET /missing HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            accept.unwrap_or_default()
+        );
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn a_json_client_gets_a_json_404() {
+        let request = request_accepting(Some("application/json"));
+        let response = render(Some(&request), 404);
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("application/json; charset=utf-8")
+        );
+        assert_eq!(response.body(), br#"{"error":"not found","status":404}"#);
+    }
+
+    #[test]
+    fn a_browser_gets_an_html_404() {
+        let accept = "text/html,application/xhtml+xml,application/json;q=0.9,*/*;q=0.8";
+        let response = render(Some(&request_accepting(Some(accept))), 404);
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("<h1>404 Not Found</h1>"), "{body}");
+    }
+
+    #[test]
+    fn html_is_the_default() {
+        let response = render(Some(&request_accepting(None)), 500);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let response = render(None, 400);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn a_404_for_a_huge_path_stays_small_and_echoes_none_of_it() {
+        let path = format!("/<script>{}", "a".repeat(4000));
+        let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        let response = render(Some(&request), 404);
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.len() < 200, "{} bytes", body.len());
+        assert!(!body.contains("<script>"));
+        assert!(!body.contains("aaaa"));
+    }
+
+    #[test]
+    fn echo_cuts_long_text_short_and_escapes_it() {
+        assert_eq!(echo("<b>&\"</b>"), "&lt;b&gt;&amp;&quot;&lt;/b&gt;");
+
+        let echoed = echo(&"<".repeat(1000));
+        assert_eq!(echoed, format!("{}...", "&lt;".repeat(MAX_ECHO_LENGTH)));
+
+        // The cut never splits a character.
+        let echoed = echo(&format!("{}é", "a".repeat(MAX_ECHO_LENGTH - 1)));
+        assert_eq!(echoed, format!("{}...", "a".repeat(MAX_ECHO_LENGTH - 1)));
+    }
+
+    #[test]
+    fn a_redirect_to_a_long_location_links_it_in_full_but_shows_it_cut() {
+        let location = format!("/next?q={}", "x".repeat(500));
+        let response = Response::redirect(302, &location);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+
+        assert!(body.starts_with(&format!("<a href=\"{location}\">")));
+        assert!(body.len() < location.len() + 200, "{} bytes", body.len());
+        assert!(body.ends_with("...</a>\n"), "{body}");
+    }
+}
@@ -0,0 +1,63 @@
+//! Answers the `/favicon.ico` request browsers make on every page visit.
+
+use std::path::Path;
+
+use crate::{request::Request, response::Response, static_files::StaticFiles};
+
+/// The path browsers request the site icon from.
+pub const PATH: &str = "/favicon.ico";
+
+/// Answer a request for the favicon.
+///
+/// With `icon` set, the file is served like any static file, with
+/// validators and range support. Without one, or if the file can't be
+/// found, the answer is `204 No Content`, so browsers stop asking without
+/// each visit producing a 404.
+pub fn serve(request: &Request, icon: Option<&Path>) -> Response {
+    icon.and_then(|icon| serve_file(request, icon))
+        .unwrap_or_else(|| Response::new(204))
+}
+
+// Serve `icon` through `StaticFiles`, rooted at the file's own directory.
+fn serve_file(request: &Request, icon: &Path) -> Option<Response> {
+    let name = icon.file_name()?.to_str()?;
+    let root = icon.parent().unwrap_or(Path::new(""));
+
+    StaticFiles::new(root).serve_file(request, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::request::Limits;
+
+    fn get_favicon() -> Request {
+        let head = format!("GET {PATH} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn a_configured_favicon_is_served_as_an_icon() {
+        let dir = std::env::temp_dir().join(format!("web_server-favicon-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let icon = dir.join("site.ico");
+        fs::write(&icon, b"\0\0\x01\0").unwrap();
+
+        let response = serve(&get_favicon(), Some(&icon));
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("image/x-icon"));
+        assert_eq!(response.body(), b"\0\0\x01\0");
+    }
+
+    #[test]
+    fn without_a_favicon_the_answer_is_204() {
+        assert_eq!(serve(&get_favicon(), None).status(), 204);
+
+        let missing = Path::new("/nonexistent/web_server/favicon.ico");
+        let response = serve(&get_favicon(), Some(missing));
+        assert_eq!(response.status(), 204);
+        assert!(response.body().is_empty());
+    }
+}
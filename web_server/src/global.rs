@@ -0,0 +1,79 @@
+//! A process-wide thread pool for programs that don't want to pass a
+//! `ThreadPool` around.
+
+use std::{env, num::NonZeroUsize, sync::OnceLock};
+
+use crate::{available_parallelism, ThreadPool};
+
+/// Environment variable that sets the size of the default pool.
+pub const POOL_SIZE_VAR: &str = "WEB_SERVER_POOL_SIZE";
+
+static DEFAULT_POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+/// The process-wide default pool, created on first use.
+///
+/// Its size is read from the `WEB_SERVER_POOL_SIZE` environment variable if
+/// that holds a positive number, and otherwise matches the number of CPUs.
+/// Initialization is race-free: concurrent first calls all get the same pool.
+///
+/// The pool lives for the rest of the process and is never dropped, so its
+/// workers are not joined at exit and queued jobs may not run once `main`
+/// returns.
+pub fn default_pool() -> &'static ThreadPool {
+    DEFAULT_POOL.get_or_init(|| ThreadPool::new(default_size()))
+}
+
+/// Execute a function on the default pool.
+///
+/// This is shorthand for `default_pool().execute(f)`.
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    default_pool().execute(f);
+}
+
+// Work out how many threads the default pool should have.
+fn default_size() -> usize {
+    let from_env = env::var(POOL_SIZE_VAR)
+        .ok()
+        .and_then(|size| size.trim().parse::<NonZeroUsize>().ok());
+
+    from_env
+        .map(NonZeroUsize::get)
+        .unwrap_or_else(available_parallelism)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn jobs_spawned_on_the_default_pool_run() {
+        let (done, finished) = crossbeam_channel::unbounded();
+        for i in 0..16 {
+            let done = done.clone();
+            spawn(move || done.send(i).unwrap());
+        }
+
+        let mut ran: Vec<_> = (0..16)
+            .map(|_| finished.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        ran.sort_unstable();
+        assert_eq!(ran, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn every_thread_gets_the_same_default_pool() {
+        let pools: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| default_pool() as *const ThreadPool as usize))
+            .collect();
+        let first = default_pool() as *const ThreadPool as usize;
+        for pool in pools {
+            assert_eq!(pool.join().unwrap(), first);
+        }
+        assert!(default_pool().size() >= 1);
+    }
+}
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use crate::{
+    error_page,
+    request::{BodyChunks, BodyReader, Request},
+    response::Response,
+    ThreadPool,
+};
+
+/// Turns a request into a response.
+///
+/// This is the extension point for request dispatch: the server calls
+/// `handle` once for every request it reads, from whichever worker thread
+/// owns the connection. Closures taking a `&Request` and returning a
+/// `Response` implement it directly, so a plain function works as a handler.
+pub trait Handler: Send + Sync {
+    /// Produce the response to `request`.
+    fn handle(&self, request: &Request) -> Response;
+
+    /// Whether `request`'s body should be handed to `handle_stream` as it
+    /// arrives instead of being read into memory first.
+    ///
+    /// Defaults to `false`. A handler accepting large uploads can return
+    /// `true` for them and implement `handle_stream`.
+    fn streams_body(&self, _request: &Request) -> bool {
+        false
+    }
+
+    /// Produce the response to `request`, reading its body from `body`.
+    ///
+    /// This is called instead of `handle` when `streams_body` returns
+    /// `true`, and `request.body()` is empty then. A handler can return
+    /// without reading the body, e.g. to reject the request. Whatever part
+    /// of it is left unread is skipped afterwards, up to
+    /// `Limits::max_drain_size`; a bigger remainder closes the connection
+    /// once the response is sent. If reading the body fails because it is
+    /// too large or malformed, the response is replaced with the matching
+    /// error.
+    ///
+    /// The default ignores the body and calls `handle`.
+    fn handle_stream(&self, request: &Request, _body: &mut BodyReader<'_>) -> Response {
+        self.handle(request)
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// A handler that streams every request's body to `consume`, run on a pool
+/// of its own while the connection reads the body, through
+/// `BodyReader::pipe`.
+///
+/// `consume` gets the request and its chunks and produces the response. A
+/// body that can't be read still gets the matching error, whatever
+/// `consume` returns, and a pool shut down before `consume` could run
+/// gets a `503`. A request whose body was read before it got here, e.g.
+/// one the server handles itself, is handed over in one chunk.
+pub struct PipedBody<F> {
+    pool: Arc<ThreadPool>, // Where `consume` runs
+    capacity: usize,       // How many chunks may wait for `consume`
+    consume: Arc<F>,
+}
+
+impl<F> PipedBody<F>
+where
+    F: Fn(Request, BodyChunks) -> Response + Send + Sync + 'static,
+{
+    /// Create a handler consuming bodies with `consume` on `pool`, holding
+    /// at most `capacity` chunks of each for it.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if `capacity` is zero or `pool` is
+    /// single-threaded.
+    pub fn new(pool: Arc<ThreadPool>, capacity: usize, consume: F) -> PipedBody<F> {
+        assert!(capacity > 0, "a pipe needs room for a chunk");
+        assert!(!pool.inline, "a single-threaded pool can't consume a pipe");
+        PipedBody {
+            pool,
+            capacity,
+            consume: Arc::new(consume),
+        }
+    }
+}
+
+impl<F> Handler for PipedBody<F>
+where
+    F: Fn(Request, BodyChunks) -> Response + Send + Sync + 'static,
+{
+    fn handle(&self, request: &Request) -> Response {
+        let chunks = BodyChunks::buffered(request.body().to_vec());
+        (self.consume)(request.clone(), chunks)
+    }
+
+    fn streams_body(&self, _request: &Request) -> bool {
+        true
+    }
+
+    fn handle_stream(&self, request: &Request, body: &mut BodyReader<'_>) -> Response {
+        let consume = Arc::clone(&self.consume);
+        let owned = request.clone();
+        let piped = body.pipe(&self.pool, self.capacity, move |chunks| {
+            consume(owned, chunks)
+        });
+        // A body that couldn't be read has this replaced with its own
+        // error by the connection, so what is left is the pool dropping
+        // `consume` unrun.
+        piped.unwrap_or_else(|_| error_page::render(Some(request), 503))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, thread};
+
+    use super::*;
+    use crate::request::Limits;
+
+    fn teapot(_: &Request) -> Response {
+        Response::new(418)
+    }
+
+    #[test]
+    fn functions_and_closures_are_handlers() {
+        let head = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+
+        let status = 204;
+        let handlers: [Box<dyn Handler>; 2] = [
+            Box::new(teapot),
+            Box::new(move |_: &Request| Response::new(status)),
+        ];
+        let statuses: Vec<_> = handlers
+            .iter()
+            .map(|handler| handler.handle(&request).status())
+            .collect();
+        assert_eq!(statuses, [418, 204]);
+        assert!(!handlers[0].streams_body(&request));
+    }
+
+    // Answers with the size of the body and the thread that read it.
+    fn measure(_: Request, mut chunks: BodyChunks) -> Response {
+        let mut body = Vec::new();
+        chunks.read_to_end(&mut body).unwrap();
+        let thread = format!("{:?}", thread::current().id());
+        Response::new(200).with_body(format!("{} {thread}", body.len()))
+    }
+
+    #[test]
+    fn a_piped_body_is_consumed_on_the_pool() {
+        let handler = PipedBody::new(Arc::new(ThreadPool::new(2)), 2, measure);
+        let length = 100 * 1024;
+        let head =
+            format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {length}\r\n\r\n");
+        let request = Request::read_head(&mut head.as_bytes(), &Limits::default()).unwrap();
+        assert!(handler.streams_body(&request));
+
+        let uploaded = vec![b'x'; length];
+        let limits = Limits {
+            max_body_size: length,
+            ..Limits::default()
+        };
+        let mut source = uploaded.as_slice();
+        let mut body = BodyReader::new(&mut source, &request, &limits).unwrap();
+        let response = handler.handle_stream(&request, &mut body);
+
+        let answer = String::from_utf8(response.body().to_vec()).unwrap();
+        let (size, thread) = answer.split_once(' ').unwrap();
+        assert_eq!(size, length.to_string());
+        assert_ne!(thread, format!("{:?}", thread::current().id()));
+        assert!(body.is_finished());
+    }
+
+    #[test]
+    fn a_body_read_up_front_is_handed_over_in_one_go() {
+        let handler = PipedBody::new(Arc::new(ThreadPool::new(2)), 1, measure);
+        let post = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello";
+        let request = Request::read_from(&mut post.as_bytes(), &Limits::default()).unwrap();
+
+        let response = handler.handle(&request);
+        let answer = String::from_utf8(response.body().to_vec()).unwrap();
+        assert_eq!(answer, format!("5 {:?}", thread::current().id()));
+    }
+
+    #[test]
+    #[should_panic(expected = "single-threaded")]
+    fn a_piped_body_needs_a_pool_with_workers() {
+        PipedBody::new(Arc::new(ThreadPool::single_threaded()), 1, measure);
+    }
+}
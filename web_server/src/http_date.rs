@@ -0,0 +1,255 @@
+//! Formatting and parsing of HTTP dates (RFC 7231, section 7.1.1.1).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const LONG_DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a point in time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+///
+/// Times before the Unix epoch are clamped to the epoch, and sub-second
+/// precision is dropped since HTTP dates only carry whole seconds.
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    // 1970-01-01 was a Thursday, which is index 3 in `DAYS`.
+    let weekday = DAYS[((days + 3) % 7) as usize];
+
+    format!(
+        "{weekday}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+        MONTHS[month as usize - 1],
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Parse an HTTP date.
+///
+/// Dates are sent as IMF-fixdates such as `Sun, 06 Nov 1994 08:49:37 GMT`,
+/// but for leniency the two obsolete formats are accepted too: RFC 850
+/// dates like `Sunday, 06-Nov-94 08:49:37 GMT`, whose two-digit years are
+/// read as 1970 to 2069, and asctime dates like `Sun Nov  6 08:49:37 1994`.
+///
+/// Returns `None` if the value is not a valid date in any of them.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+
+    parse_imf_fixdate(value)
+        .or_else(|| parse_rfc850(value))
+        .or_else(|| parse_asctime(value))
+}
+
+// "Sun, 06 Nov 1994 08:49:37 GMT"
+fn parse_imf_fixdate(value: &str) -> Option<SystemTime> {
+    let (weekday, rest) = value.split_once(", ")?;
+    if !DAYS.contains(&weekday) {
+        return None;
+    }
+
+    let mut parts = rest.split(' ');
+    let day = parse_number(parts.next()?, 2)?;
+    let month = parse_month(parts.next()?)?;
+    let year = parse_number(parts.next()?, 4)?;
+    let time = parts.next()?;
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+
+    to_system_time(year, month, day, time)
+}
+
+// "Sunday, 06-Nov-94 08:49:37 GMT"
+fn parse_rfc850(value: &str) -> Option<SystemTime> {
+    let (weekday, rest) = value.split_once(", ")?;
+    if !LONG_DAYS.contains(&weekday) {
+        return None;
+    }
+
+    let mut parts = rest.split(' ');
+    let mut date = parts.next()?.split('-');
+    let time = parts.next()?;
+    if parts.next()? != "GMT" || parts.next().is_some() {
+        return None;
+    }
+
+    let day = parse_number(date.next()?, 2)?;
+    let month = parse_month(date.next()?)?;
+    let year = parse_number(date.next()?, 2)?;
+    if date.next().is_some() {
+        return None;
+    }
+    let year = if year < 70 { 2000 + year } else { 1900 + year };
+
+    to_system_time(year, month, day, time)
+}
+
+// "Sun Nov  6 08:49:37 1994", with the day padded by a space, not a zero.
+fn parse_asctime(value: &str) -> Option<SystemTime> {
+    let (weekday, rest) = value.split_once(' ')?;
+    if !DAYS.contains(&weekday) {
+        return None;
+    }
+    let (month, rest) = rest.split_once(' ')?;
+    let month = parse_month(month)?;
+
+    // The day is two characters wide, either "06"-style digits or " 6".
+    let day = rest.get(..2)?;
+    let day = parse_number(&day.replace(' ', "0"), 2)?;
+
+    let mut parts = rest.get(2..)?.strip_prefix(' ')?.split(' ');
+    let time = parts.next()?;
+    let year = parse_number(parts.next()?, 4)?;
+    if parts.next().is_some() {
+        return None;
+    }
+
+    to_system_time(year, month, day, time)
+}
+
+// Parse a fixed-width decimal field.
+fn parse_number(field: &str, width: usize) -> Option<u32> {
+    if field.len() != width || !field.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    field.parse().ok()
+}
+
+// Map a three-letter month name to its number, starting at 1 for January.
+fn parse_month(name: &str) -> Option<u32> {
+    MONTHS
+        .iter()
+        .position(|month| *month == name)
+        .map(|index| index as u32 + 1)
+}
+
+// Combine a calendar date and an `HH:MM:SS` time into a `SystemTime`.
+fn to_system_time(year: u32, month: u32, day: u32, time: &str) -> Option<SystemTime> {
+    let mut fields = time.split(':');
+    let hour = parse_number(fields.next()?, 2)?;
+    let minute = parse_number(fields.next()?, 2)?;
+    let second = parse_number(fields.next()?, 2)?;
+    if fields.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    if day == 0 || day > days_in_month(year, month) || year < 1970 {
+        return None;
+    }
+
+    let days = days_from_civil(year as i64, month, day) as u64;
+    let secs = days * 86_400 + u64::from(hour * 3600 + minute * 60 + second);
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        _ if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        _ => 28,
+    }
+}
+
+// Convert days since 1970-01-01 into a (year, month, day) triple.
+//
+// This is Howard Hinnant's `civil_from_days` algorithm, which works on
+// 400-year "eras" so that leap years fall out of plain integer arithmetic.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097); // Day of era, [0, 146096]
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365; // Year of era, [0, 399]
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // Day of year, [0, 365]
+    let mp = (5 * doy + 2) / 153; // Month starting in March, [0, 11]
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+// The inverse of `civil_from_days`.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example from RFC 9110, in each of its three formats.
+    const RFC_EXAMPLE: u64 = 784_111_777;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn known_timestamps_are_formatted_as_imf_fixdates() {
+        assert_eq!(format(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format(at(RFC_EXAMPLE)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(format(at(951_782_400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(format(at(4_102_444_799)), "Thu, 31 Dec 2099 23:59:59 GMT");
+        assert_eq!(
+            format(at(RFC_EXAMPLE) + Duration::from_millis(999)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+    }
+
+    #[test]
+    fn formatted_dates_parse_back_to_the_same_time() {
+        for secs in [0, RFC_EXAMPLE, 951_782_400, 1_700_000_000, 4_102_444_799] {
+            assert_eq!(parse(&format(at(secs))), Some(at(secs)), "{secs}");
+        }
+    }
+
+    #[test]
+    fn each_accepted_format_parses() {
+        let expected = Some(at(RFC_EXAMPLE));
+        assert_eq!(parse("Sun, 06 Nov 1994 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sunday, 06-Nov-94 08:49:37 GMT"), expected);
+        assert_eq!(parse("Sun Nov  6 08:49:37 1994"), expected);
+    }
+
+    #[test]
+    fn malformed_dates_are_refused() {
+        for value in [
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 31 Feb 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 6 Nov 1994 08:49:37 GMT",
+        ] {
+            assert_eq!(parse(value), None, "{value:?}");
+        }
+    }
+}
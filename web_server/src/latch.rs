@@ -0,0 +1,183 @@
+//! Waiting for a group of jobs to finish.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::ThreadPool;
+
+/// Counts the jobs tied to it that have yet to finish, so their submitter
+/// can wait for all of them at once.
+///
+/// Each job submitted with `execute` adds one to the count, and takes one
+/// off when it is done, whether it returned, panicked or was dropped
+/// without running. `wait` blocks until the count is back at zero. Only
+/// the jobs tied to the latch are waited for, not the rest of the pool's
+/// queue, and a latch can be used again once it has been waited on.
+/// Clones share the same count, so jobs can be tied to it from several
+/// places.
+///
+/// ```
+/// use std::sync::{
+///     atomic::{AtomicUsize, Ordering},
+///     Arc,
+/// };
+/// use web_server::{CountdownLatch, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let latch = CountdownLatch::new();
+/// let done = Arc::new(AtomicUsize::new(0));
+///
+/// for _ in 0..10 {
+///     let done = Arc::clone(&done);
+///     latch.execute(&pool, move || {
+///         done.fetch_add(1, Ordering::SeqCst);
+///     });
+/// }
+///
+/// latch.wait();
+/// assert_eq!(done.load(Ordering::SeqCst), 10);
+/// ```
+#[derive(Clone, Default)]
+pub struct CountdownLatch {
+    shared: Arc<Shared>, // Shared by every clone of the latch
+}
+
+#[derive(Default)]
+struct Shared {
+    pending: AtomicUsize, // Jobs tied to the latch that haven't finished
+    lock: Mutex<()>,      // Held while checking or signalling the count
+    finished: Condvar,    // Signalled when the count reaches zero
+}
+
+impl CountdownLatch {
+    /// Create a latch with no jobs tied to it.
+    pub fn new() -> CountdownLatch {
+        CountdownLatch::default()
+    }
+
+    /// Run `f` on `pool` as one more job tied to the latch.
+    ///
+    /// This blocks like `ThreadPool::execute` while the pool's queue or
+    /// in-flight limit is full.
+    pub fn execute<F>(&self, pool: &ThreadPool, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.pending.fetch_add(1, Ordering::SeqCst);
+
+        // The guard counts the job down when it is dropped, which it is
+        // however the job ends, even unrun.
+        let guard = CountDown(Arc::clone(&self.shared));
+        pool.execute(move || {
+            let _guard = guard;
+            f();
+        });
+    }
+
+    /// The number of jobs tied to the latch that haven't finished yet.
+    pub fn pending(&self) -> usize {
+        self.shared.pending.load(Ordering::SeqCst)
+    }
+
+    /// Wait until every job tied to the latch has finished.
+    ///
+    /// Returns straight away if none is pending.
+    pub fn wait(&self) {
+        let mut lock = self.shared.lock.lock().unwrap();
+        while self.pending() > 0 {
+            lock = self.shared.finished.wait(lock).unwrap();
+        }
+    }
+
+    /// Wait up to `timeout` for every job tied to the latch to finish,
+    /// returning whether they all did.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut lock = self.shared.lock.lock().unwrap();
+        while self.pending() > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            lock = self.shared.finished.wait_timeout(lock, left).unwrap().0;
+        }
+        true
+    }
+}
+
+// Takes one job off a latch's count when it is dropped.
+struct CountDown(Arc<Shared>);
+
+impl Drop for CountDown {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Taking the lock first means a waiter between checking the
+            // count and going to sleep can't miss the signal.
+            let _lock = self.0.lock.lock().unwrap();
+            self.0.finished.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+
+    #[test]
+    fn wait_returns_only_once_all_ten_jobs_ran() {
+        let pool = ThreadPool::new(4);
+        let latch = CountdownLatch::new();
+        let done = Arc::new(AtomicUsize::new(0));
+
+        for n in 0..10 {
+            let done = Arc::clone(&done);
+            latch.execute(&pool, move || {
+                thread::sleep(Duration::from_millis(n * 3));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        latch.wait();
+        assert_eq!(done.load(Ordering::SeqCst), 10);
+        assert_eq!(latch.pending(), 0);
+
+        // Nothing is pending any more, so it returns straight away.
+        latch.wait();
+    }
+
+    #[test]
+    fn a_panicking_job_still_counts_down() {
+        let pool = ThreadPool::new(2);
+        let latch = CountdownLatch::new();
+        latch.execute(&pool, || panic!("the job fails"));
+        latch.execute(&pool, || {});
+        assert!(latch.wait_timeout(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn only_the_jobs_tied_to_the_latch_are_waited_for() {
+        let pool = ThreadPool::new(2);
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = released.recv();
+        });
+
+        let latch = CountdownLatch::new();
+        let (go, gone) = mpsc::channel::<()>();
+        latch.execute(&pool, move || {
+            let _ = gone.recv();
+        });
+        assert!(!latch.wait_timeout(Duration::from_millis(20)));
+        assert_eq!(latch.pending(), 1);
+
+        drop(go);
+        assert!(latch.wait_timeout(Duration::from_secs(5)));
+        drop(release);
+    }
+}
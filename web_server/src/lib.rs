@@ -1,17 +1,487 @@
+pub mod access_log;
+pub mod admin;
+pub mod app;
+pub mod compress;
+pub mod config;
+pub mod connection;
+pub mod cookie;
+pub mod cors;
+mod deflate;
+pub mod embedded;
+pub mod error;
+pub mod error_page;
+pub mod favicon;
+mod global;
+pub mod handler;
+pub mod http_date;
+mod latch;
+pub mod listener;
+pub mod maintenance;
+pub mod memory;
+pub mod method;
+pub mod metrics;
+pub mod mime;
+pub mod negotiate;
+mod pool_set;
+pub mod prelude;
+pub mod request;
+pub mod response;
+mod result_set;
+pub mod root_page;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+#[cfg(unix)]
+pub mod signal;
+pub mod spa;
+pub mod static_files;
+mod trace;
+pub mod tracker;
+pub mod upgrade;
+pub mod uri;
+pub mod zip;
+
+pub use global::{default_pool, spawn, POOL_SIZE_VAR};
+pub use handler::Handler;
+pub use latch::CountdownLatch;
+pub use pool_set::{PoolSet, PoolSetReport};
+pub use result_set::{OrderedResults, ResultSet, Results};
+
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    error::Error,
+    fmt, io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Condvar, Mutex,
+    },
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+use crossbeam_channel::{select, Receiver, Sender, TrySendError};
+
 // ThreadPool struct manages a pool of threads.
 pub struct ThreadPool {
-    workers: Vec<Worker>,              // Vector of workers (threads)
-    sender: Option<mpsc::Sender<Job>>, // Sender for sending jobs to the worker threads
+    workers: Mutex<Workers>, // Vector of workers (threads), and the pool's size
+    unstarted: AtomicBool,   // Whether `workers` holds any unstarted workers
+    sender: Option<Sender<Message>>, // Sender for sending jobs to the worker threads
+    receiver: Receiver<Message>, // Used to take back queued jobs on shutdown
+    drain: Arc<Drain>,       // Asks workers to hand back jobs instead of running them
+    pause: Arc<Pause>,       // Holds workers back from starting jobs while set
+    completed: Arc<AtomicU64>, // Jobs run to completion over the pool's lifetime
+    in_flight: Option<Arc<InFlight>>, // Limit on jobs submitted but not yet finished
+    shutdown_timeout: Option<Duration>, // How long shutdown waits before detaching workers
+    shutdown_mode: ShutdownMode, // Whether shutdown runs or drops the queued jobs
+    max_jobs_per_worker: Option<usize>, // Jobs a thread runs before it is replaced
+    lazy: bool,              // Whether workers are started as jobs arrive
+    retire: Sender<()>,      // Asks one idle worker per message to exit
+    retire_requests: Receiver<()>, // Used to take back requests no worker has seen
+    idle: Option<Arc<IdleWatch>>, // Calls the `on_idle` callback when the last job finishes
+    inline: bool,            // Runs jobs on the submitting thread, with no workers at all
+    spawner: Spawner,        // Starts the workers' threads
+}
+
+// The pool's workers, behind one lock so that resizing can't race with a
+// lazy pool starting a worker.
+struct Workers {
+    all: Vec<Worker>,              // Every worker that hasn't retired, in id order
+    unstarted: Vec<WorkerContext>, // Workers a lazy pool has yet to start, next one last
+    size: usize,                   // How many workers the pool is meant to have
+    next_id: usize,                // Id for the next worker added
+    growth: Growth,                // Holds off starting workers after spawning failed
+}
+
+// How starting workers on demand is getting on. Once the OS refuses to
+// spawn a thread, the pool waits a little longer after each failure before
+// trying again, so it doesn't hammer a system that is out of threads with
+// a retry per submitted job. After `MAX_SPAWN_FAILURES` failures in a row
+// it stops trying until a worker is free, which shows the pool is keeping
+// up at the size it has. A pool with no worker running at all has none to
+// wait for, so it keeps trying at the longest backoff instead.
+#[derive(Default)]
+struct Growth {
+    failures: u32,             // Spawns that failed in a row
+    retry_at: Option<Instant>, // When spawning may be tried again
+    jitter: Jitter,            // Spreads out the waits between tries
+}
+
+impl Growth {
+    // Forget the failures, once a worker has started or is free again.
+    fn reset(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    // Whether the pool may try to start a worker now, given whether it has
+    // any running.
+    fn may_try(&self, running: bool) -> bool {
+        (self.failures < MAX_SPAWN_FAILURES || !running)
+            && self
+                .retry_at
+                .is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    // Record a failed spawn and pick when to try again.
+    fn failed(&mut self, id: usize, err: &io::Error) {
+        self.failures = self.failures.saturating_add(1);
+
+        // Each wait is twice as long as the last, less a random part of up
+        // to half of it, so pools that failed together don't retry together.
+        let backoff = SPAWN_BACKOFF_MIN
+            .saturating_mul(1 << (self.failures - 1).min(16))
+            .min(SPAWN_BACKOFF_MAX);
+        let backoff = backoff.mul_f64(1.0 - self.jitter.next() / 2.0);
+        self.retry_at = Some(Instant::now() + backoff);
+
+        if self.failures == MAX_SPAWN_FAILURES {
+            println!(
+                "Failed to start worker {id}: {err}; not growing the pool until a worker is free."
+            );
+        } else {
+            println!("Failed to start worker {id}: {err}; retrying in {backoff:?}.");
+        }
+        trace::error("failed to start a worker", err);
+    }
+}
+
+// A xorshift generator of fractions in `[0, 1)`, seeded from the clock.
+// It only has to differ between pools, not be hard to guess.
+struct Jitter(u64);
+
+impl Default for Jitter {
+    fn default() -> Jitter {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.subsec_nanos());
+        // The state must never be zero, or it stays zero.
+        Jitter(u64::from(nanos) << 1 | 1)
+    }
+}
+
+impl Jitter {
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        // The top 53 bits, which an `f64` holds exactly.
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A job to be executed by the thread pool.
+///
+/// The job is a boxed closure that takes no parameters and returns nothing.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// Starts a thread running a worker. Tests swap in one that can fail.
+type Spawner = Arc<dyn Fn(Job) -> io::Result<thread::JoinHandle<()>> + Send + Sync>;
+
+// Start worker threads with `thread::Builder`, which reports failing to
+// spawn one instead of panicking.
+fn spawn_thread() -> Spawner {
+    Arc::new(|run| thread::Builder::new().spawn(run))
+}
+
+/// The error returned by `ThreadPool::try_execute` when a job can't be queued.
+///
+/// Both variants hand the rejected job back so the caller can decide what to
+/// do with it. `QueueFull` is temporary: the same job can be submitted again
+/// once a worker has freed up a slot. `ShuttingDown` is permanent, since the
+/// pool will never accept work again.
+///
+/// A typical retry loop looks like this:
+///
+/// ```no_run
+/// use std::{thread, time::Duration};
+/// use web_server::{ExecuteError, Job, ThreadPool};
+///
+/// let pool = ThreadPool::with_capacity(2, 8);
+/// let mut job: Job = Box::new(|| println!("hello"));
+///
+/// loop {
+///     match pool.try_execute(job) {
+///         Ok(()) => break,
+///         Err(ExecuteError::QueueFull(rejected)) => {
+///             // Back off for a moment and then offer the same job again.
+///             job = rejected;
+///             thread::sleep(Duration::from_millis(10));
+///         }
+///         Err(ExecuteError::ShuttingDown(_)) => break, // Give up
+///     }
+/// }
+/// ```
+pub enum ExecuteError {
+    QueueFull(Job),    // The queue or the in-flight limit has no room right now
+    ShuttingDown(Job), // The pool is shutting down and accepts no new jobs
+}
+
+impl ExecuteError {
+    /// Take back the job that could not be queued.
+    pub fn into_job(self) -> Job {
+        match self {
+            ExecuteError::QueueFull(job) | ExecuteError::ShuttingDown(job) => job,
+        }
+    }
+}
+
+impl fmt::Debug for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The job itself is an opaque closure, so only the variant is shown.
+        match self {
+            ExecuteError::QueueFull(_) => f.write_str("QueueFull(..)"),
+            ExecuteError::ShuttingDown(_) => f.write_str("ShuttingDown(..)"),
+        }
+    }
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecuteError::QueueFull(_) => f.write_str("the job queue is full"),
+            ExecuteError::ShuttingDown(_) => f.write_str("the thread pool is shutting down"),
+        }
+    }
+}
+
+impl Error for ExecuteError {}
+
+/// What shutting a pool down, or dropping it, does with the jobs still in
+/// its queue. Set with `ThreadPoolBuilder::shutdown_mode`.
+///
+/// Either way, jobs already running are left to finish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownMode {
+    #[default]
+    Drain, // Run every queued job before the workers stop
+    Abort, // Drop the queued jobs without running them
+}
+
+// How long shutdown waits for the workers before it reports which of them
+// are still busy.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+// How often shutdown checks whether a worker thread has finished.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(1);
+
+// How long a pool first waits to start a worker again after spawning its
+// thread failed, the most it waits as failures go on, and how many failures
+// in a row it takes to stop trying until a worker is free.
+const SPAWN_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const SPAWN_BACKOFF_MAX: Duration = Duration::from_secs(1);
+const MAX_SPAWN_FAILURES: u32 = 5;
+
+// Pool size used when the number of CPUs can't be determined.
+const FALLBACK_POOL_SIZE: usize = 4;
+
+// The number of logical CPUs, or `FALLBACK_POOL_SIZE` if the OS won't say.
+pub(crate) fn available_parallelism() -> usize {
+    thread::available_parallelism()
+        .map(|size| size.get())
+        .unwrap_or(FALLBACK_POOL_SIZE)
+}
+
+/// Configures and creates a `ThreadPool`.
+///
+/// `ThreadPool::new` and `ThreadPool::with_capacity` cover the common cases;
+/// the builder exposes the remaining options.
+pub struct ThreadPoolBuilder {
+    size: usize,                        // Number of worker threads
+    capacity: Option<usize>,            // Maximum queued jobs, or None for unbounded
+    max_jobs_per_worker: Option<usize>, // Jobs a thread runs before it is replaced
+    max_in_flight: Option<usize>,       // Jobs queued or running at once, or None for no limit
+    shutdown_timeout: Option<Duration>, // How long shutdown waits before detaching workers
+    shutdown_mode: ShutdownMode,        // Whether shutdown runs or drops the queued jobs
+    lazy: bool,                         // Start worker threads as jobs arrive
+    on_idle: Option<IdleCallback>,      // Called whenever the pool runs out of work
+    inline: bool,                       // Run jobs on the submitting thread instead
+    spawner: Spawner,                   // Starts the workers' threads
 }
 
-// Type alias for a job to be executed by the thread pool.
-// The job is a boxed closure that takes no parameters and returns nothing.
-type Job = Box<dyn FnOnce() + Send + 'static>;
+// What `ThreadPoolBuilder::on_idle` calls.
+type IdleCallback = Box<dyn Fn() + Send + Sync>;
+
+impl ThreadPoolBuilder {
+    /// Start configuring a pool of `size` threads with an unbounded queue.
+    pub fn new(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            capacity: None,
+            max_jobs_per_worker: None,
+            max_in_flight: None,
+            shutdown_timeout: None,
+            shutdown_mode: ShutdownMode::Drain,
+            lazy: false,
+            on_idle: None,
+            inline: false,
+            spawner: spawn_thread(),
+        }
+    }
+
+    /// Limit the queue to `capacity` jobs. See `ThreadPool::with_capacity`.
+    pub fn capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Recycle each worker thread after it has run `max_jobs` jobs.
+    ///
+    /// On reaching the limit, a worker finishes its current job, spawns a
+    /// fresh thread to take its place and exits. This releases anything the
+    /// old thread accumulated in thread-local storage, while the pool stays
+    /// at full size throughout.
+    ///
+    /// # Panics
+    ///
+    /// The `build` function will panic if `max_jobs` is zero.
+    pub fn max_jobs_per_worker(mut self, max_jobs: usize) -> ThreadPoolBuilder {
+        self.max_jobs_per_worker = Some(max_jobs);
+        self
+    }
+
+    /// Allow at most `max` jobs to be in flight, queued or running, at once.
+    ///
+    /// Submitting a job beyond the limit makes `execute` block until an
+    /// earlier job has finished, and `try_execute` return
+    /// `ExecuteError::QueueFull`. This bounds the memory and downstream
+    /// resources held by submitted work independently of the pool size.
+    ///
+    /// A `capacity` only counts jobs waiting in the queue, so it lets up to
+    /// `size + capacity` jobs be in flight. With both set, whichever limit
+    /// is reached first applies.
+    ///
+    /// # Panics
+    ///
+    /// The `build` function will panic if `max` is zero.
+    pub fn max_in_flight(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Give up on workers that are still busy `timeout` after shutdown began.
+    ///
+    /// By default, shutting the pool down waits for every worker however
+    /// long its job takes, so a job that never returns hangs the program.
+    /// With a timeout, workers still running a job after it has passed are
+    /// detached instead and left to finish, or not, on their own.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+        self.shutdown_timeout = Some(timeout);
+        self
+    }
+
+    /// Choose what shutting the pool down does with the jobs still queued:
+    /// run them all, the default, or drop them.
+    ///
+    /// With `ShutdownMode::Abort`, workers stop taking jobs as soon as
+    /// shutdown begins and only the jobs already running finish, so a long
+    /// queue doesn't hold shutdown up. The dropped jobs never run; a
+    /// `PoolJoinHandle` for one of them returns an error from `join`.
+    /// `ThreadPool::shutdown_returning_pending` hands them back instead.
+    pub fn shutdown_mode(mut self, mode: ShutdownMode) -> ThreadPoolBuilder {
+        self.shutdown_mode = mode;
+        self
+    }
+
+    /// Start worker threads on demand instead of all when the pool is built.
+    ///
+    /// A lazy pool starts with no threads at all. When a job is submitted
+    /// and every thread started so far is busy, one more is started, until
+    /// the pool reaches its full size. A pool that is never used then costs
+    /// nothing but the pool itself. Threads, once started, stay for the
+    /// life of the pool.
+    pub fn lazy(mut self, lazy: bool) -> ThreadPoolBuilder {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Call `on_idle` each time the pool runs out of work.
+    ///
+    /// The pool is idle once every job submitted to it has finished, with
+    /// none left running or queued. The callback runs once per transition,
+    /// on the worker thread that finished the last job, so it fires again
+    /// only after new jobs have arrived and finished. It isn't called for a
+    /// pool that has never had a job, nor once `shutdown_returning_pending`
+    /// or a `ShutdownMode::Abort` shutdown has begun, since the jobs left
+    /// then never run. It should be quick, since that worker takes no new
+    /// job while it runs.
+    pub fn on_idle(mut self, on_idle: impl Fn() + Send + Sync + 'static) -> ThreadPoolBuilder {
+        self.on_idle = Some(Box::new(on_idle));
+        self
+    }
+
+    /// Create the pool and spawn its workers, unless it is lazy.
+    ///
+    /// # Panics
+    ///
+    /// The `build` function will panic if the size is zero.
+    pub fn build(self) -> ThreadPool {
+        assert!(self.size > 0); // Ensure that the pool size is greater than 0
+        assert!(self.max_jobs_per_worker != Some(0)); // A worker must run at least one job
+        assert!(self.max_in_flight != Some(0)); // At least one job must be able to run
+
+        // Create a channel for sending jobs to workers. A bounded channel's
+        // buffer holds `capacity` jobs.
+        let (sender, receiver) = match self.capacity {
+            Some(capacity) => crossbeam_channel::bounded(capacity),
+            None => crossbeam_channel::unbounded(),
+        };
+
+        // The channel is multi-consumer, so every worker holds its own clone
+        // of the receiver and blocks in `recv` at the same time. With a
+        // `Mutex<mpsc::Receiver>`, the worker holding the lock sat in `recv`
+        // while every other idle worker queued up on the mutex, so jobs were
+        // handed out one worker at a time and a burst of jobs waited on lock
+        // handoffs. Now a job wakes an idle worker directly.
+
+        // Pre-allocate space for the workers.
+        let workers = Workers {
+            all: Vec::with_capacity(self.size),
+            unstarted: Vec::with_capacity(self.size),
+            size: 0,
+            next_id: 0,
+            growth: Growth::default(),
+        };
+
+        // Workers are asked to retire on a channel of their own, so the
+        // request isn't stuck in the queue behind jobs.
+        let (retire, retire_requests) = crossbeam_channel::unbounded();
+
+        let pool = ThreadPool {
+            workers: Mutex::new(workers),
+            unstarted: AtomicBool::new(false),
+            sender: Some(sender),
+            receiver,
+            drain: Arc::new(Drain::default()),
+            pause: Arc::new(Pause::default()),
+            completed: Arc::new(AtomicU64::new(0)),
+            in_flight: self.max_in_flight.map(|max| Arc::new(InFlight::new(max))),
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_mode: self.shutdown_mode,
+            max_jobs_per_worker: self.max_jobs_per_worker,
+            lazy: self.lazy,
+            retire,
+            retire_requests,
+            idle: self.on_idle.map(|on_idle| {
+                Arc::new(IdleWatch {
+                    state: Mutex::default(),
+                    on_idle,
+                })
+            }),
+            inline: self.inline,
+            spawner: self.spawner,
+        };
+
+        // Create the workers and add them to the pool, the same way a
+        // running pool grows. The submitting thread is an inline pool's
+        // only worker.
+        if self.inline {
+            pool.workers.lock().unwrap().size = 1;
+        } else {
+            pool.resize(self.size);
+        }
+        pool
+    }
+}
 
 impl ThreadPool {
     /// Create a new ThreadPool.
@@ -22,27 +492,49 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0); // Ensure that the pool size is greater than 0
-
-        // Create a channel for sending jobs to workers.
-        let (sender, receiver) = mpsc::channel();
-
-        // Wrap the receiver in an Arc and a Mutex to safely share it across threads.
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPoolBuilder::new(size).build()
+    }
 
-        // Pre-allocate space for the workers.
-        let mut workers = Vec::with_capacity(size);
+    /// Create a new ThreadPool with one thread per logical CPU.
+    ///
+    /// The size comes from `std::thread::available_parallelism`. If that
+    /// query fails, the pool falls back to 4 threads.
+    pub fn with_available_parallelism() -> ThreadPool {
+        ThreadPool::new(available_parallelism())
+    }
 
-        // Create worker threads and add them to the pool.
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+    /// Create a pool that runs every job on the thread submitting it.
+    ///
+    /// `execute` and `try_execute` call the job straight away and return
+    /// once it has finished, so jobs run one at a time, in the order they
+    /// were submitted, and a panic unwinds into the caller unless the job
+    /// was submitted with `spawn`. There are no worker threads and nothing
+    /// is queued. Code written against a `ThreadPool` runs unchanged, but
+    /// deterministically and with plain stack traces, for debugging and
+    /// tests. `resize` has no effect on such a pool.
+    pub fn single_threaded() -> ThreadPool {
+        ThreadPoolBuilder {
+            inline: true,
+            ..ThreadPoolBuilder::new(1)
         }
+        .build()
+    }
 
-        // Return the ThreadPool instance with the workers and the sender.
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
+    /// Create a new ThreadPool whose queue holds at most `capacity` jobs.
+    ///
+    /// Once the queue is full, `execute` blocks until a worker takes a job,
+    /// and `try_execute` returns `ExecuteError::QueueFull` instead.
+    ///
+    /// # Panics
+    ///
+    /// The `with_capacity` function will panic if the size is zero.
+    pub fn with_capacity(size: usize, capacity: usize) -> ThreadPool {
+        ThreadPoolBuilder::new(size).capacity(capacity).build()
+    }
+
+    /// Start configuring a pool of `size` threads.
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder::new(size)
     }
 
     /// Execute a function using the thread pool.
@@ -50,70 +542,2057 @@ impl ThreadPool {
     /// The function must implement the `FnOnce` trait, which means it can be called once,
     /// and it must be `Send` to move between threads safely and `'static` to ensure
     /// it lives long enough to be executed.
+    ///
+    /// This blocks while a bounded queue is full or the in-flight limit set
+    /// with `ThreadPoolBuilder::max_in_flight` is reached.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        // Box the function to turn it into a `Job`.
-        let job = Box::new(f);
+        self.submit(Box::new(f), None);
+    }
+
+    // Queue `job` as `execute` does. A spawned job comes with its `state`,
+    // by which the worker skips it if it was cancelled.
+    fn submit(&self, job: Job, state: Option<Arc<AtomicU8>>) {
+        if self.inline {
+            job();
+            self.completed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Wait for room under the in-flight limit if there is one.
+        let message = Message {
+            job,
+            permit: self.in_flight.as_ref().map(InFlight::acquire),
+            idle: self.idle.as_ref().map(IdleWatch::count),
+            state,
+        };
 
-        // Send the job to the worker threads via the channel.
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        // Send the job to the worker threads via the channel. A bounded
+        // queue blocks here until there is room.
+        self.start_worker_if_needed();
+        self.sender.as_ref().unwrap().send(message).unwrap();
     }
-}
 
-impl Drop for ThreadPool {
-    /// The `Drop` trait implementation ensures that when the ThreadPool goes out of scope,
-    /// all threads are properly shut down.
-    fn drop(&mut self) {
-        // Close the sending side of the channel to signal the workers to shut down.
-        drop(self.sender.take());
+    /// Execute a function using the thread pool and get a handle to wait on it.
+    ///
+    /// This is like `execute`, but the returned `PoolJoinHandle` can be used
+    /// to block until this particular job has finished, or to cancel it
+    /// while it is still queued. A panic in the job is caught and handed to
+    /// `join` instead of taking the worker thread down.
+    pub fn spawn<F>(&self, f: F) -> PoolJoinHandle
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // A channel with room for a single message serves as a oneshot: the
+        // worker never blocks sending the result, even if nobody joins.
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let state = Arc::new(AtomicU8::new(JOB_QUEUED));
 
-        // Join each worker thread to ensure they have finished before the pool is destroyed.
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+        let job_state = Arc::clone(&state);
+        let job = Box::new(move || {
+            // A worker claims the job before running it, and skips it if it
+            // was cancelled. A job run some other way, on a single-threaded
+            // pool or after `shutdown_returning_pending` handed it back, is
+            // claimed here, and dropping the sender unrun tells `join` it
+            // was cancelled.
+            let claimed = job_state.compare_exchange(
+                JOB_QUEUED,
+                JOB_STARTED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            if claimed == Err(JOB_CANCELLED) {
+                return;
+            }
 
-            // If the worker thread exists, join it to wait for its completion.
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // The handle may already have been dropped, which is fine.
+            let _ = sender.send(result);
+        });
+        self.submit(job, Some(Arc::clone(&state)));
+
+        PoolJoinHandle { receiver, state }
+    }
+
+    /// Try to execute a function using the thread pool without blocking.
+    ///
+    /// Returns `ExecuteError::QueueFull` when a bounded queue has no free slot
+    /// or the in-flight limit is reached, and `ExecuteError::ShuttingDown`
+    /// when the pool no longer accepts work. Either way the job is handed
+    /// back inside the error.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.inline {
+            f();
+            self.completed.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let job: Job = Box::new(f);
+
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => return Err(ExecuteError::ShuttingDown(job)),
+        };
+
+        let permit = match &self.in_flight {
+            Some(in_flight) => match in_flight.try_acquire() {
+                Some(permit) => Some(permit),
+                None => return Err(ExecuteError::QueueFull(job)),
+            },
+            None => None,
+        };
+
+        // A rejected message drops its permit here, before the job is
+        // handed back. It is taken off the idle count without counting as
+        // the pool running out of work, since it never ran.
+        self.start_worker_if_needed();
+        let idle = self.idle.as_ref().map(IdleWatch::count);
+        sender
+            .try_send(Message {
+                job,
+                permit,
+                idle,
+                state: None,
+            })
+            .map_err(|err| {
+                let (message, rejected): (_, fn(Job) -> ExecuteError) = match err {
+                    TrySendError::Full(message) => (message, ExecuteError::QueueFull),
+                    TrySendError::Disconnected(message) => (message, ExecuteError::ShuttingDown),
+                };
+                rejected(message.unrun())
+            })
+    }
+
+    /// Whether `try_execute` would accept a job right now.
+    ///
+    /// This is false while a bounded queue is full, the in-flight limit is
+    /// reached or the pool is shutting down, so a caller shedding load can
+    /// turn work away before building an expensive job. It is only a hint:
+    /// other threads can take the last slot, or workers free one, between
+    /// this check and the submission, so `try_execute` can still fail and
+    /// its error still has to be handled. A queue with a capacity of zero
+    /// never has room to wait in, so this is always false for one.
+    pub fn has_capacity(&self) -> bool {
+        if self.inline {
+            return true;
+        }
+
+        let Some(sender) = self.sender.as_ref() else {
+            return false;
+        };
+        !sender.is_full()
+            && self
+                .in_flight
+                .as_ref()
+                .is_none_or(|in_flight| in_flight.has_room())
+    }
+
+    /// The number of jobs waiting in the queue for a free worker.
+    ///
+    /// Jobs that are already running aren't counted. This is a moment's
+    /// snapshot, cheap enough to poll for monitoring.
+    pub fn queue_len(&self) -> usize {
+        self.receiver.len()
+    }
+
+    /// The most jobs the queue holds, or `None` if it is unbounded.
+    pub fn queue_capacity(&self) -> Option<usize> {
+        self.receiver.capacity()
+    }
+
+    /// The number of worker threads the pool is meant to have.
+    pub fn size(&self) -> usize {
+        self.workers.lock().unwrap().size
+    }
+
+    /// The number of worker threads started and still running.
+    ///
+    /// This is the pool size, unless the pool is lazy and hasn't needed all
+    /// of its threads yet, or it was just shrunk and surplus workers are
+    /// still finishing their jobs.
+    pub fn started_workers(&self) -> usize {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .all
+            .iter()
+            .filter(|worker| worker.is_running())
+            .count()
+    }
+
+    /// The number of workers running a job right now.
+    pub fn busy_workers(&self) -> usize {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .all
+            .iter()
+            .filter(|worker| worker.busy.lock().unwrap().is_some())
+            .count()
+    }
+
+    /// How many jobs each worker has run, as `(worker id, jobs)` pairs in
+    /// id order.
+    ///
+    /// A worker's count carries over when its thread is recycled. A job is
+    /// counted once it has returned, so one that panicked isn't. Workers
+    /// that left when the pool shrank aren't listed. Comparing the counts
+    /// shows whether jobs are spread evenly across the pool.
+    pub fn per_worker_stats(&self) -> Vec<(usize, u64)> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .all
+            .iter()
+            .filter(|worker| !worker.has_retired())
+            .map(|worker| (worker.id, worker.completed.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// How many jobs the pool has run to completion since it was created.
+    ///
+    /// Like `per_worker_stats`, a job counts once it has returned, so one
+    /// that panicked doesn't, but unlike the sum of those counts this
+    /// includes jobs run by workers that have since left the pool.
+    pub fn completed_jobs(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Take a reading of `completed_jobs` along with the time it was taken,
+    /// to work out the pool's throughput from two of them.
+    ///
+    /// ```no_run
+    /// use std::{thread, time::Duration};
+    /// use web_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let before = pool.sample();
+    /// thread::sleep(Duration::from_secs(10));
+    /// let rate = pool.sample().jobs_per_second_since(&before);
+    /// println!("{rate:.1} jobs/s");
+    /// ```
+    pub fn sample(&self) -> JobsSample {
+        JobsSample {
+            completed: self.completed_jobs(),
+            taken: Instant::now(),
+        }
+    }
+
+    /// Stop workers from starting new jobs until `resume` is called.
+    ///
+    /// Workers finish the job they are running and then wait, and jobs
+    /// submitted meanwhile stay queued, so nothing races with whatever the
+    /// caller does while the pool is paused, such as reloading the
+    /// configuration. An idle worker that was already waiting for a job
+    /// may take one off the queue, but holds it unstarted too. A queue with
+    /// a capacity still fills up, after which `execute` blocks and
+    /// `try_execute` fails as usual. Pausing doesn't wait for running jobs,
+    /// and a paused pool still shuts down, resuming first so that the queued
+    /// jobs run, or are handed back by `shutdown_returning_pending`. Pausing
+    /// an inline pool does nothing, since its jobs run on the submitting
+    /// thread.
+    pub fn pause(&self) {
+        if !self.inline {
+            self.pause.set(true);
+        }
+    }
+
+    /// Let workers start jobs again after `pause`.
+    pub fn resume(&self) {
+        self.pause.set(false);
+    }
+
+    /// Whether the pool is paused.
+    pub fn is_paused(&self) -> bool {
+        *self.pause.paused.lock().unwrap()
+    }
+
+    /// Change the number of worker threads while the pool is running.
+    ///
+    /// Growing the pool adds workers straight away, or as jobs arrive in a
+    /// lazy pool. Shrinking it asks the surplus workers to exit, which each
+    /// one does once it is done with its current job, so `started_workers`
+    /// catches up with `size` only then. Resizing to the current size does
+    /// nothing, and it is safe to resize from several threads at once.
+    ///
+    /// # Panics
+    ///
+    /// The `resize` function will panic if the size is zero.
+    pub fn resize(&self, size: usize) {
+        assert!(size > 0); // Ensure that the pool size is greater than 0
+        if self.inline {
+            return;
+        }
+
+        let mut workers = self.workers.lock().unwrap();
+        workers.all.retain(|worker| !worker.has_retired());
+
+        if size > workers.size {
+            // A worker asked to retire that hasn't gone yet can simply stay.
+            let mut missing = size - workers.size;
+            missing -= self.retire_requests.try_iter().take(missing).count();
+
+            for _ in 0..missing {
+                let id = workers.next_id;
+                workers.next_id += 1;
+
+                let (worker, context) = Worker::new(id, self);
+                workers.all.push(worker);
+
+                // A lazy pool starts workers in id order as jobs arrive, so
+                // the newest goes first in line, behind the others. A worker
+                // whose thread can't be spawned now is started on demand
+                // later, like a lazy pool's.
+                if self.lazy {
+                    workers.unstarted.insert(0, context);
+                } else if let Err(err) = context.start() {
+                    println!("Failed to start worker {id}: {err}; starting it when needed.");
+                    trace::error("failed to start a worker", &err);
+                    workers.unstarted.insert(0, context);
+                }
+            }
+        } else {
+            let mut surplus = workers.size - size;
+
+            // Workers that never started are dropped first, newest first.
+            while surplus > 0 && !workers.unstarted.is_empty() {
+                let context = workers.unstarted.remove(0);
+                workers.all.retain(|worker| worker.id != context.id);
+                surplus -= 1;
+            }
+
+            for _ in 0..surplus {
+                // The receiving end is held by the pool, so this can't fail.
+                let _ = self.retire.send(());
             }
         }
+
+        workers.size = size;
+        self.unstarted
+            .store(!workers.unstarted.is_empty(), Ordering::Release);
     }
-}
 
-// Worker struct represents a single thread in the pool.
-struct Worker {
-    id: usize,                              // Unique ID of the worker
-    thread: Option<thread::JoinHandle<()>>, // The thread handle
-}
+    // In a lazy pool, start one more worker if the jobs already queued would
+    // keep every running worker busy, so the next job doesn't wait for them.
+    // If its thread can't be spawned, the pool carries on at its current
+    // size, and `Growth` decides when to try again. A pool with every worker
+    // started is told apart without the lock, so submitting a job doesn't
+    // contend on it.
+    fn start_worker_if_needed(&self) {
+        if !self.unstarted.load(Ordering::Acquire) {
+            return;
+        }
+        let mut workers = self.workers.lock().unwrap();
+        if workers.unstarted.is_empty() {
+            return;
+        }
 
-impl Worker {
-    /// Create a new worker and spawn a thread to listen for jobs.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        // Spawn a new thread and move the receiver into the thread's closure.
-        let thread = thread::spawn(move || loop {
-            // Lock the receiver to get a job from the channel.
-            let message = receiver.lock().unwrap().recv();
+        let running = workers.all.iter().filter(|worker| worker.is_running());
+        let (mut started, mut idle) = (0, 0);
+        for worker in running {
+            started += 1;
+            if worker.busy.lock().unwrap().is_none() {
+                idle += 1;
+            }
+        }
+        if idle > 0 && workers.growth.failures >= MAX_SPAWN_FAILURES {
+            workers.growth.reset();
+        }
+        if self.receiver.len() < idle || !workers.growth.may_try(started > 0) {
+            return;
+        }
 
-            match message {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
+        if let Some(context) = workers.unstarted.pop() {
+            match context.start() {
+                Ok(()) => workers.growth.reset(),
+                Err(err) => {
+                    workers.growth.failed(context.id, &err);
+                    workers.unstarted.push(context);
+                }
+            }
+            self.unstarted
+                .store(!workers.unstarted.is_empty(), Ordering::Release);
+        }
+    }
 
-                    // Execute the job.
-                    job();
+    /// Shut the pool down and wait for every worker to finish.
+    ///
+    /// Jobs already in the queue still run, unless the pool was built with
+    /// `ShutdownMode::Abort`, which drops them. Unlike simply dropping the pool,
+    /// this reports how each worker exited, so a worker that was killed by a
+    /// panicking job can be noticed. Workers a lazy pool never started are
+    /// left out of the report.
+    ///
+    /// If some workers are still busy after a five second grace period, the
+    /// ones in the middle of a job are logged along with how long that job
+    /// has been running. Waiting then continues, up to the limit set with
+    /// `ThreadPoolBuilder::shutdown_timeout`.
+    ///
+    /// A pool's queue, workers and counters are its own, and shutting it
+    /// down, or dropping it, touches nothing shared with other pools; the
+    /// only process-wide pool is `default_pool`, which is never shut down.
+    /// So pools on different threads can shut down at the same time
+    /// without waiting on or disturbing each other:
+    ///
+    /// ```
+    /// use std::thread;
+    /// use web_server::{ThreadPool, WorkerExit};
+    ///
+    /// let threads: Vec<_> = (0..8)
+    ///     .map(|_| {
+    ///         thread::spawn(|| {
+    ///             let pool = ThreadPool::new(2);
+    ///             for _ in 0..10 {
+    ///                 pool.execute(|| {});
+    ///             }
+    ///             pool.shutdown()
+    ///         })
+    ///     })
+    ///     .collect();
+    ///
+    /// for thread in threads {
+    ///     let report = thread.join().unwrap();
+    ///     assert!(report.workers().iter().all(|(_, exit)| *exit == WorkerExit::Clean));
+    /// }
+    /// ```
+    pub fn shutdown(mut self) -> ShutdownReport {
+        self.join_workers()
+    }
+
+    /// Shut the pool down without running the jobs still in the queue, and
+    /// return them instead.
+    ///
+    /// Workers finish the job they are running and then stop. The jobs
+    /// that no worker had started come back in roughly the order they were
+    /// submitted, so they can be persisted or handed to another pool.
+    pub fn shutdown_returning_pending(mut self) -> Vec<Job> {
+        // From here on, a worker that picks up a job hands it back.
+        self.drain.requested.store(true, Ordering::SeqCst);
+        if let Some(idle) = &self.idle {
+            idle.stop();
+        }
+
+        let mut pending: Vec<Job> = self.receiver.try_iter().map(Message::unrun).collect();
+        self.join_workers();
+
+        // Jobs the workers took off the queue while we were draining it, and
+        // any left behind if every worker had died.
+        pending.append(&mut self.drain.returned.lock().unwrap());
+        pending.extend(self.receiver.try_iter().map(Message::unrun));
+        pending
+    }
+
+    // Close the queue and join every worker, recording how each one exited.
+    fn join_workers(&mut self) -> ShutdownReport {
+        // Workers that take a job from here on put it aside unrun, and it
+        // is dropped along with the pool.
+        if self.shutdown_mode == ShutdownMode::Abort {
+            self.drain.requested.store(true, Ordering::SeqCst);
+            if let Some(idle) = &self.idle {
+                idle.stop();
+            }
+        }
+
+        // Close the sending side of the channel to signal the workers to shut down.
+        drop(self.sender.take());
+
+        // Paused workers would never get to see that.
+        self.pause.set(false);
+
+        let started = Instant::now();
+        let mut warned = false;
+        let all = mem::take(&mut self.workers.get_mut().unwrap().all);
+        let mut workers = Vec::with_capacity(all.len());
+
+        // Join each worker thread to ensure they have finished before the pool is destroyed.
+        for worker in &all {
+            // If the worker thread exists, join it to wait for its completion.
+            let mut thread = worker.thread.lock().unwrap().take();
+            if thread.is_none() {
+                continue;
+            }
+
+            println!("Shutting down worker {}", worker.id);
+
+            // A worker that is being recycled may put its replacement's
+            // handle in the slot while we join it, so keep going until the
+            // slot stays empty.
+            let mut exit = WorkerExit::Clean;
+            while let Some(handle) = thread {
+                // Wait for the thread without blocking in `join`, so a stuck
+                // job can be reported and, with a timeout, abandoned.
+                while !handle.is_finished() {
+                    let waited = started.elapsed();
+                    if !warned && waited >= SHUTDOWN_GRACE {
+                        log_busy_workers(&all, waited);
+                        warned = true;
+                    }
+                    if self
+                        .shutdown_timeout
+                        .is_some_and(|timeout| waited >= timeout)
+                    {
+                        break;
+                    }
+                    thread::sleep(SHUTDOWN_POLL);
                 }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
-                    // If the channel is closed, exit the loop and end the thread.
+
+                if !handle.is_finished() {
+                    // Dropping the handle detaches the thread.
+                    println!("Worker {} is still busy; detaching it.", worker.id);
+                    exit = WorkerExit::Detached;
                     break;
                 }
+
+                // `join` returns an error if the thread panicked.
+                if handle.join().is_err() {
+                    exit = WorkerExit::Panicked;
+                }
+                thread = worker.thread.lock().unwrap().take();
             }
-        });
 
-        // Return the Worker instance with its thread.
-        Worker {
-            id,
-            thread: Some(thread),
+            workers.push((worker.id, exit));
+        }
+
+        // Jobs no worker is left to take, if every worker died, are put
+        // aside unrun, so they don't count as work done either.
+        let mut returned = self.drain.returned.lock().unwrap();
+        returned.extend(self.receiver.try_iter().map(Message::unrun));
+        drop(returned);
+
+        ShutdownReport { workers }
+    }
+}
+
+// Log every worker that is in the middle of a job, so a shutdown that hangs
+// on a stuck job says which workers it is waiting for.
+fn log_busy_workers(workers: &[Worker], waited: Duration) {
+    for line in busy_workers(workers, waited) {
+        println!("{line}");
+    }
+}
+
+// A line for each worker that is in the middle of a job, naming it and
+// saying how long its job has been running.
+fn busy_workers(workers: &[Worker], waited: Duration) -> Vec<String> {
+    workers
+        .iter()
+        .filter_map(|worker| {
+            let since = (*worker.busy.lock().unwrap())?;
+            Some(format!(
+                "Worker {} still busy {:.1?} into shutdown, running a job started {:.1?} ago.",
+                worker.id,
+                waited,
+                since.elapsed()
+            ))
+        })
+        .collect()
+}
+
+impl Drop for ThreadPool {
+    /// The `Drop` trait implementation ensures that when the ThreadPool goes out of scope,
+    /// all threads are properly shut down, after running or dropping the queued jobs
+    /// as the `ShutdownMode` says. Use `shutdown` instead to find out how
+    /// each worker exited.
+    fn drop(&mut self) {
+        self.join_workers();
+    }
+}
+
+/// A handle to a job submitted with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't cancel the job; it just means nobody waits
+/// for it.
+pub struct PoolJoinHandle {
+    receiver: Receiver<thread::Result<()>>, // Receives the job's outcome once it has run
+    state: Arc<AtomicU8>,                   // Whether the job is queued, started or cancelled
+}
+
+// The states of a spawned job. It starts out queued and moves to exactly
+// one of the others, whichever the worker or the handle gets to first.
+const JOB_QUEUED: u8 = 0;
+const JOB_STARTED: u8 = 1;
+const JOB_CANCELLED: u8 = 2;
+
+impl PoolJoinHandle {
+    /// Make sure the job never runs, if no worker has started it yet.
+    ///
+    /// Returns `true` if the job was cancelled, and `false` if it has
+    /// already started, or finished, or was cancelled before. A cancelled
+    /// job keeps its place in the queue, and under the in-flight limit,
+    /// until a worker reaches it and skips it, without counting it in
+    /// `completed_jobs` or towards recycling or `on_idle`.
+    pub fn cancel(&self) -> bool {
+        self.state
+            .compare_exchange(
+                JOB_QUEUED,
+                JOB_CANCELLED,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok()
+    }
+
+    /// Whether a worker has started running the job.
+    pub fn has_started(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == JOB_STARTED
+    }
+
+    /// Whether the job was cancelled with `cancel`.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.load(Ordering::SeqCst) == JOB_CANCELLED
+    }
+
+    /// Wait for the job to finish.
+    ///
+    /// Returns `Ok(())` if the job ran to completion, or the panic payload
+    /// if it panicked, just like `std::thread::JoinHandle::join`. A job
+    /// that was cancelled returns an error straight away.
+    pub fn join(self) -> thread::Result<()> {
+        if self.is_cancelled() {
+            return Err(Box::new("the job was cancelled"));
+        }
+        match self.receiver.recv() {
+            Ok(result) => result,
+            // The job was dropped without running, which only happens if the
+            // worker that picked it up died first, or the pool was shut down
+            // in `ShutdownMode::Abort`.
+            Err(_) => Err(Box::new("the job was dropped before it ran")),
+        }
+    }
+}
+
+impl fmt::Debug for PoolJoinHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolJoinHandle").finish_non_exhaustive()
+    }
+}
+
+/// A reading of `ThreadPool::completed_jobs`, taken with `ThreadPool::sample`.
+#[derive(Debug, Clone, Copy)]
+pub struct JobsSample {
+    completed: u64, // Jobs completed when the sample was taken
+    taken: Instant, // When the sample was taken
+}
+
+impl JobsSample {
+    /// The number of jobs completed when the sample was taken.
+    pub fn completed(&self) -> u64 {
+        self.completed
+    }
+
+    /// When the sample was taken.
+    pub fn taken(&self) -> Instant {
+        self.taken
+    }
+
+    /// The jobs completed per second between `earlier` and this sample.
+    ///
+    /// Returns 0 if no time passed between them, or `earlier` is in fact
+    /// the later of the two.
+    pub fn jobs_per_second_since(&self, earlier: &JobsSample) -> f64 {
+        let elapsed = self.taken.saturating_duration_since(earlier.taken);
+        if elapsed.is_zero() {
+            return 0.0;
+        }
+        self.completed.saturating_sub(earlier.completed) as f64 / elapsed.as_secs_f64()
+    }
+}
+
+/// How a worker thread ended when the pool was shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerExit {
+    Clean,    // The worker finished its jobs and stopped normally
+    Panicked, // A job panicked and took the worker thread down with it
+    Detached, // The worker was still busy when the shutdown timeout ran out
+}
+
+/// The outcome of `ThreadPool::shutdown`, listing how each worker exited.
+#[derive(Debug)]
+pub struct ShutdownReport {
+    workers: Vec<(usize, WorkerExit)>, // Worker id and how it exited, in id order
+}
+
+impl ShutdownReport {
+    /// Each worker id along with how that worker exited.
+    pub fn workers(&self) -> &[(usize, WorkerExit)] {
+        &self.workers
+    }
+
+    /// The ids of the workers that panicked.
+    pub fn panicked(&self) -> Vec<usize> {
+        self.workers
+            .iter()
+            .filter(|(_, exit)| *exit == WorkerExit::Panicked)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Whether every worker exited cleanly.
+    pub fn is_clean(&self) -> bool {
+        self.workers
+            .iter()
+            .all(|(_, exit)| *exit == WorkerExit::Clean)
+    }
+}
+
+// What travels down the channel to the workers.
+struct Message {
+    job: Job,               // The job to run
+    permit: Option<Permit>, // Counts the job as in flight until it is dropped
+    idle: Option<Pending>,  // Keeps the pool from counting as idle until it is dropped
+
+    // The state of a job submitted with `spawn`, which may be cancelled
+    // while it waits in the queue.
+    state: Option<Arc<AtomicU8>>,
+}
+
+impl Message {
+    // Take back a job that won't run, giving up its place in the idle
+    // count without counting as work done.
+    fn unrun(self) -> Job {
+        if let Some(idle) = self.idle {
+            idle.cancel();
+        }
+        self.job
+    }
+
+    // Mark a spawned job as started, unless it was cancelled first. Other
+    // jobs can't be cancelled.
+    fn claim(&self) -> bool {
+        self.state.as_ref().is_none_or(|state| {
+            let claimed =
+                state.compare_exchange(JOB_QUEUED, JOB_STARTED, Ordering::SeqCst, Ordering::SeqCst);
+            claimed != Err(JOB_CANCELLED)
+        })
+    }
+}
+
+// Shared by the pool and its workers, so shutting down can take back the
+// jobs that haven't started.
+#[derive(Default)]
+struct Drain {
+    requested: AtomicBool,     // Whether workers should stop running jobs
+    returned: Mutex<Vec<Job>>, // Jobs the workers received after that
+}
+
+// Shared by the pool and its workers, so the pool can hold the workers
+// back from starting jobs.
+#[derive(Default)]
+struct Pause {
+    paused: Mutex<bool>, // Whether workers should wait before their next job
+    resumed: Condvar,    // Signalled when the pool is resumed
+}
+
+impl Pause {
+    fn set(&self, paused: bool) {
+        *self.paused.lock().unwrap() = paused;
+        if !paused {
+            self.resumed.notify_all();
+        }
+    }
+
+    // Block for as long as the pool is paused.
+    fn wait(&self) {
+        let paused = self.paused.lock().unwrap();
+        drop(self.resumed.wait_while(paused, |paused| *paused).unwrap());
+    }
+}
+
+// Counts the jobs that have been submitted but haven't finished yet.
+struct InFlight {
+    max: usize,          // Most jobs allowed in flight at once
+    count: Mutex<usize>, // Jobs currently in flight
+    finished: Condvar,   // Signalled whenever a job leaves the count
+}
+
+// A job's place under the in-flight limit, given back when it is dropped.
+struct Permit {
+    in_flight: Arc<InFlight>, // The count to give the place back to
+}
+
+impl InFlight {
+    fn new(max: usize) -> InFlight {
+        InFlight {
+            max,
+            count: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    // Wait until there is room for one more job and take it.
+    fn acquire(self: &Arc<InFlight>) -> Permit {
+        let count = self.count.lock().unwrap();
+        let mut count = self
+            .finished
+            .wait_while(count, |count| *count >= self.max)
+            .unwrap();
+        *count += 1;
+
+        Permit {
+            in_flight: Arc::clone(self),
+        }
+    }
+
+    // Whether there is room for one more job right now.
+    fn has_room(&self) -> bool {
+        *self.count.lock().unwrap() < self.max
+    }
+
+    // Take room for one more job if there is any, without waiting.
+    fn try_acquire(self: &Arc<InFlight>) -> Option<Permit> {
+        let mut count = self.count.lock().unwrap();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+
+        Some(Permit {
+            in_flight: Arc::clone(self),
+        })
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        // The lock is never held across a job, so this can't be poisoned
+        // even when the permit is dropped by a panicking job.
+        *self.in_flight.count.lock().unwrap() -= 1;
+        self.in_flight.finished.notify_one();
+    }
+}
+
+// Counts the jobs that have been submitted but haven't finished, to tell
+// when the pool runs out of work.
+struct IdleWatch {
+    state: Mutex<IdleState>, // The count, behind one lock
+    on_idle: IdleCallback,   // Called when the count drops to zero
+}
+
+#[derive(Default)]
+struct IdleState {
+    pending: usize, // Jobs queued or running
+    worked: bool,   // Whether a job has finished since the pool was last idle
+    stopped: bool,  // Set once the pool hands back or drops its queued jobs
+}
+
+// A job's place in the idle count, given back when it is dropped.
+struct Pending {
+    watch: Arc<IdleWatch>, // The count to give the place back to
+    queued: bool,          // Cleared for a job the queue turned away
+}
+
+impl IdleWatch {
+    // Count one more job.
+    fn count(self: &Arc<IdleWatch>) -> Pending {
+        self.state.lock().unwrap().pending += 1;
+        Pending {
+            watch: Arc::clone(self),
+            queued: true,
+        }
+    }
+
+    // Stop calling back, for a pool whose queued jobs won't run.
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+}
+
+impl Pending {
+    // Give back the place of a job that was never queued. That alone
+    // doesn't make the pool idle, since no work was done.
+    fn cancel(mut self) {
+        self.queued = false;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let idle = {
+            let mut state = self.watch.state.lock().unwrap();
+            state.pending -= 1;
+            state.worked |= self.queued;
+            // A job that panicked leaves its worker unwinding, which is no
+            // time to run more code, so the callback waits for the next job
+            // to finish instead.
+            let idle = state.pending == 0 && state.worked && !state.stopped && !thread::panicking();
+            if idle {
+                state.worked = false;
+            }
+            idle
+        };
+
+        // Call back outside the lock, so the callback can submit jobs.
+        if idle {
+            (self.watch.on_idle)();
+        }
+    }
+}
+
+// Worker struct represents a single thread in the pool.
+struct Worker {
+    id: usize,                 // Unique ID of the worker
+    thread: ThreadSlot,        // Handle of the thread currently running as this worker
+    busy: BusySince,           // When the worker's current job started
+    retired: Arc<AtomicBool>,  // Set once the worker exits because the pool shrank
+    completed: Arc<AtomicU64>, // Jobs the worker has run, across recycled threads
+}
+
+// Holds the handle of a worker's current thread. A recycled thread swaps in
+// its replacement's handle before it exits.
+type ThreadSlot = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
+// When a worker's current job started, or `None` while it is idle.
+type BusySince = Arc<Mutex<Option<Instant>>>;
+
+// Marks a worker busy for as long as it lives, including while a panicking
+// job unwinds.
+struct BusyGuard<'a> {
+    busy: &'a BusySince, // The worker's busy marker
+}
+
+impl<'a> BusyGuard<'a> {
+    fn new(busy: &'a BusySince) -> BusyGuard<'a> {
+        *busy.lock().unwrap() = Some(Instant::now());
+        BusyGuard { busy }
+    }
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        // The lock is only ever held briefly, never across a job, so it
+        // can't have been poisoned by the job panicking.
+        *self.busy.lock().unwrap() = None;
+    }
+}
+
+// Everything a worker thread needs, kept together so that a thread being
+// recycled can hand it on to its replacement.
+#[derive(Clone)]
+struct WorkerContext {
+    id: usize,                      // Unique ID of the worker
+    receiver: Receiver<Message>,    // Where jobs come from
+    max_jobs: Option<usize>,        // Jobs to run before recycling the thread
+    thread: ThreadSlot,             // Slot to put the replacement's handle in
+    busy: BusySince,                // Where to record when the current job started
+    drain: Arc<Drain>,              // Where to hand back jobs once draining starts
+    pause: Arc<Pause>,              // Where to wait while the pool is paused
+    retire: Receiver<()>,           // Where requests to exit come from when the pool shrinks
+    retired: Arc<AtomicBool>,       // Where to record having exited for one
+    completed: Arc<AtomicU64>,      // Where to count the jobs run
+    pool_completed: Arc<AtomicU64>, // Where to count them for the whole pool
+    spawner: Spawner,               // What starts the threads
+}
+
+impl Worker {
+    /// Create a new worker for `pool`, along with the context its thread
+    /// will run in once it is started.
+    fn new(id: usize, pool: &ThreadPool) -> (Worker, WorkerContext) {
+        let thread: ThreadSlot = Arc::new(Mutex::new(None));
+        let busy: BusySince = Arc::new(Mutex::new(None));
+        let retired = Arc::new(AtomicBool::new(false));
+        let completed = Arc::new(AtomicU64::new(0));
+        let context = WorkerContext {
+            id,
+            receiver: pool.receiver.clone(),
+            max_jobs: pool.max_jobs_per_worker,
+            thread: Arc::clone(&thread),
+            busy: Arc::clone(&busy),
+            drain: Arc::clone(&pool.drain),
+            pause: Arc::clone(&pool.pause),
+            retire: pool.retire_requests.clone(),
+            retired: Arc::clone(&retired),
+            completed: Arc::clone(&completed),
+            pool_completed: Arc::clone(&pool.completed),
+            spawner: Arc::clone(&pool.spawner),
+        };
+
+        let worker = Worker {
+            id,
+            thread,
+            busy,
+            retired,
+            completed,
+        };
+        (worker, context)
+    }
+
+    // Whether the worker's thread has been started and hasn't exited.
+    fn is_running(&self) -> bool {
+        self.thread
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    // Whether the worker has exited because the pool shrank.
+    fn has_retired(&self) -> bool {
+        self.retired.load(Ordering::SeqCst) && !self.is_running()
+    }
+}
+
+impl WorkerContext {
+    // Spawn the worker's first thread, with a copy of the context, and put
+    // its handle in the slot. If the thread can't be spawned, the context
+    // is still there to try again.
+    fn start(&self) -> io::Result<()> {
+        // Hold the slot while spawning, so a thread that recycles itself
+        // straight away can't store its replacement before we store it.
+        let mut slot = self.thread.lock().unwrap();
+        *slot = Some(self.clone().spawn()?);
+        Ok(())
+    }
+
+    // Spawn a new thread and move the context into the thread's closure.
+    fn spawn(self) -> io::Result<thread::JoinHandle<()>> {
+        let spawner = Arc::clone(&self.spawner);
+        spawner(Box::new(move || self.run()))
+    }
+
+    // Run jobs until the channel closes, the pool shrinks or it is time to
+    // recycle the thread.
+    fn run(self) {
+        let id = self.id;
+        let mut completed = 0;
+
+        loop {
+            // A paused pool keeps its jobs queued rather than handed out.
+            self.pause.wait();
+
+            // Wait for a job from the channel, unless the pool asks this
+            // worker to exit first.
+            let message = select! {
+                recv(self.receiver) -> message => message,
+                recv(self.retire) -> _ => {
+                    println!("Worker {id} retiring; the pool shrank.");
+                    self.retired.store(true, Ordering::SeqCst);
+                    break;
+                }
+            };
+
+            match message {
+                Ok(message) => {
+                    // The pool may have been paused while this worker was
+                    // waiting for the job, which then waits with it.
+                    self.pause.wait();
+
+                    // The pool is taking back its queued jobs, so this one
+                    // goes back unrun.
+                    if self.drain.requested.load(Ordering::SeqCst) {
+                        self.drain.returned.lock().unwrap().push(message.unrun());
+                        continue;
+                    }
+
+                    // A cancelled job is dropped unrun, and isn't counted as
+                    // run or as finishing any work.
+                    if !message.claim() {
+                        drop(message.unrun());
+                        continue;
+                    }
+                    let Message {
+                        job, permit, idle, ..
+                    } = message;
+
+                    let _span = trace::job(id);
+                    println!("Worker {id} got a job; executing.");
+
+                    // Execute the job. It stays in flight until it has
+                    // finished, even if it panics.
+                    let guard = BusyGuard::new(&self.busy);
+                    job();
+                    drop(guard);
+                    drop(permit);
+                    drop(idle);
+                    self.completed.fetch_add(1, Ordering::Relaxed);
+                    self.pool_completed.fetch_add(1, Ordering::Relaxed);
+
+                    completed += 1;
+                    if self.max_jobs.is_some_and(|max_jobs| completed >= max_jobs) {
+                        println!("Worker {id} ran {completed} jobs; recycling.");
+                        if self.recycle() {
+                            break;
+                        }
+                        // This thread stays on until it is time to try again.
+                        completed = 0;
+                    }
+                }
+                Err(_) => {
+                    println!("Worker {id} disconnected; shutting down.");
+                    // If the channel is closed, exit the loop and end the thread.
+                    // Every idle worker is blocked in `select!` on the channel
+                    // itself, with no lock in front of it, so closing it
+                    // wakes them all at once, and shutdown resumes a paused
+                    // pool first so none is left waiting there.
+                    break;
+                }
+            }
+        }
+    }
+
+    // Start a replacement thread for this worker, which takes over its place
+    // in the pool, and return whether it started. The current thread exits
+    // if it did, and otherwise carries on in its place.
+    fn recycle(&self) -> bool {
+        let mut slot = self.thread.lock().unwrap();
+
+        // Storing the replacement's handle drops our own, which detaches this
+        // thread so it is cleaned up as soon as it exits. If shutdown already
+        // took our handle, it will join us and then find the replacement.
+        match self.clone().spawn() {
+            Ok(handle) => {
+                *slot = Some(handle);
+                true
+            }
+            Err(err) => {
+                println!(
+                    "Failed to recycle worker {}: {err}; keeping its thread.",
+                    self.id
+                );
+                trace::error("failed to recycle a worker", &err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // How long a test waits for something that should happen straight away.
+    const PATIENCE: Duration = Duration::from_secs(5);
+
+    // Submit a job to `pool` that holds its worker until the returned
+    // sender is dropped, and wait for it to start.
+    fn block_worker(pool: &ThreadPool) -> Sender<()> {
+        let (started, has_started) = crossbeam_channel::bounded(1);
+        let (release, released) = crossbeam_channel::bounded::<()>(0);
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = released.recv();
+        });
+        has_started.recv_timeout(PATIENCE).unwrap();
+        release
+    }
+
+    #[test]
+    fn a_full_queue_hands_the_job_back_to_be_resubmitted() {
+        let pool = ThreadPool::with_capacity(1, 1);
+        let release = block_worker(&pool);
+        pool.try_execute(|| {}).unwrap();
+
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        let err = pool.try_execute(move || ran.send(()).unwrap()).unwrap_err();
+        assert!(matches!(err, ExecuteError::QueueFull(_)), "{err:?}");
+        let mut job = err.into_job();
+
+        // Once the worker frees up, there is room for the same job again.
+        drop(release);
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            match pool.try_execute(job) {
+                Ok(()) => break,
+                Err(ExecuteError::QueueFull(rejected)) if Instant::now() < deadline => {
+                    job = rejected;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) => panic!("{err}"),
+            }
+        }
+        has_run.recv_timeout(PATIENCE).unwrap();
+    }
+
+    #[test]
+    fn a_pool_shutting_down_hands_the_job_back() {
+        let mut pool = ThreadPool::new(1);
+        drop(pool.sender.take());
+
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        let err = pool.try_execute(move || ran.send(()).unwrap()).unwrap_err();
+        assert!(matches!(err, ExecuteError::ShuttingDown(_)), "{err:?}");
+        assert_eq!(err.to_string(), "the thread pool is shutting down");
+
+        // The job never ran, and can go to another pool instead.
+        assert!(has_run.try_recv().is_err());
+        ThreadPool::new(1).execute(err.into_job());
+        has_run.recv_timeout(PATIENCE).unwrap();
+    }
+
+    #[test]
+    fn the_shutdown_report_flags_a_worker_whose_last_job_panicked() {
+        let pool = ThreadPool::new(2);
+        let release = block_worker(&pool);
+        pool.execute(|| panic!("the last job failed"));
+        drop(release);
+
+        let report = pool.shutdown();
+        assert_eq!(report.workers().len(), 2);
+        assert_eq!(report.panicked().len(), 1);
+        assert!(!report.is_clean());
+        let clean = report
+            .workers()
+            .iter()
+            .filter(|(_, exit)| *exit == WorkerExit::Clean)
+            .count();
+        assert_eq!(clean, 1);
+    }
+
+    #[test]
+    fn the_shutdown_report_is_clean_when_every_job_returns() {
+        let pool = ThreadPool::new(3);
+        for _ in 0..10 {
+            pool.execute(|| {});
+        }
+
+        let report = pool.shutdown();
+        let ids: Vec<_> = report.workers().iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+        assert!(report.is_clean());
+        assert!(report.panicked().is_empty());
+    }
+
+    #[test]
+    fn a_pool_sized_from_available_parallelism_has_one_worker_per_cpu() {
+        let pool = ThreadPool::with_available_parallelism();
+        assert!((1..=1024).contains(&pool.size()), "{}", pool.size());
+        assert_eq!(pool.size(), available_parallelism());
+
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        pool.execute(move || ran.send(()).unwrap());
+        has_run.recv_timeout(PATIENCE).unwrap();
+    }
+
+    #[test]
+    fn every_idle_worker_waits_for_jobs_at_once() {
+        const WORKERS: usize = 4;
+        let pool = ThreadPool::new(WORKERS);
+        let running = Arc::new(AtomicU64::new(0));
+        let (done, finished) = crossbeam_channel::unbounded();
+
+        // Each job holds its worker until all of them are running, which
+        // only happens if the jobs are dispatched to every worker at once.
+        for _ in 0..WORKERS {
+            let (running, done) = (Arc::clone(&running), done.clone());
+            pool.execute(move || {
+                running.fetch_add(1, Ordering::SeqCst);
+                let deadline = Instant::now() + PATIENCE;
+                while running.load(Ordering::SeqCst) < WORKERS as u64 && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                done.send(running.load(Ordering::SeqCst)).unwrap();
+            });
+        }
+
+        for _ in 0..WORKERS {
+            assert_eq!(finished.recv_timeout(PATIENCE * 2).unwrap(), WORKERS as u64);
+        }
+    }
+
+    #[test]
+    fn concurrent_submitters_keep_every_worker_busy() {
+        const WORKERS: usize = 4;
+        const SUBMITTERS: usize = 4;
+        const JOBS: usize = 500;
+        let pool = Arc::new(ThreadPool::new(WORKERS));
+        let (done, finished) = crossbeam_channel::unbounded();
+
+        let submitters: Vec<_> = (0..SUBMITTERS)
+            .map(|_| {
+                let (pool, done) = (Arc::clone(&pool), done.clone());
+                thread::spawn(move || {
+                    for _ in 0..JOBS {
+                        let done = done.clone();
+                        pool.execute(move || {
+                            thread::sleep(Duration::from_micros(100));
+                            done.send(()).unwrap();
+                        });
+                    }
+                })
+            })
+            .collect();
+        for submitter in submitters {
+            submitter.join().unwrap();
+        }
+        for _ in 0..SUBMITTERS * JOBS {
+            finished.recv_timeout(PATIENCE).unwrap();
+        }
+
+        // The jobs were spread across the pool rather than run one worker
+        // at a time.
+        let stats = pool.per_worker_stats();
+        assert_eq!(stats.len(), WORKERS);
+        assert!(stats.iter().all(|(_, jobs)| *jobs > 0), "{stats:?}");
+        assert_eq!(pool.completed_jobs(), (SUBMITTERS * JOBS) as u64);
+    }
+
+    #[test]
+    fn a_worker_is_recycled_after_its_job_cap() {
+        let pool = ThreadPool::builder(1).max_jobs_per_worker(3).build();
+        let (ran_on, ran) = crossbeam_channel::unbounded();
+        for _ in 0..7 {
+            let ran_on = ran_on.clone();
+            pool.execute(move || ran_on.send(thread::current().id()).unwrap());
+        }
+        let threads: Vec<_> = (0..7)
+            .map(|_| ran.recv_timeout(PATIENCE).unwrap())
+            .collect();
+
+        // Three jobs per thread, each thread a fresh one.
+        assert!(threads[..3].iter().all(|id| *id == threads[0]));
+        assert!(threads[3..6].iter().all(|id| *id == threads[3]));
+        assert_ne!(threads[0], threads[3]);
+        assert_ne!(threads[3], threads[6]);
+
+        // The replacement takes the same place in the pool.
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.started_workers(), 1);
+        assert!(pool.shutdown().is_clean());
+    }
+
+    #[test]
+    fn joining_a_spawned_job_waits_for_it() {
+        let pool = ThreadPool::new(2);
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        let handle = pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            flag.store(true, Ordering::SeqCst);
+        });
+
+        handle.join().unwrap();
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn joining_a_panicking_job_hands_back_the_panic() {
+        let pool = ThreadPool::new(1);
+        let payload = pool.spawn(|| panic!("job failed")).join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"job failed"));
+
+        // The panic was caught, so the worker is still there for the next one.
+        pool.spawn(|| {}).join().unwrap();
+        assert!(pool.shutdown().is_clean());
+    }
+
+    #[test]
+    fn the_shutdown_diagnostic_names_the_stuck_worker() {
+        let pool = ThreadPool::new(3);
+        let release = block_worker(&pool);
+
+        let workers = pool.workers.lock().unwrap();
+        let stuck: Vec<_> = workers
+            .all
+            .iter()
+            .filter(|worker| worker.busy.lock().unwrap().is_some())
+            .map(|worker| worker.id)
+            .collect();
+        assert_eq!(stuck.len(), 1);
+
+        let lines = busy_workers(&workers.all, SHUTDOWN_GRACE);
+        assert_eq!(lines.len(), 1, "{lines:?}");
+        let named = format!("Worker {} still busy 5.0s into shutdown", stuck[0]);
+        assert!(lines[0].starts_with(&named), "{}", lines[0]);
+        drop(workers);
+        drop(release);
+    }
+
+    #[test]
+    fn a_stuck_worker_is_detached_once_the_shutdown_timeout_passes() {
+        let pool = ThreadPool::builder(2)
+            .shutdown_timeout(Duration::from_millis(50))
+            .build();
+        let release = block_worker(&pool);
+
+        let started = Instant::now();
+        let report = pool.shutdown();
+        assert!(started.elapsed() < PATIENCE);
+        let detached: Vec<_> = report
+            .workers()
+            .iter()
+            .filter(|(_, exit)| *exit == WorkerExit::Detached)
+            .collect();
+        assert_eq!(detached.len(), 1, "{report:?}");
+        drop(release);
+    }
+
+    #[test]
+    fn try_execute_accepts_no_more_than_max_in_flight() {
+        let pool = ThreadPool::builder(4).max_in_flight(2).build();
+        let (release, released) = crossbeam_channel::bounded::<()>(0);
+
+        let accepted = (0..10)
+            .filter(|_| {
+                let released = released.clone();
+                pool.try_execute(move || {
+                    let _ = released.recv();
+                })
+                .is_ok()
+            })
+            .count();
+        // Idle workers don't make room; only finished jobs do.
+        assert_eq!(accepted, 2);
+        assert!(!pool.has_capacity());
+
+        drop(release);
+        let deadline = Instant::now() + PATIENCE;
+        while !pool.has_capacity() {
+            assert!(Instant::now() < deadline, "the jobs never finished");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn execute_blocks_to_keep_jobs_under_max_in_flight() {
+        const MAX: u64 = 3;
+        let pool = ThreadPool::builder(8).max_in_flight(MAX as usize).build();
+        let running = Arc::new(AtomicU64::new(0));
+        let most = Arc::new(AtomicU64::new(0));
+        let (done, finished) = crossbeam_channel::unbounded();
+
+        for _ in 0..30 {
+            let (running, most, done) = (Arc::clone(&running), Arc::clone(&most), done.clone());
+            pool.execute(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(2));
+                running.fetch_sub(1, Ordering::SeqCst);
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..30 {
+            finished.recv_timeout(PATIENCE).unwrap();
+        }
+
+        let most = most.load(Ordering::SeqCst);
+        assert!((1..=MAX).contains(&most), "{most} jobs ran at once");
+    }
+
+    #[test]
+    fn shutting_down_hands_back_the_jobs_still_queued() {
+        let pool = ThreadPool::new(1);
+        let release = block_worker(&pool);
+        let (ran, has_run) = crossbeam_channel::unbounded();
+        for i in 0..3 {
+            let ran = ran.clone();
+            pool.execute(move || ran.send(i).unwrap());
+        }
+
+        // The worker finishes its job part way through the shutdown.
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(release);
+        });
+        let pending = pool.shutdown_returning_pending();
+        releaser.join().unwrap();
+
+        assert_eq!(pending.len(), 3);
+        assert!(has_run.try_recv().is_err(), "a queued job ran");
+        for job in pending {
+            job();
+        }
+        assert_eq!(has_run.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn a_lazy_pool_starts_no_threads_until_a_job_arrives() {
+        let pool = ThreadPool::builder(4).lazy(true).build();
+        assert_eq!(pool.started_workers(), 0);
+        assert_eq!(pool.size(), 4);
+
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        pool.execute(move || ran.send(()).unwrap());
+        has_run.recv_timeout(PATIENCE).unwrap();
+        assert_eq!(pool.started_workers(), 1);
+    }
+
+    #[test]
+    fn submitting_to_a_fully_started_pool_skips_the_workers_lock() {
+        let pool = ThreadPool::new(1);
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+
+        // Held for the whole test, so submitting would deadlock if it locked.
+        let _workers = pool.workers.lock().unwrap();
+        pool.execute(move || ran.send(()).unwrap());
+        has_run.recv_timeout(PATIENCE).unwrap();
+    }
+
+    #[test]
+    fn the_queue_fills_while_workers_are_blocked_and_empties_after() {
+        let pool = ThreadPool::with_capacity(1, 8);
+        assert_eq!(pool.queue_capacity(), Some(8));
+        assert_eq!(ThreadPool::new(1).queue_capacity(), None);
+
+        let release = block_worker(&pool);
+        let (ran, has_run) = crossbeam_channel::unbounded();
+        for _ in 0..3 {
+            let ran = ran.clone();
+            pool.execute(move || ran.send(()).unwrap());
+        }
+        assert_eq!(pool.queue_len(), 3);
+
+        drop(release);
+        for _ in 0..3 {
+            has_run.recv_timeout(PATIENCE).unwrap();
+        }
+        assert_eq!(pool.queue_len(), 0);
+    }
+
+    // Wait for `condition` to hold, failing the test if it doesn't in time.
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + PATIENCE;
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn growing_the_pool_starts_workers_straight_away() {
+        let pool = ThreadPool::new(2);
+        pool.resize(4);
+        assert_eq!(pool.size(), 4);
+        wait_until(|| pool.started_workers() == 4);
+
+        pool.resize(4);
+        assert_eq!(pool.started_workers(), 4);
+    }
+
+    #[test]
+    fn shrinking_the_pool_retires_workers_once_their_jobs_finish() {
+        let pool = ThreadPool::new(3);
+        let releases: Vec<_> = (0..3).map(|_| block_worker(&pool)).collect();
+
+        pool.resize(1);
+        assert_eq!(pool.size(), 1);
+        // Every worker is still busy, so none can have retired yet.
+        assert_eq!(pool.started_workers(), 3);
+
+        drop(releases);
+        wait_until(|| pool.started_workers() == 1);
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        pool.execute(move || ran.send(()).unwrap());
+        has_run.recv_timeout(PATIENCE).unwrap();
+    }
+
+    #[test]
+    fn resizing_from_several_threads_settles_on_one_size() {
+        let pool = ThreadPool::new(2);
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for size in [5, 1, 3] {
+                        pool.resize(size);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(pool.size(), 3);
+        wait_until(|| pool.started_workers() == 3);
+    }
+
+    // A pool whose `on_idle` callback counts its calls.
+    fn counting_idle(size: usize) -> (ThreadPoolBuilder, Arc<AtomicU64>) {
+        let calls = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&calls);
+        let builder = ThreadPool::builder(size).on_idle(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        (builder, calls)
+    }
+
+    #[test]
+    fn on_idle_fires_once_after_a_burst_of_jobs() {
+        let (builder, calls) = counting_idle(4);
+        let pool = builder.build();
+        let release = block_worker(&pool);
+        let (done, finished) = crossbeam_channel::unbounded();
+        for _ in 0..20 {
+            let done = done.clone();
+            pool.execute(move || done.send(()).unwrap());
+        }
+
+        for _ in 0..20 {
+            finished.recv_timeout(PATIENCE).unwrap();
+        }
+        // The blocked job keeps the pool busy until it is released.
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        drop(release);
+        wait_until(|| calls.load(Ordering::SeqCst) == 1);
+
+        // Staying idle doesn't call it again, and nor does shutting down.
+        thread::sleep(Duration::from_millis(20));
+        drop(pool);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn jobs_handed_back_unrun_dont_make_the_pool_idle() {
+        let (builder, calls) = counting_idle(1);
+        let pool = builder.build();
+        let release = block_worker(&pool);
+        for _ in 0..3 {
+            pool.execute(|| {});
+        }
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(release);
+        });
+        let pending = pool.shutdown_returning_pending();
+        releaser.join().unwrap();
+
+        // Only the job that ran counts, and it finished while the others
+        // were still queued.
+        assert_eq!(pending.len(), 3);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn jobs_dropped_by_an_aborting_shutdown_dont_make_the_pool_idle() {
+        let (builder, calls) = counting_idle(1);
+        let pool = builder.shutdown_mode(ShutdownMode::Abort).build();
+        let release = block_worker(&pool);
+        for _ in 0..3 {
+            pool.execute(|| {});
+        }
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(release);
+        });
+        drop(pool);
+        releaser.join().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn a_panicking_last_job_defers_on_idle_to_the_next_one() {
+        // The panic kills its worker, so the next job needs another one.
+        let (builder, calls) = counting_idle(2);
+        let pool = builder.build();
+        pool.execute(|| panic!("the job failed"));
+        wait_until(|| pool.started_workers() == 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let (ran, has_run) = crossbeam_channel::bounded(1);
+        pool.execute(move || ran.send(()).unwrap());
+        has_run.recv_timeout(PATIENCE).unwrap();
+        wait_until(|| calls.load(Ordering::SeqCst) == 1);
+    }
+
+    #[test]
+    fn per_worker_counts_add_up_to_every_job_run() {
+        let pool = ThreadPool::new(4);
+        let (done, finished) = crossbeam_channel::unbounded();
+        for _ in 0..200 {
+            let done = done.clone();
+            pool.execute(move || done.send(()).unwrap());
+        }
+        for _ in 0..200 {
+            finished.recv_timeout(PATIENCE).unwrap();
+        }
+
+        let total = || {
+            pool.per_worker_stats()
+                .iter()
+                .map(|&(_, jobs)| jobs)
+                .sum::<u64>()
+        };
+        wait_until(|| total() == 200);
+        let ids: Vec<_> = pool.per_worker_stats().iter().map(|&(id, _)| id).collect();
+        assert_eq!(ids, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn a_workers_count_carries_over_when_its_thread_is_recycled() {
+        let pool = ThreadPool::builder(1).max_jobs_per_worker(3).build();
+        let (done, finished) = crossbeam_channel::unbounded();
+        for _ in 0..7 {
+            let done = done.clone();
+            pool.execute(move || done.send(()).unwrap());
+        }
+        for _ in 0..7 {
+            finished.recv_timeout(PATIENCE).unwrap();
+        }
+
+        wait_until(|| pool.per_worker_stats() == [(0, 7)]);
+    }
+
+    #[test]
+    fn a_single_threaded_pool_runs_jobs_in_order_on_the_caller() {
+        let pool = ThreadPool::single_threaded();
+        let caller = thread::current().id();
+        let (ran, has_run) = crossbeam_channel::unbounded();
+        for i in 0..5 {
+            let ran = ran.clone();
+            pool.execute(move || ran.send((i, thread::current().id())).unwrap());
+            // Each job has finished by the time `execute` returns.
+            assert_eq!(has_run.try_recv().unwrap(), (i, caller));
+        }
+        pool.try_execute(|| {}).unwrap();
+
+        assert_eq!(pool.started_workers(), 0);
+        assert_eq!(pool.completed_jobs(), 6);
+        assert!(pool.shutdown().is_clean());
+    }
+
+    #[test]
+    fn a_single_threaded_pool_hands_a_spawned_jobs_panic_to_join() {
+        let pool = ThreadPool::single_threaded();
+        let handle = pool.spawn(|| panic!("the job failed"));
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn a_paused_pool_runs_nothing_until_it_is_resumed() {
+        let pool = ThreadPool::new(2);
+        pool.pause();
+        assert!(pool.is_paused());
+        let (ran, has_run) = crossbeam_channel::unbounded();
+        for i in 0..5 {
+            let ran = ran.clone();
+            pool.execute(move || ran.send(i).unwrap());
+        }
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(has_run.try_recv().is_err(), "a job ran while paused");
+
+        pool.resume();
+        let mut ran: Vec<_> = (0..5)
+            .map(|_| has_run.recv_timeout(PATIENCE).unwrap())
+            .collect();
+        ran.sort();
+        assert_eq!(ran, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn a_paused_pool_still_shuts_down_and_runs_its_queue() {
+        let pool = ThreadPool::new(1);
+        pool.pause();
+        let (ran, has_run) = crossbeam_channel::unbounded();
+        for _ in 0..3 {
+            let ran = ran.clone();
+            pool.execute(move || ran.send(()).unwrap());
+        }
+
+        assert!(pool.shutdown().is_clean());
+        assert_eq!(has_run.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn has_capacity_follows_the_queue_filling_and_draining() {
+        let pool = ThreadPool::with_capacity(1, 1);
+        let release = block_worker(&pool);
+        assert!(pool.has_capacity());
+
+        pool.try_execute(|| {}).unwrap();
+        assert!(!pool.has_capacity());
+        assert!(pool.try_execute(|| {}).is_err());
+
+        drop(release);
+        wait_until(|| pool.has_capacity());
+        pool.try_execute(|| {}).unwrap();
+    }
+
+    #[test]
+    fn a_pool_shutting_down_has_no_capacity() {
+        let mut pool = ThreadPool::new(1);
+        assert!(pool.has_capacity());
+        drop(pool.sender.take());
+        assert!(!pool.has_capacity());
+        assert!(ThreadPool::single_threaded().has_capacity());
+    }
+
+    #[test]
+    fn a_job_cancelled_in_the_queue_never_runs() {
+        let pool = ThreadPool::new(1);
+        let release = block_worker(&pool);
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        let handle = pool.spawn(move || flag.store(true, Ordering::SeqCst));
+
+        assert!(handle.cancel());
+        assert!(!handle.cancel(), "cancelled twice");
+        assert!(handle.is_cancelled());
+        drop(release);
+
+        // A job queued behind it runs, so the worker has passed it by.
+        let after = pool.spawn(|| {});
+        after.join().unwrap();
+        assert!(!ran.load(Ordering::SeqCst));
+        assert!(!handle.has_started());
+        assert!(handle.join().is_err());
+
+        // Once the worker holds a paused job, it has counted every job
+        // before it, and the cancelled one isn't among them.
+        pool.pause();
+        let last = pool.spawn(|| {});
+        wait_until(|| pool.queue_len() == 0);
+        assert_eq!(pool.completed_jobs(), 2);
+        assert_eq!(pool.per_worker_stats(), [(0, 2)]);
+        pool.resume();
+        last.join().unwrap();
+    }
+
+    #[test]
+    fn a_job_that_has_started_cant_be_cancelled() {
+        let pool = ThreadPool::new(1);
+        let (started, has_started) = crossbeam_channel::bounded(1);
+        let (release, released) = crossbeam_channel::bounded::<()>(0);
+        let handle = pool.spawn(move || {
+            started.send(()).unwrap();
+            let _ = released.recv();
+        });
+        has_started.recv_timeout(PATIENCE).unwrap();
+
+        assert!(handle.has_started());
+        assert!(!handle.cancel());
+        drop(release);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn every_idle_worker_exits_promptly_once_the_channel_closes() {
+        let pool = ThreadPool::new(8);
+        for _ in 0..8 {
+            pool.execute(|| {});
+        }
+        wait_until(|| pool.started_workers() == 8 && pool.queue_len() == 0);
+
+        // Each worker waits on the channel itself, not behind a lock, so
+        // none has to wait for another to wake before it can exit.
+        let started = Instant::now();
+        let report = pool.shutdown();
+        let took = started.elapsed();
+        assert!(took < Duration::from_millis(250), "took {took:?}");
+        assert_eq!(report.workers().len(), 8);
+        assert!(report.is_clean(), "{report:?}");
+    }
+
+    // Shut down a one-worker pool in `mode` with three jobs queued behind a
+    // running one, and count the queued jobs that ran.
+    fn queued_jobs_run_on_shutdown(mode: ShutdownMode) -> (u64, ShutdownReport) {
+        let pool = ThreadPoolBuilder::new(1).shutdown_mode(mode).build();
+        let release = block_worker(&pool);
+        let ran = Arc::new(AtomicU64::new(0));
+        for _ in 0..3 {
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // The running job only finishes once shutdown has begun.
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(release);
+        });
+        let report = pool.shutdown();
+        releaser.join().unwrap();
+        (ran.load(Ordering::SeqCst), report)
+    }
+
+    #[test]
+    fn draining_runs_the_queued_jobs_on_shutdown() {
+        let (ran, report) = queued_jobs_run_on_shutdown(ShutdownMode::Drain);
+        assert_eq!(ran, 3);
+        assert!(report.is_clean(), "{report:?}");
+    }
+
+    #[test]
+    fn aborting_drops_the_queued_jobs_but_lets_the_running_one_finish() {
+        let (ran, report) = queued_jobs_run_on_shutdown(ShutdownMode::Abort);
+        assert_eq!(ran, 0);
+        assert!(report.is_clean(), "{report:?}");
+    }
+
+    #[test]
+    fn dropping_an_aborting_pool_fails_the_handles_of_queued_jobs() {
+        let pool = ThreadPoolBuilder::new(1)
+            .shutdown_mode(ShutdownMode::Abort)
+            .build();
+        let release = block_worker(&pool);
+        let handle = pool.spawn(|| {});
+
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(release);
+        });
+        drop(pool);
+        releaser.join().unwrap();
+        assert!(handle.join().is_err());
+    }
+
+    #[test]
+    fn completed_jobs_counts_every_job_that_returned() {
+        let pool = ThreadPoolBuilder::new(4).max_jobs_per_worker(5).build();
+        for _ in 0..50 {
+            pool.execute(|| {});
+        }
+        pool.execute(|| panic!("doesn't count"));
+
+        // Recycled workers' jobs still count, the panicked one doesn't.
+        wait_until(|| pool.completed_jobs() == 50);
+        pool.spawn(|| {}).join().unwrap();
+        wait_until(|| pool.completed_jobs() == 51);
+    }
+
+    #[test]
+    fn throughput_is_the_jobs_completed_between_two_samples_per_second() {
+        let taken = Instant::now();
+        let earlier = JobsSample {
+            completed: 100,
+            taken,
+        };
+        let later = JobsSample {
+            completed: 350,
+            taken: taken + Duration::from_millis(500),
+        };
+        assert_eq!(later.jobs_per_second_since(&earlier), 500.0);
+
+        // Samples out of order or at the same instant give no rate.
+        assert_eq!(earlier.jobs_per_second_since(&later), 0.0);
+        assert_eq!(later.jobs_per_second_since(&later), 0.0);
+
+        let pool = ThreadPool::new(1);
+        let before = pool.sample();
+        pool.spawn(|| {}).join().unwrap();
+        wait_until(|| pool.completed_jobs() == 1);
+        let after = pool.sample();
+        assert_eq!(after.completed() - before.completed(), 1);
+        assert!(after.taken() >= before.taken());
+    }
+
+    // A spawner that fails the first `failures` times it is asked for a
+    // thread, and counts how often it was asked.
+    fn failing_spawner(failures: u64) -> (Spawner, Arc<AtomicU64>) {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let counted = Arc::clone(&attempts);
+        let spawner: Spawner = Arc::new(move |run| {
+            if counted.fetch_add(1, Ordering::SeqCst) < failures {
+                return Err(io::ErrorKind::OutOfMemory.into());
+            }
+            thread::Builder::new().spawn(run)
+        });
+        (spawner, attempts)
+    }
+
+    #[test]
+    fn a_lazy_pool_backs_off_between_failed_spawns_and_then_grows() {
+        let (spawner, attempts) = failing_spawner(3);
+        let mut builder = ThreadPoolBuilder::new(2).lazy(true);
+        builder.spawner = spawner;
+        let pool = builder.build();
+
+        // Every job submitted asks for a worker, but while spawning fails
+        // only some of them get to try.
+        let started = Instant::now();
+        let ran = Arc::new(AtomicU64::new(0));
+        let mut submitted = 0;
+        while pool.started_workers() == 0 {
+            assert!(started.elapsed() < PATIENCE, "the pool never grew");
+            let ran = Arc::clone(&ran);
+            pool.execute(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+            submitted += 1;
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Three failures wait at least 5, 10 and 20ms in turn.
+        assert!(started.elapsed() >= Duration::from_millis(35));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        assert!(submitted > 8, "only {submitted} jobs were submitted");
+        wait_until(|| ran.load(Ordering::SeqCst) == submitted);
+    }
+
+    #[test]
+    fn an_eager_pool_starts_a_worker_that_failed_to_spawn_on_demand() {
+        let (spawner, attempts) = failing_spawner(1);
+        let mut builder = ThreadPoolBuilder::new(2);
+        builder.spawner = spawner;
+        let pool = builder.build();
+        assert_eq!(pool.started_workers(), 1);
+
+        let release = block_worker(&pool);
+        wait_until(|| {
+            pool.execute(|| {});
+            pool.started_workers() == 2
+        });
+        drop(release);
+        assert!(attempts.load(Ordering::SeqCst) >= 3);
+        assert!(pool.shutdown().is_clean());
+    }
+
+    #[test]
+    fn growth_stops_after_too_many_failures_only_while_a_worker_runs() {
+        let mut growth = Growth::default();
+        let err = io::Error::from(io::ErrorKind::OutOfMemory);
+        for _ in 0..MAX_SPAWN_FAILURES {
+            growth.failed(0, &err);
+        }
+        growth.retry_at = Some(Instant::now());
+
+        assert!(!growth.may_try(true));
+        assert!(growth.may_try(false));
+        growth.reset();
+        assert!(growth.may_try(true));
+    }
+
+    #[test]
+    fn jitter_stays_within_zero_and_one_and_varies() {
+        let mut jitter = Jitter(1);
+        let fractions: Vec<f64> = (0..1000).map(|_| jitter.next()).collect();
+        assert!(fractions.iter().all(|f| (0.0..1.0).contains(f)));
+        assert!(fractions.iter().any(|&f| f < 0.25));
+        assert!(fractions.iter().any(|&f| f > 0.75));
+    }
+
+    #[test]
+    fn pools_shutting_down_on_many_threads_at_once_dont_interfere() {
+        const THREADS: usize = 8;
+        const JOBS: u64 = 25;
+
+        for _ in 0..20 {
+            let barrier = std::sync::Barrier::new(THREADS);
+            thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let barrier = &barrier;
+                    scope.spawn(move || {
+                        let pool = ThreadPool::new(2);
+                        let ran = Arc::new(AtomicU64::new(0));
+                        for job in 0..JOBS {
+                            let ran = Arc::clone(&ran);
+                            // Every other pool has a job that panics and takes its
+                            // worker down, leaving the rest to the other one.
+                            let panics = thread % 2 == 1 && job == 0;
+                            pool.execute(move || {
+                                ran.fetch_add(1, Ordering::SeqCst);
+                                assert!(!panics, "job panicked on purpose");
+                            });
+                        }
+
+                        // All the pools go at the same moment, half of them
+                        // shut down and half dropped.
+                        barrier.wait();
+                        if thread < THREADS / 2 {
+                            let report = pool.shutdown();
+                            assert_eq!(report.workers().len(), 2);
+                            if thread % 2 == 1 {
+                                assert_eq!(report.panicked().len(), 1);
+                            } else {
+                                assert!(report.is_clean());
+                            }
+                        } else {
+                            drop(pool);
+                        }
+                        assert_eq!(ran.load(Ordering::SeqCst), JOBS);
+                    });
+                }
+            });
         }
     }
 }
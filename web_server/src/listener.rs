@@ -0,0 +1,246 @@
+#[cfg(unix)]
+use std::{
+    fs,
+    os::{
+        fd::OwnedFd,
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::Path,
+};
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+#[cfg(unix)]
+use socket2::SockAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Bind a `TcpListener` to `addr` with an explicit listen backlog.
+///
+/// `TcpListener::bind` always uses the OS default backlog, which can drop
+/// connections under bursty load. Building the socket by hand lets us pass
+/// our own value to `listen`.
+pub fn bind(addr: SocketAddr, backlog: i32) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    // Match what std does on Unix so a restarted server can rebind its port
+    // straight away instead of waiting for TIME_WAIT to expire.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+
+    Ok(socket.into())
+}
+
+/// Bind `count` TCP listeners to `addr`, each to be served by its own
+/// accept loop, e.g. by adding them all to one `Server`.
+///
+/// On Linux the listeners share the address with `SO_REUSEPORT`, and the
+/// kernel spreads new connections across their separate queues, so no
+/// single accept loop limits how fast connections come in. Elsewhere the
+/// option doesn't spread the load, so only one listener is bound, whatever
+/// `count` says.
+///
+/// # Panics
+///
+/// The `bind_many` function will panic if `count` is zero.
+pub fn bind_many(addr: SocketAddr, backlog: i32, count: usize) -> io::Result<Vec<TcpListener>> {
+    assert!(count > 0, "at least one listener is needed");
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if count > 1 {
+        return bind_reuseport(addr, backlog, count);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    if count > 1 {
+        println!("SO_REUSEPORT isn't supported here; accepting on one thread.");
+    }
+
+    Ok(vec![bind(addr, backlog)?])
+}
+
+/// Bind `count` TCP listeners to the same `addr` with `SO_REUSEPORT`.
+///
+/// If `addr` has port 0, the first listener picks a free port and the rest
+/// join it there.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_reuseport(
+    addr: SocketAddr,
+    backlog: i32,
+    count: usize,
+) -> io::Result<Vec<TcpListener>> {
+    let mut addr = addr;
+    let mut listeners = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(backlog)?;
+
+        let listener = TcpListener::from(socket);
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+/// Bind a `UnixListener` to the socket file at `path` with an explicit
+/// listen backlog.
+///
+/// A socket file left behind by a server that didn't exit cleanly is
+/// removed first. If another server is still listening on it, or `path` is
+/// some other kind of file, binding fails with `AddrInUse` instead.
+#[cfg(unix)]
+pub fn bind_unix(path: &Path, backlog: i32) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    socket.bind(&SockAddr::unix(path)?)?;
+    socket.listen(backlog)?;
+
+    Ok(UnixListener::from(OwnedFd::from(socket)))
+}
+
+// Remove the socket file at `path` if nobody is listening on it any more.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    // A live socket accepts the connection, a stale one refuses it.
+    let stale = metadata.file_type().is_socket()
+        && UnixStream::connect(path)
+            .is_err_and(|err| err.kind() == io::ErrorKind::ConnectionRefused);
+    if !stale {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is already in use", path.display()),
+        ));
+    }
+
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpStream},
+    };
+
+    use super::*;
+
+    #[test]
+    fn binds_with_a_custom_backlog_and_accepts() {
+        let listener = bind((Ipv4Addr::LOCALHOST, 0).into(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+
+        client.write_all(b"ping").unwrap();
+        let mut received = [0; 4];
+        accepted.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[test]
+    fn queues_connections_up_to_the_backlog_before_accepting() {
+        let listener = bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The handshakes complete in the backlog, with nobody accepting yet.
+        let clients: Vec<_> = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for _ in &clients {
+            listener.accept().unwrap();
+        }
+    }
+
+    #[test]
+    fn rebinding_a_port_in_use_fails() {
+        let listener = bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let err = bind(addr, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_stale_unix_socket_is_replaced_but_a_live_one_is_not() {
+        let path =
+            std::env::temp_dir().join(format!("web_server-listener-{}.sock", std::process::id()));
+        drop(bind_unix(&path, 8).unwrap());
+
+        // Nobody listens on the file left behind, so it is taken over.
+        let live = bind_unix(&path, 8).unwrap();
+        let err = bind_unix(&path, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(live);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_file_that_is_not_a_socket_is_left_alone() {
+        let path =
+            std::env::temp_dir().join(format!("web_server-listener-{}.txt", std::process::id()));
+        fs::write(&path, b"keep me").unwrap();
+
+        let err = bind_unix(&path, 8).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(fs::read(&path).unwrap(), b"keep me");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn reuseport_listeners_share_a_port_and_both_accept() {
+        let listeners = bind_reuseport((Ipv4Addr::LOCALHOST, 0).into(), 64, 2).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(listeners[1].local_addr().unwrap(), addr);
+
+        // The kernel picks a listener by hashing each connection's source
+        // port, so a few dozen connections reach both.
+        let clients: Vec<_> = (0..48).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let accepted: Vec<usize> = listeners
+            .iter()
+            .map(|listener| {
+                listener.set_nonblocking(true).unwrap();
+                std::iter::from_fn(|| listener.accept().ok()).count()
+            })
+            .collect();
+
+        assert_eq!(accepted.iter().sum::<usize>(), clients.len());
+        assert!(accepted.iter().all(|&count| count > 0), "{accepted:?}");
+    }
+
+    #[test]
+    fn bind_many_binds_one_listener_when_one_is_asked_for() {
+        let listeners = bind_many((Ipv4Addr::LOCALHOST, 0).into(), 8, 1).unwrap();
+        assert_eq!(listeners.len(), 1);
+
+        // Without SO_REUSEPORT the port stays exclusive.
+        let addr = listeners[0].local_addr().unwrap();
+        assert_eq!(bind(addr, 8).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one listener is needed")]
+    fn bind_many_refuses_zero_listeners() {
+        let _ = bind_many((Ipv4Addr::LOCALHOST, 0).into(), 8, 0);
+    }
+}
@@ -1,41 +1,161 @@
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    env, fs,
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
 };
-use web_server::ThreadPool;
+use web_server::{
+    admin,
+    config::{Config, SharedConfig},
+    error_page, favicon, listener, metrics,
+    request::Request,
+    response::Response,
+    root_page,
+    server::{self, Server},
+    static_files::StaticFiles,
+    ThreadPool,
+};
+
+// How long connections still open at shutdown get to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    // An optional configuration file can be passed as the first argument.
+    let config_path = env::args().nth(1);
+    let config = match &config_path {
+        Some(path) => Config::from_file(path).unwrap(),
+        None => Config::default(),
+    };
+    let shared = Arc::new(SharedConfig::new(config));
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
+    // With a configuration file, `kill -HUP` re-reads it.
+    #[cfg(unix)]
+    if let Some(path) = config_path {
+        web_server::signal::reload_on_hangup(Arc::clone(&shared), move || Config::from_file(&path))
+            .unwrap();
+    }
 
-        pool.execute(|| {
-            handle_connection(stream);
-        });
+    let config = shared.load();
+    let pool = Arc::new(ThreadPool::new(config.workers));
+
+    // Ctrl-C or `kill` stops accepting, and the connections still open get
+    // a while to finish.
+    #[cfg(unix)]
+    web_server::signal::shut_down_on_terminate(config.shutdown.clone()).unwrap();
+
+    // The handler only holds on to the pool weakly; a worker owning it
+    // would be left to join itself when the pool is dropped.
+    let handler = {
+        let shared = Arc::clone(&shared);
+        let pool = Arc::downgrade(&pool);
+        move |request: &Request| route(request, &shared.load(), &pool)
+    };
+
+    // Listen on a socket file instead of TCP if one is configured.
+    match &config.unix_socket {
+        #[cfg(unix)]
+        Some(path) => {
+            let listener = listener::bind_unix(path, config.backlog).unwrap();
+            server::serve_unix(listener, &pool, shared, handler);
+        }
+        _ => {
+            let listeners =
+                listener::bind_many(config.addr, config.backlog, config.accept_threads).unwrap();
+            let server = listeners.into_iter().fold(Server::new(), Server::listener);
+            server.run(&pool, Arc::clone(&shared), handler);
+
+            server.shutdown_graceful(sole_owner(pool), &shared, DRAIN_TIMEOUT);
+        }
     }
 
     println!("Shutting down.");
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let buf_reader = BufReader::new(&mut stream);
-    let request_line = buf_reader.lines().next().unwrap().unwrap();
+// Take the pool back from the handler's requests, which only hold on to it
+// for as long as one of them is reading its stats.
+fn sole_owner(mut pool: Arc<ThreadPool>) -> ThreadPool {
+    loop {
+        match Arc::try_unwrap(pool) {
+            Ok(pool) => return pool,
+            Err(shared) => pool = shared,
+        }
+        thread::yield_now();
+    }
+}
+
+fn route(request: &Request, config: &Config, pool: &Weak<ThreadPool>) -> Response {
+    let files = StaticFiles::from_config(config);
 
-    let (status_line, file_path) = match request_line.as_str() {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "./util/hello.html"),
-        _ => ("HTTP/1.1 404 NOT FOUND", "./util/404.html"),
+    let found = match request.path() {
+        root_page::PATH => match &config.root_page {
+            Some(page) => Some(page.serve(request)),
+            None => files.serve_file(request, "hello.html"),
+        },
+        admin::POOL_PATH => pool
+            .upgrade()
+            .map(|pool| admin::pool(request, config, &pool)),
+        metrics::PATH => Some(metrics::serve(request, config, pool.upgrade().as_deref())),
+        favicon::PATH if config.serve_favicon => {
+            Some(favicon::serve(request, config.favicon.as_deref()))
+        }
+        _ => None,
     };
 
-    let response = build_response(status_line, file_path);
-    stream.write_all(response.as_bytes()).unwrap();
+    found.unwrap_or_else(|| not_found(request, config))
+}
+
+fn not_found(request: &Request, config: &Config) -> Response {
+    // API clients asking for JSON get a JSON error instead of the HTML page.
+    if error_page::wants_json(Some(request)) {
+        return error_page::json(404);
+    }
+
+    let contents = fs::read_to_string(config.static_root.join("404.html")).unwrap();
+
+    Response::new(404)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(contents)
 }
 
-fn build_response(status_line: &str, file_path: &str) -> String {
-    let contents = fs::read_to_string(file_path).unwrap();
-    let length = contents.len();
+#[cfg(test)]
+mod tests {
+    use web_server::{request::Limits, root_page::RootPage};
+
+    use super::*;
+
+    fn get_root(config: &Config) -> Response {
+        let head = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        route(&request, config, &Weak::new())
+    }
 
-    format!("{status_line}\r\nContent-Length: {length}\r\n\r\n{contents}")
+    fn site_with_hello() -> Config {
+        let root = env::temp_dir().join(format!("web_server-main-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("hello.html"), b"<p>from disk</p>").unwrap();
+        Config {
+            static_root: root,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn the_root_is_served_from_memory_over_the_file() {
+        let config = Config {
+            root_page: Some(RootPage::new("text/plain", "from memory")),
+            ..site_with_hello()
+        };
+
+        let response = get_root(&config);
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.body(), b"from memory");
+    }
+
+    #[test]
+    fn without_a_root_page_the_root_is_the_file() {
+        let response = get_root(&site_with_hello());
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), b"<p>from disk</p>");
+    }
 }
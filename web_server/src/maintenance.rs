@@ -0,0 +1,133 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{error_page, request::Request, response::Response};
+
+/// A switch that puts the whole server into maintenance mode.
+///
+/// While it is on, every request gets `503 Service Unavailable` with a
+/// `Retry-After` header, except for paths on the allow-list (such as a health
+/// check). The switch is a shared `AtomicBool`, so it can be flipped at
+/// runtime from another thread, a signal handler or an admin endpoint.
+/// Clones share the same switch.
+#[derive(Clone)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>, // Whether maintenance mode is currently on
+    allowed: Vec<String>,     // Paths that are still served while it is on
+    retry_after: Duration,    // How long clients are told to wait
+}
+
+impl Maintenance {
+    /// Create a maintenance gate controlled by `enabled`.
+    pub fn new(enabled: Arc<AtomicBool>) -> Maintenance {
+        Maintenance {
+            enabled,
+            allowed: Vec::new(),
+            retry_after: Duration::from_secs(120),
+        }
+    }
+
+    /// Keep serving `path` normally while maintenance mode is on.
+    pub fn allow(mut self, path: impl Into<String>) -> Maintenance {
+        self.allowed.push(path.into());
+        self
+    }
+
+    /// Set the delay advertised in the `Retry-After` header.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Maintenance {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// The shared switch, for turning maintenance mode on and off.
+    pub fn switch(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.enabled)
+    }
+
+    /// Whether maintenance mode is currently on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// The 503 response for `request`, or `None` if it should be routed as usual.
+    pub fn check(&self, request: &Request) -> Option<Response> {
+        if !self.is_enabled() || self.allowed.iter().any(|path| path == request.path()) {
+            return None;
+        }
+
+        let response = if error_page::wants_json(Some(request)) {
+            error_page::json(503)
+        } else {
+            Response::new(503)
+                .with_header("Content-Type", "text/html; charset=utf-8")
+                .with_body(MAINTENANCE_PAGE)
+        };
+
+        Some(response.retry_after(self.retry_after))
+    }
+}
+
+impl Default for Maintenance {
+    /// A gate that starts switched off, with an empty allow-list.
+    fn default() -> Maintenance {
+        Maintenance::new(Arc::new(AtomicBool::new(false)))
+    }
+}
+
+const MAINTENANCE_PAGE: &str = "<!doctype html>
+<html lang=\"en\">
+    <head>
+        <meta charset=\"utf-8\" />
+        <title>Down for maintenance</title>
+    </head>
+    <body>
+        <h1>Down for maintenance</h1>
+        <p>We'll be back shortly. Please try again in a few minutes.</p>
+    </body>
+</html>
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn get(path: &str) -> Request {
+        let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn maintenance_mode_answers_503_except_for_allowed_paths() {
+        let maintenance = Maintenance::default()
+            .allow("/healthz")
+            .with_retry_after(Duration::from_secs(30));
+        maintenance.switch().store(true, Ordering::Relaxed);
+
+        let response = maintenance.check(&get("/")).unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.header("Retry-After"), Some("30"));
+        assert_eq!(response.body(), MAINTENANCE_PAGE.as_bytes());
+
+        assert!(maintenance.check(&get("/healthz")).is_none());
+    }
+
+    #[test]
+    fn requests_pass_through_while_maintenance_mode_is_off() {
+        let maintenance = Maintenance::default();
+        assert!(!maintenance.is_enabled());
+        assert!(maintenance.check(&get("/")).is_none());
+
+        // Clones share the switch.
+        let clone = maintenance.clone();
+        clone.switch().store(true, Ordering::Relaxed);
+        assert!(maintenance.check(&get("/")).is_some());
+        clone.switch().store(false, Ordering::Relaxed);
+        assert!(maintenance.check(&get("/")).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+//! Accounting for the memory buffered by requests and responses in flight.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// A ceiling on the bytes buffered across all connections at once.
+///
+/// Per-request limits such as `Limits::max_body_size` bound each request on
+/// its own, but many large requests arriving together can still exhaust
+/// memory. Connections reserve room for each request and response body
+/// here first, and are answered with 503 when the ceiling would be
+/// exceeded. Clones share the same counter.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    ceiling: Option<usize>, // Most bytes allowed at once, or None for no limit
+    used: Arc<AtomicUsize>, // Bytes currently reserved
+}
+
+/// Room reserved in a `MemoryBudget`, given back when this is dropped.
+pub struct Reservation {
+    bytes: usize,         // Bytes this reservation holds
+    budget: MemoryBudget, // The budget to give them back to
+}
+
+impl MemoryBudget {
+    /// A budget allowing at most `ceiling` bytes to be buffered at once.
+    pub fn new(ceiling: usize) -> MemoryBudget {
+        MemoryBudget {
+            ceiling: Some(ceiling),
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// A budget that never refuses a reservation but still counts them.
+    pub fn unlimited() -> MemoryBudget {
+        MemoryBudget {
+            ceiling: None,
+            used: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reserve `bytes`, or return `None` if that would exceed the ceiling.
+    pub fn reserve(&self, bytes: usize) -> Option<Reservation> {
+        if !self.take(bytes) {
+            return None;
+        }
+
+        Some(Reservation {
+            bytes,
+            budget: self.clone(),
+        })
+    }
+
+    // Add `bytes` to the count unless that would pass the ceiling.
+    fn take(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let total = used.checked_add(bytes)?;
+                match self.ceiling {
+                    Some(ceiling) if total > ceiling => None,
+                    _ => Some(total),
+                }
+            })
+            .is_ok()
+    }
+
+    /// The bytes currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    /// The ceiling, or `None` if the budget is unlimited.
+    pub fn ceiling(&self) -> Option<usize> {
+        self.ceiling
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> MemoryBudget {
+        MemoryBudget::unlimited()
+    }
+}
+
+impl Reservation {
+    /// The number of bytes reserved.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Reserve `bytes` more, for a buffer that turned out larger than
+    /// expected. Returns `false`, leaving the reservation as it was, if that
+    /// would exceed the ceiling.
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        if !self.budget.take(bytes) {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_past_the_ceiling_are_refused_until_room_is_freed() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.reserve(60).unwrap();
+        let second = budget.reserve(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        assert!(budget.reserve(1).is_none());
+
+        drop(first);
+        assert_eq!(budget.used(), 40);
+        let third = budget.reserve(60).unwrap();
+        assert_eq!(third.bytes(), 60);
+        drop((second, third));
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn a_reservation_grows_only_within_the_ceiling() {
+        let budget = MemoryBudget::new(100);
+        let mut reservation = budget.reserve(50).unwrap();
+        assert!(reservation.try_grow(50));
+        assert!(!reservation.try_grow(1));
+        assert_eq!(reservation.bytes(), 100);
+
+        drop(reservation);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn an_unlimited_budget_still_counts() {
+        let budget = MemoryBudget::unlimited();
+        let reservation = budget.reserve(usize::MAX / 2).unwrap();
+        assert_eq!(budget.used(), usize::MAX / 2);
+        assert_eq!(budget.ceiling(), None);
+        assert!(budget.reserve(usize::MAX).is_none(), "the count overflowed");
+        drop(reservation);
+    }
+}
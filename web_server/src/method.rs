@@ -0,0 +1,111 @@
+//! The request method.
+
+use std::fmt;
+
+/// A request method, as named in the request line.
+///
+/// The methods defined by HTTP have variants of their own, and any other
+/// token is kept as `Other`. Methods are case-sensitive, so `get` is an
+/// `Other` method and not `Get`. Converting from a name never makes an
+/// `Other` for a method that has a variant of its own, so build one with
+/// `From` rather than by hand, for it to compare equal.
+///
+/// ```
+/// use web_server::method::Method;
+///
+/// assert_eq!(Method::from("GET"), Method::Get);
+/// assert_eq!(Method::from("PURGE"), Method::Other(String::from("PURGE")));
+/// assert_eq!(Method::Delete.as_str(), "DELETE");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    Patch,
+    Trace,
+    Connect,
+    Other(String), // Any other method, e.g. a WebDAV one
+}
+
+impl Method {
+    /// The method's name, as sent in the request line.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Head => "HEAD",
+            Method::Options => "OPTIONS",
+            Method::Patch => "PATCH",
+            Method::Trace => "TRACE",
+            Method::Connect => "CONNECT",
+            Method::Other(method) => method,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    fn from(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            "TRACE" => Method::Trace,
+            "CONNECT" => Method::Connect,
+            _ => Method::Other(method.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD: [(&str, Method); 9] = [
+        ("GET", Method::Get),
+        ("POST", Method::Post),
+        ("PUT", Method::Put),
+        ("DELETE", Method::Delete),
+        ("HEAD", Method::Head),
+        ("OPTIONS", Method::Options),
+        ("PATCH", Method::Patch),
+        ("TRACE", Method::Trace),
+        ("CONNECT", Method::Connect),
+    ];
+
+    #[test]
+    fn each_standard_method_parses_to_its_variant_and_back() {
+        for (name, method) in STANDARD {
+            assert_eq!(Method::from(name), method);
+            assert_eq!(method.as_str(), name);
+            assert_eq!(method.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn any_other_token_is_kept_as_other() {
+        let purge = Method::from("PURGE");
+        assert_eq!(purge, Method::Other(String::from("PURGE")));
+        assert_eq!(purge.as_str(), "PURGE");
+        assert_eq!(purge.to_string(), "PURGE");
+
+        // Methods are case-sensitive.
+        assert_eq!(Method::from("get"), Method::Other(String::from("get")));
+        assert_ne!(Method::from("get"), Method::Get);
+    }
+}
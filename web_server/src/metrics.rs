@@ -0,0 +1,304 @@
+//! Request and pool metrics, served in the Prometheus text format.
+//!
+//! Metrics are only collected and served when `Config::metrics` is set.
+//! Every response the server writes for a request is counted by status and
+//! timed into a histogram, and a scrape of `PATH` reports those along with
+//! the pool and connection gauges at that moment.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::Config, error_page, method::Method, request::Request, response::Response, ThreadPool,
+};
+
+/// Where the metrics are served.
+pub const PATH: &str = "/metrics";
+
+/// The upper bounds, in seconds, of the request duration histogram's
+/// buckets.
+pub const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// Status codes are three digits, so one counter for each fits them all.
+const STATUSES: usize = 1000;
+
+/// Counters for the requests a server has answered.
+///
+/// Recording takes a few atomic additions and no lock, so it is cheap
+/// enough to do for every request. Clones share the same counters, which
+/// is how the registry in `Config` survives a reload.
+#[derive(Clone)]
+pub struct Metrics {
+    shared: Arc<Registry>, // Shared by every clone
+}
+
+struct Registry {
+    requests: Box<[AtomicU64]>, // Requests answered, indexed by status
+    buckets: Box<[AtomicU64]>,  // Requests per duration bucket, not cumulative
+    duration_nanos: AtomicU64,  // Sum of every request's duration
+    count: AtomicU64,           // Requests timed into the histogram
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        let counters = |n| (0..n).map(|_| AtomicU64::new(0)).collect();
+        Metrics {
+            shared: Arc::new(Registry {
+                requests: counters(STATUSES),
+                buckets: counters(DURATION_BUCKETS.len()),
+                duration_nanos: AtomicU64::new(0),
+                count: AtomicU64::new(0),
+            }),
+        }
+    }
+}
+
+impl Metrics {
+    /// Create a registry with every counter at zero.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Count a request answered with `status` that took `duration`.
+    ///
+    /// A status outside `0..1000`, which can't be sent anyway, is timed but
+    /// not counted by status.
+    pub fn record(&self, status: u16, duration: Duration) {
+        let registry = &self.shared;
+        if let Some(counter) = registry.requests.get(usize::from(status)) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|&le| seconds <= le) {
+            registry.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        registry.duration_nanos.fetch_add(nanos, Ordering::Relaxed);
+        registry.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How many requests have been answered with `status`.
+    pub fn requests(&self, status: u16) -> u64 {
+        self.shared
+            .requests
+            .get(usize::from(status))
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    ///
+    /// The request counters always appear. The pool gauges `pool_workers`,
+    /// `pool_active` and `pool_queued`, and the pool's completed jobs,
+    /// appear when `pool` is given, and the connection gauges when `config`
+    /// is.
+    pub fn render(&self, config: Option<&Config>, pool: Option<&ThreadPool>) -> String {
+        let mut out = String::new();
+
+        if let Some(pool) = pool {
+            gauge(
+                &mut out,
+                "pool_workers",
+                "Worker threads the pool is sized for.",
+                pool.size(),
+            );
+            gauge(
+                &mut out,
+                "pool_active",
+                "Workers running a job.",
+                pool.busy_workers(),
+            );
+            gauge(
+                &mut out,
+                "pool_queued",
+                "Jobs waiting for a worker.",
+                pool.queue_len(),
+            );
+            header(
+                &mut out,
+                "pool_jobs_completed_total",
+                "Jobs the pool has run to completion.",
+                "counter",
+            );
+            let _ = writeln!(out, "pool_jobs_completed_total {}", pool.completed_jobs());
+        }
+        if let Some(config) = config {
+            let connections = &config.connections;
+            gauge(
+                &mut out,
+                "http_connections_open",
+                "Connections open, busy or idle.",
+                connections.open_connections(),
+            );
+            gauge(
+                &mut out,
+                "http_connections_idle",
+                "Keep-alive connections waiting for a request.",
+                connections.idle_connections(),
+            );
+        }
+
+        let registry = &self.shared;
+        header(
+            &mut out,
+            "http_requests_total",
+            "Requests answered, by status code.",
+            "counter",
+        );
+        for (status, counter) in registry.requests.iter().enumerate() {
+            let count = counter.load(Ordering::Relaxed);
+            if count > 0 {
+                let _ = writeln!(out, "http_requests_total{{status=\"{status}\"}} {count}");
+            }
+        }
+
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "Time from reading a request to having written its response.",
+            "histogram",
+        );
+        let mut cumulative = 0;
+        for (le, bucket) in DURATION_BUCKETS.iter().zip(registry.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{le=\"{le}\"}} {cumulative}"
+            );
+        }
+        // The counters are read one at a time while requests keep being
+        // recorded, so the count could lag the buckets just read. `+Inf`
+        // must cover every bucket, so it never reports fewer.
+        let count = registry.count.load(Ordering::Relaxed).max(cumulative);
+        let sum = registry.duration_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(
+            out,
+            "http_request_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(out, "http_request_duration_seconds_sum {sum}");
+        let _ = writeln!(out, "http_request_duration_seconds_count {count}");
+
+        out
+    }
+}
+
+/// Answer a request for `PATH`.
+///
+/// `GET` renders `config`'s metrics along with those of `pool`, if the
+/// pool is still around, and `HEAD` gets the same headers without them.
+/// Without metrics in `config` the endpoint doesn't exist and this answers
+/// 404.
+pub fn serve(request: &Request, config: &Config, pool: Option<&ThreadPool>) -> Response {
+    let Some(metrics) = &config.metrics else {
+        return error_page::render(Some(request), 404);
+    };
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return error_page::render(Some(request), 405).with_header("Allow", "GET, HEAD");
+    }
+
+    let response = Response::new(200)
+        .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+        .with_header("Cache-Control", "no-store")
+        .with_body(metrics.render(Some(config), pool));
+    match request.method() {
+        Method::Head => response.without_body(),
+        _ => response,
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str) -> Request {
+        let head = format!("{method} {PATH} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn requests_are_counted_by_status_and_timed_into_cumulative_buckets() {
+        let metrics = Metrics::new();
+        metrics.record(200, Duration::from_millis(2));
+        metrics.record(200, Duration::from_millis(30));
+        metrics.record(404, Duration::from_secs(20));
+        metrics.record(1000, Duration::from_millis(2));
+        assert_eq!(metrics.requests(200), 2);
+        assert_eq!(metrics.requests(404), 1);
+        assert_eq!(metrics.requests(1000), 0);
+
+        let text = metrics.render(None, None);
+        assert!(text.contains("# TYPE http_requests_total counter\n"));
+        assert!(text.contains("http_requests_total{status=\"200\"} 2\n"));
+        assert!(text.contains("http_requests_total{status=\"404\"} 1\n"));
+        assert!(
+            !text.contains("status=\"500\""),
+            "unused statuses are left out"
+        );
+
+        assert!(text.contains("# TYPE http_request_duration_seconds histogram\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"0.05\"} 3\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"10\"} 3\n"));
+        assert!(text.contains("http_request_duration_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("http_request_duration_seconds_count 4\n"));
+        assert!(!text.contains("pool_workers"), "no pool was given");
+    }
+
+    #[test]
+    fn the_pool_and_connection_gauges_appear_when_given() {
+        let pool = ThreadPool::new(3);
+        let text = Metrics::new().render(Some(&Config::default()), Some(&pool));
+        for line in [
+            "pool_workers 3\n",
+            "pool_active 0\n",
+            "pool_queued 0\n",
+            "pool_jobs_completed_total 0\n",
+            "http_connections_open 0\n",
+            "http_connections_idle 0\n",
+        ] {
+            assert!(text.contains(line), "{line:?} missing from\n{text}");
+        }
+    }
+
+    #[test]
+    fn the_endpoint_only_exists_with_metrics_turned_on() {
+        let off = Config::default();
+        assert_eq!(serve(&request("GET"), &off, None).status(), 404);
+
+        let on = Config {
+            metrics: Some(Metrics::new()),
+            ..Config::default()
+        };
+        let response = serve(&request("GET"), &on, None);
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/plain; version=0.0.4; charset=utf-8")
+        );
+        assert_eq!(serve(&request("POST"), &on, None).status(), 405);
+
+        let head = serve(&request("HEAD"), &on, None);
+        assert_eq!(head.status(), 200);
+        assert!(head.body().is_empty());
+        let length = response.body().len().to_string();
+        assert_eq!(head.header("Content-Length"), Some(length.as_str()));
+    }
+}
@@ -0,0 +1,76 @@
+use std::path::Path;
+
+/// Guess a file's media type from its extension.
+///
+/// Unknown extensions fall back to `application/octet-stream`.
+pub fn from_path(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "application/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Whether responses of this media type carry text, and so need a charset.
+pub fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || matches!(
+            media_type,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+/// The `Content-Type` value for `media_type`, with `; charset=...` appended
+/// for text types. Binary types are returned unchanged.
+pub fn content_type(media_type: &str, charset: &str) -> String {
+    if is_text(media_type) {
+        format!("{media_type}; charset={charset}")
+    } else {
+        media_type.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_types_get_a_charset_and_binary_types_do_not() {
+        assert_eq!(
+            content_type(from_path(Path::new("index.HTML")), "utf-8"),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(from_path(Path::new("app.js")), "utf-8"),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(from_path(Path::new("logo.png")), "utf-8"),
+            "image/png"
+        );
+        assert_eq!(
+            content_type(from_path(Path::new("archive")), "utf-8"),
+            "application/octet-stream"
+        );
+    }
+}
@@ -0,0 +1,267 @@
+//! Content negotiation helpers for headers such as `Accept`,
+//! `Accept-Language` and `Accept-Encoding`.
+
+/// Split a header like `text/html;q=0.9, */*;q=0.1` into its items and their
+/// quality values.
+///
+/// Items without a `q` parameter get the default quality of 1. Any other
+/// parameters are dropped, and malformed quality values count as 0.
+pub fn parse_quality_list(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim();
+            if value.is_empty() {
+                return None;
+            }
+
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                .map(|(_, q)| q.trim().parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0))
+                .unwrap_or(1.0);
+
+            Some((value, quality))
+        })
+        .collect()
+}
+
+/// The quality an `Accept` header assigns to `media_type`.
+///
+/// The most specific matching media range wins, so `text/html` takes
+/// precedence over `text/*`, which takes precedence over `*/*`. A media type
+/// no range matches gets a quality of 0.
+pub fn media_quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+
+    let mut best: Option<(u8, f32)> = None;
+    for (range, quality) in parse_quality_list(accept) {
+        let specificity = if range.eq_ignore_ascii_case(media_type) {
+            3
+        } else if range
+            .strip_suffix("/*")
+            .is_some_and(|range_kind| range_kind.eq_ignore_ascii_case(kind))
+        {
+            2
+        } else if range == "*/*" {
+            1
+        } else {
+            continue;
+        };
+
+        if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map(|(_, quality)| quality).unwrap_or(0.0)
+}
+
+/// Whether a client sending this `Accept` header prefers JSON over HTML.
+///
+/// Ties and a missing header go to HTML, since that's what browsers expect.
+pub fn prefers_json(accept: Option<&str>) -> bool {
+    match accept {
+        Some(accept) => {
+            let json = media_quality(accept, "application/json");
+            json > 0.0 && json > media_quality(accept, "text/html")
+        }
+        None => false,
+    }
+}
+
+/// The quality an `Accept-Language` header assigns to the language `tag`.
+///
+/// A language range matches the tag itself and any tag it is a prefix of
+/// when followed by `-`, so `en` matches `en-GB` but `en-GB` doesn't match
+/// `en`. Tags compare case-insensitively. The longest matching range wins,
+/// with `*` matching anything as a last resort. A tag no range matches gets
+/// a quality of 0.
+pub fn language_quality(accept_language: &str, tag: &str) -> f32 {
+    let mut best: Option<(usize, f32)> = None;
+    for (range, quality) in parse_quality_list(accept_language) {
+        let specificity = if range == "*" {
+            0
+        } else if tag
+            .as_bytes()
+            .get(..range.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(range.as_bytes()))
+            && matches!(tag.as_bytes().get(range.len()), None | Some(b'-'))
+        {
+            range.len()
+        } else {
+            continue;
+        };
+
+        if best.is_none_or(|(best_specificity, _)| specificity > best_specificity) {
+            best = Some((specificity, quality));
+        }
+    }
+
+    best.map(|(_, quality)| quality).unwrap_or(0.0)
+}
+
+/// The language from `supported` that an `Accept-Language` header likes
+/// best.
+///
+/// Ties go to whichever language comes first in `supported`. Returns `None`
+/// if the header accepts none of them.
+pub fn preferred_language<'a>(accept_language: &str, supported: &[&'a str]) -> Option<&'a str> {
+    let mut best: Option<(&str, f32)> = None;
+    for &tag in supported {
+        let quality = language_quality(accept_language, tag);
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((tag, quality));
+        }
+    }
+
+    best.map(|(tag, _)| tag)
+}
+
+/// The content coding from `supported` that an `Accept-Encoding` header
+/// likes best, or `identity` for sending the body as it is.
+///
+/// A coding gets the quality of its own entry, compared case-insensitively,
+/// or of `*` if it has none, and a coding the header doesn't mention is
+/// refused. `identity` is acceptable unless the header gives it, or `*`
+/// without an entry for it, a quality of 0. A coding is only chosen over
+/// `identity` if the header likes it at least as much, and ties between
+/// codings go to whichever comes first in `supported`. A missing header
+/// gets `identity`.
+///
+/// Returns `None` if the header accepts neither a supported coding nor
+/// `identity`, for which the server should answer 406.
+///
+/// ```
+/// use web_server::negotiate::preferred_encoding;
+///
+/// let header = Some("gzip;q=0.5, br;q=1.0");
+/// assert_eq!(preferred_encoding(header, &["gzip", "br"]), Some("br"));
+/// assert_eq!(preferred_encoding(header, &["gzip"]), Some("gzip"));
+/// assert_eq!(preferred_encoding(Some("br"), &["gzip"]), Some("identity"));
+/// assert_eq!(preferred_encoding(Some("identity;q=0, gzip;q=0"), &["gzip"]), None);
+/// assert_eq!(preferred_encoding(None, &["gzip"]), Some("identity"));
+/// ```
+pub fn preferred_encoding<'a>(
+    accept_encoding: Option<&str>,
+    supported: &[&'a str],
+) -> Option<&'a str> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Some("identity");
+    };
+    let codings = parse_quality_list(accept_encoding);
+    let quality = |coding: &str| {
+        let entry = |name: &str| {
+            codings
+                .iter()
+                .find(|(listed, _)| listed.eq_ignore_ascii_case(name))
+                .map(|&(_, quality)| quality)
+        };
+        entry(coding).or_else(|| entry("*"))
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for &coding in supported {
+        let quality = quality(coding).unwrap_or(0.0);
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((coding, quality));
+        }
+    }
+
+    let identity = quality("identity");
+    match best {
+        Some((coding, quality)) if identity.is_none_or(|identity| quality >= identity) => {
+            Some(coding)
+        }
+        _ if identity.is_none_or(|identity| identity > 0.0) => Some("identity"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quality_values_are_parsed_with_a_default_of_one() {
+        assert_eq!(
+            parse_quality_list("text/html;q=0.9, */*;q=0.1, application/json"),
+            [("text/html", 0.9), ("*/*", 0.1), ("application/json", 1.0)]
+        );
+        assert_eq!(
+            parse_quality_list("a;q=oops, ,b;q=7"),
+            [("a", 0.0), ("b", 1.0)]
+        );
+    }
+
+    #[test]
+    fn the_most_specific_media_range_wins() {
+        let accept = "*/*;q=0.1, application/*;q=0.5, application/json;q=0.8";
+        assert_eq!(media_quality(accept, "application/json"), 0.8);
+        assert_eq!(media_quality(accept, "application/xml"), 0.5);
+        assert_eq!(media_quality(accept, "text/html"), 0.1);
+        assert_eq!(media_quality("text/html", "image/png"), 0.0);
+    }
+
+    #[test]
+    fn json_wins_only_with_a_higher_quality() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(!prefers_json(Some("application/json, text/html")));
+        assert!(!prefers_json(Some("text/html, application/json;q=0.9")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        assert!(!prefers_json(None));
+    }
+
+    #[test]
+    fn the_best_supported_language_is_picked_by_quality() {
+        let header = "en-US,en;q=0.8,fr;q=0.5";
+        assert_eq!(preferred_language(header, &["fr", "en"]), Some("en"));
+        assert_eq!(preferred_language(header, &["fr", "en-us"]), Some("en-us"));
+        assert_eq!(preferred_language(header, &["fr", "de"]), Some("fr"));
+        assert_eq!(preferred_language(header, &["de"]), None);
+    }
+
+    #[test]
+    fn language_ranges_match_by_prefix_and_wildcard() {
+        assert_eq!(language_quality("EN", "en-GB"), 1.0);
+        assert_eq!(language_quality("en-GB", "en"), 0.0);
+        assert_eq!(language_quality("eng", "en"), 0.0);
+        assert_eq!(language_quality("fr, *;q=0.1", "de"), 0.1);
+        assert_eq!(language_quality("*;q=0.1, de;q=0.7", "de-AT"), 0.7);
+        assert_eq!(preferred_language("*;q=0.5", &["fr", "en"]), Some("fr"));
+    }
+
+    #[test]
+    fn the_highest_quality_supported_encoding_wins() {
+        let header = Some("gzip;q=0.5, br;q=1.0");
+        assert_eq!(preferred_encoding(header, &["gzip", "br"]), Some("br"));
+        assert_eq!(preferred_encoding(header, &["gzip"]), Some("gzip"));
+        assert_eq!(preferred_encoding(Some("*;q=0.3"), &["gzip"]), Some("gzip"));
+        assert_eq!(preferred_encoding(Some("GZIP"), &["gzip"]), Some("gzip"));
+    }
+
+    #[test]
+    fn identity_is_the_fallback_unless_it_is_refused() {
+        assert_eq!(preferred_encoding(None, &["gzip"]), Some("identity"));
+        assert_eq!(
+            preferred_encoding(Some("gzip;q=0"), &["gzip"]),
+            Some("identity")
+        );
+        assert_eq!(
+            preferred_encoding(Some("identity;q=0, gzip;q=0"), &["gzip"]),
+            None
+        );
+        assert_eq!(preferred_encoding(Some("*;q=0"), &["gzip"]), None);
+        assert_eq!(
+            preferred_encoding(Some("identity;q=0, *"), &["gzip"]),
+            Some("gzip")
+        );
+        // An identity the client prefers to gzip wins.
+        assert_eq!(
+            preferred_encoding(Some("gzip;q=0.2, identity;q=0.8"), &["gzip"]),
+            Some("identity")
+        );
+    }
+}
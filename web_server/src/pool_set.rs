@@ -0,0 +1,171 @@
+//! A pair of pools, one for jobs that block and one for jobs that compute,
+//! so neither kind holds up the other.
+
+use std::thread;
+
+use crate::{available_parallelism, ShutdownReport, ThreadPool};
+
+/// Two pools sized independently: `io` for jobs that spend their time
+/// waiting, such as reading files or calling other services, and `cpu` for
+/// jobs that keep a core busy.
+///
+/// In a single pool, a burst of jobs stuck waiting on slow I/O takes every
+/// worker, and short computations queue up behind them. With a pool for
+/// each, the I/O pool can have many more threads than there are cores, as
+/// most of them are asleep at any moment, while the CPU pool keeps to about
+/// one per core, so computations don't fight over them. Each job is
+/// submitted to the pool matching what it spends its time on:
+///
+/// ```no_run
+/// use std::fs;
+/// use web_server::PoolSet;
+///
+/// let pools = PoolSet::new(32, 4);
+///
+/// pools.io().execute(|| {
+///     let log = fs::read_to_string("access.log").unwrap();
+///     println!("{} lines", log.lines().count());
+/// });
+/// pools.cpu().execute(|| {
+///     let sum: u64 = (0..10_000_000).sum();
+///     println!("{sum}");
+/// });
+///
+/// assert!(pools.shutdown().is_clean());
+/// ```
+pub struct PoolSet {
+    io: ThreadPool,  // Runs jobs that block
+    cpu: ThreadPool, // Runs jobs that compute
+}
+
+/// The outcome of `PoolSet::shutdown`, with a report for each pool.
+#[derive(Debug)]
+pub struct PoolSetReport {
+    pub io: ShutdownReport,  // How the I/O pool's workers exited
+    pub cpu: ShutdownReport, // How the CPU pool's workers exited
+}
+
+impl PoolSetReport {
+    /// Whether every worker in both pools exited cleanly.
+    pub fn is_clean(&self) -> bool {
+        self.io.is_clean() && self.cpu.is_clean()
+    }
+}
+
+impl PoolSet {
+    /// Create an I/O pool of `io_size` threads and a CPU pool of
+    /// `cpu_size` threads.
+    ///
+    /// # Panics
+    ///
+    /// The `new` function will panic if either size is zero.
+    pub fn new(io_size: usize, cpu_size: usize) -> PoolSet {
+        PoolSet::from_pools(ThreadPool::new(io_size), ThreadPool::new(cpu_size))
+    }
+
+    /// Create an I/O pool of `io_size` threads and a CPU pool with one
+    /// thread per CPU.
+    ///
+    /// # Panics
+    ///
+    /// The `with_available_parallelism` function will panic if `io_size`
+    /// is zero.
+    pub fn with_available_parallelism(io_size: usize) -> PoolSet {
+        PoolSet::new(io_size, available_parallelism())
+    }
+
+    /// Pair two pools already built, e.g. with `ThreadPoolBuilder` to give
+    /// the CPU pool a bounded queue.
+    pub fn from_pools(io: ThreadPool, cpu: ThreadPool) -> PoolSet {
+        PoolSet { io, cpu }
+    }
+
+    /// The pool for jobs that block.
+    pub fn io(&self) -> &ThreadPool {
+        &self.io
+    }
+
+    /// The pool for jobs that compute.
+    pub fn cpu(&self) -> &ThreadPool {
+        &self.cpu
+    }
+
+    /// Shut both pools down, running the jobs still queued in each, and
+    /// wait for every worker to finish.
+    ///
+    /// The pools are shut down at the same time, so this takes as long as
+    /// the slower of them rather than both in turn.
+    pub fn shutdown(self) -> PoolSetReport {
+        let PoolSet { io, cpu } = self;
+
+        thread::scope(|scope| {
+            let io = scope.spawn(move || io.shutdown());
+            let cpu = cpu.shutdown();
+            PoolSetReport {
+                // `shutdown` catches the workers' panics, so it can't panic
+                // itself.
+                io: io.join().unwrap(),
+                cpu,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn a_blocked_io_job_doesnt_hold_up_a_cpu_job() {
+        let pools = PoolSet::new(1, 1);
+        let (release, released) = mpsc::channel::<()>();
+        let (io_done, io_finished) = mpsc::channel();
+        let (sum, summed) = mpsc::channel();
+
+        pools.io().execute(move || {
+            released.recv().unwrap();
+            io_done.send(()).unwrap();
+        });
+        pools.cpu().execute(move || {
+            sum.send((1..=1000u64).sum::<u64>()).unwrap();
+        });
+
+        // The computation finishes while the only I/O worker is still stuck.
+        let timeout = Duration::from_secs(5);
+        assert_eq!(summed.recv_timeout(timeout), Ok(500_500));
+        assert!(io_finished.try_recv().is_err());
+
+        release.send(()).unwrap();
+        assert_eq!(io_finished.recv_timeout(timeout), Ok(()));
+        assert!(pools.shutdown().is_clean());
+    }
+
+    #[test]
+    fn shutdown_runs_what_is_queued_in_both_pools_at_once() {
+        let pools = PoolSet::new(1, 1);
+        let (done, finished) = mpsc::channel();
+        for pool in [pools.io(), pools.cpu()] {
+            let done = done.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(200));
+                done.send(()).unwrap();
+            });
+        }
+        drop(done);
+
+        let started = Instant::now();
+        let report = pools.shutdown();
+        let took = started.elapsed();
+        assert!(report.is_clean(), "{report:?}");
+        assert_eq!(finished.iter().count(), 2);
+        assert!(
+            took < Duration::from_millis(380),
+            "shut down in turn: {took:?}"
+        );
+    }
+}
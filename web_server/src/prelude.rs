@@ -0,0 +1,71 @@
+//! The types and traits most programs need, for a single glob import.
+//!
+//! ```no_run
+//! use std::{net::TcpListener, sync::Arc};
+//! use web_server::prelude::*;
+//!
+//! let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+//! let pool = ThreadPool::new(4);
+//! let config = Arc::new(SharedConfig::new(Config::default()));
+//!
+//! serve(listener, &pool, config, |_request: &Request| {
+//!     Response::new(200).with_body("Hello!")
+//! });
+//! ```
+
+pub use crate::{
+    app::ServerBuilder,
+    config::{Config, SharedConfig},
+    error::HttpError,
+    handler::Handler,
+    method::Method,
+    request::{BodyReader, Request},
+    response::Response,
+    router::Router,
+    server::{serve, Server},
+    static_files::StaticFiles,
+    ThreadPool, ThreadPoolBuilder,
+};
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::Arc,
+        thread,
+        time::Duration,
+    };
+
+    // Nothing but the prelude, to prove it's enough for a whole server.
+    use super::*;
+
+    #[test]
+    fn the_prelude_is_enough_to_run_a_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = ThreadPool::new(2);
+        let config = Arc::new(SharedConfig::new(Config::default()));
+        let router = Router::new().route(Method::Get, "/", |_: &Request| {
+            Response::new(200).with_body("Hello!")
+        });
+        let server = Server::new().listener(listener);
+
+        let response = thread::scope(|scope| {
+            scope.spawn(|| server.run(&pool, Arc::clone(&config), router));
+
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            config.load().shutdown.trigger();
+            response
+        });
+        assert!(response.ends_with("\r\n\r\nHello!"), "{response}");
+
+        let report = server.shutdown_graceful(pool, &config, Duration::from_secs(5));
+        assert_eq!(report.forced_connections, 0);
+    }
+}
@@ -0,0 +1,1490 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read},
+    panic,
+    sync::OnceLock,
+};
+
+use crossbeam_channel::Receiver;
+
+use crate::{cookie, error::HttpError, method::Method, negotiate, uri, ThreadPool};
+
+// Header fields that apply to a single connection and are never forwarded.
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+// The most body bytes `BodyReader::pipe` puts in one chunk.
+const PIPE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A parsed HTTP request.
+#[derive(Clone)]
+pub struct Request {
+    method: Method,                          // Request method, e.g. `Method::Get`
+    target: String,                          // Raw request target, e.g. "/index.html?lang=en"
+    version: String,                         // Protocol version, e.g. "HTTP/1.1"
+    headers: Vec<(String, String)>,          // Header fields in the order they were received
+    body: Vec<u8>,                           // Message body, already de-chunked
+    params: OnceLock<Vec<(String, String)>>, // Path parameters captured by the router
+}
+
+/// Limits applied while reading a request.
+#[derive(Clone)]
+pub struct Limits {
+    pub max_body_size: usize,   // Largest body accepted, in bytes
+    pub max_headers: usize,     // Most header fields accepted in one request
+    pub max_line_length: usize, // Longest request line or header line, in bytes
+    pub max_uri_length: usize,  // Longest request target, in bytes
+
+    // Most unread body bytes skipped to keep a connection open; a bigger
+    // remainder closes the connection instead.
+    pub max_drain_size: usize,
+
+    // Whether lines must end in CRLF. Otherwise a bare LF, which some
+    // clients send, ends a line too.
+    pub strict_line_endings: bool,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_body_size: 1024 * 1024,
+            max_headers: 100,
+            max_line_length: 8 * 1024,
+            max_uri_length: 4 * 1024,
+            max_drain_size: 64 * 1024,
+            strict_line_endings: false,
+        }
+    }
+}
+
+/// A request that doesn't follow the HTTP message syntax.
+#[derive(Debug)]
+pub struct ParseError {
+    reason: &'static str, // What was wrong with the request
+}
+
+impl ParseError {
+    pub(crate) fn new(reason: &'static str) -> ParseError {
+        ParseError { reason }
+    }
+
+    /// A short description of what was wrong with the request.
+    pub fn reason(&self) -> &'static str {
+        self.reason
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad request: {}", self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+impl Request {
+    /// Read a request line, its headers and its body from `reader`.
+    ///
+    /// The body is framed by `Content-Length` or by `Transfer-Encoding:
+    /// chunked`. Reading stops right after the body, so a pipelined request
+    /// that follows is left in the reader.
+    ///
+    /// If the stream ends before the first byte, this returns an
+    /// `HttpError::Io` of kind `UnexpectedEof`: the client closed the
+    /// connection without sending anything. A request cut off part way
+    /// through is an `HttpError::Parse` instead.
+    ///
+    /// An `Expect` header other than `100-continue` fails with
+    /// `HttpError::ExpectationFailed` before the body is read. This can't
+    /// send `100 Continue` itself; use `read_head` and `read_body` to answer
+    /// between the two.
+    pub fn read_from<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, HttpError> {
+        let mut request = Request::read_head(reader, limits)?;
+        request.expects_continue()?;
+        request.read_body(reader, limits)?;
+
+        Ok(request)
+    }
+
+    /// Read only the request line and headers from `reader`.
+    ///
+    /// The body, if any, is left in the reader for `read_body`.
+    pub fn read_head<R: BufRead>(reader: &mut R, limits: &Limits) -> Result<Request, HttpError> {
+        let request_line = read_line(reader, limits)?.ok_or_else(|| {
+            HttpError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before the request line",
+            ))
+        })?;
+
+        // The request line is "METHOD SP TARGET SP VERSION".
+        let mut parts = request_line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::new("malformed request line").into()),
+        };
+        if method.is_empty() || target.is_empty() || !version.starts_with("HTTP/") {
+            return Err(ParseError::new("malformed request line").into());
+        }
+        // A long target gets its own status, unlike the rest of the line.
+        if target.len() > limits.max_uri_length {
+            return Err(HttpError::UriTooLong);
+        }
+        let method = Method::from(method);
+        // The asterisk form of the target is only meaningful for OPTIONS.
+        if target == "*" && method != Method::Options {
+            return Err(ParseError::new("asterisk target outside OPTIONS").into());
+        }
+        // CONNECT names the host and port to tunnel to, and nothing else.
+        if method == Method::Connect && !is_authority_form(target) {
+            return Err(ParseError::new("malformed CONNECT target").into());
+        }
+
+        // Header fields follow, one per line, until an empty line.
+        let mut headers = Vec::new();
+        loop {
+            let line = read_line(reader, limits)?
+                .ok_or(ParseError::new("connection closed inside the headers"))?;
+            if line.is_empty() {
+                break;
+            }
+
+            // Count the fields as they arrive, so a flood of tiny headers is
+            // cut off before it takes up much memory.
+            if headers.len() >= limits.max_headers {
+                return Err(HttpError::TooManyHeaders);
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or(ParseError::new("malformed header field"))?;
+            if name.is_empty() || name.contains(|c: char| c.is_ascii_whitespace()) {
+                return Err(ParseError::new("malformed header field").into());
+            }
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+        check_framing(&headers)?;
+
+        let request = Request {
+            method,
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            params: OnceLock::new(),
+        };
+
+        // HTTP/1.1 requires the host to be named, in the `Host` header or
+        // an absolute-form target.
+        if request.version == "HTTP/1.1" && request.host().is_none() {
+            return Err(ParseError::new("missing host").into());
+        }
+
+        Ok(request)
+    }
+
+    /// Read the body that follows the headers read by `read_head`.
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<(), HttpError> {
+        self.body = read_body(reader, self, limits)?;
+        Ok(())
+    }
+
+    /// Whether the client is waiting for `100 Continue` before it sends the
+    /// body.
+    ///
+    /// `100-continue` is the only expectation defined by HTTP, so any other
+    /// `Expect` value fails with `HttpError::ExpectationFailed`. HTTP/1.0
+    /// clients don't understand interim responses, so they are never told
+    /// to continue.
+    pub fn expects_continue(&self) -> Result<bool, HttpError> {
+        match self.header("Expect") {
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => {
+                Ok(self.version == "HTTP/1.1")
+            }
+            Some(_) => Err(HttpError::ExpectationFailed),
+            None => Ok(false),
+        }
+    }
+
+    /// Check that the body announced by the headers can be read within
+    /// `limits`, without reading any of it.
+    ///
+    /// Fails with `HttpError::Parse` for a `Transfer-Encoding` other than
+    /// chunked or a malformed `Content-Length`, and with
+    /// `HttpError::TooLarge` for a `Content-Length` over the limit.
+    pub fn check_body(&self, limits: &Limits) -> Result<(), HttpError> {
+        BodyState::for_request(self, limits).map(|_| ())
+    }
+
+    /// Whether the headers announce a body, via a non-zero `Content-Length`
+    /// or a `Transfer-Encoding`.
+    pub fn has_body(&self) -> bool {
+        self.header("Transfer-Encoding").is_some()
+            || self
+                .header("Content-Length")
+                .is_some_and(|length| first_item(length) != "0")
+    }
+
+    /// The request method, e.g. `Method::Get`.
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    /// The request target exactly as it appeared in the request line.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The path component of the request target, without the query string.
+    pub fn path(&self) -> &str {
+        match self.target.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.target,
+        }
+    }
+
+    /// A parameter captured from the path by the route that matched it,
+    /// such as `id` for a request for `/users/42` routed by `/users/:id`.
+    ///
+    /// The value is percent-decoded. Returns `None` if the route has no
+    /// such parameter or the request wasn't routed by a `Router`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .get()?
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Record the parameters of the route that matched. Only the first
+    // router to route a request sets them.
+    pub(crate) fn set_params(&self, params: Vec<(String, String)>) {
+        let _ = self.params.set(params);
+    }
+
+    // A copy of the request made with `method`, for a handler to hand the
+    // one it wraps in place of the original. The copy has no path
+    // parameters yet, for the router to capture them again.
+    pub(crate) fn with_method(&self, method: Method) -> Request {
+        Request {
+            method,
+            target: self.target.clone(),
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            params: OnceLock::new(),
+        }
+    }
+
+    // Replace the value of every `name` header field with `value` of it.
+    pub(crate) fn map_header(&mut self, name: &str, value: impl Fn(&str) -> String) {
+        for (key, existing) in &mut self.headers {
+            if key.eq_ignore_ascii_case(name) {
+                *existing = value(existing);
+            }
+        }
+    }
+
+    /// The query string of the request target, if there is one.
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Let a POST request stand in for another method, for clients such as
+    /// HTML forms that can't send it themselves.
+    ///
+    /// The method to use comes from the `X-HTTP-Method-Override` header or,
+    /// failing that, a `_method` field in a form-encoded body. Only `PUT`,
+    /// `PATCH` and `DELETE` can be requested this way; anything else, and
+    /// any request that isn't a POST, is left as it is.
+    pub fn apply_method_override(&mut self) {
+        if self.method != Method::Post {
+            return;
+        }
+
+        let requested = match self.header("X-HTTP-Method-Override") {
+            Some(method) => Some(method.to_string()),
+            None => self.form_method_field(),
+        };
+
+        if let Some(method) = requested {
+            let method = Method::from(method.trim().to_ascii_uppercase().as_str());
+            if matches!(method, Method::Put | Method::Patch | Method::Delete) {
+                self.method = method;
+            }
+        }
+    }
+
+    // The `_method` field of a form-encoded body, if there is one.
+    fn form_method_field(&self) -> Option<String> {
+        let content_type = self.header("Content-Type")?;
+        let media_type = content_type.split(';').next().unwrap_or("").trim();
+        if !media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+            return None;
+        }
+
+        std::str::from_utf8(&self.body)
+            .ok()?
+            .split('&')
+            .find_map(|field| field.strip_prefix("_method="))
+            .map(str::to_string)
+    }
+
+    /// Whether this is `OPTIONS *`, a request about the server as a whole
+    /// rather than any one resource.
+    pub fn is_server_wide(&self) -> bool {
+        self.method == Method::Options && self.target == "*"
+    }
+
+    /// The host the request is addressed to, possibly with a port, e.g.
+    /// `example.com:7878`.
+    ///
+    /// This is the authority of an absolute-form target such as
+    /// `http://example.com/index.html` if there is one, since that takes
+    /// precedence over the `Host` header, and the `Host` header otherwise.
+    /// A `CONNECT` request's target is nothing but the authority.
+    pub fn host(&self) -> Option<&str> {
+        if self.method == Method::Connect {
+            return Some(&self.target);
+        }
+        if let Some((_, rest)) = self.target.split_once("://") {
+            if !self.target.starts_with('/') {
+                let authority = rest.split(['/', '?']).next().unwrap_or("");
+                return Some(authority).filter(|authority| !authority.is_empty());
+            }
+        }
+
+        self.header("Host").filter(|host| !host.is_empty())
+    }
+
+    /// The protocol version, e.g. `HTTP/1.1`.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Look up a header value by name. Header names are case-insensitive.
+    ///
+    /// If the header was sent more than once, the first value is returned.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All header fields in the order they were received.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The options listed in the `Connection` header, such as `keep-alive`
+    /// and `Upgrade` for `Connection: keep-alive, Upgrade`.
+    ///
+    /// The comma-separated items are trimmed, empty ones are skipped, and
+    /// the lists of several `Connection` headers are combined in the order
+    /// they were received. Tokens are case-insensitive, so compare them
+    /// with `eq_ignore_ascii_case` or use `has_connection_token`.
+    pub fn connection_tokens(&self) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(','))
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .collect()
+    }
+
+    /// Whether the `Connection` header lists `token`, compared
+    /// case-insensitively.
+    pub fn has_connection_token(&self, token: &str) -> bool {
+        self.connection_tokens()
+            .iter()
+            .any(|listed| listed.eq_ignore_ascii_case(token))
+    }
+
+    /// Whether the client asked to switch the connection to `protocol`,
+    /// e.g. `websocket`, with `Connection: upgrade` and an `Upgrade` header
+    /// listing it. Protocol names compare case-insensitively, and a
+    /// version such as `HTTP/2.0` must match exactly.
+    ///
+    /// Only HTTP/1.1 connections can be upgraded, so this is always false
+    /// for HTTP/1.0.
+    pub fn wants_upgrade(&self, protocol: &str) -> bool {
+        self.version == "HTTP/1.1"
+            && self.has_connection_token("upgrade")
+            && self.header("Upgrade").is_some_and(|offered| {
+                offered
+                    .split(',')
+                    .any(|offered| offered.trim().eq_ignore_ascii_case(protocol))
+            })
+    }
+
+    /// The header fields to pass on when forwarding the request, as a
+    /// proxy does, in the order they were received.
+    ///
+    /// Hop-by-hop fields only concern the connection the request came in
+    /// on, so they are left out: `Connection` and `Keep-Alive`,
+    /// `Proxy-Connection`, `TE`, `Trailer`, `Transfer-Encoding` and
+    /// `Upgrade`, as well as any field the `Connection` header names, such
+    /// as `X-Trace` for `Connection: X-Trace`.
+    pub fn end_to_end_headers(&self) -> Vec<(&str, &str)> {
+        let tokens = self.connection_tokens();
+        self.headers
+            .iter()
+            .filter(|(name, _)| {
+                !HOP_BY_HOP_HEADERS
+                    .iter()
+                    .chain(&tokens)
+                    .any(|hop| hop.eq_ignore_ascii_case(name))
+            })
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    /// Look up a cookie sent in the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().remove(name)
+    }
+
+    /// All cookies sent in the `Cookie` header, by name.
+    ///
+    /// Browsers normally send a single `Cookie` header, but if there are
+    /// several, their cookies are combined.
+    pub fn cookies(&self) -> HashMap<String, String> {
+        let mut cookies = HashMap::new();
+        for (name, value) in &self.headers {
+            if name.eq_ignore_ascii_case("Cookie") {
+                for (name, value) in cookie::parse(value) {
+                    cookies.entry(name).or_insert(value);
+                }
+            }
+        }
+        cookies
+    }
+
+    /// The language from `supported` that the client's `Accept-Language`
+    /// header ranks highest.
+    ///
+    /// Returns `None` if there is no such header or it accepts none of the
+    /// supported languages. See `negotiate::language_quality` for how the
+    /// header's ranges are matched.
+    pub fn preferred_language(&self, supported: &[&str]) -> Option<String> {
+        let accept_language = self.header("Accept-Language")?;
+        negotiate::preferred_language(accept_language, supported).map(str::to_string)
+    }
+
+    /// The message body. Empty if the request had none.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Look up a field of a submitted HTML form.
+    ///
+    /// The body is read as `key=value&key2=value2` with `%XX` escapes and
+    /// `+` for spaces. Returns `None` unless the request's `Content-Type`
+    /// is `application/x-www-form-urlencoded` and the body isn't empty. If
+    /// the field was sent more than once, the first value is returned, and
+    /// a field whose escapes are malformed is skipped.
+    pub fn form(&self, key: &str) -> Option<String> {
+        let content_type = self.header("Content-Type")?;
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        if !essence.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+            || self.body.is_empty()
+        {
+            return None;
+        }
+
+        let body = std::str::from_utf8(&self.body).ok()?;
+        body.split('&').find_map(|field| {
+            let (name, value) = field.split_once('=').unwrap_or((field, ""));
+            if uri::decode_form_component(name)? == key {
+                uri::decode_form_component(value)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// Refuse headers that frame the body in more than one way. A proxy in front
+// of us might pick a different framing than we would, and see the rest of
+// the body as a request of its own, or the other way around: that is how
+// requests get smuggled past it. RFC 9112 lets `Transfer-Encoding` win, but
+// a client has no business sending both, so it is safer to refuse.
+fn check_framing(headers: &[(String, String)]) -> Result<(), HttpError> {
+    let has = |field: &str| {
+        headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case(field))
+    };
+    if has("Transfer-Encoding") && has("Content-Length") {
+        return Err(ParseError::new("both content length and transfer encoding").into());
+    }
+
+    // Repeating the same length, in one field or several, is harmless.
+    let mut lengths = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim);
+    if let Some(first) = lengths.next() {
+        if lengths.any(|length| length != first) {
+            return Err(ParseError::new("conflicting content lengths").into());
+        }
+    }
+
+    Ok(())
+}
+
+// Whether a request target is in authority form, `host:port`, as CONNECT
+// targets are.
+fn is_authority_form(target: &str) -> bool {
+    match target.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && !host.contains(['/', '?', '#', '@'])
+                && !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// The first item of a comma-separated header value, trimmed.
+fn first_item(value: &str) -> &str {
+    value.split(',').next().unwrap_or("").trim()
+}
+
+/// A request body read as it arrives, instead of all at once.
+///
+/// Handlers that opt in with `Handler::streams_body` get one of these in
+/// place of a buffered body, so a large upload can be copied straight to a
+/// file with `io::copy`. The `Content-Length` or chunked framing is decoded
+/// on the way, and reads end at the end of the body. A body larger than
+/// `Limits::max_body_size`, or one that isn't framed properly, fails with
+/// `ErrorKind::InvalidData`.
+pub struct BodyReader<'a> {
+    reader: &'a mut dyn BufRead, // The connection, positioned inside the body
+    state: BodyState,            // Where in the body's framing we are
+    read: usize,                 // Body bytes handed out so far
+    limits: Limits,              // Limits applied while reading
+    failure: Option<HttpError>,  // Why reading failed, if it did
+}
+
+// Where a `BodyReader` is in the body's framing.
+enum BodyState {
+    Length(usize),    // Inside a `Content-Length` body, with this much left
+    ChunkSize,        // Expecting a chunk size line
+    ChunkData(usize), // Inside a chunk, with this much left
+    ChunkEnd,         // Expecting the line break after a chunk
+    Done,             // The body has been read in full
+}
+
+impl BodyState {
+    // The state to start reading `request`'s body in, as framed by its
+    // headers.
+    fn for_request(request: &Request, limits: &Limits) -> Result<BodyState, HttpError> {
+        if let Some(encoding) = request.header("Transfer-Encoding") {
+            // Chunked must be the final coding, or the body's end can't be found.
+            let last = encoding.rsplit(',').next().unwrap_or("").trim();
+            if !last.eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::new("unsupported transfer coding").into());
+            }
+            return Ok(BodyState::ChunkSize);
+        }
+
+        // `read_head` made sure a repeated length is the same every time.
+        let length = match request.header("Content-Length") {
+            Some(length) => first_item(length)
+                .parse::<usize>()
+                .map_err(|_| ParseError::new("malformed content length"))?,
+            None => 0,
+        };
+        if length > limits.max_body_size {
+            return Err(HttpError::TooLarge);
+        }
+
+        Ok(BodyState::Length(length))
+    }
+}
+
+impl<'a> BodyReader<'a> {
+    /// Prepare to read the body of `request` from `reader`, which must be
+    /// positioned right after its headers.
+    ///
+    /// This fails the same way as `Request::check_body`, before any of the
+    /// body is read.
+    pub fn new(
+        reader: &'a mut dyn BufRead,
+        request: &Request,
+        limits: &Limits,
+    ) -> Result<BodyReader<'a>, HttpError> {
+        Ok(BodyReader {
+            reader,
+            state: BodyState::for_request(request, limits)?,
+            read: 0,
+            limits: limits.clone(),
+            failure: None,
+        })
+    }
+
+    /// Whether the whole body has been read.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, BodyState::Done)
+    }
+
+    /// The number of body bytes read so far.
+    pub fn bytes_read(&self) -> usize {
+        self.read
+    }
+
+    /// Read and discard the rest of the body, as long as at most `max`
+    /// bytes of it are left.
+    ///
+    /// Returns whether the end of the body was reached. If more than `max`
+    /// bytes are left, it returns `false` with the body partly read, or not
+    /// read at all when its length is known up front.
+    pub fn skip_rest(&mut self, max: usize) -> io::Result<bool> {
+        if let BodyState::Length(remaining) = self.state {
+            if remaining > max {
+                return Ok(false);
+            }
+        }
+
+        // One byte past `max` tells a body that ends right at the limit
+        // from one that goes on.
+        let limit = (max as u64).saturating_add(1);
+        io::copy(&mut self.by_ref().take(limit), &mut io::sink())?;
+        Ok(self.is_finished())
+    }
+
+    /// Hand the rest of the body to `consume`, run as a job on `pool`, in
+    /// chunks sent through a channel holding at most `capacity` of them,
+    /// and return what it returns.
+    ///
+    /// The body is read on the calling thread as it arrives, in chunks of
+    /// up to 16 KiB, while `consume` works through them at its own pace on
+    /// one of the pool's workers. When the channel is full reading waits for
+    /// it, and so, in time, does the client, so a slow consumer never has
+    /// more than about `capacity` chunks held in memory for it. A body that
+    /// turns out too large or malformed ends the chunks with the error,
+    /// which this returns too once `consume` is done. If `consume` returns
+    /// before taking every chunk, reading stops there, and the rest of the
+    /// body is left unread.
+    ///
+    /// The calling thread waits for the job, so `pool` shouldn't be the
+    /// pool running the connections: with all of its workers reading
+    /// bodies, none would be left to consume them. A job the pool drops
+    /// without running, when it is shut down with `ShutdownMode::Abort`,
+    /// makes this fail with `ErrorKind::Other`. `handler::PipedBody` does
+    /// all of this for a handler.
+    ///
+    /// ```no_run
+    /// use std::{fs::File, io};
+    /// use web_server::{request::BodyReader, response::Response, ThreadPool};
+    ///
+    /// fn save_upload(body: &mut BodyReader<'_>, uploads: &ThreadPool) -> Response {
+    ///     let saved = body.pipe(uploads, 8, |mut chunks| {
+    ///         let mut file = File::create("upload.bin")?;
+    ///         io::copy(&mut chunks, &mut file)
+    ///     });
+    ///     match saved {
+    ///         Ok(Ok(bytes)) => Response::new(201).with_body(format!("{bytes} bytes\n")),
+    ///         _ => Response::new(500),
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// The `pipe` function will panic if `capacity` is zero or `pool` is
+    /// single-threaded, which would run `consume` before there is anything
+    /// to consume, and with the same payload as `consume` if that panics.
+    pub fn pipe<F, T>(&mut self, pool: &ThreadPool, capacity: usize, consume: F) -> io::Result<T>
+    where
+        F: FnOnce(BodyChunks) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        assert!(capacity > 0, "the channel must hold at least one chunk");
+        assert!(!pool.inline, "a single-threaded pool can't consume a pipe");
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let (done, value) = crossbeam_channel::bounded(1);
+
+        let consumer = pool.spawn(move || {
+            let _ = done.send(consume(BodyChunks::new(receiver)));
+        });
+
+        let mut outcome = Ok(());
+        loop {
+            let mut chunk = vec![0; PIPE_CHUNK_SIZE];
+            match self.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(read) => {
+                    chunk.truncate(read);
+                    // A consumer that has gone, or was dropped unrun, wants
+                    // no more of it.
+                    if sender.send(Ok(chunk)).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    let _ = sender.send(Err(io::Error::new(err.kind(), err.to_string())));
+                    outcome = Err(err);
+                    break;
+                }
+            }
+        }
+        drop(sender);
+
+        let started = consumer.has_started();
+        if let Err(payload) = consumer.join() {
+            if started {
+                panic::resume_unwind(payload);
+            }
+        }
+        let value = value
+            .try_recv()
+            .map_err(|_| io::Error::other("the pool dropped the consumer unrun"))?;
+        outcome.map(|()| value)
+    }
+
+    // Why the last read failed, if the body itself was at fault rather than
+    // the connection.
+    pub(crate) fn take_failure(&mut self) -> Option<HttpError> {
+        self.failure.take()
+    }
+
+    // Read the next piece of the body into `buf`, returning 0 at its end.
+    fn read_next(&mut self, buf: &mut [u8]) -> Result<usize, HttpError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            match self.state {
+                BodyState::Done => return Ok(0),
+                BodyState::Length(0) => self.state = BodyState::Done,
+                BodyState::Length(remaining) => {
+                    let read = self.read_data(buf, remaining)?;
+                    self.state = BodyState::Length(remaining - read);
+                    return Ok(read);
+                }
+                BodyState::ChunkData(0) => self.state = BodyState::ChunkEnd,
+                BodyState::ChunkData(remaining) => {
+                    let read = self.read_data(buf, remaining)?;
+                    self.state = BodyState::ChunkData(remaining - read);
+                    return Ok(read);
+                }
+                BodyState::ChunkSize => {
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        self.skip_trailers()?;
+                        self.state = BodyState::Done;
+                    } else {
+                        // The limit applies to the body as a whole, not to
+                        // each chunk.
+                        if size > self.limits.max_body_size - self.read {
+                            return Err(HttpError::TooLarge);
+                        }
+                        self.state = BodyState::ChunkData(size);
+                    }
+                }
+                BodyState::ChunkEnd => {
+                    // Each chunk's data is followed by its own line break.
+                    match read_line(self.reader, &self.limits)? {
+                        Some(line) if line.is_empty() => self.state = BodyState::ChunkSize,
+                        _ => return Err(ParseError::new("malformed chunk").into()),
+                    }
+                }
+            }
+        }
+    }
+
+    // Read up to `remaining` bytes of body data into `buf`.
+    fn read_data(&mut self, buf: &mut [u8], remaining: usize) -> Result<usize, HttpError> {
+        let len = buf.len().min(remaining);
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(HttpError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.read += read;
+        Ok(read)
+    }
+
+    // Read a chunk size line: a hex size, optionally followed by extensions.
+    fn read_chunk_size(&mut self) -> Result<usize, HttpError> {
+        let line = read_line(self.reader, &self.limits)?
+            .ok_or(ParseError::new("connection closed inside a chunk"))?;
+
+        // Chunk extensions after a ';' are allowed but carry nothing we use.
+        let size = line.split(';').next().unwrap_or("").trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::new("malformed chunk size").into());
+        }
+        usize::from_str_radix(size, 16).map_err(|_| ParseError::new("malformed chunk size").into())
+    }
+
+    // Skip any trailer fields up to the blank line that ends the message.
+    // They count against the same limit as header fields.
+    fn skip_trailers(&mut self) -> Result<(), HttpError> {
+        let mut trailers = 0;
+        loop {
+            match read_line(self.reader, &self.limits)? {
+                Some(line) if line.is_empty() => return Ok(()),
+                Some(_) if trailers >= self.limits.max_headers => {
+                    return Err(HttpError::TooManyHeaders)
+                }
+                Some(_) => trailers += 1,
+                None => return Err(ParseError::new("connection closed inside the trailers").into()),
+            }
+        }
+    }
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.read_next(buf) {
+            Ok(read) => Ok(read),
+            Err(HttpError::Io(err)) => Err(err),
+            Err(err) => {
+                let kind = match err {
+                    HttpError::Timeout => io::ErrorKind::TimedOut,
+                    _ => io::ErrorKind::InvalidData,
+                };
+                let io_err = io::Error::new(kind, err.to_string());
+                self.failure = Some(err);
+                Err(io_err)
+            }
+        }
+    }
+}
+
+/// The chunks of a body handed over by `BodyReader::pipe`, in order.
+///
+/// Iterating yields each chunk as it arrives, or the error that stopped
+/// the body being read, and ends with the body. The chunks can be read as
+/// one stream with `Read` instead, e.g. to `io::copy` them to a file.
+pub struct BodyChunks {
+    receiver: Receiver<io::Result<Vec<u8>>>, // Where the reader sends the chunks
+    current: Vec<u8>,                        // The chunk `Read` is partway through
+    position: usize,                         // How much of `current` has been read
+}
+
+impl BodyChunks {
+    fn new(receiver: Receiver<io::Result<Vec<u8>>>) -> BodyChunks {
+        BodyChunks {
+            receiver,
+            current: Vec::new(),
+            position: 0,
+        }
+    }
+
+    // The chunks of a body that has already been read in full.
+    pub(crate) fn buffered(body: Vec<u8>) -> BodyChunks {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        if !body.is_empty() {
+            let _ = sender.send(Ok(body));
+        }
+        BodyChunks::new(receiver)
+    }
+}
+
+impl Iterator for BodyChunks {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Read for BodyChunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.current.len() {
+            match self.next() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+            self.position = 0;
+        }
+
+        let read = buf.len().min(self.current.len() - self.position);
+        buf[..read].copy_from_slice(&self.current[self.position..][..read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+// Read the body that follows the headers, as framed by the headers.
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    request: &Request,
+    limits: &Limits,
+) -> Result<Vec<u8>, HttpError> {
+    let mut body_reader = BodyReader::new(reader, request, limits)?;
+
+    let mut body = Vec::new();
+    if let BodyState::Length(length) = body_reader.state {
+        body.reserve_exact(length);
+    }
+
+    match body_reader.read_to_end(&mut body) {
+        Ok(_) => Ok(body),
+        Err(err) => Err(body_reader.take_failure().unwrap_or_else(|| err.into())),
+    }
+}
+
+// Read one line and strip its line ending. Returns `None` at end of stream.
+//
+// A line cut off by the end of the stream is an error rather than a line,
+// since the client can't have sent a complete request. The line is read as
+// bytes, straight from the reader's buffer, so whatever follows it, such as
+// a binary body, stays there untouched, and a line that isn't UTF-8 is a
+// malformed request rather than a broken connection.
+//
+// A line may end in a bare LF unless `limits.strict_line_endings` is set,
+// and either way no CR is left in it: one anywhere but before the LF is an
+// error, as it could otherwise be taken for a line break by something
+// further along. So is a NUL, which could end the line early for it, and
+// which a response echoing the line couldn't send either.
+fn read_line<R: BufRead + ?Sized>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<Option<String>, HttpError> {
+    // Read no more than the longest line allowed and its CRLF, so an
+    // endless line can't take up unbounded memory.
+    let limit = limits.max_line_length.saturating_add(2);
+    let mut line = Vec::new();
+    if reader.take(limit as u64).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+
+    if !line.ends_with(b"\n") && line.len() == limit {
+        return Err(ParseError::new("line too long").into());
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(ParseError::new("connection closed in the middle of a line").into());
+    }
+    if line.ends_with(b"\r") {
+        line.pop();
+    } else if limits.strict_line_endings {
+        return Err(ParseError::new("line not terminated by CRLF").into());
+    }
+    if line.contains(&b'\r') {
+        return Err(ParseError::new("stray carriage return in line").into());
+    }
+    if line.contains(&0) {
+        return Err(ParseError::new("NUL in line").into());
+    }
+    if line.len() > limits.max_line_length {
+        return Err(ParseError::new("line too long").into());
+    }
+
+    let line = String::from_utf8(line).map_err(|_| ParseError::new("line is not UTF-8"))?;
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    fn parse(request: &str) -> Result<Request, HttpError> {
+        Request::read_from(&mut request.as_bytes(), &Limits::default())
+    }
+
+    fn parse_with(request: &str, limits: &Limits) -> Result<Request, HttpError> {
+        Request::read_from(&mut request.as_bytes(), limits)
+    }
+
+    // The error reading `request` fails with.
+    fn parse_err(request: &str, limits: &Limits) -> HttpError {
+        match parse_with(request, limits) {
+            Ok(_) => panic!("{request:?} was accepted"),
+            Err(err) => err,
+        }
+    }
+
+    const CHUNKED: &str =
+        "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n";
+
+    #[test]
+    fn a_chunked_body_is_assembled_from_its_chunks() {
+        let request = parse(&format!(
+            "{CHUNKED}5\r\nhello\r\n1;name=value\r\n \r\nB\r\nchunked wor\r\n2\r\nld\r\n0\r\nExpires: never\r\n\r\n"
+        ))
+        .unwrap();
+        assert_eq!(request.body(), b"hello chunked world");
+    }
+
+    #[test]
+    fn a_chunked_body_leaves_the_next_request_in_the_reader() {
+        let input =
+            format!("{CHUNKED}3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let mut reader = input.as_bytes();
+        let first = Request::read_from(&mut reader, &Limits::default()).unwrap();
+        assert_eq!(first.body(), b"abc");
+
+        let second = Request::read_from(&mut reader, &Limits::default()).unwrap();
+        assert_eq!(second.path(), "/");
+    }
+
+    #[test]
+    fn an_invalid_chunk_size_is_a_bad_request() {
+        for size in ["zz", "", "-1", "0x5", "5 5"] {
+            let request = format!("{CHUNKED}{size}\r\nhello\r\n0\r\n\r\n");
+            let err = parse_err(&request, &Limits::default());
+            assert_eq!(err.status(), Some(400), "{size:?}: {err}");
+        }
+    }
+
+    #[test]
+    fn chunk_data_must_end_with_a_line_break() {
+        let request = format!("{CHUNKED}3\r\nabcdef\r\n0\r\n\r\n");
+        let err = parse_err(&request, &Limits::default());
+        assert_eq!(err.status(), Some(400), "{err}");
+    }
+
+    #[test]
+    fn the_body_limit_covers_all_the_chunks_together() {
+        let limits = Limits {
+            max_body_size: 8,
+            ..Limits::default()
+        };
+        let request = format!("{CHUNKED}4\r\nabcd\r\n4\r\nefgh\r\n0\r\n\r\n");
+        assert_eq!(parse_with(&request, &limits).unwrap().body(), b"abcdefgh");
+
+        let request = format!("{CHUNKED}4\r\nabcd\r\n4\r\nefgh\r\n1\r\ni\r\n0\r\n\r\n");
+        let err = parse_err(&request, &limits);
+        assert!(matches!(err, HttpError::TooLarge), "{err}");
+    }
+
+    #[test]
+    fn cookies_are_looked_up_by_name() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nCookie: a=1; b=2\r\nCookie: c=3; a=4\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(request.cookie("a").as_deref(), Some("1"));
+        assert_eq!(request.cookie("c").as_deref(), Some("3"));
+        assert_eq!(request.cookie("missing"), None);
+        assert_eq!(request.cookies().len(), 3);
+    }
+
+    #[test]
+    fn more_header_fields_than_allowed_is_431() {
+        let limits = Limits {
+            max_headers: 3,
+            ..Limits::default()
+        };
+        let fields = "A: 1\r\nB: 2\r\n";
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{fields}\r\n");
+        assert_eq!(parse_with(&request, &limits).unwrap().headers().len(), 3);
+
+        let request = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{fields}C: 3\r\n\r\n");
+        let err = parse_err(&request, &limits);
+        assert!(matches!(err, HttpError::TooManyHeaders), "{err}");
+        assert_eq!(err.status(), Some(431));
+    }
+
+    #[test]
+    fn only_http_1_1_clients_are_told_to_continue() {
+        let head = |version: &str, expect: &str| {
+            let request =
+                format!("POST / {version}\r\nHost: localhost\r\nExpect: {expect}\r\n\r\n");
+            Request::read_head(&mut request.as_bytes(), &Limits::default())
+                .unwrap_or_else(|err| panic!("{err}"))
+                .expects_continue()
+        };
+
+        assert!(head("HTTP/1.1", "100-Continue").unwrap());
+        assert!(!head("HTTP/1.0", "100-continue").unwrap());
+        assert!(matches!(
+            head("HTTP/1.1", "something-else"),
+            Err(HttpError::ExpectationFailed)
+        ));
+    }
+
+    #[test]
+    fn the_preferred_language_comes_from_accept_language() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nAccept-Language: en-US,en;q=0.8,fr;q=0.5\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request.preferred_language(&["fr", "en"]).as_deref(),
+            Some("en")
+        );
+
+        let request = parse("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(request.preferred_language(&["fr", "en"]), None);
+    }
+
+    #[test]
+    fn the_asterisk_target_is_only_for_options() {
+        let request = parse("OPTIONS * HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert!(request.is_server_wide());
+        assert!(!parse("OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .is_server_wide());
+
+        let err = parse_err(
+            "GET * HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            &Limits::default(),
+        );
+        assert_eq!(err.status(), Some(400), "{err}");
+    }
+
+    #[test]
+    fn only_posts_are_overridden_and_only_to_put_patch_or_delete() {
+        let overridden = |request: &str| {
+            let mut request = parse(request).unwrap();
+            request.apply_method_override();
+            request.method().clone()
+        };
+
+        let form = "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: 19\r\n\r\nname=x&_method=put\n";
+        assert_eq!(overridden(form), Method::Put);
+        let header = "POST / HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: patch\r\n\r\n";
+        assert_eq!(overridden(header), Method::Patch);
+
+        let unsafe_method =
+            "POST / HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: CONNECT\r\n\r\n";
+        assert_eq!(overridden(unsafe_method), Method::Post);
+        let not_a_post =
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-HTTP-Method-Override: DELETE\r\n\r\n";
+        assert_eq!(overridden(not_a_post), Method::Get);
+    }
+
+    #[test]
+    fn an_over_long_target_is_414_but_an_over_long_line_is_400() {
+        let limits = Limits {
+            max_uri_length: 100,
+            ..Limits::default()
+        };
+        let get = |target: &str| format!("GET {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+
+        assert!(parse_with(&get(&format!("/{}", "a".repeat(99))), &limits).is_ok());
+        // The query counts towards the target's length.
+        let err = parse_err(&get(&format!("/?{}", "a".repeat(99))), &limits);
+        assert_eq!(err.status(), Some(414), "{err}");
+
+        // A short target on a line that is too long anyway is just malformed.
+        let limits = Limits {
+            max_line_length: 64,
+            ..limits
+        };
+        let request = format!(
+            "GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n",
+            "a".repeat(70)
+        );
+        let err = parse_err(&request, &limits);
+        assert_eq!(err.status(), Some(400), "{err}");
+    }
+
+    fn form_request(content_type: &str, body: &str) -> Request {
+        parse(&format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn form_fields_are_decoded_from_the_body() {
+        let request = form_request(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            "name=J%C3%BCrgen+M&tag=a&tag=b&empty=&flag&bad=%zz&e%3Dq=1",
+        );
+        assert_eq!(request.form("name").as_deref(), Some("Jürgen M"));
+        assert_eq!(request.form("tag").as_deref(), Some("a"));
+        assert_eq!(request.form("empty").as_deref(), Some(""));
+        assert_eq!(request.form("flag").as_deref(), Some(""));
+        assert_eq!(request.form("bad"), None);
+        assert_eq!(request.form("e=q").as_deref(), Some("1"));
+        assert_eq!(request.form("missing"), None);
+    }
+
+    #[test]
+    fn a_body_that_is_not_a_form_has_no_fields() {
+        assert_eq!(
+            form_request("application/json", "name=x").form("name"),
+            None
+        );
+        let empty = form_request("application/x-www-form-urlencoded", "");
+        assert_eq!(empty.form("name"), None);
+        let untyped =
+            parse("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 6\r\n\r\nname=x").unwrap();
+        assert_eq!(untyped.form("name"), None);
+    }
+
+    #[test]
+    fn connection_tokens_are_split_trimmed_and_combined() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, Upgrade\r\nconnection: ,X-Trace ,\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request.connection_tokens(),
+            ["keep-alive", "Upgrade", "X-Trace"]
+        );
+        assert!(request.has_connection_token("Keep-Alive"));
+        assert!(request.has_connection_token("upgrade"));
+        assert!(!request.has_connection_token("close"));
+    }
+
+    #[test]
+    fn forwarding_leaves_out_hop_by_hop_fields_and_those_connection_names() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, x-trace\r\nKeep-Alive: timeout=5\r\nX-Trace: abc\r\nTE: trailers\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            request.end_to_end_headers(),
+            [("Host", "localhost"), ("Accept", "*/*")]
+        );
+    }
+
+    #[test]
+    fn ambiguous_body_framing_is_refused() {
+        let post = |fields: &str| format!("POST / HTTP/1.1\r\nHost: localhost\r\n{fields}\r\nabc");
+        let refused = [
+            "Content-Length: 3\r\nTransfer-Encoding: chunked\r\n",
+            "transfer-encoding: chunked\r\ncontent-length: 3\r\n",
+            "Content-Length: 3\r\nContent-Length: 4\r\n",
+            "Content-Length: 3, 4\r\n",
+        ];
+        for fields in refused {
+            let err = parse_err(&post(fields), &Limits::default());
+            assert_eq!(err.status(), Some(400), "{fields:?}: {err}");
+        }
+
+        // The same length given twice frames the body just one way.
+        for fields in [
+            "Content-Length: 3\r\nContent-Length: 3\r\n",
+            "Content-Length: 3, 3\r\n",
+        ] {
+            let request = parse(&post(fields)).unwrap();
+            assert_eq!(request.body(), b"abc");
+        }
+    }
+
+    #[test]
+    fn a_connect_target_must_be_an_authority() {
+        let request = parse("CONNECT example.com:443 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(*request.method(), Method::Connect);
+        assert_eq!(request.host(), Some("example.com:443"));
+
+        for target in [
+            "/",
+            "example.com",
+            "http://example.com:443/",
+            ":443",
+            "a/b:443",
+        ] {
+            let head = format!("CONNECT {target} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+            let err = parse_err(&head, &Limits::default());
+            assert_eq!(err.status(), Some(400), "{target}: {err}");
+        }
+    }
+
+    #[test]
+    fn the_head_and_a_binary_body_come_off_one_reader() {
+        let body: &[u8] = b"\x00\xff\r\n\xc3(\n";
+        let mut input = format!(
+            "PUT /blob HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        input.extend_from_slice(body);
+        input.extend_from_slice(b"GET /next HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        // A small buffer, so the parts straddle its refills.
+        let mut reader = io::BufReader::with_capacity(8, &input[..]);
+        let limits = Limits::default();
+
+        let mut request = Request::read_head(&mut reader, &limits).unwrap();
+        assert_eq!(request.path(), "/blob");
+        assert_eq!(request.header("content-length"), Some("7"));
+        request.read_body(&mut reader, &limits).unwrap();
+        assert_eq!(request.body(), body);
+
+        let next = Request::read_from(&mut reader, &limits).unwrap();
+        assert_eq!(next.path(), "/next");
+        assert!(next.body().is_empty());
+    }
+
+    #[test]
+    fn bare_lf_line_endings_are_accepted_unless_strict() {
+        let request = "POST /a HTTP/1.1\nHost: localhost\nX-Mixed: yes\r\nContent-Length: 2\n\nhi";
+        let parsed = parse(request).unwrap();
+        assert_eq!(parsed.path(), "/a");
+        assert_eq!(parsed.header("Host"), Some("localhost"));
+        assert_eq!(parsed.header("X-Mixed"), Some("yes"));
+        assert_eq!(parsed.body(), b"hi");
+
+        let strict = Limits {
+            strict_line_endings: true,
+            ..Limits::default()
+        };
+        let err = parse_err(request, &strict);
+        assert_eq!(err.status(), Some(400), "{err}");
+        assert!(parse_with("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", &strict).is_ok());
+    }
+
+    #[test]
+    fn wants_upgrade_needs_the_connection_token_and_the_protocol_offered() {
+        let request = parse(
+            "GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive, Upgrade\r\nUpgrade: h2c, WebSocket\r\n\r\n",
+        )
+        .unwrap();
+        assert!(request.wants_upgrade("websocket"));
+        assert!(request.wants_upgrade("h2c"));
+        assert!(!request.wants_upgrade("echo"));
+
+        let no_token = parse("GET / HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!no_token.wants_upgrade("websocket"));
+
+        let http_1_0 =
+            parse("GET / HTTP/1.0\r\nConnection: upgrade\r\nUpgrade: websocket\r\n\r\n").unwrap();
+        assert!(!http_1_0.wants_upgrade("websocket"));
+    }
+
+    #[test]
+    fn a_nul_in_a_header_line_is_refused() {
+        assert!(parse("GET / HTTP/1.1\r\nHost: a\r\nX-Name: a\0b\r\n\r\n").is_err());
+        assert!(parse("GET /\0 HTTP/1.1\r\nHost: a\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn the_request_line_method_is_parsed_into_a_method() {
+        let patch = parse("PATCH /item HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(*patch.method(), Method::Patch);
+
+        let purge = parse("PURGE /cache HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!(*purge.method(), Method::Other(String::from("PURGE")));
+        assert_eq!(purge.method().as_str(), "PURGE");
+    }
+
+    // An endless body source that counts the bytes taken from it.
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Read for Counting {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            buf.fill(b'x');
+            self.0.fetch_add(buf.len(), Ordering::SeqCst);
+            Ok(buf.len())
+        }
+    }
+
+    // A request head announcing a body of `length` bytes.
+    fn upload(length: usize) -> Request {
+        let head =
+            format!("POST /upload HTTP/1.1\r\nHost: localhost\r\nContent-Length: {length}\r\n\r\n");
+        Request::read_head(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn a_slow_consumer_holds_the_reader_back() {
+        const LENGTH: usize = 4 * 1024 * 1024;
+        let request = upload(LENGTH);
+        let limits = Limits {
+            max_body_size: LENGTH,
+            ..Limits::default()
+        };
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let mut source = io::BufReader::new(Counting(Arc::clone(&pulled)));
+        let mut body = BodyReader::new(&mut source, &request, &limits).unwrap();
+
+        let pool = ThreadPool::new(1);
+        let consumed = body
+            .pipe(&pool, 2, move |mut chunks| {
+                let first = chunks.next().unwrap().unwrap();
+                // Give the reader time to run ahead as far as it can.
+                thread::sleep(Duration::from_millis(100));
+                let ahead = pulled.load(Ordering::SeqCst);
+                let rest: usize = chunks.map(|chunk| chunk.unwrap().len()).sum();
+                (first.len() + rest, ahead)
+            })
+            .unwrap();
+
+        let (total, ahead) = consumed;
+        assert_eq!(total, LENGTH);
+        // The chunk taken, two in the channel, one waiting to be sent and
+        // the read buffer: nowhere near the whole body.
+        assert!(
+            ahead <= 4 * PIPE_CHUNK_SIZE + 8 * 1024,
+            "read {ahead} bytes ahead"
+        );
+        assert!(body.is_finished());
+    }
+
+    #[test]
+    fn a_piped_body_over_the_limit_ends_with_an_error() {
+        let head = "POST /upload HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let request = Request::read_head(&mut head.as_bytes(), &Limits::default()).unwrap();
+        let limits = Limits {
+            max_body_size: 8,
+            ..Limits::default()
+        };
+        let mut chunked = "5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n".as_bytes();
+        let mut body = BodyReader::new(&mut chunked, &request, &limits).unwrap();
+
+        let pool = ThreadPool::new(1);
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&seen);
+        let err = body
+            .pipe(&pool, 4, move |chunks| {
+                for chunk in chunks {
+                    match chunk {
+                        Ok(chunk) => counted.fetch_add(chunk.len(), Ordering::SeqCst),
+                        Err(err) => return Some(err.kind()),
+                    };
+                }
+                None
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(seen.load(Ordering::SeqCst) <= 8);
+    }
+
+    #[test]
+    fn a_consumer_that_stops_early_stops_the_reading() {
+        let request = upload(1024 * 1024);
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let mut source = io::BufReader::new(Counting(Arc::clone(&pulled)));
+        let mut body = BodyReader::new(&mut source, &request, &Limits::default()).unwrap();
+
+        let pool = ThreadPool::new(1);
+        let first = body
+            .pipe(&pool, 1, |mut chunks| chunks.next().unwrap().unwrap().len())
+            .unwrap();
+        assert_eq!(first, PIPE_CHUNK_SIZE);
+        assert!(!body.is_finished());
+        assert!(pulled.load(Ordering::SeqCst) < 1024 * 1024);
+    }
+}
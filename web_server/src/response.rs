@@ -0,0 +1,609 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    io::{self, BufWriter, Write},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    cookie::{self, CookieAttributes},
+    error_page, http_date,
+    upgrade::{self, OnUpgrade},
+};
+
+/// An HTTP response, built up by a handler and then written to the client.
+///
+/// Header fields are sent as they were set, so a name or value holding a
+/// CR or LF, which may well come from the request, could end the field
+/// early and start a field, or a whole response, of the handler's choosing.
+/// Setting such a field is a bug in the handler: debug builds panic on it
+/// straight away, while release builds send the value with every CR, LF
+/// and NUL removed, and leave out a field whose name isn't valid.
+pub struct Response {
+    status: u16,                    // Status code, e.g. 200
+    reason: Option<String>,         // Reason phrase, if not the standard one
+    headers: Vec<(String, String)>, // Header fields in the order they will be sent
+    body: Vec<u8>,                  // Response body
+    on_upgrade: Option<OnUpgrade>,  // Takes over the connection after a `101`
+}
+
+impl Response {
+    /// Create an empty response with the given status code.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            reason: None,
+            headers: Vec::new(),
+            body: Vec::new(),
+            on_upgrade: None,
+        }
+    }
+
+    /// Switch the connection to `protocol`, as named in the request's
+    /// `Upgrade` header, and hand it to `on_upgrade` once this response
+    /// has been sent.
+    ///
+    /// The response is `101 Switching Protocols` with `Connection: Upgrade`
+    /// and `Upgrade: protocol`, and the protocol's own handshake headers
+    /// can be added to it as usual. The server only switches if the client
+    /// asked to upgrade; answering a request that didn't with this is a bug
+    /// in the handler and gets a 500 instead. See the `upgrade` module.
+    pub fn upgrade(
+        protocol: &str,
+        on_upgrade: impl FnOnce(&mut dyn upgrade::Stream) + Send + 'static,
+    ) -> Response {
+        let mut response = Response::new(101)
+            .with_header("Connection", "Upgrade")
+            .with_header("Upgrade", protocol);
+        response.on_upgrade = Some(Box::new(on_upgrade));
+        response
+    }
+
+    /// Whether this response switches the connection to another protocol,
+    /// having been made with `upgrade`.
+    pub fn is_upgrade(&self) -> bool {
+        self.status == 101 && self.on_upgrade.is_some()
+    }
+
+    // The callback taking over the connection, if this is an upgrade.
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        if self.status != 101 {
+            return None;
+        }
+        self.on_upgrade.take()
+    }
+
+    /// Create a redirect to `location` with the given status code, e.g. 301
+    /// or 302.
+    ///
+    /// `location` is used exactly as given, so it must already be a valid
+    /// URI reference; see `uri::percent_encode_path` for building one from
+    /// a file name. A short HTML body links to the new location for clients
+    /// that don't follow redirects. Its text is cut short for a long
+    /// location, which may come from the request.
+    pub fn redirect(status: u16, location: &str) -> Response {
+        let href = error_page::escape_html(location);
+        let text = error_page::echo(location);
+
+        Response::new(status)
+            .with_header("Location", location)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(format!("<a href=\"{href}\">{text}</a>\n"))
+    }
+
+    /// Answer with `status` and `reason` in the status line, instead of the
+    /// standard reason phrase for the status.
+    ///
+    /// Clients are meant to ignore the phrase, but some rely on an exact
+    /// one. The phrase may be empty, which the status line allows.
+    ///
+    /// ```
+    /// use web_server::response::Response;
+    ///
+    /// let response = Response::new(200).status_with_reason(418, "Short and Stout");
+    /// assert_eq!(response.reason(), "Short and Stout");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// The `status_with_reason` function will panic if `reason` contains a
+    /// control character other than tab, such as CR or LF, which would let
+    /// it end the status line early and add header fields of its own.
+    pub fn status_with_reason(mut self, status: u16, reason: impl Into<String>) -> Response {
+        let reason = reason.into();
+        assert!(
+            !reason.chars().any(|c| c.is_control() && c != '\t'),
+            "reason phrase {reason:?} contains a control character"
+        );
+        self.status = status;
+        self.reason = Some(reason);
+        self
+    }
+
+    /// Add a header field to the response.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, the `with_header` function will panic if the name
+    /// or value contains CR, LF or NUL, or the name isn't a valid field
+    /// name. See `Response` for what happens in release builds.
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Response {
+        let value = value.into();
+        check_field(name, &value);
+        self.headers.push((name.to_string(), value));
+        self
+    }
+
+    /// Tell the client when to try again, with a `Retry-After` header
+    /// replacing any already set.
+    ///
+    /// A `Duration` is sent as a number of seconds, rounded up so that a
+    /// short delay isn't sent as zero, and a `SystemTime` as an HTTP date.
+    /// A zero delay, or a time already past, tells the client it may retry
+    /// straight away.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use web_server::response::Response;
+    ///
+    /// let response = Response::new(503).retry_after(Duration::from_secs(120));
+    /// assert_eq!(response.header("Retry-After"), Some("120"));
+    /// ```
+    pub fn retry_after(mut self, when: impl Into<RetryAfter>) -> Response {
+        self.set_header("Retry-After", when.into().to_string());
+        self
+    }
+
+    /// Set the response body.
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// Turn the response to a GET into the response to a HEAD for the same
+    /// resource: the same status and header fields, with the body's length
+    /// in `Content-Length` in place of the body.
+    ///
+    /// A `Content-Length` already set is kept, and none is added for a
+    /// status that never has a body.
+    pub fn without_body(mut self) -> Response {
+        if self.header("Content-Length").is_none() && !matches!(self.status, 100..=199 | 204 | 304)
+        {
+            let length = self.body.len();
+            self.set_header("Content-Length", length.to_string());
+        }
+        self.with_body(Vec::new())
+    }
+
+    /// The status code.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// The reason phrase sent with the status code.
+    pub fn reason(&self) -> &str {
+        self.reason
+            .as_deref()
+            .unwrap_or_else(|| reason_phrase(self.status))
+    }
+
+    /// Look up a header value by name. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All header fields in the order they will be sent.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The response body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The number of bytes the status line and header fields take up when
+    /// written, up to and including the blank line ending them.
+    ///
+    /// This counts the fields set so far, not the `Content-Length` that
+    /// `write_to` may add.
+    pub fn head_len(&self) -> usize {
+        let status_line = "HTTP/1.1 000 \r\n".len() + self.reason().len();
+        let fields: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + ": \r\n".len() + value.len())
+            .sum();
+        status_line + fields + "\r\n".len()
+    }
+
+    /// Set a header field, replacing any existing fields with the same name.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, the `set_header` function will panic on the same
+    /// fields as `with_header`.
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        check_field(name, &value);
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value));
+    }
+
+    /// Add a `Set-Cookie` header.
+    ///
+    /// Each call adds a separate header, so several cookies can be set on
+    /// one response.
+    pub fn set_cookie(&mut self, name: &str, value: &str, attrs: &CookieAttributes) {
+        let cookie = cookie::format_set_cookie(name, value, attrs);
+        check_field("Set-Cookie", &cookie);
+        self.headers.push((String::from("Set-Cookie"), cookie));
+    }
+
+    /// Serialize the response onto `writer`.
+    ///
+    /// A `Content-Length` header matching the body is added unless the
+    /// response already carries one, or is informational (1xx), a `204 No
+    /// Content` or a `304 Not Modified`, which have no body to measure.
+    ///
+    /// The status line and headers go through a `BufWriter`, so they reach
+    /// `writer` in as few writes as possible, and the whole response is
+    /// flushed before this returns. A large body bypasses the buffer.
+    ///
+    /// A header field that couldn't be sent safely as it is, as described
+    /// for `Response`, is cleaned up or left out here.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, self.reason())?;
+        for (name, value) in &self.headers {
+            if !is_field_name(name) {
+                println!("Leaving out header field {name:?}; it isn't a valid name.");
+                continue;
+            }
+            write!(writer, "{name}: {}\r\n", clean_value(value))?;
+        }
+        if self.header("Content-Length").is_none() && !matches!(self.status, 100..=199 | 204 | 304)
+        {
+            write!(writer, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)?;
+
+        // Flush explicitly so a failed write is reported rather than being
+        // swallowed when the `BufWriter` is dropped.
+        writer.flush()
+    }
+}
+
+// Whether `name` is a field name, a token in RFC 9110's terms.
+fn is_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// Whether `c` would break a field value out of its line, or trip up the
+// client reading it.
+fn is_line_break(c: char) -> bool {
+    matches!(c, '\r' | '\n' | '\0')
+}
+
+// `value` without the characters that can't be sent in a field value.
+fn clean_value(value: &str) -> Cow<'_, str> {
+    if value.contains(is_line_break) {
+        Cow::Owned(value.replace(is_line_break, ""))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// Catch a header field that can't be sent as it is where it was set, in
+// debug builds. Release builds clean it up in `write_to` instead.
+fn check_field(name: &str, value: &str) {
+    debug_assert!(is_field_name(name), "invalid header name {name:?}");
+    debug_assert!(
+        !value.contains(is_line_break),
+        "header {name} has a value {value:?} with CR, LF or NUL in it"
+    );
+}
+
+/// When a client should try a request again, as sent in `Retry-After`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfter {
+    Delay(Duration), // After this long, sent as delta-seconds
+    At(SystemTime),  // Once this time has come, sent as an HTTP date
+}
+
+impl From<Duration> for RetryAfter {
+    fn from(delay: Duration) -> RetryAfter {
+        RetryAfter::Delay(delay)
+    }
+}
+
+impl From<SystemTime> for RetryAfter {
+    fn from(time: SystemTime) -> RetryAfter {
+        RetryAfter::At(time)
+    }
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryAfter::Delay(delay) => {
+                let seconds = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+                write!(f, "{seconds}")
+            }
+            RetryAfter::At(time) => f.write_str(&http_date::format(*time)),
+        }
+    }
+}
+
+/// The standard reason phrase for a status code.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records each write separately, to count the writes that reach it.
+    #[derive(Default)]
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_response_with_many_headers_is_written_byte_for_byte_in_few_writes() {
+        let mut response = Response::new(200).with_body("hello");
+        let mut expected = String::from("HTTP/1.1 200 OK\r\n");
+        for i in 0..200 {
+            response = response.with_header(&format!("X-Field-{i}"), format!("value {i}"));
+            expected.push_str(&format!("X-Field-{i}: value {i}\r\n"));
+        }
+        expected.push_str("Content-Length: 5\r\n\r\nhello");
+
+        let mut writes = Writes::default();
+        response.write_to(&mut writes).unwrap();
+
+        assert_eq!(writes.0.concat(), expected.as_bytes());
+        // The head fills the buffer only a handful of times.
+        assert!(
+            writes.0.len() <= expected.len() / 8192 + 2,
+            "{} writes",
+            writes.0.len()
+        );
+    }
+
+    #[test]
+    fn a_failed_write_is_reported() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let err = Response::new(200).write_to(&mut Broken).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn each_cookie_gets_its_own_set_cookie_header() {
+        let mut response = Response::new(200);
+        let attrs = CookieAttributes {
+            secure: true,
+            ..CookieAttributes::default()
+        };
+        response.set_cookie("a", "1", &attrs);
+        response.set_cookie("b", "2", &CookieAttributes::default());
+
+        let cookies: Vec<_> = response
+            .headers()
+            .iter()
+            .filter(|(name, _)| name == "Set-Cookie")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(cookies, ["a=1; Secure", "b=2"]);
+    }
+
+    #[test]
+    fn a_redirect_sets_its_status_location_and_a_link() {
+        let response = Response::redirect(302, "/new?a=1&b=2");
+        assert_eq!(response.status(), 302);
+        assert_eq!(response.header("Location"), Some("/new?a=1&b=2"));
+        assert_eq!(
+            response.body(),
+            b"<a href=\"/new?a=1&amp;b=2\">/new?a=1&amp;b=2</a>\n"
+        );
+
+        let permanent = Response::redirect(301, "/docs/");
+        assert_eq!(permanent.status(), 301);
+        assert_eq!(permanent.reason(), "Moved Permanently");
+    }
+
+    #[test]
+    fn retry_after_sends_a_delay_as_whole_seconds_rounded_up() {
+        let after = |delay| Response::new(503).retry_after(delay);
+        assert_eq!(
+            after(Duration::from_secs(120)).header("Retry-After"),
+            Some("120")
+        );
+        assert_eq!(
+            after(Duration::from_millis(1500)).header("Retry-After"),
+            Some("2")
+        );
+        assert_eq!(
+            after(Duration::from_millis(1)).header("Retry-After"),
+            Some("1")
+        );
+        assert_eq!(after(Duration::ZERO).header("Retry-After"), Some("0"));
+    }
+
+    #[test]
+    fn retry_after_sends_a_time_as_an_http_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let response = Response::new(429).retry_after(time);
+        assert_eq!(
+            response.header("Retry-After"),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+
+        // Setting it again replaces the first.
+        let response = response.retry_after(Duration::from_secs(5));
+        assert_eq!(response.header("Retry-After"), Some("5"));
+    }
+
+    #[test]
+    fn a_custom_reason_phrase_goes_in_the_status_line() {
+        let response = Response::new(200).status_with_reason(299, "All Good");
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        assert!(written.starts_with(b"HTTP/1.1 299 All Good\r\n"));
+
+        // Tabs and empty phrases are allowed, and the default is unchanged.
+        assert_eq!(Response::new(200).status_with_reason(200, "").reason(), "");
+        assert_eq!(
+            Response::new(200).status_with_reason(200, "A\tB").reason(),
+            "A\tB"
+        );
+        assert_eq!(Response::new(404).reason(), "Not Found");
+    }
+
+    #[test]
+    #[should_panic(expected = "contains a control character")]
+    fn a_reason_phrase_with_crlf_is_refused() {
+        let _ = Response::new(200).status_with_reason(200, "OK\r\nSet-Cookie: x=1");
+    }
+
+    #[test]
+    fn an_ordinary_header_value_is_sent_as_set() {
+        let response = Response::new(200).with_header("X-Note", "a, b; c=\"d\"\te");
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(
+            written.contains("\r\nX-Note: a, b; c=\"d\"\te\r\n"),
+            "{written}"
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "with CR, LF or NUL in it")]
+    fn a_header_value_with_crlf_is_refused() {
+        let _ = Response::new(200).with_header("Location", "/a\r\nSet-Cookie: session=stolen");
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "invalid header name")]
+    fn a_header_name_that_isnt_a_token_is_refused() {
+        let mut response = Response::new(200);
+        response.set_header("Bad Name:", "value");
+    }
+
+    #[test]
+    fn fields_that_slipped_through_are_cleaned_up_when_written() {
+        // As release builds keep them, bypassing the debug check.
+        let mut response = Response::new(200);
+        response
+            .headers
+            .push(("Location".into(), "/a\r\nSet-Cookie: x=1\0".into()));
+        response.headers.push(("Bad Name".into(), "value".into()));
+
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+        let written = String::from_utf8(written).unwrap();
+        assert!(
+            written.contains("\r\nLocation: /aSet-Cookie: x=1\r\n"),
+            "{written}"
+        );
+        assert!(!written.contains("Bad Name"), "{written}");
+        assert!(!written.contains("\r\nSet-Cookie"), "{written}");
+    }
+
+    #[test]
+    fn head_len_counts_the_status_line_and_fields_written() {
+        let mut response = Response::new(404);
+        response.set_header("X-Test", "yes");
+        let mut written = Vec::new();
+        response.write_to(&mut written).unwrap();
+
+        let head_end = written.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        // `write_to` adds `Content-Length: 0\r\n`, which isn't counted.
+        assert_eq!(
+            response.head_len(),
+            head_end - "Content-Length: 0\r\n".len()
+        );
+    }
+
+    #[test]
+    fn without_body_keeps_the_length_of_the_body_it_drops() {
+        let head = Response::new(200).with_body("hello").without_body();
+        assert!(head.body().is_empty());
+        assert_eq!(head.header("Content-Length"), Some("5"));
+
+        let set = Response::new(200)
+            .with_header("Content-Length", "99")
+            .without_body();
+        assert_eq!(set.header("Content-Length"), Some("99"));
+
+        let not_modified = Response::new(304).without_body();
+        assert_eq!(not_modified.header("Content-Length"), None);
+    }
+}
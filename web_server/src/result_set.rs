@@ -0,0 +1,277 @@
+//! Collects the results of jobs fanned out over a pool.
+
+use std::{
+    collections::HashMap,
+    iter::FusedIterator,
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::ThreadPool;
+
+// What a job sends back: its place in the order of submission, and what it
+// returned or the payload it panicked with.
+type Outcome<T> = (usize, thread::Result<T>);
+
+/// A batch of jobs submitted to a pool, each returning a `T`, whose results
+/// are then read back as one iterator.
+///
+/// Iterating the set yields each result as its job finishes, in whatever
+/// order that happens, and `ordered` yields them in the order the jobs were
+/// submitted instead. Either way there is exactly one item per job. A job
+/// that panicked yields `Err` with the panic payload, like
+/// `std::thread::JoinHandle::join`, and so does one that never ran, which
+/// happens to jobs still queued when the pool is shut down in
+/// `ShutdownMode::Abort`, so iterating never hangs on a job that won't
+/// finish.
+///
+/// ```
+/// use web_server::{ResultSet, ThreadPool};
+///
+/// let pool = ThreadPool::new(4);
+/// let mut squares = ResultSet::new(&pool);
+/// for n in 0..10u64 {
+///     squares.submit(move || n * n);
+/// }
+///
+/// let squares: Vec<u64> = squares.ordered().map(Result::unwrap).collect();
+/// assert_eq!(squares, [0, 1, 4, 9, 16, 25, 36, 49, 64, 81]);
+/// ```
+pub struct ResultSet<'a, T> {
+    pool: &'a ThreadPool,           // Where the jobs run
+    sender: Sender<Outcome<T>>,     // Handed to each job to send its result on
+    receiver: Receiver<Outcome<T>>, // Where the results arrive
+    submitted: usize,               // Jobs submitted so far
+}
+
+impl<'a, T: Send + 'static> ResultSet<'a, T> {
+    /// Start an empty set of jobs to run on `pool`.
+    pub fn new(pool: &'a ThreadPool) -> ResultSet<'a, T> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        ResultSet {
+            pool,
+            sender,
+            receiver,
+            submitted: 0,
+        }
+    }
+
+    /// Run `f` on the pool as one more job of the set.
+    ///
+    /// This blocks like `ThreadPool::execute` while the pool's queue or
+    /// in-flight limit is full.
+    pub fn submit<F>(&mut self, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let index = self.submitted;
+        self.submitted += 1;
+
+        let sender = self.sender.clone();
+        self.pool.execute(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            // The set holds the receiver until every result has been read,
+            // unless it was dropped first, in which case nobody wants this.
+            let _ = sender.send((index, result));
+        });
+    }
+
+    /// The number of jobs submitted to the set.
+    pub fn len(&self) -> usize {
+        self.submitted
+    }
+
+    /// Whether no job has been submitted to the set.
+    pub fn is_empty(&self) -> bool {
+        self.submitted == 0
+    }
+
+    /// Iterate the results in the order the jobs were submitted, waiting
+    /// for each one in turn. Results that arrive early are held until
+    /// their turn comes.
+    pub fn ordered(self) -> OrderedResults<T> {
+        OrderedResults {
+            receiver: self.receiver,
+            next: 0,
+            submitted: self.submitted,
+            early: HashMap::new(),
+        }
+    }
+}
+
+impl<T> IntoIterator for ResultSet<'_, T> {
+    type Item = thread::Result<T>;
+    type IntoIter = Results<T>;
+
+    /// Iterate the results as the jobs finish.
+    fn into_iter(self) -> Results<T> {
+        // Only the jobs hold senders from here on, so the channel closes
+        // once every job has either sent its result or been dropped.
+        Results {
+            receiver: self.receiver,
+            remaining: self.submitted,
+        }
+    }
+}
+
+/// The results of a `ResultSet` in the order its jobs finish.
+pub struct Results<T> {
+    receiver: Receiver<Outcome<T>>, // Where the results arrive
+    remaining: usize,               // Results not yet yielded
+}
+
+impl<T> Iterator for Results<T> {
+    type Item = thread::Result<T>;
+
+    fn next(&mut self) -> Option<thread::Result<T>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        match self.receiver.recv() {
+            Ok((_, result)) => Some(result),
+            // Every job left has been dropped without running.
+            Err(_) => Some(Err(never_ran())),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<T> ExactSizeIterator for Results<T> {}
+
+impl<T> FusedIterator for Results<T> {}
+
+/// The results of a `ResultSet` in the order its jobs were submitted.
+pub struct OrderedResults<T> {
+    receiver: Receiver<Outcome<T>>,           // Where the results arrive
+    next: usize,                              // Index of the next result to yield
+    submitted: usize,                         // How many results there are in all
+    early: HashMap<usize, thread::Result<T>>, // Results that arrived before their turn
+}
+
+impl<T> Iterator for OrderedResults<T> {
+    type Item = thread::Result<T>;
+
+    fn next(&mut self) -> Option<thread::Result<T>> {
+        if self.next == self.submitted {
+            return None;
+        }
+        let index = self.next;
+        self.next += 1;
+
+        if let Some(result) = self.early.remove(&index) {
+            return Some(result);
+        }
+        loop {
+            match self.receiver.recv() {
+                Ok((arrived, result)) if arrived == index => return Some(result),
+                Ok((arrived, result)) => {
+                    self.early.insert(arrived, result);
+                }
+                // Every job still to report was dropped without running, so
+                // this one's result isn't coming.
+                Err(_) => return Some(Err(never_ran())),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.submitted - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T> ExactSizeIterator for OrderedResults<T> {}
+
+impl<T> FusedIterator for OrderedResults<T> {}
+
+// The error yielded for a job that was dropped before it could run.
+fn never_ran() -> Box<dyn std::any::Any + Send> {
+    Box::new("the job was dropped before it ran")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+    use crate::{ShutdownMode, ThreadPoolBuilder};
+
+    // Fan 50 jobs out over `pool`, the one for 17 panicking.
+    fn fan_out(pool: &ThreadPool) -> ResultSet<'_, usize> {
+        let mut results = ResultSet::new(pool);
+        for n in 0..50 {
+            results.submit(move || {
+                assert_ne!(n, 17, "job 17 panics");
+                n * 2
+            });
+        }
+        results
+    }
+
+    #[test]
+    fn collects_every_result_as_it_comes_including_a_panic() {
+        let pool = ThreadPool::new(4);
+        let results = fan_out(&pool);
+        assert_eq!(results.len(), 50);
+
+        let results: Vec<_> = results.into_iter().collect();
+        assert_eq!(results.len(), 50);
+        let mut values: Vec<usize> = results
+            .iter()
+            .filter_map(|r| r.as_ref().ok().copied())
+            .collect();
+        values.sort_unstable();
+        let expected: Vec<usize> = (0..50).filter(|&n| n != 17).map(|n| n * 2).collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn ordered_yields_the_results_in_submission_order() {
+        let pool = ThreadPool::new(4);
+        let results: Vec<_> = fan_out(&pool).ordered().collect();
+        assert_eq!(results.len(), 50);
+        for (n, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => assert_eq!(value, n * 2),
+                Err(_) => assert_eq!(n, 17),
+            }
+        }
+    }
+
+    #[test]
+    fn jobs_dropped_unrun_yield_errors_instead_of_hanging() {
+        let pool = ThreadPoolBuilder::new(1)
+            .shutdown_mode(ShutdownMode::Abort)
+            .build();
+        let (release, released) = mpsc::channel::<()>();
+        let mut results = ResultSet::new(&pool);
+        let (started, has_started) = mpsc::channel();
+        results.submit(move || {
+            started.send(()).unwrap();
+            released.recv_timeout(Duration::from_secs(5)).is_err()
+        });
+        results.submit(|| true);
+        results.submit(|| true);
+        has_started.recv().unwrap();
+
+        // The pool goes away with two of the jobs still queued.
+        let results = results.ordered();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                drop(release);
+            });
+            drop(pool);
+        });
+        let results: Vec<_> = results.collect();
+        assert!(matches!(results[0], Ok(true)));
+        assert!(results[1].is_err() && results[2].is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! An in-memory response for the site root, so a server can answer `/`
+//! without any files on disk.
+
+use crate::{embedded, method::Method, request::Request, response::Response};
+
+/// The path the root page is served at.
+pub const PATH: &str = "/";
+
+/// A body and content type served for `/`, set as `Config::root_page`.
+///
+/// When a root page is configured it wins over a file for `/` in the
+/// static root, such as `hello.html`, which is then never read. Like an
+/// embedded asset, the page gets an `ETag` computed from its bytes.
+#[derive(Clone)]
+pub struct RootPage {
+    body: Vec<u8>,        // The page's contents
+    content_type: String, // Value of the Content-Type header
+    etag: String,         // Quoted entity tag computed from the contents
+}
+
+impl RootPage {
+    /// A root page of `body`, served as `content_type`, e.g.
+    /// `text/html; charset=utf-8`.
+    pub fn new(content_type: impl Into<String>, body: impl Into<Vec<u8>>) -> RootPage {
+        let body = body.into();
+        RootPage {
+            etag: embedded::etag(&body),
+            body,
+            content_type: content_type.into(),
+        }
+    }
+
+    /// The page's contents.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The page's content type.
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Answer a request for `/` with the page.
+    ///
+    /// Methods other than `GET` and `HEAD` answer 405.
+    pub fn serve(&self, request: &Request) -> Response {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Response::new(405).with_header("Allow", "GET, HEAD");
+        }
+
+        // The client's cached copy is still current.
+        if request.header("If-None-Match") == Some(self.etag.as_str()) {
+            return Response::new(304).with_header("ETag", self.etag.clone());
+        }
+
+        let response = Response::new(200)
+            .with_header("Content-Type", self.content_type.clone())
+            .with_header("ETag", self.etag.clone());
+
+        // HEAD gets the same headers as GET, but no body.
+        if *request.method() == Method::Head {
+            return response.with_header("Content-Length", self.body.len().to_string());
+        }
+
+        response.with_body(self.body.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Limits;
+
+    fn request(method: &str, headers: &str) -> Request {
+        let head = format!("{method} / HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn the_page_is_served_with_its_type_and_a_tag_for_revalidation() {
+        let page = RootPage::new("text/html; charset=utf-8", "<h1>Hi</h1>");
+        let response = page.serve(&request("GET", ""));
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        assert_eq!(response.body(), b"<h1>Hi</h1>");
+
+        let etag = response.header("ETag").unwrap().to_string();
+        let cached = page.serve(&request("GET", &format!("If-None-Match: {etag}\r\n")));
+        assert_eq!(cached.status(), 304);
+        assert!(cached.body().is_empty());
+    }
+
+    #[test]
+    fn head_gets_the_length_and_other_methods_405() {
+        let page = RootPage::new("text/plain", "12345");
+        let head = page.serve(&request("HEAD", ""));
+        assert_eq!(head.header("Content-Length"), Some("5"));
+        assert!(head.body().is_empty());
+
+        let post = page.serve(&request("POST", "Content-Length: 0\r\n"));
+        assert_eq!(post.status(), 405);
+        assert_eq!(post.header("Allow"), Some("GET, HEAD"));
+    }
+}
@@ -0,0 +1,527 @@
+use std::iter;
+
+use crate::{
+    error_page,
+    handler::Handler,
+    method::Method,
+    request::{BodyReader, Request},
+    response::Response,
+    uri,
+};
+
+/// Dispatches requests to handlers by method and path.
+///
+/// A request goes to the handler registered for its method and path. A
+/// path registered only for other methods answers 405 with an `Allow`
+/// header listing them, and an unknown path goes to the `fallback`
+/// handler, which answers 404 unless one is set.
+///
+/// A segment of a route's path starting with `:` is a parameter matching
+/// any one non-empty segment, which the handler reads with
+/// `Request::param`, so `/users/:id` matches `/users/42` with `id` being
+/// `42`. Where several routes match, the one with a fixed segment where
+/// the others have a parameter wins, from the left, so `/users/me` beats
+/// `/users/:id`. A trailing slash is a segment of its own: `/users/:id`
+/// matches neither `/users/` nor `/users/42/`.
+///
+/// A last segment starting with `*` catches the rest of the path,
+/// slashes and all, so `/static/*path` matches `/static/css/app.css` with
+/// `path` being `css/app.css`, and `/static/` with it empty. It ranks
+/// below a parameter or a fixed segment in the same place. This mounts a
+/// handler under a prefix; `StaticFiles::serve_file` can be given the
+/// captured path directly, since it refuses anything escaping its root.
+///
+/// A `HEAD` request that no `HEAD` route matches is answered by the `GET`
+/// route for its path, if there is one, with the same status and headers,
+/// including the `Content-Length` of the body, but without the body. A
+/// route registered for `HEAD` itself always wins; `auto_head` turns this
+/// off altogether.
+///
+/// ```no_run
+/// use web_server::{prelude::*, router::Router};
+///
+/// let router = Router::new()
+///     .route("GET", "/", |_request: &Request| Response::new(200).with_body("Hello!"))
+///     .route("POST", "/echo", |request: &Request| {
+///         Response::new(200).with_body(request.body().to_vec())
+///     })
+///     .route("GET", "/users/:id", |request: &Request| {
+///         Response::new(200).with_body(format!("User {}", request.param("id").unwrap()))
+///     });
+///
+/// assert!(router.has_route("GET", "/users/:id"));
+/// ```
+pub struct Router {
+    routes: Vec<Route>, // Registered routes, in the order they were added
+    fallback: Option<Box<dyn Handler>>, // Serves paths no route knows; `None` answers 404
+    auto_head: bool,    // Whether `GET` routes answer `HEAD` requests too
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: None,
+            auto_head: true,
+        }
+    }
+}
+
+// One method and path and the handler serving them.
+struct Route {
+    method: Method,            // Method the route answers, e.g. `Method::Get`
+    path: String,              // Path pattern as registered, e.g. "/users/:id"
+    segments: Vec<Segment>,    // The pattern split at each `/`
+    handler: Box<dyn Handler>, // What produces the response
+}
+
+// One `/`-separated part of a route's path.
+enum Segment {
+    Fixed(String), // Matches only itself
+    Param(String), // Matches any non-empty segment, captured under this name
+    Rest(String),  // Matches the rest of the path, captured under this name
+}
+
+impl Route {
+    // The parameters captured from `path`, if the route matches it.
+    fn captures(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut parts = path.split('/');
+        let mut params = Vec::new();
+
+        for segment in &self.segments {
+            let part = parts.next()?;
+            match segment {
+                Segment::Fixed(fixed) => {
+                    if part != fixed {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), uri::percent_decode(part)?));
+                }
+                Segment::Rest(name) => {
+                    let rest: Vec<&str> = iter::once(part).chain(parts).collect();
+                    params.push((name.clone(), uri::percent_decode(&rest.join("/"))?));
+                    return Some(params);
+                }
+            }
+        }
+
+        // The path may not go on past the pattern.
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+
+    // How specific the route is: fixed segments rank above parameters and
+    // parameters above the rest of the path, compared from the left.
+    fn precedence(&self) -> Vec<u8> {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Fixed(_) => 2,
+                Segment::Param(_) => 1,
+                Segment::Rest(_) => 0,
+            })
+            .collect()
+    }
+}
+
+// Split a route's path into segments.
+fn parse_pattern(path: &str) -> Vec<Segment> {
+    let segments: Vec<Segment> = path
+        .split('/')
+        .map(|part| {
+            if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Fixed(part.to_string())
+            }
+        })
+        .collect();
+
+    // Nothing can follow a segment that takes the rest of the path.
+    let last = segments.len() - 1;
+    assert!(
+        !segments[..last]
+            .iter()
+            .any(|segment| matches!(segment, Segment::Rest(_))),
+        "a `*` segment must come last in route `{path}`"
+    );
+    segments
+}
+
+impl Router {
+    /// Create a router without any routes.
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Serve requests for `method` and `path` with `handler`.
+    ///
+    /// `path` may contain parameters, as described on `Router`.
+    /// Registering the same method and path again replaces the earlier
+    /// handler. `method` is a `Method` or its name, such as `"GET"`, and
+    /// is case-sensitive, as in HTTP.
+    ///
+    /// # Panics
+    ///
+    /// The `route` function will panic if a `*` segment isn't the last
+    /// one in `path`.
+    pub fn route(
+        mut self,
+        method: impl Into<Method>,
+        path: &str,
+        handler: impl Handler + 'static,
+    ) -> Router {
+        let method = method.into();
+        let handler: Box<dyn Handler> = Box::new(handler);
+        match self.find_mut(&method, path) {
+            Some(route) => route.handler = handler,
+            None => self.routes.push(Route {
+                method,
+                path: path.to_string(),
+                segments: parse_pattern(path),
+                handler,
+            }),
+        }
+        self
+    }
+
+    /// Serve requests for paths that no route matches with `handler`,
+    /// instead of answering 404.
+    ///
+    /// It gets the whole request, so it can render a 404 page of its own
+    /// or serve a single-page app's `index.html` for every path the app
+    /// routes itself. A path that some route matches under another method
+    /// still answers 405.
+    pub fn fallback(mut self, handler: impl Handler + 'static) -> Router {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Choose whether `GET` routes answer `HEAD` requests for their paths,
+    /// as described on `Router`. This is on by default; with it off, a
+    /// `HEAD` request needs a route of its own, or is answered 405.
+    pub fn auto_head(mut self, enabled: bool) -> Router {
+        self.auto_head = enabled;
+        self
+    }
+
+    /// Whether a handler is registered for exactly `method` and `path`.
+    ///
+    /// `path` is compared with the registered patterns as written, so
+    /// `/users/:id` is registered but `/users/42` isn't.
+    pub fn has_route(&self, method: impl Into<Method>, path: &str) -> bool {
+        self.find(&method.into(), path).is_some()
+    }
+
+    /// Every registered method and path, in the order they were added.
+    pub fn routes(&self) -> Vec<(Method, String)> {
+        self.routes
+            .iter()
+            .map(|route| (route.method.clone(), route.path.clone()))
+            .collect()
+    }
+
+    // The registered route for `method` and `path` as written.
+    fn find(&self, method: &Method, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.method == *method && route.path == path)
+    }
+
+    fn find_mut(&mut self, method: &Method, path: &str) -> Option<&mut Route> {
+        self.routes
+            .iter_mut()
+            .find(|route| route.method == *method && route.path == path)
+    }
+
+    // The route for `method` that serves `request`, with the parameters it
+    // captures.
+    fn matching(
+        &self,
+        request: &Request,
+        method: &Method,
+    ) -> Option<(&Route, Vec<(String, String)>)> {
+        let mut best: Option<(&Route, Vec<(String, String)>)> = None;
+        for route in &self.routes {
+            if route.method != *method {
+                continue;
+            }
+            let Some(params) = route.captures(request.path()) else {
+                continue;
+            };
+            // On a tie, the route registered first wins.
+            if best
+                .as_ref()
+                .is_none_or(|(best, _)| route.precedence() > best.precedence())
+            {
+                best = Some((route, params));
+            }
+        }
+        best
+    }
+
+    // The handler that serves `request`, after handing it the parameters
+    // of its route, and whether it is a `GET` route answering `HEAD`, which
+    // leaves the body out. `None` leaves the request to `unrouted`.
+    fn dispatch(&self, request: &Request) -> Option<(&dyn Handler, bool)> {
+        if let Some((route, params)) = self.matching(request, request.method()) {
+            request.set_params(params);
+            return Some((&*route.handler, false));
+        }
+        if self.auto_head && *request.method() == Method::Head {
+            if let Some((route, params)) = self.matching(request, &Method::Get) {
+                request.set_params(params);
+                return Some((&*route.handler, true));
+            }
+        }
+
+        if self.allowed_methods(request).is_empty() {
+            self.fallback.as_deref().map(|handler| (handler, false))
+        } else {
+            None
+        }
+    }
+
+    // The methods the request's path is routed for.
+    fn allowed_methods(&self, request: &Request) -> Vec<&str> {
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            if !allowed.contains(&route.method.as_str()) && route.captures(request.path()).is_some()
+            {
+                allowed.push(route.method.as_str());
+            }
+        }
+        if self.auto_head && allowed.contains(&"GET") && !allowed.contains(&"HEAD") {
+            allowed.push("HEAD");
+        }
+        allowed
+    }
+
+    // The response to a request no handler takes.
+    fn unrouted(&self, request: &Request) -> Response {
+        let allowed = self.allowed_methods(request);
+        if allowed.is_empty() {
+            error_page::render(Some(request), 404)
+        } else {
+            error_page::render(Some(request), 405).with_header("Allow", allowed.join(", "))
+        }
+    }
+}
+
+impl Handler for Router {
+    fn handle(&self, request: &Request) -> Response {
+        match self.dispatch(request) {
+            Some((handler, false)) => handler.handle(request),
+            Some((handler, true)) => handler.handle(request).without_body(),
+            None => self.unrouted(request),
+        }
+    }
+
+    // A route streams the bodies it asks to, and everything else is read
+    // into memory as usual.
+    fn streams_body(&self, request: &Request) -> bool {
+        self.dispatch(request)
+            .is_some_and(|(handler, _)| handler.streams_body(request))
+    }
+
+    fn handle_stream(&self, request: &Request, body: &mut BodyReader<'_>) -> Response {
+        match self.dispatch(request) {
+            Some((handler, false)) => handler.handle_stream(request, body),
+            Some((handler, true)) => handler.handle_stream(request, body).without_body(),
+            None => self.unrouted(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ok(_: &Request) -> Response {
+        Response::new(200)
+    }
+
+    // Answer with the route and the value of each of its parameters.
+    fn echo(route: &'static str, params: &'static [&'static str]) -> impl Handler {
+        move |request: &Request| {
+            let values: Vec<_> = params
+                .iter()
+                .map(|name| format!("{name}={}", request.param(name).unwrap_or("-")))
+                .collect();
+            Response::new(200).with_body(format!("{route} {}", values.join(" ")))
+        }
+    }
+
+    // Route `path` with `router`, returning the status and body.
+    fn get(router: &Router, path: &str) -> (u16, String) {
+        let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = Request::read_from(&mut head.as_bytes(), &Default::default()).unwrap();
+        let response = router.handle(&request);
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        (response.status(), body)
+    }
+
+    #[test]
+    fn registered_routes_are_listed_as_written() {
+        let router = Router::new()
+            .route("GET", "/", ok)
+            .route(Method::Post, "/users", ok)
+            .route("GET", "/users/:id", ok)
+            .route("GET", "/", ok);
+
+        assert!(router.has_route("GET", "/"));
+        assert!(router.has_route(Method::Get, "/users/:id"));
+        assert!(!router.has_route("GET", "/users/42"));
+        assert!(!router.has_route("GET", "/users"));
+        assert!(!router.has_route("get", "/"));
+        assert_eq!(
+            router.routes(),
+            [
+                (Method::Get, "/".to_string()),
+                (Method::Post, "/users".to_string()),
+                (Method::Get, "/users/:id".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parameters_capture_one_non_empty_segment() {
+        let router = Router::new()
+            .route("GET", "/users/:id", echo("user", &["id"]))
+            .route(
+                "GET",
+                "/users/:id/posts/:post_id",
+                echo("post", &["id", "post_id"]),
+            );
+
+        assert_eq!(get(&router, "/users/42"), (200, "user id=42".into()));
+        assert_eq!(get(&router, "/users/a%20b"), (200, "user id=a b".into()));
+        assert_eq!(
+            get(&router, "/users/42/posts/7"),
+            (200, "post id=42 post_id=7".into())
+        );
+        for path in ["/users/", "/users", "/users/42/", "/users//posts/7"] {
+            assert_eq!(get(&router, path).0, 404, "{path}");
+        }
+    }
+
+    #[test]
+    fn a_fixed_segment_beats_a_parameter_wherever_it_was_registered() {
+        let router = Router::new()
+            .route("GET", "/users/:id", echo("user", &["id"]))
+            .route("GET", "/users/me", echo("me", &[]))
+            .route("GET", "/:kind/:id", echo("any", &["kind", "id"]));
+
+        assert_eq!(get(&router, "/users/me"), (200, "me ".into()));
+        assert_eq!(get(&router, "/users/42"), (200, "user id=42".into()));
+        assert_eq!(
+            get(&router, "/teams/7"),
+            (200, "any kind=teams id=7".into())
+        );
+    }
+
+    #[test]
+    fn a_catch_all_takes_the_rest_of_the_path() {
+        let router = Router::new()
+            .route("GET", "/static/*path", echo("static", &["path"]))
+            .route("GET", "/static/:file", echo("file", &["file"]));
+
+        assert_eq!(
+            get(&router, "/static/css/app.css"),
+            (200, "static path=css/app.css".into())
+        );
+        assert_eq!(get(&router, "/static/"), (200, "static path=".into()));
+        // One segment is the parameter route's, which ranks higher.
+        assert_eq!(
+            get(&router, "/static/app.js"),
+            (200, "file file=app.js".into())
+        );
+        assert_eq!(get(&router, "/assets/css/app.css").0, 404);
+        assert_eq!(get(&router, "/static").0, 404);
+    }
+
+    #[test]
+    #[should_panic(expected = "must come last")]
+    fn a_catch_all_before_the_end_is_refused() {
+        let _ = Router::new().route("GET", "/static/*path/edit", ok);
+    }
+
+    #[test]
+    fn unmatched_paths_go_to_the_fallback() {
+        let router = Router::new().route("GET", "/", ok);
+        assert_eq!(get(&router, "/missing").0, 404);
+
+        let router = router.fallback(|request: &Request| {
+            Response::new(200).with_body(format!("fallback for {}", request.path()))
+        });
+        assert_eq!(
+            get(&router, "/missing/page"),
+            (200, "fallback for /missing/page".into())
+        );
+        assert_eq!(get(&router, "/"), (200, String::new()));
+
+        // A path routed for another method still answers 405.
+        let head = "DELETE / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let request = Request::read_from(&mut head.as_bytes(), &Default::default()).unwrap();
+        let response = router.handle(&request);
+        assert_eq!(response.status(), 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD"));
+    }
+
+    // Send `method` for `path` through `router`.
+    fn send(router: &Router, method: &str, path: &str) -> Response {
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let request = Request::read_from(&mut head.as_bytes(), &Default::default()).unwrap();
+        router.handle(&request)
+    }
+
+    fn page(request: &Request) -> Response {
+        Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_header("ETag", "\"v1\"")
+            .with_body(format!("page {}", request.param("id").unwrap_or("-")))
+    }
+
+    #[test]
+    fn head_is_answered_from_the_get_route_without_the_body() {
+        let router = Router::new().route("GET", "/pages/:id", page);
+        let get = send(&router, "GET", "/pages/7");
+        let head = send(&router, "HEAD", "/pages/7");
+
+        assert_eq!(head.status(), 200);
+        assert!(head.body().is_empty());
+        assert_eq!(head.header("Content-Length"), Some("6"));
+        assert_eq!(head.header("Content-Type"), get.header("Content-Type"));
+        assert_eq!(head.header("ETag"), get.header("ETag"));
+
+        // HEAD is allowed wherever GET is.
+        let post = send(&router, "POST", "/pages/7");
+        assert_eq!(post.status(), 405);
+        assert_eq!(post.header("Allow"), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn a_head_route_of_its_own_wins_over_the_get_route() {
+        let router = Router::new()
+            .route("GET", "/", page)
+            .route("HEAD", "/", |_: &Request| Response::new(204));
+        assert_eq!(send(&router, "HEAD", "/").status(), 204);
+    }
+
+    #[test]
+    fn with_auto_head_off_head_needs_a_route() {
+        let router = Router::new().route("GET", "/", page).auto_head(false);
+        let head = send(&router, "HEAD", "/");
+        assert_eq!(head.status(), 405);
+        assert_eq!(head.header("Allow"), Some("GET"));
+    }
+}
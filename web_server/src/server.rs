@@ -0,0 +1,909 @@
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    num::NonZeroU32,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use socket2::SockRef;
+
+use crate::{
+    config::SharedConfig,
+    connection::{self, Peer},
+    error_page,
+    handler::Handler,
+    shutdown::Waker,
+    trace, ShutdownReport, ThreadPool,
+};
+
+/// Accept connections on `listener` and serve them on `pool` with `handler`.
+///
+/// Each accepted connection becomes one job on the pool, which reads
+/// requests from it until it closes. This only returns once
+/// `Config::shutdown` is triggered.
+pub fn serve<H>(listener: TcpListener, pool: &ThreadPool, config: Arc<SharedConfig>, handler: H)
+where
+    H: Handler + 'static,
+{
+    accept_loop(&listener, pool, &config, &Arc::new(handler));
+}
+
+/// Accept connections on a Unix domain socket and serve them on `pool` with
+/// `handler`.
+///
+/// This behaves exactly like `serve`, for local clients and sidecars that
+/// talk to the server through a socket file rather than TCP.
+#[cfg(unix)]
+pub fn serve_unix<H>(
+    listener: UnixListener,
+    pool: &ThreadPool,
+    config: Arc<SharedConfig>,
+    handler: H,
+) where
+    H: Handler + 'static,
+{
+    accept_loop(&listener, pool, &config, &Arc::new(handler));
+}
+
+/// Serves connections from several listeners on one pool.
+///
+/// Each listener gets an accept loop on its own thread, and the connections
+/// they accept all go to the same pool and handler, e.g. to serve one port
+/// for the public and another for an internal network:
+///
+/// ```no_run
+/// use std::{net::TcpListener, sync::Arc};
+/// use web_server::prelude::*;
+///
+/// let pool = ThreadPool::new(4);
+/// let config = Arc::new(SharedConfig::new(Config::default()));
+///
+/// Server::new()
+///     .listener(TcpListener::bind("0.0.0.0:8080").unwrap())
+///     .listener(TcpListener::bind("127.0.0.1:8081").unwrap())
+///     .run(&pool, config, |_request: &Request| Response::new(204));
+/// ```
+#[derive(Default)]
+pub struct Server {
+    listeners: Vec<Listener>, // Where connections come from, one accept loop each
+}
+
+// One of the kinds of listener a `Server` can accept connections on.
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Server {
+    /// Create a server without any listeners.
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Accept connections on a TCP listener as well.
+    pub fn listener(mut self, listener: TcpListener) -> Server {
+        self.listeners.push(Listener::Tcp(listener));
+        self
+    }
+
+    /// Accept connections on a Unix domain socket as well.
+    #[cfg(unix)]
+    pub fn unix_listener(mut self, listener: UnixListener) -> Server {
+        self.listeners.push(Listener::Unix(listener));
+        self
+    }
+
+    /// Accept connections on every listener and serve them on `pool` with
+    /// `handler`.
+    ///
+    /// This returns once every accept loop has stopped, which they all do
+    /// when `Config::shutdown` is triggered. Connections still open then
+    /// are left to finish on the pool; see `shutdown_graceful` for waiting
+    /// on them.
+    pub fn run<H>(&self, pool: &ThreadPool, config: Arc<SharedConfig>, handler: H)
+    where
+        H: Handler + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        thread::scope(|scope| {
+            for listener in &self.listeners {
+                let (config, handler) = (&config, &handler);
+                scope.spawn(move || match listener {
+                    Listener::Tcp(listener) => accept_loop(listener, pool, config, handler),
+                    #[cfg(unix)]
+                    Listener::Unix(listener) => accept_loop(listener, pool, config, handler),
+                });
+            }
+        });
+    }
+
+    /// Shut the server down for good, giving open connections up to
+    /// `timeout` to finish.
+    ///
+    /// This triggers `Config::shutdown` if nothing has yet, so `run` stops
+    /// accepting and idle connections close, and closes the listeners.
+    /// Connections in the middle of a request, or still waiting in the
+    /// pool's queue, get until the timeout to answer it; any still open then
+    /// are cut off, so their reads and writes fail. Finally the pool is shut
+    /// down, which waits for their jobs to notice, within the pool's own
+    /// `shutdown_timeout`.
+    ///
+    /// For a rolling deploy, call this once `run` has returned, for
+    /// instance after `signal::shut_down_on_terminate` triggered the
+    /// shutdown, as the binary does on Unix:
+    ///
+    /// ```no_run
+    /// use std::{net::TcpListener, sync::Arc, time::Duration};
+    /// use web_server::prelude::*;
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// let config = Arc::new(SharedConfig::new(Config::default()));
+    /// let server = Server::new().listener(TcpListener::bind("0.0.0.0:8080").unwrap());
+    ///
+    /// // Returns once something, e.g. a signal, triggers shutdown.
+    /// server.run(&pool, Arc::clone(&config), |_request: &Request| Response::new(204));
+    ///
+    /// let report = server.shutdown_graceful(pool, &config, Duration::from_secs(30));
+    /// println!("{} connections were cut off", report.forced_connections);
+    /// ```
+    pub fn shutdown_graceful(
+        self,
+        pool: ThreadPool,
+        config: &SharedConfig,
+        timeout: Duration,
+    ) -> DrainReport {
+        let shutdown = config.load().shutdown.clone();
+        shutdown.trigger();
+        drop(self.listeners);
+
+        let forced_connections = shutdown.wait_for_connections(timeout);
+        if forced_connections > 0 {
+            println!(
+                "{forced_connections} connections still open after {timeout:?}; closing them."
+            );
+            shutdown.close_connections();
+        }
+
+        DrainReport {
+            forced_connections,
+            pool: pool.shutdown(),
+        }
+    }
+}
+
+/// The outcome of `Server::shutdown_graceful`.
+#[derive(Debug)]
+pub struct DrainReport {
+    pub forced_connections: usize, // Connections still open at the timeout, which were cut off
+    pub pool: ShutdownReport,      // How the pool's workers exited
+}
+
+// A listener the accept loop can take connections from.
+trait Accept {
+    type Stream: Stream;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+
+    // Something that makes a blocked accept return, so the accept loop can
+    // stop on shutdown.
+    fn waker(&self) -> Option<Waker>;
+}
+
+impl Accept for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    // Connecting to the listener is what wakes it, unless it shares its
+    // port with others, in which case the kernel may hand the connection to
+    // any of them. Shutting its socket down makes its accept fail instead.
+    fn waker(&self) -> Option<Waker> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if SockRef::from(self).reuse_port().unwrap_or(false) {
+            let listener = self.try_clone().ok()?;
+            return Some(Arc::new(move || {
+                let _ = SockRef::from(&listener).shutdown(Shutdown::Both);
+            }));
+        }
+
+        let mut addr = self.local_addr().ok()?;
+
+        // A listener on every interface can be reached on loopback.
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+
+        Some(Arc::new(move || {
+            let _ = TcpStream::connect(addr);
+        }))
+    }
+}
+
+#[cfg(unix)]
+impl Accept for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let path = self.local_addr().ok()?.as_pathname()?.to_path_buf();
+
+        Some(Arc::new(move || {
+            let _ = UnixStream::connect(&path);
+        }))
+    }
+}
+
+// A stream the accept loop can hand to `connection::handle_connection_from`.
+trait Stream: Read + Write + Send + 'static {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // The client's IP address, for the access log.
+    fn remote_ip(&self) -> Option<IpAddr>;
+
+    // Something that makes a read or write blocked on this stream return,
+    // so a connection can be closed on shutdown.
+    fn waker(&self) -> Option<Waker>;
+}
+
+impl Stream for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn remote_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    // Shutting the socket down makes a blocked read see end of stream and
+    // a blocked write fail.
+    fn waker(&self) -> Option<Waker> {
+        let stream = self.try_clone().ok()?;
+        Some(Arc::new(move || {
+            let _ = stream.shutdown(Shutdown::Both);
+        }))
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    // Local clients have no IP address.
+    fn remote_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        let stream = self.try_clone().ok()?;
+        Some(Arc::new(move || {
+            let _ = stream.shutdown(Shutdown::Both);
+        }))
+    }
+}
+
+// Serve every connection `listener` accepts, whatever kind of socket it is,
+// until shutdown begins.
+fn accept_loop<L, H>(listener: &L, pool: &ThreadPool, config: &Arc<SharedConfig>, handler: &Arc<H>)
+where
+    L: Accept,
+    H: Handler + 'static,
+{
+    let shutdown = config.load().shutdown.clone();
+    let waker = listener.waker();
+    let _watch = waker.as_ref().map(|waker| shutdown.watch_idle(waker));
+    let mut rate = AcceptRate::new();
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        // Past the accept rate, new connections wait in the listen backlog
+        // rather than piling onto the pool. If a flood fills the backlog,
+        // the OS turns away the rest before they cost a thread anything.
+        if let Some(max) = config.load().max_accept_rate {
+            rate.wait(max);
+        }
+
+        let stream = listener.accept_stream();
+
+        // Whatever woke us once shutdown has begun, the connection that
+        // came with it is dropped unserved.
+        if shutdown.is_triggered() {
+            break;
+        }
+
+        let stream = match stream {
+            Ok(stream) => {
+                backoff = ACCEPT_BACKOFF_MIN;
+                stream
+            }
+            Err(err) => match AcceptError::classify(&err) {
+                AcceptError::Interrupted => continue,
+                AcceptError::Connection => {
+                    trace::debug("connection failed before it was accepted", &err);
+                    continue;
+                }
+                AcceptError::Exhausted => {
+                    // Every accept would fail the same way until something
+                    // frees up, so wait rather than spin, a little longer
+                    // each time.
+                    println!("Failed to accept a connection: {err}; retrying in {backoff:?}.");
+                    trace::error("failed to accept a connection", &err);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                    continue;
+                }
+                AcceptError::Fatal => {
+                    println!("The listener failed: {err}; no longer accepting connections.");
+                    trace::error("the listener failed", &err);
+                    break;
+                }
+            },
+        };
+
+        // A connection past the limit is turned away straight away. The
+        // answer is small enough to fit in the socket's send buffer, so
+        // writing it doesn't hold up the accept loop.
+        let current = config.load();
+        let Some(open) = current.connections.open(current.max_connections) else {
+            let mut stream = stream;
+            let response = error_page::render(None, 503).with_header("Connection", "close");
+            if let Err(err) = response.write_to(&mut stream) {
+                trace::debug("failed to turn away a connection", &err);
+            }
+            continue;
+        };
+
+        // An idle connection is closed once the keep-alive timeout passes
+        // without a new request arriving.
+        if let Err(err) = stream.set_read_timeout(Some(config.load().keep_alive_timeout)) {
+            println!("Failed to set a read timeout: {err}");
+            trace::error("failed to set a read timeout", &err);
+            continue;
+        }
+
+        let config = Arc::clone(config);
+        let handler = Arc::clone(handler);
+
+        let peer = Peer {
+            remote_ip: stream.remote_ip(),
+            waker: stream.waker(),
+        };
+
+        // The connection counts as open from now on, even while it waits in
+        // the queue, so a graceful shutdown waits for it and can cut it off.
+        let tracked = shutdown.track_connection(peer.waker.clone());
+        pool.execute(move || {
+            connection::serve_connection(stream, &peer, &config, &*handler);
+            drop((tracked, open));
+        });
+    }
+}
+
+// How long the accept loop first waits after running out of file
+// descriptors or memory, and the most it waits as that goes on.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// Raw error codes for running out of file descriptors, which have no
+// `io::ErrorKind` of their own. They are the same on every Unix.
+#[cfg(unix)]
+const FD_EXHAUSTED: [i32; 2] = [
+    24, // EMFILE: the process's limit
+    23, // ENFILE: the system's limit
+];
+#[cfg(windows)]
+const FD_EXHAUSTED: [i32; 2] = [
+    10024, // WSAEMFILE
+    10055, // WSAENOBUFS
+];
+#[cfg(not(any(unix, windows)))]
+const FD_EXHAUSTED: [i32; 0] = [];
+
+// A closed or otherwise invalid listener descriptor, EBADF.
+#[cfg(unix)]
+const BAD_DESCRIPTOR: Option<i32> = Some(9);
+#[cfg(not(unix))]
+const BAD_DESCRIPTOR: Option<i32> = None;
+
+// What a failed accept means for the accept loop.
+enum AcceptError {
+    Interrupted, // A signal interrupted the call; just try again
+    Connection,  // Only the connection being accepted failed, e.g. the client reset it
+    Exhausted,   // Out of file descriptors or memory, or an unknown failure; back off
+    Fatal,       // The listener itself is unusable; stop accepting
+}
+
+impl AcceptError {
+    fn classify(err: &io::Error) -> AcceptError {
+        let raw = err.raw_os_error();
+        if raw.is_some_and(|code| FD_EXHAUSTED.contains(&code)) {
+            return AcceptError::Exhausted;
+        }
+        if raw.is_some() && raw == BAD_DESCRIPTOR {
+            return AcceptError::Fatal;
+        }
+
+        match err.kind() {
+            io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => AcceptError::Interrupted,
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::PermissionDenied => AcceptError::Connection,
+            io::ErrorKind::InvalidInput | io::ErrorKind::NotConnected => AcceptError::Fatal,
+            // Including `OutOfMemory`, for ENOMEM and ENOBUFS.
+            _ => AcceptError::Exhausted,
+        }
+    }
+}
+
+// A token bucket pacing an accept loop. It holds up to a second's worth of
+// connections, so a burst within the rate is accepted at once.
+struct AcceptRate {
+    tokens: f64,     // Connections that may be accepted right away
+    filled: Instant, // When `tokens` was last topped up
+}
+
+impl AcceptRate {
+    fn new() -> AcceptRate {
+        AcceptRate {
+            tokens: f64::INFINITY,
+            filled: Instant::now(),
+        }
+    }
+
+    // Take a token for the next connection, sleeping until one is free if
+    // `max` per second have already been accepted.
+    fn wait(&mut self, max: NonZeroU32) {
+        let max = f64::from(max.get());
+        let now = Instant::now();
+        let refill = now.duration_since(self.filled).as_secs_f64() * max;
+        self.tokens = (self.tokens + refill).min(max);
+        self.filled = now;
+
+        if self.tokens < 1.0 {
+            thread::sleep(Duration::from_secs_f64((1.0 - self.tokens) / max));
+            self.tokens = 1.0;
+            self.filled = Instant::now();
+        }
+        self.tokens -= 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::Ipv4Addr,
+        panic::{self, AssertUnwindSafe},
+        sync::Mutex,
+    };
+
+    use super::*;
+    use crate::{config::Config, listener, request::Request, response::Response};
+
+    // Run `server` with `handler` while `client` talks to it, then shut it
+    // down and wait for its accept loops to stop.
+    fn with_server<H, T>(
+        server: Server,
+        config: &Arc<SharedConfig>,
+        handler: H,
+        client: impl FnOnce() -> T,
+    ) -> T
+    where
+        H: Handler + 'static,
+    {
+        let pool = ThreadPool::new(2);
+        thread::scope(|scope| {
+            scope.spawn(|| server.run(&pool, Arc::clone(config), handler));
+            // Even a client that fails an assertion stops the server, or
+            // the scope would wait for it forever.
+            let result = panic::catch_unwind(AssertUnwindSafe(client));
+            config.load().shutdown.trigger();
+            result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+        })
+    }
+
+    // Send `request` on `stream` and read everything that comes back.
+    fn fetch(mut stream: impl Read + Write, request: &str) -> String {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn hello(_: &Request) -> Response {
+        Response::new(200).with_body("hello")
+    }
+
+    const GET_AND_CLOSE: &str = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+    #[cfg(unix)]
+    #[test]
+    fn serves_over_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("web_server-{}.sock", std::process::id()));
+        let listener = listener::bind_unix(&path, 8).unwrap();
+        let config = Arc::new(SharedConfig::new(Config::default()));
+
+        let response = with_server(
+            Server::new().unix_listener(listener),
+            &config,
+            hello,
+            || fetch(UnixStream::connect(&path).unwrap(), GET_AND_CLOSE),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+
+        // The socket file outlives the listener, and is cleaned up as stale
+        // by the next bind.
+        assert!(path.exists());
+        drop(listener::bind_unix(&path, 8).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn serves_over_tcp() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config::default()));
+
+        let response = with_server(Server::new().listener(listener), &config, hello, || {
+            fetch(TcpStream::connect(addr).unwrap(), GET_AND_CLOSE)
+        });
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+    }
+
+    #[test]
+    fn shutdown_closes_an_idle_keep_alive_connection() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config::default()));
+        let pool = ThreadPool::new(1);
+        let server = Server::new().listener(listener);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| server.run(&pool, Arc::clone(&config), hello));
+
+            // The connection is left idle after its first response.
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = Vec::new();
+            while !response.ends_with(b"hello") {
+                let mut buf = [0; 256];
+                let read = stream.read(&mut buf).unwrap();
+                assert!(read > 0, "closed before the response ended");
+                response.extend_from_slice(&buf[..read]);
+            }
+
+            // Long before the keep-alive timeout, the server hangs up.
+            config.load().shutdown.trigger();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+        });
+
+        let report = server.shutdown_graceful(pool, &config, Duration::from_secs(5));
+        assert_eq!(report.forced_connections, 0);
+        assert!(report.pool.is_clean(), "{:?}", report.pool);
+    }
+
+    #[test]
+    fn every_listener_is_served_on_the_same_pool() {
+        let first = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let second = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addrs = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+        let config = Arc::new(SharedConfig::new(Config::default()));
+        let pool = ThreadPool::new(1);
+        let server = Server::new().listener(first).listener(second);
+
+        // The pool has one worker, so both answers come from the same thread.
+        let which_thread =
+            |_: &Request| Response::new(200).with_body(format!("{:?}", thread::current().id()));
+        let answers = thread::scope(|scope| {
+            scope.spawn(|| server.run(&pool, Arc::clone(&config), which_thread));
+            let answers = addrs.map(|addr| fetch(TcpStream::connect(addr).unwrap(), GET_AND_CLOSE));
+            config.load().shutdown.trigger();
+            answers
+        });
+
+        let threads = answers.map(|answer| answer.split("\r\n\r\n").nth(1).unwrap().to_string());
+        assert_eq!(threads[0], threads[1]);
+        assert_ne!(threads[0], format!("{:?}", thread::current().id()));
+        assert_eq!(pool.shutdown().workers().len(), 1);
+    }
+
+    #[test]
+    fn the_accept_rate_lets_a_burst_through_then_paces_the_rest() {
+        let max = NonZeroU32::new(50).unwrap();
+        let mut rate = AcceptRate::new();
+        let start = Instant::now();
+        for _ in 0..50 {
+            rate.wait(max);
+        }
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        // Past the burst, each connection waits for its share of a second.
+        let start = Instant::now();
+        for _ in 0..5 {
+            rate.wait(max);
+        }
+        assert!(
+            start.elapsed() >= Duration::from_millis(80),
+            "{:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn a_client_closing_its_write_half_is_answered_and_hung_up_on() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config::default()));
+
+        let response = with_server(Server::new().listener(listener), &config, hello, || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+
+            // The server answers, sees no more requests are coming, and
+            // closes its side well before the keep-alive timeout.
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+    }
+
+    #[test]
+    fn a_graceful_shutdown_counts_and_cuts_off_connections_still_open() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config::default()));
+        let pool = ThreadPool::new(1);
+        let server = Server::new().listener(listener);
+        let slow = |_: &Request| {
+            thread::sleep(Duration::from_millis(300));
+            Response::new(200)
+        };
+
+        // The one worker is busy with the first request, so the second
+        // connection is still waiting in the queue when time runs out.
+        let clients: Vec<_> = thread::scope(|scope| {
+            scope.spawn(|| server.run(&pool, Arc::clone(&config), slow));
+            let clients = (0..2)
+                .map(|_| {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream.write_all(GET_AND_CLOSE.as_bytes()).unwrap();
+                    stream
+                })
+                .collect();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while config.load().shutdown.active_connections() < 2 {
+                assert!(Instant::now() < deadline, "connections weren't accepted");
+                thread::sleep(Duration::from_millis(1));
+            }
+            config.load().shutdown.trigger();
+            clients
+        });
+
+        let started = Instant::now();
+        let report = server.shutdown_graceful(pool, &config, Duration::from_millis(50));
+        assert_eq!(report.forced_connections, 2);
+        assert!(report.pool.is_clean(), "{:?}", report.pool);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(config.load().shutdown.active_connections(), 0);
+
+        // Neither client got an answer.
+        for mut client in clients {
+            let mut response = Vec::new();
+            let _ = client.read_to_end(&mut response);
+            assert!(
+                response.is_empty(),
+                "{}",
+                String::from_utf8_lossy(&response)
+            );
+        }
+    }
+
+    // A listener that fails or succeeds as scripted, then fails for good.
+    struct Scripted(Mutex<VecDeque<io::Result<TcpStream>>>);
+
+    impl Accept for Scripted {
+        type Stream = TcpStream;
+
+        fn accept_stream(&self) -> io::Result<TcpStream> {
+            let next = self.0.lock().unwrap().pop_front();
+            next.unwrap_or_else(|| Err(io::ErrorKind::InvalidInput.into()))
+        }
+
+        fn waker(&self) -> Option<Waker> {
+            None
+        }
+    }
+
+    #[test]
+    fn accept_errors_are_classified_by_what_they_mean_for_the_listener() {
+        let classify = |err: io::Error| AcceptError::classify(&err);
+        assert!(matches!(
+            classify(io::ErrorKind::Interrupted.into()),
+            AcceptError::Interrupted
+        ));
+        assert!(matches!(
+            classify(io::ErrorKind::ConnectionAborted.into()),
+            AcceptError::Connection
+        ));
+        assert!(matches!(
+            classify(io::ErrorKind::OutOfMemory.into()),
+            AcceptError::Exhausted
+        ));
+        assert!(matches!(
+            classify(io::ErrorKind::InvalidInput.into()),
+            AcceptError::Fatal
+        ));
+        #[cfg(unix)]
+        {
+            assert!(matches!(
+                classify(io::Error::from_raw_os_error(FD_EXHAUSTED[0])),
+                AcceptError::Exhausted
+            ));
+            assert!(matches!(
+                classify(io::Error::from_raw_os_error(9)),
+                AcceptError::Fatal
+            ));
+        }
+    }
+
+    #[test]
+    fn the_accept_loop_serves_on_after_transient_errors_and_backs_off_when_exhausted() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+
+        let exhausted = || io::Error::from(io::ErrorKind::OutOfMemory);
+        let scripted = Scripted(Mutex::new(VecDeque::from([
+            Err(io::ErrorKind::Interrupted.into()),
+            Err(io::ErrorKind::ConnectionAborted.into()),
+            Err(exhausted()),
+            Err(exhausted()),
+            Ok(accepted),
+        ])));
+        let config = Arc::new(SharedConfig::new(Config::default()));
+        let pool = ThreadPool::new(1);
+
+        // The loop only stops on the fatal error after the script.
+        let started = Instant::now();
+        accept_loop(&scripted, &pool, &config, &Arc::new(hello));
+        let waited = started.elapsed();
+        assert!(
+            waited >= ACCEPT_BACKOFF_MIN * 3,
+            "backed off {waited:?}, not once and then twice as long"
+        );
+
+        let response = fetch(&mut client, GET_AND_CLOSE);
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+    }
+
+    // Send a keep-alive request on `stream` and read the response to it.
+    fn fetch_keep_alive(stream: &mut TcpStream) -> String {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"hello") {
+            let mut buf = [0; 256];
+            let read = stream.read(&mut buf).unwrap();
+            assert!(read > 0, "closed before the response ended");
+            response.extend_from_slice(&buf[..read]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn past_the_idle_cap_the_connection_idle_longest_is_closed() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config {
+            max_idle_connections: Some(1),
+            ..Config::default()
+        }));
+
+        with_server(Server::new().listener(listener), &config, hello, || {
+            let mut first = TcpStream::connect(addr).unwrap();
+            first
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let response = fetch_keep_alive(&mut first);
+            assert!(response.contains("Connection: keep-alive"), "{response}");
+            let connections = config.load().connections.clone();
+            while connections.idle_connections() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            // The second connection going idle pushes the first one out.
+            let mut second = TcpStream::connect(addr).unwrap();
+            let response = fetch_keep_alive(&mut second);
+            assert!(response.contains("Connection: keep-alive"), "{response}");
+            assert_eq!(first.read(&mut [0; 16]).unwrap(), 0);
+            // Which leaves the second open and idle in its place.
+            while connections.open_connections() > 1 || connections.idle_connections() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+    }
+
+    #[test]
+    fn connections_past_the_limit_are_turned_away_with_a_503() {
+        let listener = listener::bind((Ipv4Addr::LOCALHOST, 0).into(), 8).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(SharedConfig::new(Config {
+            max_connections: Some(1),
+            ..Config::default()
+        }));
+
+        with_server(Server::new().listener(listener), &config, hello, || {
+            let mut first = TcpStream::connect(addr).unwrap();
+            fetch_keep_alive(&mut first);
+
+            // The answer comes without waiting for a request, which the
+            // server would never read.
+            let mut response = String::new();
+            let mut rejected = TcpStream::connect(addr).unwrap();
+            rejected.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+            assert!(response.contains("Connection: close"), "{response}");
+
+            // Once the first closes, there is room again.
+            drop(first);
+            let connections = config.load().connections.clone();
+            while connections.open_connections() > 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            let response = fetch(TcpStream::connect(addr).unwrap(), GET_AND_CLOSE);
+            assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn reuseport_listeners_all_serve_and_all_stop_on_shutdown() {
+        let listeners = listener::bind_reuseport((Ipv4Addr::LOCALHOST, 0).into(), 8, 3).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        let server = listeners.into_iter().fold(Server::new(), Server::listener);
+        let config = Arc::new(SharedConfig::new(Config::default()));
+
+        // `with_server` returning at all means every accept loop woke up,
+        // however the connections were spread.
+        let responses = with_server(server, &config, hello, || {
+            (0..12)
+                .map(|_| fetch(TcpStream::connect(addr).unwrap(), GET_AND_CLOSE))
+                .collect::<Vec<_>>()
+        });
+        for response in &responses {
+            assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Interrupts a read or write that is blocked on a connection, for instance
+/// by shutting down its socket.
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+/// Tells the connections being served that the server is shutting down.
+///
+/// Once the token is triggered, a connection finishes the request it is
+/// serving, answers it with `Connection: close` and stops instead of waiting
+/// for another one. Connections idling between requests are woken through
+/// their `Waker` and closed straight away, so their workers are free to be
+/// joined, and the server's accept loops stop too. Clones share the same
+/// token.
+///
+/// The token also keeps count of the connections being served, so a
+/// graceful shutdown can wait for them and close those that overstay.
+#[derive(Clone, Default)]
+pub struct ShutdownToken {
+    shared: Arc<Shared>, // Shared by every clone of the token
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<TokenState>, // Whether it was triggered, and who to wake then
+    closed: Condvar,          // Signalled whenever a connection closes
+}
+
+#[derive(Default)]
+struct TokenState {
+    triggered: bool,                            // Whether shutdown has begun
+    next_id: usize,                             // Id for the next registration
+    idle: HashMap<usize, Waker>,                // Wakers of whatever is waiting to be interrupted
+    connections: HashMap<usize, Option<Waker>>, // Open connections, and how to close them
+}
+
+impl ShutdownToken {
+    /// Create a token that hasn't been triggered.
+    pub fn new() -> ShutdownToken {
+        ShutdownToken::default()
+    }
+
+    /// Begin shutting down, waking every idle connection.
+    pub fn trigger(&self) {
+        let idle = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.triggered = true;
+            mem::take(&mut state.idle)
+        };
+
+        // Wake outside the lock, in case a waker blocks for a moment.
+        for waker in idle.into_values() {
+            waker();
+        }
+    }
+
+    /// Whether shutdown has begun.
+    pub fn is_triggered(&self) -> bool {
+        self.shared.state.lock().unwrap().triggered
+    }
+
+    /// Have `waker` called if shutdown begins while the returned guard is
+    /// alive, which is meant to be while a connection waits for its next
+    /// request or a listener for its next connection. If shutdown has
+    /// already begun, it is called right away.
+    pub fn watch_idle(&self, waker: &Waker) -> IdleGuard<'_> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.triggered {
+            drop(state);
+            waker();
+            return IdleGuard {
+                token: self,
+                id: None,
+            };
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.idle.insert(id, Arc::clone(waker));
+
+        IdleGuard {
+            token: self,
+            id: Some(id),
+        }
+    }
+
+    /// The number of connections being served.
+    pub fn active_connections(&self) -> usize {
+        self.shared.state.lock().unwrap().connections.len()
+    }
+
+    // Count a connection as open until the returned guard is dropped.
+    // `closer` is what `close_connections` calls to cut it off.
+    pub(crate) fn track_connection(&self, closer: Option<Waker>) -> ConnectionGuard {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.connections.insert(id, closer);
+
+        ConnectionGuard {
+            token: self.clone(),
+            id,
+        }
+    }
+
+    // Wait up to `timeout` for every connection to close, returning how
+    // many are still open.
+    pub(crate) fn wait_for_connections(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        while !state.connections.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            state = self.shared.closed.wait_timeout(state, left).unwrap().0;
+        }
+        state.connections.len()
+    }
+
+    // Cut off every open connection, so reads and writes on it fail.
+    pub(crate) fn close_connections(&self) {
+        let closers: Vec<Waker> = {
+            let state = self.shared.state.lock().unwrap();
+            state.connections.values().flatten().cloned().collect()
+        };
+
+        // Close outside the lock, like `trigger` wakes.
+        for closer in closers {
+            closer();
+        }
+    }
+}
+
+/// Keeps a waker registered with a `ShutdownToken` until it is dropped.
+pub struct IdleGuard<'a> {
+    token: &'a ShutdownToken, // The token the connection is registered with
+    id: Option<usize>,        // The registration, unless it was woken at once
+}
+
+impl Drop for IdleGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.token.shared.state.lock().unwrap().idle.remove(&id);
+        }
+    }
+}
+
+// Counts a connection as open until it is dropped. It owns its token, so
+// it can travel with a connection waiting in the pool's queue.
+pub(crate) struct ConnectionGuard {
+    token: ShutdownToken, // The token counting the connection
+    id: usize,            // The connection's registration
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.token.shared.state.lock().unwrap();
+        state.connections.remove(&self.id);
+        self.token.shared.closed.notify_all();
+    }
+}
@@ -0,0 +1,107 @@
+//! Unix signal handling.
+
+use std::{io, sync::Arc, thread};
+
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+use crate::{
+    config::{Config, SharedConfig},
+    shutdown::ShutdownToken,
+};
+
+/// Reload the configuration whenever the process receives `SIGHUP`.
+///
+/// A background thread waits for the signal, calls `load` to build the new
+/// configuration and swaps it into `shared` (see `SharedConfig::reload` for
+/// which settings take effect). In-flight requests keep the configuration
+/// they started with. If `load` fails, the current configuration stays.
+pub fn reload_on_hangup<F>(shared: Arc<SharedConfig>, load: F) -> io::Result<()>
+where
+    F: Fn() -> io::Result<Config> + Send + 'static,
+{
+    let mut signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match load() {
+                Ok(config) => {
+                    shared.reload(config);
+                    println!("Reloaded configuration.");
+                }
+                Err(err) => println!("Failed to reload configuration: {err}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Trigger `shutdown` when the process receives `SIGINT` or `SIGTERM`.
+///
+/// A background thread waits for either signal and triggers the token, so
+/// the accept loops watching it stop and `Server::run` returns, ready for
+/// `Server::shutdown_graceful`. A second signal, from someone tired of
+/// waiting for that, exits the process straight away.
+pub fn shut_down_on_terminate(shutdown: ShutdownToken) -> io::Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if signals.next().is_some() {
+            println!("Shutting down; signal again to exit now.");
+            shutdown.trigger();
+        }
+        if signals.next().is_some() {
+            std::process::exit(1);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use signal_hook::low_level::raise;
+
+    use super::*;
+
+    #[test]
+    fn a_hangup_reloads_the_configuration() {
+        let shared = Arc::new(SharedConfig::new(Config::default()));
+        reload_on_hangup(Arc::clone(&shared), || {
+            Ok(Config {
+                charset: String::from("latin1"),
+                ..Config::default()
+            })
+        })
+        .unwrap();
+
+        raise(SIGHUP).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while shared.load().charset != "latin1" {
+            assert!(
+                Instant::now() < deadline,
+                "the configuration wasn't reloaded"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn a_terminate_triggers_the_shutdown() {
+        let shutdown = ShutdownToken::new();
+        shut_down_on_terminate(shutdown.clone()).unwrap();
+
+        raise(SIGTERM).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !shutdown.is_triggered() {
+            assert!(Instant::now() < deadline, "the shutdown wasn't triggered");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Serves a single-page application, whose client-side router owns paths
+//! the server has no files for.
+
+use std::path::PathBuf;
+
+use crate::{
+    error_page, handler::Handler, method::Method, request::Request, response::Response,
+    static_files::StaticFiles,
+};
+
+/// Serves an app's files, and its `index.html` for any other page.
+///
+/// A request naming a file under the root gets that file. Any other
+/// request for a page, such as `/some/app/route`, gets the index file, so
+/// the app can route it in the browser. Requests that look like they were
+/// for an asset (their last segment has an extension, or they are under one
+/// of the asset prefixes) still answer 404, so a missing script shows up
+/// as missing rather than as a page of HTML. Only `GET` and `HEAD` are
+/// answered, and other methods answer 405.
+///
+/// It can serve a whole site, or be a `Router`'s fallback behind the
+/// server's own routes:
+///
+/// ```no_run
+/// use web_server::{prelude::*, spa::SpaFallback};
+///
+/// let router = Router::new()
+///     .route("GET", "/api/status", |_request: &Request| Response::new(204))
+///     .fallback(SpaFallback::new("./dist").asset_prefix("/assets/"));
+/// ```
+pub struct SpaFallback {
+    files: StaticFiles,          // Where the app's files are served from
+    index: String,               // The page served for the app's own routes
+    asset_prefixes: Vec<String>, // Paths under which everything is an asset
+}
+
+impl SpaFallback {
+    /// Serve the app in `root`, with `index.html` as its page.
+    pub fn new(root: impl Into<PathBuf>) -> SpaFallback {
+        SpaFallback {
+            files: StaticFiles::new(root),
+            index: String::from("index.html"),
+            asset_prefixes: Vec::new(),
+        }
+    }
+
+    /// Serve `index`, relative to the root, for the app's routes.
+    pub fn index(mut self, index: impl Into<String>) -> SpaFallback {
+        self.index = index.into();
+        self
+    }
+
+    /// Treat every path starting with `prefix`, such as `/assets/`, as an
+    /// asset, so a missing one answers 404 even without an extension.
+    pub fn asset_prefix(mut self, prefix: impl Into<String>) -> SpaFallback {
+        self.asset_prefixes.push(prefix.into());
+        self
+    }
+
+    /// Set the charset advertised in the `Content-Type` of text files.
+    pub fn with_charset(mut self, charset: impl Into<String>) -> SpaFallback {
+        self.files = self.files.with_charset(charset);
+        self
+    }
+
+    // Whether `path` asks for an asset rather than a page of the app.
+    fn is_asset(&self, path: &str) -> bool {
+        let last = path.rsplit('/').next().unwrap_or("");
+        last.contains('.')
+            || self
+                .asset_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+}
+
+impl Handler for SpaFallback {
+    fn handle(&self, request: &Request) -> Response {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return error_page::render(Some(request), 405).with_header("Allow", "GET, HEAD");
+        }
+
+        if let Some(response) = self.files.serve(request) {
+            return response;
+        }
+        if self.is_asset(request.path()) {
+            return error_page::render(Some(request), 404);
+        }
+
+        self.files
+            .serve_file(request, &self.index)
+            .unwrap_or_else(|| error_page::render(Some(request), 404))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::request::Limits;
+
+    // A temporary app with an index page and one script.
+    fn app() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "web_server-spa-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), b"<app>").unwrap();
+        fs::write(root.join("app.js"), b"run()").unwrap();
+        root
+    }
+
+    fn request(method: &str, path: &str) -> Request {
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn app_routes_get_the_index_but_missing_assets_are_404() {
+        let root = app();
+        let spa = SpaFallback::new(&root).asset_prefix("/assets/");
+
+        let page = spa.handle(&request("GET", "/some/app/route"));
+        assert_eq!(page.status(), 200);
+        assert_eq!(page.body(), b"<app>");
+        assert_eq!(spa.handle(&request("GET", "/app.js")).body(), b"run()");
+
+        for path in ["/missing.js", "/assets/logo", "/some/route/style.css"] {
+            assert_eq!(spa.handle(&request("GET", path)).status(), 404, "{path}");
+        }
+        assert_eq!(
+            spa.handle(&request("POST", "/some/app/route")).status(),
+            405
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,737 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    config::Config, error_page, http_date, method::Method, mime, request::Request,
+    response::Response, uri,
+};
+
+/// Serves files from a directory on disk.
+///
+/// Responses carry `ETag` and `Last-Modified` validators and honour single
+/// byte ranges (`Range`), optionally guarded by `If-Range`, and a
+/// `Cache-Control` header if one is configured for the file. Directories
+/// can be listed, but aren't by default.
+pub struct StaticFiles {
+    root: PathBuf,                      // Directory that request paths are resolved against
+    charset: String,                    // Charset advertised for text files
+    path_mapper: Option<PathMapper>,    // Rewrites request paths; `None` uses them as they are
+    cache_control: Option<String>,      // Sent with files no rule matches; `None` sends none
+    cache_rules: Vec<(String, String)>, // File name patterns and what they send instead
+    list_directories: bool,             // Whether a request for a directory lists it
+}
+
+// Turns a decoded request path into the path of a file under the root.
+type PathMapper = Box<dyn Fn(&str) -> Option<PathBuf> + Send + Sync>;
+
+// The outcome of evaluating a `Range` header against a file's length.
+enum ByteRange {
+    Ignore,                // No usable range; serve the whole file
+    Satisfiable(u64, u64), // Inclusive start and end offsets
+    Unsatisfiable,         // The range lies entirely outside the file
+}
+
+impl StaticFiles {
+    /// Create a handler serving files from `root`.
+    ///
+    /// Text files are served with `charset=utf-8` unless `with_charset`
+    /// says otherwise.
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: root.into(),
+            charset: String::from("utf-8"),
+            path_mapper: None,
+            cache_control: None,
+            cache_rules: Vec::new(),
+            list_directories: false,
+        }
+    }
+
+    /// Create a handler serving `config.static_root` with the charset and
+    /// caching settings in `config`.
+    pub fn from_config(config: &Config) -> StaticFiles {
+        StaticFiles {
+            cache_control: config.cache_control.clone(),
+            cache_rules: config.cache_rules.clone(),
+            ..StaticFiles::new(&config.static_root).with_charset(&config.charset)
+        }
+    }
+
+    /// Set the charset advertised in the `Content-Type` of text files.
+    pub fn with_charset(mut self, charset: impl Into<String>) -> StaticFiles {
+        self.charset = charset.into();
+        self
+    }
+
+    /// Send `value` as the `Cache-Control` header of every file served,
+    /// unless a cache rule matches it.
+    ///
+    /// Only files actually served get it; a 404, 405 or 416 doesn't.
+    pub fn with_cache_control(mut self, value: impl Into<String>) -> StaticFiles {
+        self.cache_control = Some(value.into());
+        self
+    }
+
+    /// Send `value` as the `Cache-Control` header of files whose name
+    /// matches `pattern`, instead of the default.
+    ///
+    /// In the pattern, `*` stands for any run of characters and `?` for
+    /// any one, and the rest must match exactly, e.g. `*.woff2`. Only the
+    /// file name is matched, not the directories leading to it. Where
+    /// several rules match, the one added first wins.
+    ///
+    /// ```
+    /// use web_server::static_files::StaticFiles;
+    ///
+    /// // Fingerprinted bundles such as `app.3f9a1c.js` never change.
+    /// let files = StaticFiles::new("./public")
+    ///     .with_cache_control("public, max-age=3600")
+    ///     .with_cache_rule("*.*.js", "public, max-age=31536000, immutable");
+    /// ```
+    pub fn with_cache_rule(mut self, pattern: &str, value: impl Into<String>) -> StaticFiles {
+        self.cache_rules.push((pattern.to_string(), value.into()));
+        self
+    }
+
+    /// Rewrite request paths with `mapper` before they are looked up.
+    ///
+    /// `serve` passes the mapper the percent-decoded request path, and it
+    /// returns the path of the file to serve, relative to the root, or
+    /// `None` to answer as if there were no such file. Without a mapper the
+    /// request path is used as it is. What the mapper returns is checked
+    /// like a request path, so a mapping that would escape the root with
+    /// `..` finds nothing either. `serve_file` is given its path by the
+    /// caller and doesn't consult the mapper.
+    ///
+    /// ```
+    /// use std::path::PathBuf;
+    /// use web_server::static_files::StaticFiles;
+    ///
+    /// // Serve `/about` from `about.html`.
+    /// let files = StaticFiles::new("./public").with_path_mapper(|path| {
+    ///     let mut file = PathBuf::from(path);
+    ///     if !path.ends_with('/') && file.extension().is_none() {
+    ///         file.set_extension("html");
+    ///     }
+    ///     Some(file)
+    /// });
+    /// ```
+    pub fn with_path_mapper<F>(mut self, mapper: F) -> StaticFiles
+    where
+        F: Fn(&str) -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        self.path_mapper = Some(Box::new(mapper));
+        self
+    }
+
+    /// Answer a request for a directory, such as `/docs/`, with a page
+    /// linking to each file and subdirectory in it.
+    ///
+    /// Off by default, so the only files anyone can find are those linked
+    /// from elsewhere. Names are escaped in the page and percent-encoded in
+    /// its links, so any file name can be listed and followed.
+    pub fn with_directory_listing(mut self, list_directories: bool) -> StaticFiles {
+        self.list_directories = list_directories;
+        self
+    }
+
+    /// Serve the file named by the request path.
+    ///
+    /// The path is percent-decoded, then rewritten by the path mapper if
+    /// there is one, before it is resolved. A request for a directory
+    /// without a trailing slash, such as `/docs`, is redirected with a 301
+    /// to `/docs/`, so relative links inside it resolve against the
+    /// directory. With `with_directory_listing`, `/docs/` itself is
+    /// answered with a listing of the directory.
+    ///
+    /// Returns `None` if the path doesn't name a regular file under the
+    /// root, or a directory it may list.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        let path = uri::percent_decode(request.path())?;
+        let file_path = match &self.path_mapper {
+            Some(mapper) => self.resolve(&mapper(&path)?)?,
+            None => self.resolve(Path::new(&path))?,
+        };
+
+        if !path.ends_with('/') && file_path.is_dir() {
+            let mut location = uri::percent_encode_path(&format!("{path}/"));
+            if let Some(query) = request.query() {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Some(Response::redirect(301, &location));
+        }
+        if self.list_directories && path.ends_with('/') && file_path.is_dir() {
+            return self.list_directory(request, &path, &file_path);
+        }
+
+        self.serve_path(request, &file_path)
+    }
+
+    /// Serve `path`, resolved against the root, in response to `request`.
+    ///
+    /// Returns `None` if the path doesn't name a regular file under the root.
+    pub fn serve_file(&self, request: &Request, path: &str) -> Option<Response> {
+        let file_path = self.resolve(Path::new(path))?;
+        self.serve_path(request, &file_path)
+    }
+
+    // Serve the file at `file_path`, already resolved under the root.
+    fn serve_path(&self, request: &Request, file_path: &Path) -> Option<Response> {
+        let metadata = fs::metadata(file_path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Some(Response::new(405).with_header("Allow", "GET, HEAD"));
+        }
+
+        let contents = fs::read(file_path).ok()?;
+        let length = contents.len() as u64;
+
+        // Validators derived from the file's metadata.
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        let etag = format!("\"{modified:x}-{length:x}\"");
+        let last_modified = http_date::format(UNIX_EPOCH + Duration::from_secs(modified));
+
+        // A Range header is only honoured if the If-Range validator, when
+        // present, still matches the current version of the file. Otherwise
+        // the client gets the full file so it can start over.
+        let range = match request.header("Range") {
+            Some(range) => match request.header("If-Range") {
+                Some(validator) if !if_range_matches(validator, &etag, modified) => {
+                    ByteRange::Ignore
+                }
+                _ => parse_range(range, length),
+            },
+            None => ByteRange::Ignore,
+        };
+
+        let (mut response, body) = match range {
+            ByteRange::Ignore => (Response::new(200), contents),
+            ByteRange::Satisfiable(start, end) => {
+                let response = Response::new(206)
+                    .with_header("Content-Range", format!("bytes {start}-{end}/{length}"));
+                (response, contents[start as usize..=end as usize].to_vec())
+            }
+            ByteRange::Unsatisfiable => {
+                let response =
+                    Response::new(416).with_header("Content-Range", format!("bytes */{length}"));
+                return Some(response);
+            }
+        };
+
+        response.set_header(
+            "Content-Type",
+            mime::content_type(mime::from_path(file_path), &self.charset),
+        );
+        response.set_header("Accept-Ranges", "bytes");
+        response.set_header("ETag", etag);
+        response.set_header("Last-Modified", last_modified);
+        if let Some(cache_control) = self.cache_control_for(file_path) {
+            response.set_header("Cache-Control", cache_control);
+        }
+
+        // HEAD gets the same headers as GET, but no body.
+        if *request.method() == Method::Head {
+            return Some(response.with_header("Content-Length", body.len().to_string()));
+        }
+
+        Some(response.with_body(body))
+    }
+
+    // Answer a request for the directory `path`, found at `dir_path`, with a
+    // page linking to everything in it, in name order. Subdirectories get a
+    // trailing `/`, and names that aren't UTF-8, which a request path
+    // couldn't name, are left out.
+    fn list_directory(&self, request: &Request, path: &str, dir_path: &Path) -> Option<Response> {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Some(Response::new(405).with_header("Allow", "GET, HEAD"));
+        }
+
+        let mut names: Vec<String> = fs::read_dir(dir_path)
+            .ok()?
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let mut name = entry.file_name().into_string().ok()?;
+                if entry.path().is_dir() {
+                    name.push('/');
+                }
+                Some(name)
+            })
+            .collect();
+        names.sort_unstable();
+        if path != "/" {
+            names.insert(0, String::from("../"));
+        }
+
+        let title = format!("Index of {}", error_page::escape_html(path));
+        let mut body = format!(
+            "<!doctype html>\n<html lang=\"en\">\n    <head>\n        <meta charset=\"utf-8\" />\n        <title>{title}</title>\n    </head>\n    <body>\n        <h1>{title}</h1>\n        <ul>\n"
+        );
+        for name in &names {
+            // A relative link, so the listing works under any prefix. A
+            // name with a `:` in it is encoded too, so it can't pass for a
+            // scheme.
+            let href = uri::percent_encode_path(name);
+            let text = error_page::escape_html(name);
+            body.push_str(&format!(
+                "            <li><a href=\"{href}\">{text}</a></li>\n"
+            ));
+        }
+        body.push_str("        </ul>\n    </body>\n</html>\n");
+
+        let response = Response::new(200).with_header(
+            "Content-Type",
+            mime::content_type("text/html", &self.charset),
+        );
+
+        // HEAD gets the same headers as GET, but no body.
+        if *request.method() == Method::Head {
+            return Some(response.with_header("Content-Length", body.len().to_string()));
+        }
+
+        Some(response.with_body(body))
+    }
+
+    // The `Cache-Control` value to send with the file at `file_path`.
+    fn cache_control_for(&self, file_path: &Path) -> Option<&str> {
+        let name = file_path.file_name()?.to_str()?;
+        self.cache_rules
+            .iter()
+            .find(|(pattern, _)| matches_pattern(pattern.as_bytes(), name.as_bytes()))
+            .map(|(_, value)| value.as_str())
+            .or(self.cache_control.as_deref())
+    }
+
+    // Map a request path onto a file under the root, refusing anything that
+    // would escape it (such as `..` segments). A leading `/` is taken as the
+    // root itself.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let mut file_path = self.root.clone();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(segment) => file_path.push(segment),
+                Component::RootDir | Component::CurDir => {}
+                _ => return None,
+            }
+        }
+
+        Some(file_path)
+    }
+}
+
+// Whether `name` matches `pattern`, where `*` matches any run of bytes and
+// `?` any single byte. A `*` that fails to match is retried one byte
+// further along, so this takes no more than quadratic time.
+fn matches_pattern(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // The last `*` and where it matched from
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&b) if b == b'?' || b == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+// Whether an If-Range validator matches the current file. An entity tag is
+// compared with the strong comparison function, so weak tags never match. A
+// date must equal the Last-Modified time exactly.
+fn if_range_matches(validator: &str, etag: &str, modified: u64) -> bool {
+    let validator = validator.trim();
+
+    if validator.starts_with("W/") {
+        return false;
+    }
+    if validator.starts_with('"') {
+        return validator == etag;
+    }
+
+    match http_date::parse(validator) {
+        Some(date) => date == UNIX_EPOCH + Duration::from_secs(modified),
+        None => false,
+    }
+}
+
+// Evaluate a `Range: bytes=...` header against a file of `length` bytes.
+//
+// Only a single range is supported. Anything else, including syntax we don't
+// understand, is ignored and the whole file is served, which the spec allows.
+fn parse_range(value: &str, length: u64) -> ByteRange {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Ignore,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some(bounds) => bounds,
+        None => return ByteRange::Ignore,
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // "bytes=500-999"
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(length.saturating_sub(1))),
+        // "bytes=500-"
+        (Ok(start), Err(_)) if end.is_empty() => (start, length.saturating_sub(1)),
+        // "bytes=-500", the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (length.saturating_sub(suffix), length.saturating_sub(1))
+        }
+        _ => return ByteRange::Ignore,
+    };
+
+    if start >= length {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Satisfiable(start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::request::Limits;
+
+    const ETAG: &str = "\"5f5e1000-64\"";
+    const MODIFIED: u64 = 0x5f5e_1000;
+
+    // A fresh directory under the system temp dir holding `name`.
+    fn root_with(name: &str, contents: &[u8]) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "web_server-static-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(name), contents).unwrap();
+        root
+    }
+
+    fn get(path: &str, headers: &str) -> Request {
+        let head = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n");
+        Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn a_range_with_a_matching_if_range_is_partial() {
+        let files = StaticFiles::new(root_with("file.txt", b"0123456789"));
+        let full = files.serve(&get("/file.txt", "")).unwrap();
+        let etag = full.header("ETag").unwrap();
+
+        let headers = format!("Range: bytes=2-5\r\nIf-Range: {etag}\r\n");
+        let response = files.serve(&get("/file.txt", &headers)).unwrap();
+        assert_eq!(response.status(), 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-5/10"));
+        assert_eq!(response.body(), b"2345");
+
+        let modified = full.header("Last-Modified").unwrap();
+        let headers = format!("Range: bytes=2-5\r\nIf-Range: {modified}\r\n");
+        let response = files.serve(&get("/file.txt", &headers)).unwrap();
+        assert_eq!(response.status(), 206);
+    }
+
+    #[test]
+    fn a_range_with_a_stale_if_range_gets_the_whole_file() {
+        let files = StaticFiles::new(root_with("file.txt", b"0123456789"));
+
+        let headers = "Range: bytes=2-5\r\nIf-Range: \"0-0\"\r\n";
+        let response = files.serve(&get("/file.txt", headers)).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.header("Content-Range"), None);
+        assert_eq!(response.body(), b"0123456789");
+
+        let headers = "Range: bytes=2-5\r\nIf-Range: Thu, 01 Jan 1970 00:00:00 GMT\r\n";
+        let response = files.serve(&get("/file.txt", headers)).unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn if_range_with_the_current_etag_matches() {
+        assert!(if_range_matches(ETAG, ETAG, MODIFIED));
+    }
+
+    #[test]
+    fn if_range_with_an_old_etag_does_not_match() {
+        assert!(!if_range_matches("\"5f5e0fff-64\"", ETAG, MODIFIED));
+    }
+
+    #[test]
+    fn if_range_with_a_weak_etag_never_matches() {
+        assert!(!if_range_matches(&format!("W/{ETAG}"), ETAG, MODIFIED));
+    }
+
+    #[test]
+    fn if_range_with_the_last_modified_date_matches() {
+        let date = http_date::format(UNIX_EPOCH + Duration::from_secs(MODIFIED));
+        assert!(if_range_matches(&date, ETAG, MODIFIED));
+    }
+
+    #[test]
+    fn if_range_with_another_date_does_not_match() {
+        let date = http_date::format(UNIX_EPOCH + Duration::from_secs(MODIFIED - 1));
+        assert!(!if_range_matches(&date, ETAG, MODIFIED));
+        assert!(!if_range_matches("yesterday", ETAG, MODIFIED));
+    }
+
+    #[test]
+    fn ranges_are_clamped_to_the_file() {
+        assert!(matches!(
+            parse_range("bytes=0-9", 100),
+            ByteRange::Satisfiable(0, 9)
+        ));
+        assert!(matches!(
+            parse_range("bytes=90-", 100),
+            ByteRange::Satisfiable(90, 99)
+        ));
+        assert!(matches!(
+            parse_range("bytes=-10", 100),
+            ByteRange::Satisfiable(90, 99)
+        ));
+        assert!(matches!(
+            parse_range("bytes=50-500", 100),
+            ByteRange::Satisfiable(50, 99)
+        ));
+        assert!(matches!(
+            parse_range("bytes=100-", 100),
+            ByteRange::Unsatisfiable
+        ));
+        assert!(matches!(
+            parse_range("bytes=0-1,5-6", 100),
+            ByteRange::Ignore
+        ));
+    }
+
+    #[test]
+    fn text_files_are_served_with_the_configured_charset() {
+        let root = root_with("index.html", b"<p>caf\xe9</p>");
+        fs::write(root.join("logo.png"), b"\x89PNG").unwrap();
+
+        let files = StaticFiles::new(&root);
+        let html = files.serve(&get("/index.html", "")).unwrap();
+        assert_eq!(
+            html.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+        let png = files.serve(&get("/logo.png", "")).unwrap();
+        assert_eq!(png.header("Content-Type"), Some("image/png"));
+
+        let files = StaticFiles::new(&root).with_charset("iso-8859-1");
+        let html = files.serve(&get("/index.html", "")).unwrap();
+        assert_eq!(
+            html.header("Content-Type"),
+            Some("text/html; charset=iso-8859-1")
+        );
+    }
+
+    #[test]
+    fn a_directory_listing_escapes_names_and_encodes_links() {
+        let root = root_with("my file #1.txt", b"");
+        fs::write(root.join("<b>&.txt"), b"").unwrap();
+        fs::write(root.join("c:d"), b"").unwrap();
+        fs::create_dir_all(root.join("sub dir")).unwrap();
+
+        let files = StaticFiles::new(&root).with_directory_listing(true);
+        let response = files.serve(&get("/", "")).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(
+            body.contains(r#"<a href="%3Cb%3E%26.txt">&lt;b&gt;&amp;.txt</a>"#),
+            "{body}"
+        );
+        assert!(body.contains(r#"<a href="c%3Ad">c:d</a>"#), "{body}");
+        assert!(
+            body.contains(r#"<a href="my%20file%20%231.txt">my file #1.txt</a>"#),
+            "{body}"
+        );
+        assert!(
+            body.contains(r#"<a href="sub%20dir/">sub dir/</a>"#),
+            "{body}"
+        );
+        assert!(!body.contains("../"), "{body}");
+
+        let response = files.serve(&get("/sub%20dir/", "")).unwrap();
+        let body = String::from_utf8(response.body().to_vec()).unwrap();
+        assert!(body.contains("<title>Index of /sub dir/</title>"), "{body}");
+        assert!(body.contains(r#"<a href="../">../</a>"#), "{body}");
+    }
+
+    #[test]
+    fn directories_are_not_listed_by_default() {
+        let files = StaticFiles::new(root_with("file.txt", b""));
+        assert!(files.serve(&get("/", "")).is_none());
+    }
+
+    #[test]
+    fn a_directory_without_its_slash_redirects_to_the_slashed_path() {
+        let root = root_with("file.txt", b"");
+        fs::create_dir_all(root.join("my docs")).unwrap();
+
+        let files = StaticFiles::new(&root);
+        let response = files.serve(&get("/my%20docs?page=2", "")).unwrap();
+        assert_eq!(response.status(), 301);
+        assert_eq!(response.header("Location"), Some("/my%20docs/?page=2"));
+    }
+
+    // Serve `/about` and the like from `about.html`.
+    fn html_for_extensionless(path: &str) -> Option<PathBuf> {
+        let mut file = PathBuf::from(path);
+        if !path.ends_with('/') && file.extension().is_none() {
+            file.set_extension("html");
+        }
+        Some(file)
+    }
+
+    #[test]
+    fn a_path_mapper_serves_extensionless_paths_from_html_files() {
+        let root = root_with("about.html", b"<h1>About</h1>");
+        fs::write(root.join("logo.txt"), b"logo").unwrap();
+        let files = StaticFiles::new(&root).with_path_mapper(html_for_extensionless);
+
+        let about = files.serve(&get("/about", "")).unwrap();
+        assert_eq!(about.status(), 200);
+        assert_eq!(about.body(), b"<h1>About</h1>");
+        assert_eq!(
+            about.header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        // A path with an extension is left alone.
+        assert_eq!(files.serve(&get("/logo.txt", "")).unwrap().body(), b"logo");
+        assert!(files.serve(&get("/missing", "")).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_mapping_that_escapes_the_root_or_returns_none_finds_nothing() {
+        let root = root_with("inside.txt", b"inside");
+        let escaping = StaticFiles::new(root.join("public"))
+            .with_path_mapper(|_| Some(PathBuf::from("../inside.txt")));
+        assert!(escaping.serve(&get("/anything", "")).is_none());
+
+        let refusing = StaticFiles::new(&root).with_path_mapper(|_| None);
+        assert!(refusing.serve(&get("/inside.txt", "")).is_none());
+
+        // Stripping a prefix.
+        let prefixed = StaticFiles::new(&root)
+            .with_path_mapper(|path| path.strip_prefix("/static/").map(PathBuf::from));
+        let response = prefixed.serve(&get("/static/inside.txt", "")).unwrap();
+        assert_eq!(response.body(), b"inside");
+        assert!(prefixed.serve(&get("/inside.txt", "")).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_default_cache_control_is_sent_with_a_served_file() {
+        let root = root_with("style.css", b"body {}");
+        let files = StaticFiles::new(&root).with_cache_control("public, max-age=3600");
+
+        let response = files.serve(&get("/style.css", "")).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.header("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+
+        let range = files
+            .serve(&get("/style.css", "Range: bytes=0-3\r\n"))
+            .unwrap();
+        assert_eq!(range.status(), 206);
+        assert_eq!(range.header("Cache-Control"), Some("public, max-age=3600"));
+
+        // Nothing is cached without a default.
+        let plain = StaticFiles::new(&root)
+            .serve(&get("/style.css", ""))
+            .unwrap();
+        assert_eq!(plain.header("Cache-Control"), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_matching_cache_rule_overrides_the_default() {
+        let root = root_with("app.3f9a1c.js", b"run()");
+        fs::write(root.join("app.js"), b"run()").unwrap();
+        let files = StaticFiles::new(&root)
+            .with_cache_control("public, max-age=3600")
+            .with_cache_rule("*.*.js", "public, max-age=31536000, immutable")
+            .with_cache_rule("*.js", "no-cache");
+
+        let hashed = files.serve(&get("/app.3f9a1c.js", "")).unwrap();
+        assert_eq!(
+            hashed.header("Cache-Control"),
+            Some("public, max-age=31536000, immutable")
+        );
+        let plain = files.serve(&get("/app.js", "")).unwrap();
+        assert_eq!(plain.header("Cache-Control"), Some("no-cache"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn error_responses_get_no_cache_control() {
+        let root = root_with("file.txt", b"0123456789");
+        let files = StaticFiles::new(&root).with_cache_control("public, max-age=3600");
+
+        let unsatisfiable = files
+            .serve(&get("/file.txt", "Range: bytes=50-60\r\n"))
+            .unwrap();
+        assert_eq!(unsatisfiable.status(), 416);
+        assert_eq!(unsatisfiable.header("Cache-Control"), None);
+
+        let head = "POST /file.txt HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let post = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        let not_allowed = files.serve(&post).unwrap();
+        assert_eq!(not_allowed.status(), 405);
+        assert_eq!(not_allowed.header("Cache-Control"), None);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn patterns_match_stars_and_question_marks() {
+        let matches =
+            |pattern: &str, name: &str| matches_pattern(pattern.as_bytes(), name.as_bytes());
+        assert!(matches("*.woff2", "font.woff2"));
+        assert!(!matches("*.woff2", "font.woff"));
+        assert!(matches("*.*.js", "app.3f9a1c.js"));
+        assert!(!matches("*.*.js", "app.js"));
+        assert!(matches("logo.???", "logo.png"));
+        assert!(!matches("logo.???", "logo.webp"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+    }
+}
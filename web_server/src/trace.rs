@@ -0,0 +1,194 @@
+//! Spans and events for the optional `tracing` feature.
+//!
+//! With the feature on, connections, requests and jobs each run inside a
+//! `tracing` span, and failures are reported as events. With it off, every
+//! function here is an empty inline function returning an empty guard, so
+//! the call sites compile away to nothing.
+
+use std::{fmt::Display, net::IpAddr, time::Duration};
+
+use crate::request::Request;
+
+/// Keeps a span entered until it is dropped.
+#[cfg(feature = "tracing")]
+pub(crate) type Entered = tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Entered;
+
+// A span covering one connection, from accept to close.
+#[cfg(feature = "tracing")]
+pub(crate) fn connection(remote_ip: Option<IpAddr>) -> Entered {
+    tracing::info_span!(
+        "connection",
+        remote_ip = remote_ip.map(tracing::field::display)
+    )
+    .entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn connection(_remote_ip: Option<IpAddr>) -> Entered {
+    Entered
+}
+
+// A span covering one request, from its head being read to its response
+// being written.
+#[cfg(feature = "tracing")]
+pub(crate) fn request(request: &Request) -> Entered {
+    tracing::info_span!(
+        "request",
+        method = request.method().as_str(),
+        path = request.path()
+    )
+    .entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn request(_request: &Request) -> Entered {
+    Entered
+}
+
+// A span covering one job run by the pool.
+#[cfg(feature = "tracing")]
+pub(crate) fn job(worker: usize) -> Entered {
+    tracing::debug_span!("job", worker).entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn job(_worker: usize) -> Entered {
+    Entered
+}
+
+// Report something unusual but harmless, within whatever span is current.
+#[cfg(feature = "tracing")]
+pub(crate) fn debug(message: &str, err: &dyn Display) {
+    tracing::debug!(error = %err, "{message}");
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn debug(_message: &str, _err: &dyn Display) {}
+
+// Report a failure, within whatever span is current.
+#[cfg(feature = "tracing")]
+pub(crate) fn error(message: &str, err: &dyn Display) {
+    tracing::error!(error = %err, "{message}");
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn error(_message: &str, _err: &dyn Display) {}
+
+// Report a request that took longer than it should have to answer.
+#[cfg(feature = "tracing")]
+pub(crate) fn slow_request(request: &Request, duration: Duration) {
+    tracing::warn!(
+        method = request.method().as_str(),
+        path = request.path(),
+        duration_ms = duration.as_millis() as u64,
+        "slow request"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn slow_request(_request: &Request, _duration: Duration) {}
+
+// What a test's code logged on one thread.
+#[cfg(all(test, feature = "tracing"))]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Captured {
+    pub(crate) events: Vec<(tracing::Level, String)>, // Each event's level and message
+    pub(crate) spans: Vec<String>, // Each span's name and fields, e.g. `job worker=0`
+    pub(crate) fields: Vec<String>, // Each event's fields, e.g. ` path=/a`, in `events` order
+}
+
+// Run `f` and return what it logged on this thread along the way.
+#[cfg(all(test, feature = "tracing"))]
+pub(crate) fn capture<T>(f: impl FnOnce() -> T) -> (T, Captured) {
+    use std::{
+        fmt::{self, Write as _},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    #[derive(Default)]
+    struct Capture {
+        captured: Arc<Mutex<Captured>>,
+        next_id: AtomicU64,
+    }
+
+    // Collects an event's message, or a span's fields as `name=value`.
+    #[derive(Default)]
+    struct Fields {
+        message: String,
+        rendered: String,
+    }
+
+    impl Fields {
+        fn push(&mut self, field: &Field, value: fmt::Arguments<'_>) {
+            if field.name() == "message" {
+                self.message = value.to_string();
+            } else {
+                let _ = write!(self.rendered, " {}={value}", field.name());
+            }
+        }
+    }
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.push(field, format_args!("{value}"));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.push(field, format_args!("{value:?}"));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            let mut fields = Fields::default();
+            span.record(&mut fields);
+            let name = span.metadata().name();
+            let mut captured = self.captured.lock().unwrap();
+            captured.spans.push(format!("{name}{}", fields.rendered));
+            span::Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Fields::default();
+            event.record(&mut fields);
+            let level = *event.metadata().level();
+            let mut captured = self.captured.lock().unwrap();
+            captured.events.push((level, fields.message));
+            captured.fields.push(fields.rendered);
+        }
+
+        fn enter(&self, _: &span::Id) {}
+
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let capture = Capture::default();
+    let captured = Arc::clone(&capture.captured);
+    let result = tracing::subscriber::with_default(capture, f);
+    let captured = captured.lock().unwrap().clone();
+    (result, captured)
+}
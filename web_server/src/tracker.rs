@@ -0,0 +1,190 @@
+//! Counting the connections the server holds open, and which of them are
+//! idle between requests.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::shutdown::Waker;
+
+/// Keeps count of the open connections, for `Config::max_connections`, and
+/// of those idling between keep-alive requests, for
+/// `Config::max_idle_connections`.
+///
+/// A connection counts as open from the moment it is accepted, so those
+/// still queued for a worker count too, and as idle while it waits for a
+/// request after the first one. Idle connections are remembered in the
+/// order they went idle, so the one that has waited longest can be closed
+/// to make room for another. Clones share the same counts.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    state: Arc<Mutex<TrackerState>>, // Shared by every clone
+}
+
+#[derive(Default)]
+struct TrackerState {
+    open: usize,                        // Connections accepted and not yet closed
+    next_id: u64,                       // Id for the next connection to go idle
+    idle: BTreeMap<u64, Option<Waker>>, // Idle connections, longest idle first
+}
+
+impl ConnectionTracker {
+    /// Create a tracker with no connections.
+    pub fn new() -> ConnectionTracker {
+        ConnectionTracker::default()
+    }
+
+    /// The number of connections open, whether busy, idle or waiting for a
+    /// worker.
+    pub fn open_connections(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    /// The number of keep-alive connections waiting for their next request.
+    pub fn idle_connections(&self) -> usize {
+        self.state.lock().unwrap().idle.len()
+    }
+
+    // Count a new connection as open until the returned guard is dropped,
+    // unless `max` connections are open already.
+    pub(crate) fn open(&self, max: Option<usize>) -> Option<OpenConnection> {
+        let mut state = self.state.lock().unwrap();
+        if max.is_some_and(|max| state.open >= max) {
+            return None;
+        }
+        state.open += 1;
+
+        Some(OpenConnection {
+            tracker: self.clone(),
+        })
+    }
+
+    // Make sure one more connection can go idle without passing `max`, by
+    // closing the one idle longest if need be. Returns `false` if there is
+    // no room and none of the idle connections can be closed.
+    pub(crate) fn make_room_for_idle(&self, max: Option<usize>) -> bool {
+        let Some(max) = max else {
+            return true;
+        };
+
+        let evicted = {
+            let mut state = self.state.lock().unwrap();
+            if state.idle.len() < max {
+                return true;
+            }
+            let Some(id) = state
+                .idle
+                .iter()
+                .find(|(_, waker)| waker.is_some())
+                .map(|(id, _)| *id)
+            else {
+                return false;
+            };
+            state.idle.remove(&id).flatten()
+        };
+
+        // Close outside the lock, like `ShutdownToken::trigger` wakes. The
+        // connection sees its stream end and goes.
+        if let Some(waker) = evicted {
+            waker();
+        }
+        true
+    }
+
+    // Count a connection as idle until the returned guard is dropped.
+    // `waker` is what closes it if room is needed for another.
+    pub(crate) fn idle(&self, waker: Option<&Waker>) -> IdleConnection<'_> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.idle.insert(id, waker.cloned());
+
+        IdleConnection { tracker: self, id }
+    }
+}
+
+// Counts a connection as open until it is dropped.
+pub(crate) struct OpenConnection {
+    tracker: ConnectionTracker, // The tracker counting the connection
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.tracker.state.lock().unwrap().open -= 1;
+    }
+}
+
+// Counts a connection as idle until it is dropped.
+pub(crate) struct IdleConnection<'a> {
+    tracker: &'a ConnectionTracker, // The tracker counting the connection
+    id: u64,                        // The connection's place among the idle
+}
+
+impl Drop for IdleConnection<'_> {
+    fn drop(&mut self) {
+        // A connection closed to make room is already gone from the map.
+        self.tracker.state.lock().unwrap().idle.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // A waker that counts how often it was called.
+    fn counting_waker() -> (Waker, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let waker: Waker = Arc::new(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        (waker, calls)
+    }
+
+    #[test]
+    fn connections_past_the_limit_arent_opened() {
+        let tracker = ConnectionTracker::new();
+        let first = tracker.open(Some(2)).unwrap();
+        let _second = tracker.open(Some(2)).unwrap();
+        assert!(tracker.open(Some(2)).is_none());
+        assert_eq!(tracker.open_connections(), 2);
+
+        drop(first);
+        assert!(tracker.open(Some(2)).is_some());
+        assert!(tracker.open(None).is_some(), "no limit");
+    }
+
+    #[test]
+    fn room_is_made_by_closing_the_connection_idle_longest() {
+        let tracker = ConnectionTracker::new();
+        let (oldest, oldest_closed) = counting_waker();
+        let (newest, newest_closed) = counting_waker();
+        let _oldest = tracker.idle(Some(&oldest));
+        let _newest = tracker.idle(Some(&newest));
+        assert_eq!(tracker.idle_connections(), 2);
+
+        assert!(tracker.make_room_for_idle(Some(3)), "there is room already");
+        assert_eq!(oldest_closed.load(Ordering::SeqCst), 0);
+
+        assert!(tracker.make_room_for_idle(Some(2)));
+        assert_eq!(oldest_closed.load(Ordering::SeqCst), 1);
+        assert_eq!(newest_closed.load(Ordering::SeqCst), 0);
+        assert_eq!(tracker.idle_connections(), 1);
+    }
+
+    #[test]
+    fn without_a_closable_idle_connection_there_is_no_room() {
+        let tracker = ConnectionTracker::new();
+        let idle = tracker.idle(None);
+        assert!(!tracker.make_room_for_idle(Some(1)));
+        assert!(!tracker.make_room_for_idle(Some(0)));
+        assert!(tracker.make_room_for_idle(None));
+
+        drop(idle);
+        assert_eq!(tracker.idle_connections(), 0);
+        assert!(tracker.make_room_for_idle(Some(1)));
+    }
+}
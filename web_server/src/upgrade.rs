@@ -0,0 +1,48 @@
+//! Handing a connection over to another protocol, such as WebSocket, once
+//! the server has answered `101 Switching Protocols`.
+//!
+//! A handler that accepts an upgrade the client asked for, which
+//! `Request::wants_upgrade` checks, answers with `Response::upgrade`. The
+//! server sends that response and then calls the callback it carries with
+//! the raw connection, on the same worker, and reads no further HTTP from
+//! it. The connection closes when the callback returns.
+//!
+//! ```no_run
+//! use std::io::{self, Read, Write};
+//! use web_server::{prelude::*, upgrade};
+//!
+//! // A made-up protocol that echoes whatever the client sends.
+//! let echo = |request: &Request| {
+//!     if !request.wants_upgrade("echo") {
+//!         return Response::new(426).with_header("Upgrade", "echo");
+//!     }
+//!     Response::upgrade("echo", |stream: &mut dyn upgrade::Stream| {
+//!         let mut buffer = [0; 1024];
+//!         loop {
+//!             match stream.read(&mut buffer) {
+//!                 Ok(0) => break,
+//!                 Ok(n) => stream.write_all(&buffer[..n]).unwrap(),
+//!                 Err(err) if err.kind() == io::ErrorKind::TimedOut => continue,
+//!                 Err(_) => break,
+//!             }
+//!         }
+//!     })
+//! };
+//! ```
+
+use std::io::{Read, Write};
+
+/// The raw connection an upgrade callback is given.
+///
+/// Reads may first return data the client sent right behind its request,
+/// which the server had already buffered. The connection still has the
+/// keep-alive timeout as its read timeout, so a protocol that idles for
+/// longer should treat a timed-out read as a reason to read again rather
+/// than an error. On shutdown, the connection is cut off like any other
+/// that is still open when the grace period ends.
+pub trait Stream: Read + Write {}
+
+impl<T: Read + Write + ?Sized> Stream for T {}
+
+// Takes over a connection once the `101` response has been sent.
+pub(crate) type OnUpgrade = Box<dyn FnOnce(&mut dyn Stream) + Send>;
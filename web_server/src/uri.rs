@@ -0,0 +1,102 @@
+//! Helpers for converting between URI paths and file system paths.
+
+/// Percent-encode `path` for use in a URI, keeping its `/` separators.
+///
+/// Every byte outside the unreserved set (letters, digits, `-`, `.`, `_`
+/// and `~`) is encoded, so `/docs/my file #1.txt` becomes
+/// `/docs/my%20file%20%231.txt`. The input is taken to be a decoded path,
+/// so a `%` is encoded too rather than read as an existing escape.
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Decode the `%XX` escapes in a URI path.
+///
+/// Returns `None` if an escape is malformed or the decoded bytes aren't
+/// valid UTF-8. A `+` is left alone, since it only stands for a space in
+/// query strings.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // `from_str_radix` would take a sign, as in `%+1`, so both
+            // digits are checked first.
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Decode a key or value from a query string or a form body.
+///
+/// This is `percent_decode` with `+` read as a space first, as
+/// `application/x-www-form-urlencoded` encodes them.
+pub fn decode_form_component(component: &str) -> Option<String> {
+    percent_decode(&component.replace('+', " "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding_escapes_spaces_and_special_characters() {
+        assert_eq!(
+            percent_encode_path("my file #1?&=%.txt"),
+            "my%20file%20%231%3F%26%3D%25.txt"
+        );
+        assert_eq!(percent_encode_path("caf\u{e9}"), "caf%C3%A9");
+        assert_eq!(percent_encode_path("a-b_c.d~e"), "a-b_c.d~e");
+    }
+
+    #[test]
+    fn encoding_keeps_the_slashes_between_segments() {
+        assert_eq!(
+            percent_encode_path("/docs/release notes/v1 2/"),
+            "/docs/release%20notes/v1%202/"
+        );
+    }
+
+    #[test]
+    fn decoding_reverses_encoding() {
+        let path = "/docs/my file #1/caf\u{e9}.txt";
+        assert_eq!(
+            percent_decode(&percent_encode_path(path)).as_deref(),
+            Some(path)
+        );
+        assert_eq!(percent_decode("/a+b%2Fc").as_deref(), Some("/a+b/c"));
+    }
+
+    #[test]
+    fn malformed_escapes_are_refused() {
+        for path in ["/%", "/%4", "/%zz", "/%+1", "/%-1", "/%ff"] {
+            assert_eq!(percent_decode(path), None, "{path}");
+        }
+    }
+
+    #[test]
+    fn form_components_read_plus_as_a_space() {
+        assert_eq!(decode_form_component("a+b%2Bc").as_deref(), Some("a b+c"));
+    }
+}
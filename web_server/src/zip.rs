@@ -0,0 +1,425 @@
+//! Serves static files out of a zip archive, without extracting it.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    deflate, error_page, handler::Handler, method::Method, mime, request::Request,
+    response::Response, uri,
+};
+
+/// Entries up to this size stay in memory once they have been
+/// decompressed, unless `ZipFiles::cache_limit` says otherwise.
+pub const DEFAULT_CACHE_LIMIT: usize = 64 * 1024;
+
+// Signatures of the zip records read.
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const DIRECTORY_ENTRY: u32 = 0x0201_4b50;
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+
+// Compression methods supported.
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// Serves the files in a zip archive, read into memory once at startup.
+///
+/// A request path names the entry at the same path in the archive, so
+/// `/css/app.css` is served from `css/app.css`, with a content type guessed
+/// from its extension like `StaticFiles` does. Entries are decompressed
+/// when they are requested, and those no bigger than the cache limit are
+/// kept decompressed from then on. Each entry's `ETag` comes from the
+/// checksum stored in the archive, so it stays the same as long as the
+/// entry does.
+///
+/// Entries may be stored or deflated, which is what zip tools write by
+/// default. Encrypted entries, other compression methods and archives in
+/// the zip64 format, needed for archives over 4 GiB, are refused when the
+/// archive is opened.
+///
+/// ```no_run
+/// use web_server::{prelude::*, zip::ZipFiles};
+///
+/// let assets = ZipFiles::open("assets.zip").unwrap();
+/// let router = Router::new().fallback(assets);
+/// ```
+pub struct ZipFiles {
+    archive: Vec<u8>,                         // The whole archive
+    entries: HashMap<String, Entry>,          // Files in the archive, by path
+    cache: Mutex<HashMap<String, Arc<[u8]>>>, // Small entries already decompressed
+    cache_limit: usize,                       // Biggest entry kept in `cache`
+    charset: String,                          // Charset advertised for text files
+}
+
+// Where a file's data is in the archive and how to decompress it.
+struct Entry {
+    method: u16,            // How the data is compressed
+    start: usize,           // Offset of the compressed data
+    compressed_size: usize, // Length of the compressed data
+    size: usize,            // Length of the data decompressed
+    crc: u32,               // Checksum of the data decompressed
+}
+
+impl ZipFiles {
+    /// Read the archive at `path`.
+    ///
+    /// Fails if the file can't be read or isn't a zip archive this can
+    /// serve.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<ZipFiles> {
+        ZipFiles::from_bytes(fs::read(path)?)
+    }
+
+    /// Serve the archive in `archive`, e.g. one built into the executable
+    /// with `include_bytes!`.
+    pub fn from_bytes(archive: impl Into<Vec<u8>>) -> io::Result<ZipFiles> {
+        let archive = archive.into();
+        let entries = read_directory(&archive)?;
+
+        Ok(ZipFiles {
+            archive,
+            entries,
+            cache: Mutex::default(),
+            cache_limit: DEFAULT_CACHE_LIMIT,
+            charset: String::from("utf-8"),
+        })
+    }
+
+    /// Keep entries of up to `limit` bytes decompressed once they have
+    /// been served. Zero decompresses every entry on every request.
+    pub fn cache_limit(mut self, limit: usize) -> ZipFiles {
+        self.cache_limit = limit;
+        self
+    }
+
+    /// Set the charset advertised in the `Content-Type` of text files.
+    pub fn with_charset(mut self, charset: impl Into<String>) -> ZipFiles {
+        self.charset = charset.into();
+        self
+    }
+
+    /// The paths of the files in the archive, in no particular order.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Serve the entry named by the request path.
+    ///
+    /// Returns `None` if the archive has no file at that path.
+    pub fn serve(&self, request: &Request) -> Option<Response> {
+        let path = uri::percent_decode(request.path())?;
+        let path = path.trim_start_matches('/');
+        let entry = self.entries.get(path)?;
+
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Some(Response::new(405).with_header("Allow", "GET, HEAD"));
+        }
+
+        // The client's cached copy is still current.
+        let etag = format!("\"{:08x}-{:x}\"", entry.crc, entry.size);
+        if request.header("If-None-Match") == Some(etag.as_str()) {
+            return Some(Response::new(304).with_header("ETag", etag));
+        }
+
+        let media_type = mime::from_path(Path::new(path));
+        let response = Response::new(200)
+            .with_header(
+                "Content-Type",
+                mime::content_type(media_type, &self.charset),
+            )
+            .with_header("ETag", etag);
+
+        // HEAD gets the same headers as GET, but no body.
+        if *request.method() == Method::Head {
+            return Some(response.with_header("Content-Length", entry.size.to_string()));
+        }
+
+        match self.contents(path, entry) {
+            Some(contents) => Some(response.with_body(&contents[..])),
+            None => {
+                println!("Zip entry {path} is corrupt; answering 500.");
+                Some(error_page::render(Some(request), 500))
+            }
+        }
+    }
+
+    // The decompressed contents of `entry`, from the cache if it is there.
+    fn contents(&self, path: &str, entry: &Entry) -> Option<Arc<[u8]>> {
+        if let Some(contents) = self.cache.lock().unwrap().get(path) {
+            return Some(Arc::clone(contents));
+        }
+
+        let data = &self.archive[entry.start..entry.start + entry.compressed_size];
+        let contents: Arc<[u8]> = match entry.method {
+            STORED => data.into(),
+            _ => deflate::inflate(data, entry.size)?.into(),
+        };
+        if contents.len() != entry.size || deflate::crc32(&contents) != entry.crc {
+            return None;
+        }
+
+        if entry.size <= self.cache_limit {
+            let mut cache = self.cache.lock().unwrap();
+            cache.insert(path.to_string(), Arc::clone(&contents));
+        }
+        Some(contents)
+    }
+}
+
+impl Handler for ZipFiles {
+    /// Serve the entry, or a 404 if there isn't one at the request path.
+    fn handle(&self, request: &Request) -> Response {
+        self.serve(request)
+            .unwrap_or_else(|| error_page::render(Some(request), 404))
+    }
+}
+
+// Read the archive's central directory, which lists every entry.
+fn read_directory(archive: &[u8]) -> io::Result<HashMap<String, Entry>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    // The end of directory record is the last thing in the archive, apart
+    // from a comment of up to 64 KiB.
+    let end = (0..=archive.len().saturating_sub(22))
+        .rev()
+        .take(0x1_0000)
+        .find(|&offset| read_u32(archive, offset) == Some(END_OF_DIRECTORY))
+        .ok_or_else(|| invalid("not a zip archive"))?;
+
+    let count = read_u16(archive, end + 10).unwrap_or(0);
+    let mut offset = read_u32(archive, end + 16).unwrap_or(0) as usize;
+    if count == 0xFFFF || offset == 0xFFFF_FFFF {
+        return Err(invalid("zip64 archives aren't supported"));
+    }
+
+    let mut entries = HashMap::with_capacity(usize::from(count));
+    for _ in 0..count {
+        if read_u32(archive, offset) != Some(DIRECTORY_ENTRY) {
+            return Err(invalid("malformed central directory"));
+        }
+        let field16 = |at: usize| read_u16(archive, offset + at).unwrap_or(0);
+        let field32 = |at: usize| read_u32(archive, offset + at).unwrap_or(0);
+
+        let flags = field16(8);
+        let method = field16(10);
+        let crc = field32(16);
+        let compressed_size = field32(20);
+        let size = field32(24);
+        let name_length = usize::from(field16(28));
+        let extra_length = usize::from(field16(30));
+        let comment_length = usize::from(field16(32));
+        let header = field32(42) as usize;
+
+        let name = archive
+            .get(offset + 46..offset + 46 + name_length)
+            .ok_or_else(|| invalid("malformed central directory"))?;
+        offset += 46 + name_length + extra_length + comment_length;
+
+        // Directories have no data to serve.
+        if name.ends_with(b"/") {
+            continue;
+        }
+        if flags & 1 != 0 {
+            return Err(invalid("encrypted entries aren't supported"));
+        }
+        if method != STORED && method != DEFLATED {
+            return Err(invalid("unsupported compression method"));
+        }
+        if compressed_size == 0xFFFF_FFFF || size == 0xFFFF_FFFF || header == 0xFFFF_FFFF {
+            return Err(invalid("zip64 archives aren't supported"));
+        }
+        // A name that isn't UTF-8 could never be asked for.
+        let Ok(name) = String::from_utf8(name.to_vec()) else {
+            continue;
+        };
+
+        // The data follows the entry's local header, whose name and extra
+        // field may differ in length from the central directory's.
+        if read_u32(archive, header) != Some(LOCAL_HEADER) {
+            return Err(invalid("malformed local header"));
+        }
+        let local_name = usize::from(read_u16(archive, header + 26).unwrap_or(0));
+        let local_extra = usize::from(read_u16(archive, header + 28).unwrap_or(0));
+        let start = header + 30 + local_name + local_extra;
+        if start + compressed_size as usize > archive.len() {
+            return Err(invalid("entry extends past the end of the archive"));
+        }
+
+        entries.insert(
+            name,
+            Entry {
+                method,
+                start,
+                compressed_size: compressed_size as usize,
+                size: size as usize,
+                crc,
+            },
+        );
+    }
+
+    Ok(entries)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request::Limits;
+
+    use super::*;
+
+    // Build an archive holding `files`, deflating those marked so.
+    fn archive(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+
+        for &(name, contents, deflated) in files {
+            // A gzip member is a raw DEFLATE stream between a 10 byte
+            // header and an 8 byte trailer.
+            let data = if deflated {
+                let member = deflate::gzip(contents);
+                member[10..member.len() - 8].to_vec()
+            } else {
+                contents.to_vec()
+            };
+            let method = if deflated { DEFLATED } else { STORED };
+            let header = archive.len() as u32;
+
+            // The fields the local header shares with the directory entry:
+            // version, flags, method, time, date, checksum and sizes.
+            let mut common = Vec::new();
+            common.extend_from_slice(&20u16.to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes());
+            common.extend_from_slice(&method.to_le_bytes());
+            common.extend_from_slice(&[0; 4]);
+            common.extend_from_slice(&deflate::crc32(contents).to_le_bytes());
+            common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            common.extend_from_slice(&0u16.to_le_bytes());
+
+            archive.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            archive.extend_from_slice(&common);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&data);
+
+            directory.extend_from_slice(&DIRECTORY_ENTRY.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes());
+            directory.extend_from_slice(&common);
+            // No comment, disk 0, no attributes, then the header's offset.
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&header.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+
+        let start = archive.len() as u32;
+        let count = files.len() as u16;
+        archive.extend_from_slice(&directory);
+        archive.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&count.to_le_bytes());
+        archive.extend_from_slice(&count.to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&start.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive
+    }
+
+    fn get(files: &ZipFiles, method: &str, path: &str, extra: &str) -> Response {
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: example.com\r\n{extra}\r\n");
+        let request = Request::read_from(&mut head.as_bytes(), &Limits::default()).unwrap();
+        files.handle(&request)
+    }
+
+    const CSS: &[u8] = b"body { color: red; }\nbody { color: red; }\n";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n not really an image";
+
+    fn assets() -> ZipFiles {
+        ZipFiles::from_bytes(archive(&[
+            ("css/app.css", CSS, true),
+            ("logo.png", PNG, false),
+            ("images/", b"", false),
+        ]))
+        .unwrap()
+    }
+
+    #[test]
+    fn serves_stored_and_deflated_entries_with_their_content_types() {
+        let files = assets();
+        let mut paths: Vec<_> = files.paths().collect();
+        paths.sort_unstable();
+        assert_eq!(paths, ["css/app.css", "logo.png"]);
+
+        let css = get(&files, "GET", "/css/app.css", "");
+        assert_eq!(css.status(), 200);
+        assert_eq!(css.body(), CSS);
+        assert_eq!(css.header("Content-Type"), Some("text/css; charset=utf-8"));
+
+        let png = get(&files, "GET", "/logo.png", "");
+        assert_eq!(png.status(), 200);
+        assert_eq!(png.body(), PNG);
+        assert_eq!(png.header("Content-Type"), Some("image/png"));
+    }
+
+    #[test]
+    fn missing_entries_and_directories_are_not_found() {
+        let files = assets();
+        assert_eq!(get(&files, "GET", "/missing.js", "").status(), 404);
+        assert_eq!(get(&files, "GET", "/images/", "").status(), 404);
+        assert_eq!(get(&files, "POST", "/logo.png", "").status(), 405);
+    }
+
+    #[test]
+    fn head_and_conditional_requests_skip_the_body() {
+        let files = assets();
+        let head = get(&files, "HEAD", "/css/app.css", "");
+        assert!(head.body().is_empty());
+        let length = CSS.len().to_string();
+        assert_eq!(head.header("Content-Length"), Some(length.as_str()));
+
+        let etag = head.header("ETag").unwrap().to_string();
+        let cached = get(
+            &files,
+            "GET",
+            "/css/app.css",
+            &format!("If-None-Match: {etag}\r\n"),
+        );
+        assert_eq!(cached.status(), 304);
+        assert!(cached.body().is_empty());
+    }
+
+    #[test]
+    fn small_entries_are_cached_once_served() {
+        let files = assets().cache_limit(PNG.len());
+        get(&files, "GET", "/css/app.css", "");
+        get(&files, "GET", "/logo.png", "");
+        let cache = files.cache.lock().unwrap();
+        assert!(cache.contains_key("logo.png"));
+        assert!(!cache.contains_key("css/app.css"), "bigger than the limit");
+    }
+
+    #[test]
+    fn a_corrupt_entry_is_a_server_error() {
+        let mut bytes = archive(&[("a.txt", b"hello", false)]);
+        // Flip a byte of the stored data, after the 30 byte header and name.
+        bytes[35] ^= 0xFF;
+        let files = ZipFiles::from_bytes(bytes).unwrap();
+        assert_eq!(get(&files, "GET", "/a.txt", "").status(), 500);
+    }
+
+    #[test]
+    fn refuses_what_is_not_a_zip_archive() {
+        let result = ZipFiles::from_bytes(&b"not an archive at all, just some text"[..]);
+        assert!(result.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+    }
+}